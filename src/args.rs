@@ -1,10 +1,17 @@
 use anstyle::{AnsiColor, Color::Ansi, Style};
 use clap::builder::styling::Styles;
+use clap::builder::PossibleValuesParser;
 use clap::{Parser, Subcommand};
 
 use std::io::{Error, ErrorKind};
 use std::path::PathBuf;
 
+use crate::config;
+use crate::context::Context;
+use crate::export;
+use crate::filetype::{self, TypeFilter};
+use crate::util::{self, PathStyle, PermissionPolicy, Protection, Strategy};
+
 const CMD_STYLE: Style = Style::new()
     .bold()
     .fg_color(Some(Ansi(AnsiColor::BrightCyan)));
@@ -62,6 +69,292 @@ Print the graveyard path
 
 {header}Usage{rheader}: {rip_s}rip graveyard{rrip_s} [{place}OPTIONS{rplace}]
 
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "log" => format!(
+            "\
+Print the log of graves removed from the graveyard
+
+{header}Usage{rheader}: {rip_s}rip log{rrip_s} [{place}OPTIONS{rplace}]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "which" => format!(
+            "\
+Print when a path was restored from the graveyard, and from which grave
+
+{header}Usage{rheader}: {rip_s}rip which{rrip_s} <{place}PATH{rplace}>
+
+{header}Arguments{rheader}:
+    <{place}PATH{rplace}>  Path to look up
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "restore-session" => format!(
+            "\
+Restore everything buried recently, from any directory
+
+{header}Usage{rheader}: {rip_s}rip restore-session{rrip_s} [{place}OPTIONS{rplace}]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "recent" => format!(
+            "\
+Print the most recent graves from a directory
+
+{header}Usage{rheader}: {rip_s}rip recent{rrip_s} [{place}OPTIONS{rplace}]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "stats" => format!(
+            "\
+Print the size of the graveyard, or how it has grown
+
+{header}Usage{rheader}: {rip_s}rip stats{rrip_s} [{place}OPTIONS{rplace}]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "note" => format!(
+            "\
+Attach a note to a grave, shown during seance
+
+{header}Usage{rheader}: {rip_s}rip note{rrip_s} <{place}GRAVE{rplace}> <{place}TEXT{rplace}>
+
+{header}Arguments{rheader}:
+    <{place}GRAVE{rplace}>  Path of the grave in the graveyard
+    <{place}TEXT{rplace}>   The note, or an empty string to remove it
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "diff" => format!(
+            "\
+Compare a grave with a file, or with another grave
+
+{header}Usage{rheader}: {rip_s}rip diff{rrip_s} <{place}GRAVE{rplace}> [{place}OTHER{rplace}]
+
+{header}Arguments{rheader}:
+    <{place}GRAVE{rplace}>  Path of the grave in the graveyard, or @ID
+    [{place}OTHER{rplace}]  Path or @ID to compare it with; the grave's original path if not given
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "peek" => format!(
+            "\
+List what's in a directory grave
+
+{header}Usage{rheader}: {rip_s}rip peek{rrip_s} <{place}GRAVE{rplace}>
+
+{header}Arguments{rheader}:
+    <{place}GRAVE{rplace}>  Path of the grave in the graveyard, or @ID
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "doctor" => format!(
+            "\
+Check the graveyard for problems
+
+{header}Usage{rheader}: {rip_s}rip doctor{rrip_s} [{place}OPTIONS{rplace}]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "config" => format!(
+            "\
+Show, change, export or import the config file
+
+{header}Usage{rheader}: {rip_s}rip config{rrip_s} <{place}SUBCOMMAND{rplace}>
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+
+{header}Subcommands{rheader}:
+{SUBCOMMANDS_PLACEHOLDER}
+"
+        ),
+        "config show" => format!(
+            "\
+Print the config file
+
+{header}Usage{rheader}: {rip_s}rip config show{rrip_s} [{place}OPTIONS{rplace}]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "config export" => format!(
+            "\
+Print the settings in force as a config file
+
+{header}Usage{rheader}: {rip_s}rip config export{rrip_s}
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "config import" => format!(
+            "\
+Replace the config file with FILE
+
+{header}Usage{rheader}: {rip_s}rip config import{rrip_s} <{place}FILE{rplace}>
+
+{header}Arguments{rheader}:
+    <{place}FILE{rplace}>  Config file to import, e.g. from `rip config export`
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "config list" => format!(
+            "\
+List every setting, its environment variable, and its value in the config file
+
+{header}Usage{rheader}: {rip_s}rip config list{rrip_s}
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "config get" => format!(
+            "\
+Print the value of KEY in the config file
+
+{header}Usage{rheader}: {rip_s}rip config get{rrip_s} <{place}KEY{rplace}>
+
+{header}Arguments{rheader}:
+    <{place}KEY{rplace}>  Setting to print, e.g. `strategy`
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "config set" => format!(
+            "\
+Set KEY to VALUE in the config file
+
+{header}Usage{rheader}: {rip_s}rip config set{rrip_s} <{place}KEY{rplace}> <{place}VALUE{rplace}>
+
+{header}Arguments{rheader}:
+    <{place}KEY{rplace}>    Setting to change, e.g. `strategy`
+    <{place}VALUE{rplace}>  New value, checked like the corresponding flag
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "config unset" => format!(
+            "\
+Remove KEY from the config file
+
+{header}Usage{rheader}: {rip_s}rip config unset{rrip_s} <{place}KEY{rplace}>
+
+{header}Arguments{rheader}:
+    <{place}KEY{rplace}>  Setting to remove, e.g. `strategy`
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "config edit" => format!(
+            "\
+Open the config file in $VISUAL or $EDITOR, and only keep the changes if they are valid
+
+{header}Usage{rheader}: {rip_s}rip config edit{rrip_s}
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "purge" => format!(
+            "\
+Permanently delete selected graves
+
+{header}Usage{rheader}: {rip_s}rip purge{rrip_s} [{place}OPTIONS{rplace}] [{place}GRAVES{rplace}]...
+
+{header}Arguments{rheader}:
+    [{place}GRAVES{rplace}]...  Graves to delete, by path in the graveyard
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "fsck" => format!(
+            "\
+Cross-check the record against the graveyard
+
+{header}Usage{rheader}: {rip_s}rip fsck{rrip_s} [{place}OPTIONS{rplace}]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "compact" => format!(
+            "\
+Drop entries for graves that are gone, and repeated entries, from the record
+
+{header}Usage{rheader}: {rip_s}rip compact{rrip_s}
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "migrate" => format!(
+            "\
+Convert a record left by the original rip to rip2's format
+
+{header}Usage{rheader}: {rip_s}rip migrate{rrip_s} [{place}OPTIONS{rplace}]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "export" => format!(
+            "\
+Print the record as JSON or CSV
+
+{header}Usage{rheader}: {rip_s}rip export{rrip_s} [{place}OPTIONS{rplace}]
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "import" => format!(
+            "\
+Copy the graves of another graveyard into this one
+
+{header}Usage{rheader}: {rip_s}rip import{rrip_s} <{place}GRAVEYARD{rplace}>
+
+{header}Arguments{rheader}:
+    <{place}GRAVEYARD{rplace}>  Graveyard to copy the graves of, e.g. from an old machine
+
+{header}Options{rheader}:
+{OPTIONS_PLACEHOLDER}
+"
+        ),
+        "verify" => format!(
+            "\
+Check the graveyard's integrity
+
+{header}Usage{rheader}: {rip_s}rip verify{rrip_s} [{place}OPTIONS{rplace}]
+
 {header}Options{rheader}:
 {OPTIONS_PLACEHOLDER}
 "
@@ -87,6 +380,17 @@ pub struct Args {
     #[arg(long)]
     pub graveyard: Option<PathBuf>,
 
+    /// Use the settings of profile NAME
+    /// from the config file
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Resolve relative targets and seance
+    /// from DIR instead of the current
+    /// directory
+    #[arg(long, value_name = "DIR", visible_alias = "relative-to")]
+    pub cwd: Option<PathBuf>,
+
     /// Permanently deletes the graveyard
     #[arg(short, long)]
     pub decompose: bool,
@@ -96,17 +400,218 @@ pub struct Args {
     #[arg(short, long)]
     pub seance: bool,
 
-    /// Restore the specified
-    /// files or the last file
-    /// if none are specified
+    /// Restore the specified graves,
+    /// by path, as @ID from seance, or by
+    /// a glob of where they were buried from,
+    /// or the last file if none are specified
     #[arg(short, long, num_args = 0)]
     pub unbury: Option<Vec<PathBuf>>,
 
+    /// Whether to resolve symlinked
+    /// directories in the paths of graves
+    #[arg(long, value_name = "STYLE", value_enum)]
+    pub path_style: Option<PathStyle>,
+
+    /// Rewrite relative symlinks that would
+    /// break when a grave is restored under a
+    /// different name or directory
+    #[arg(long)]
+    pub retarget_symlinks: bool,
+
+    /// Restore graves from under FROM into
+    /// TO instead, e.g. if FROM was renamed
+    #[arg(long, value_name = "FROM=TO", value_parser = util::parse_remap)]
+    pub remap: Vec<(PathBuf, PathBuf)>,
+
+    /// Restore graves into DIR instead
+    /// of where they were buried from
+    #[arg(long, value_name = "DIR", conflicts_with = "remap")]
+    pub to: Option<PathBuf>,
+
+    /// Print which graves -u,--unbury would restore where, without restoring them
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Restore the last N things buried
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub last: Option<u32>,
+
+    /// Only restore the files in directory
+    /// graves matching GLOB, copying them
+    /// out and leaving the graves buried
+    #[arg(long, value_name = "GLOB")]
+    pub only: Vec<String>,
+
     /// Print some info about TARGET before
     /// burying
     #[arg(short, long)]
     pub inspect: bool,
 
+    /// Attach a note to the graves, like
+    /// why they were deleted, shown during seance
+    #[arg(short, long, value_name = "TEXT")]
+    pub message: Option<String>,
+
+    /// Don't ask before burying many targets at
+    /// once, or restoring into others' directories
+    #[arg(short, long)]
+    pub yes: bool,
+
+    /// Bury the files that symlinks point
+    /// to, rather than the links themselves
+    #[arg(short = 'L', long)]
+    pub follow_symlinks: bool,
+
+    /// Explain what is being done
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// How to move files into the
+    /// graveyard
+    #[arg(long, value_enum)]
+    pub strategy: Option<Strategy>,
+
+    /// Fail instead of copying when a target
+    /// can't be renamed into the graveyard
+    #[arg(long)]
+    pub no_copy: bool,
+
+    /// Permissions for files copied into
+    /// the graveyard
+    #[arg(long, value_name = "POLICY", value_enum)]
+    pub grave_perms: Option<PermissionPolicy>,
+
+    /// Protect graves from modification
+    /// until they're unburied or purged
+    #[arg(long, value_name = "MODE", value_enum)]
+    pub protect: Option<Protection>,
+
+    /// Store repeated buries of the same
+    /// file as deltas against the last grave
+    #[arg(long)]
+    pub delta: bool,
+
+    /// Link each new record entry to the
+    /// last with a hash, for `rip verify`
+    #[arg(long)]
+    pub chain_record: bool,
+
+    /// Record who buried each grave, on
+    /// which host, and from which directory
+    #[arg(long)]
+    pub record_origin: bool,
+
+    /// Don't checksum graves, which is
+    /// slow for big ones
+    #[arg(long)]
+    pub no_checksum: bool,
+
+    /// Give each file in a buried directory
+    /// its own entry in the record
+    #[arg(long)]
+    pub split_dirs: bool,
+
+    /// Leave files in a buried directory that
+    /// can't be read where they are, without asking
+    #[arg(long)]
+    pub skip_unreadable: bool,
+
+    /// Make the graveyard portable, keeping paths
+    /// relative to the drive it is on
+    #[arg(long)]
+    pub portable: bool,
+
+    /// Only allow writes to the graveyard and
+    /// the targets' directories (Linux only)
+    #[arg(long)]
+    pub sandbox: bool,
+
+    /// Exit with an error if there were any warnings
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Make up the same names for temporary
+    /// files on every run, starting from N
+    #[arg(long, value_name = "N")]
+    pub seed: Option<u64>,
+
+    /// Ask whether to delete files bigger than
+    /// SIZE instead of copying them (default 500MB)
+    #[arg(long, value_name = "SIZE", value_parser = util::parse_size)]
+    pub big_file: Option<u64>,
+
+    /// Ignore targets that don't exist, and don't
+    /// ask about big files or many targets
+    #[arg(short, long)]
+    pub force: bool,
+
+    /// Bury everything, ignoring the exclude
+    /// patterns of the config
+    #[arg(long)]
+    pub no_exclude: bool,
+
+    /// Bury targets even if they are protected
+    /// paths, like / or the graveyard itself
+    #[arg(long)]
+    pub allow_protected: bool,
+
+    /// Print the path of each grave once
+    /// buried, one per line
+    #[arg(long)]
+    pub print_grave_path: bool,
+
+    /// Like --print-grave-path, but end each
+    /// path with a NUL instead of a newline
+    #[arg(short = '0', long)]
+    pub print0: bool,
+
+    /// Print what burying each target would do,
+    /// without doing it
+    #[arg(long)]
+    pub explain: bool,
+
+    /// Purge the oldest graves when the
+    /// graveyard's free space drops below SIZE
+    #[arg(long, value_name = "SIZE", value_parser = util::parse_size)]
+    pub min_free: Option<u64>,
+
+    /// Purge graves older than DURATION
+    /// (e.g. 30d) whenever burying
+    #[arg(long, value_name = "DURATION", value_parser = util::parse_duration)]
+    pub max_age: Option<chrono::Duration>,
+
+    /// Only show graves that will be purged
+    /// within DURATION during seance
+    #[arg(long, value_name = "DURATION", value_parser = util::parse_duration)]
+    pub expiring: Option<chrono::Duration>,
+
+    /// Only unbury or show graves buried
+    /// within DURATION (e.g. 2h)
+    #[arg(long, value_name = "DURATION", value_parser = util::parse_duration)]
+    pub since: Option<chrono::Duration>,
+
+    /// Only unbury or show graves buried
+    /// more than DURATION ago
+    #[arg(long, value_name = "DURATION", value_parser = util::parse_duration)]
+    pub before: Option<chrono::Duration>,
+
+    /// Only show graves of TYPE during seance:
+    /// an extension like pdf, or one of image,
+    /// video, audio, document, archive, code, text
+    #[arg(long = "type", value_name = "TYPE", value_parser = filetype::parse_type_filter)]
+    pub file_type: Vec<TypeFilter>,
+
+    /// Show image sizes and dates, durations
+    /// and page counts of graves during seance
+    #[arg(long)]
+    pub preview: bool,
+
+    /// Show full original paths during seance,
+    /// rather than relative to the current
+    /// directory
+    #[arg(long)]
+    pub absolute: bool,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
@@ -128,7 +633,276 @@ pub enum Commands {
         /// of the current directory
         #[arg(short, long)]
         seance: bool,
+
+        /// Also print where the graveyard's
+        /// location came from, and whether
+        /// it's usable
+        #[arg(long, conflicts_with = "seance")]
+        explain: bool,
     },
+
+    /// Print the log of graves removed from the graveyard
+    #[command(styles=STYLES, help_template=help_template("log"))]
+    Log,
+
+    /// Print when a path was restored from the graveyard, and from which grave
+    #[command(styles=STYLES, help_template=help_template("which"))]
+    Which {
+        /// Path to look up
+        path: PathBuf,
+    },
+
+    /// Restore everything buried recently, from any directory
+    #[command(styles=STYLES, help_template=help_template("restore-session"))]
+    RestoreSession {
+        /// How far back to restore (e.g. 10m)
+        #[arg(short, long, value_name = "DURATION", value_parser = util::parse_duration)]
+        within: chrono::Duration,
+    },
+
+    /// Print the most recent graves from a directory
+    #[command(styles=STYLES, help_template=help_template("recent"))]
+    Recent {
+        /// Directory to look in
+        /// (defaults to the current directory)
+        #[arg(long)]
+        dir: Option<PathBuf>,
+
+        /// Maximum number of graves to print
+        #[arg(short = 'n', long, default_value_t = 5)]
+        limit: usize,
+
+        /// Only include graves buried within DURATION
+        #[arg(long, value_name = "DURATION", value_parser = util::parse_duration)]
+        within: Option<chrono::Duration>,
+
+        /// Print tab-separated time, original
+        /// and grave paths for scripts
+        #[arg(long)]
+        porcelain: bool,
+    },
+
+    /// Print the size of the graveyard, or how it has grown
+    #[command(styles=STYLES, help_template=help_template("stats"))]
+    Stats {
+        /// Chart the graveyard's size from
+        /// the snapshots taken each day
+        #[arg(long)]
+        history: bool,
+
+        /// How many days of history to chart
+        #[arg(long, value_name = "DAYS", default_value_t = 30)]
+        days: u32,
+    },
+
+    /// Attach a note to a grave, shown during seance
+    #[command(styles=STYLES, help_template=help_template("note"))]
+    Note {
+        /// Path of the grave in the graveyard
+        grave: PathBuf,
+
+        /// The note, or an empty string to remove it
+        text: String,
+    },
+
+    /// Compare a grave with a file, or with another grave
+    #[command(styles=STYLES, help_template=help_template("diff"))]
+    Diff {
+        /// Path of the grave in the graveyard, or @ID
+        grave: PathBuf,
+
+        /// Path or @ID to compare it with; the grave's original path
+        /// if not given
+        other: Option<PathBuf>,
+    },
+
+    /// List what's in a directory grave
+    #[command(styles=STYLES, help_template=help_template("peek"))]
+    Peek {
+        /// Path of the grave in the graveyard, or @ID
+        grave: PathBuf,
+    },
+
+    /// Permanently delete selected graves
+    #[command(styles=STYLES, help_template=help_template("purge"))]
+    Purge {
+        /// Graves to delete, by path in the graveyard
+        graves: Vec<PathBuf>,
+
+        /// Delete graves by their IDs from seance,
+        /// separated by commas
+        #[arg(long = "id", value_name = "ID", value_delimiter = ',')]
+        ids: Vec<String>,
+
+        /// Delete graves buried from paths matching GLOB;
+        /// one without a / matches the name
+        #[arg(long = "match", value_name = "GLOB")]
+        patterns: Vec<String>,
+    },
+
+    /// Check the graveyard for problems
+    #[command(styles=STYLES, help_template=help_template("doctor"))]
+    Doctor {
+        /// Restrict the graveyard and the directories
+        /// leading to each grave to their owner
+        #[arg(long)]
+        fix_perms: bool,
+
+        /// Rebuild the record from the graves in
+        /// the graveyard, if it was lost or damaged
+        #[arg(long)]
+        rebuild_record: bool,
+
+        /// Add graves that are missing from the
+        /// record to it, marked as adopted
+        #[arg(long)]
+        adopt: bool,
+
+        /// Remove the empty directories left
+        /// behind by graves that are gone
+        #[arg(long)]
+        prune_empty: bool,
+
+        /// Close the gaps in the ~N suffixes of graves
+        /// renamed on conflict, left by unburying
+        #[arg(long)]
+        renumber: bool,
+
+        /// Only print the graves that are missing
+        /// from the record, one per line
+        #[arg(
+            long,
+            conflicts_with_all = ["adopt", "rebuild_record", "fix_perms", "prune_empty", "renumber"]
+        )]
+        orphans: bool,
+    },
+
+    /// Cross-check the record against the graveyard
+    #[command(styles=STYLES, help_template=help_template("fsck"))]
+    Fsck {
+        /// Drop record entries for missing graves, unreadable
+        /// lines and duplicates, and adopt unrecorded graves
+        #[arg(long)]
+        repair: bool,
+    },
+
+    /// Drop entries for graves that are gone,
+    /// and repeated entries, from the record
+    #[command(styles=STYLES, help_template=help_template("compact"))]
+    Compact,
+
+    /// Convert a record left by the original rip
+    /// to rip2's format
+    #[command(styles=STYLES, help_template=help_template("migrate"))]
+    Migrate,
+
+    /// Print the record as JSON or CSV
+    #[command(styles=STYLES, help_template=help_template("export"))]
+    Export {
+        /// Format to print the record in
+        #[arg(long, value_enum, default_value_t = export::Format::Json)]
+        format: export::Format,
+
+        /// Only include graves buried within DURATION
+        #[arg(long, value_name = "DURATION", value_parser = util::parse_duration)]
+        since: Option<chrono::Duration>,
+    },
+
+    /// Copy the graves of another graveyard into this one
+    #[command(styles=STYLES, help_template=help_template("import"))]
+    Import {
+        /// Graveyard to copy the graves of,
+        /// e.g. from an old machine
+        #[arg(value_name = "GRAVEYARD")]
+        from: PathBuf,
+    },
+
+    /// Check the graveyard's integrity
+    #[command(styles=STYLES, help_template=help_template("verify"))]
+    Verify {
+        /// Check the hash chain of the record
+        /// (the default if nothing is selected)
+        #[arg(long)]
+        record: bool,
+
+        /// Check directory graves against the manifests written when
+        /// they were buried
+        #[arg(long)]
+        manifests: bool,
+    },
+
+    /// Show, change, export or import the config file
+    #[command(styles=STYLES, help_template=help_template("config"))]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Print the config file
+    #[command(styles=STYLES, help_template=help_template("config show"))]
+    Show {
+        /// Print every setting in force and where
+        /// it came from: a flag, the environment,
+        /// the config file, or the default
+        #[arg(long)]
+        effective: bool,
+    },
+
+    /// Print the settings in force as a config file
+    #[command(styles=STYLES, help_template=help_template("config export"))]
+    Export,
+
+    /// Replace the config file with FILE
+    #[command(styles=STYLES, help_template=help_template("config import"))]
+    Import {
+        /// Config file to import, e.g. from `rip config export`
+        file: PathBuf,
+    },
+
+    /// List every setting, its environment variable,
+    /// and its value in the config file
+    #[command(styles=STYLES, help_template=help_template("config list"))]
+    List,
+
+    /// Print the value of KEY in the config file
+    #[command(styles=STYLES, help_template=help_template("config get"))]
+    Get {
+        /// Setting to print, e.g. `strategy`
+        #[arg(value_parser = setting_keys())]
+        key: String,
+    },
+
+    /// Set KEY to VALUE in the config file
+    #[command(styles=STYLES, help_template=help_template("config set"))]
+    Set {
+        /// Setting to change, e.g. `strategy`
+        #[arg(value_parser = setting_keys())]
+        key: String,
+
+        /// New value, checked like the corresponding flag
+        value: String,
+    },
+
+    /// Remove KEY from the config file
+    #[command(styles=STYLES, help_template=help_template("config unset"))]
+    Unset {
+        /// Setting to remove, e.g. `strategy`
+        #[arg(value_parser = setting_keys())]
+        key: String,
+    },
+
+    /// Open the config file in $VISUAL or $EDITOR, and
+    /// only keep the changes if they are valid
+    #[command(styles=STYLES, help_template=help_template("config edit"))]
+    Edit,
+}
+
+/// Config keys, listed so that shells can complete them
+fn setting_keys() -> PossibleValuesParser {
+    PossibleValuesParser::new(config::KEYS.map(|(key, _)| key))
 }
 
 struct IsDefault {
@@ -149,7 +923,7 @@ impl IsDefault {
             seance: cli.seance == defaults.seance,
             unbury: cli.unbury == defaults.unbury,
             inspect: cli.inspect == defaults.inspect,
-            completions: cli.command.is_none(),
+            completions: !matches!(cli.command, Some(Commands::Completions { .. })),
         }
     }
 }
@@ -177,6 +951,105 @@ pub fn validate_args(cli: &Args) -> Result<(), Error> {
             "-d,--decompose can only be used with --graveyard",
         ));
     }
+    if cli.expiring.is_some() && defaults.seance {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--expiring can only be used with -s,--seance",
+        ));
+    }
+    if (cli.since.is_some() || cli.before.is_some()) && defaults.seance && defaults.unbury {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--since and --before can only be used with -s,--seance or -u,--unbury",
+        ));
+    }
+    if let (Some(since), Some(before)) = (cli.since, cli.before) {
+        if since <= before {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--since must be longer ago than --before, or nothing could match",
+            ));
+        }
+    }
+    if !cli.file_type.is_empty() && defaults.seance {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--type can only be used with -s,--seance",
+        ));
+    }
+    if cli.preview && defaults.seance {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--preview can only be used with -s,--seance",
+        ));
+    }
+    if cli.no_copy
+        && cli
+            .strategy
+            .is_some_and(|strategy| !strategy.allows_rename())
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--no-copy can only be used with --strategy auto or rename",
+        ));
+    }
+    if cli.to.is_some() && defaults.unbury {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--to can only be used with -u,--unbury",
+        ));
+    }
+    if cli.dry_run && defaults.unbury {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--dry-run can only be used with -u,--unbury",
+        ));
+    }
+    if cli.last.is_some() && defaults.unbury {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--last can only be used with -u,--unbury",
+        ));
+    }
+    if !cli.only.is_empty() && defaults.unbury {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--only can only be used with -u,--unbury",
+        ));
+    }
+    if cli.absolute && defaults.seance {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--absolute can only be used with -s,--seance",
+        ));
+    }
+    if cli.explain
+        && !(defaults.seance && defaults.unbury && defaults.decompose && cli.command.is_none())
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--explain can only be used when burying",
+        ));
+    }
+    if cli.force && !defaults.inspect {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "-f,--force can't be used with -i,--inspect",
+        ));
+    }
 
     Ok(())
 }
+
+/// Validate `cli`, then turn on `--force` and `--yes` if `$RIP_FORCE` or
+/// `$RIP_YES` (or `force` or `yes` in the config) ask for them. An explicit
+/// `-i` means each target should be asked about, so it wins over a
+/// `force` setting rather than clashing with it like `-f` would.
+pub fn resolve_args(mut cli: Args, ctx: &Context) -> Result<Args, Error> {
+    validate_args(&cli)?;
+    if !cli.inspect {
+        cli.force = util::flag_or_env_bool(cli.force, "RIP_FORCE", ctx);
+    }
+    cli.yes = util::flag_or_env_bool(cli.yes, "RIP_YES", ctx);
+    Ok(cli)
+}
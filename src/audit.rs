@@ -0,0 +1,199 @@
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Error, Write};
+use std::path::{Path, PathBuf};
+
+use crate::util;
+
+pub const LOG: &str = ".log";
+const HEADER: &str = "Time\tOperation\tReason\tBytes\tPath\tRestored\tGrave";
+
+/// Why graves were removed from (or restored out of) the graveyard
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reason {
+    /// The grave outlived the retention policy
+    Age,
+    /// The user asked for it
+    Manual,
+    /// The graveyard's filesystem was low on space
+    Space,
+    /// The original path was taken, so the grave was restored elsewhere
+    Conflict,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Age => write!(f, "age"),
+            Reason::Manual => write!(f, "manual"),
+            Reason::Space => write!(f, "space"),
+            Reason::Conflict => write!(f, "conflict"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogItem {
+    pub time: String,
+    pub operation: String,
+    pub reason: String,
+    pub bytes: u64,
+    pub path: PathBuf,
+    /// Where a grave was restored to, if not its original path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restored: Option<PathBuf>,
+    /// The grave a path was unburied from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grave: Option<PathBuf>,
+}
+
+impl LogItem {
+    /// Parse a line in the log into a `LogItem`
+    pub fn new(line: &str) -> Option<LogItem> {
+        let mut tokens = line.split('\t');
+        Some(LogItem {
+            time: tokens.next()?.to_string(),
+            operation: tokens.next()?.to_string(),
+            reason: tokens.next()?.to_string(),
+            bytes: tokens.next()?.parse().ok()?,
+            path: PathBuf::from(tokens.next()?),
+            restored: tokens
+                .next()
+                .filter(|restored| !restored.is_empty())
+                .map(PathBuf::from),
+            grave: tokens.next().map(PathBuf::from),
+        })
+    }
+}
+
+/// Append-only log of operations that remove graves, so that it's
+/// possible to find out later why a grave is gone, or where a file
+/// came back from.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(graveyard: &Path) -> AuditLog {
+        AuditLog {
+            path: graveyard.join(LOG),
+        }
+    }
+
+    /// Record that `path` was removed by `operation`, reclaiming `bytes`
+    pub fn write(
+        &self,
+        operation: &str,
+        reason: Reason,
+        bytes: u64,
+        path: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        self.append(operation, reason, bytes, path.as_ref(), None, None)
+    }
+
+    /// Record that `grave`, meant for `requested`, was restored to
+    /// `restored`, which is a conflict if they differ
+    pub fn write_unbury(&self, grave: &Path, requested: &Path, restored: &Path) -> io::Result<()> {
+        let bytes = fs_extra::dir::get_size(restored).unwrap_or(0);
+        let (reason, restored) = match requested == restored {
+            true => (Reason::Manual, None),
+            false => (Reason::Conflict, Some(restored)),
+        };
+        self.append("unbury", reason, bytes, requested, restored, Some(grave))
+    }
+
+    fn append(
+        &self,
+        operation: &str,
+        reason: Reason,
+        bytes: u64,
+        path: &Path,
+        restored: Option<&Path>,
+        grave: Option<&Path>,
+    ) -> io::Result<()> {
+        let exists = self.path.exists();
+        let mut log_file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if !exists {
+            writeln!(log_file, "{}", HEADER)?;
+        }
+        let extra = match (restored, grave) {
+            (restored, Some(grave)) => format!(
+                "\t{}\t{}",
+                restored.map_or(String::new(), |r| r.display().to_string()),
+                grave.display()
+            ),
+            (Some(restored), None) => format!("\t{}", restored.display()),
+            (None, None) => String::new(),
+        };
+        writeln!(
+            log_file,
+            "{}\t{}\t{}\t{}\t{}{}",
+            Local::now().to_rfc3339(),
+            operation,
+            reason,
+            bytes,
+            path.display(),
+            extra
+        )
+        .map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Failed to write log at {}", &self.path.display()),
+            )
+        })
+    }
+
+    /// Returns every entry in the log, oldest first
+    pub fn entries(&self) -> io::Result<Vec<LogItem>> {
+        let Ok(log_file) = fs::File::open(&self.path) else {
+            return Ok(Vec::new());
+        };
+        let mut reader = BufReader::new(log_file).lines();
+        reader.next();
+        Ok(reader
+            .map_while(Result::ok)
+            .filter_map(|line| LogItem::new(&line))
+            .collect())
+    }
+
+    /// Unburies that put a file at `path`, oldest first
+    pub fn restores_of(&self, path: &Path) -> io::Result<Vec<LogItem>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|item| item.operation == "unbury")
+            .filter(|item| item.restored.as_deref().unwrap_or(&item.path) == path)
+            .collect())
+    }
+
+    /// Print the log as a table
+    pub fn print(&self, stream: &mut impl Write) -> io::Result<()> {
+        writeln!(stream, "{: <19}\toperation\treason\tsize\tpath", "time")?;
+        for item in self.entries()? {
+            let parsed_time = chrono::DateTime::parse_from_rfc3339(&item.time)
+                .map(|t| t.format("%Y-%m-%dT%H:%M:%S").to_string())
+                .unwrap_or(item.time);
+            let restored = item.restored.map_or(String::new(), |restored| {
+                format!(" -> {}", restored.display())
+            });
+            writeln!(
+                stream,
+                "{}\t{}\t{}\t{}\t{}{}",
+                parsed_time,
+                item.operation,
+                item.reason,
+                util::humanize_bytes(item.bytes),
+                item.path.display(),
+                restored
+            )?;
+        }
+        Ok(())
+    }
+}
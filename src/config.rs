@@ -0,0 +1,740 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+
+use crate::args::{Args, ConfigCommand};
+use crate::context::Context;
+use crate::util;
+
+/// Settings that can be kept in the config file, each with the
+/// environment variable that overrides it
+pub const KEYS: [(&str, &str); 25] = [
+    ("profile", "RIP_PROFILE"),
+    ("graveyard", "RIP_GRAVEYARD"),
+    ("strategy", "RIP_STRATEGY"),
+    ("no-copy", "RIP_NO_COPY"),
+    ("grave-perms", "RIP_GRAVE_PERMS"),
+    ("protect", "RIP_PROTECT"),
+    ("path-style", "RIP_PATH_STYLE"),
+    ("min-free", "RIP_MIN_FREE"),
+    ("max-age", "RIP_MAX_AGE"),
+    ("big-file", "RIP_BIG_FILE"),
+    ("force", "RIP_FORCE"),
+    ("yes", "RIP_YES"),
+    ("exclude", "RIP_EXCLUDE"),
+    ("protected-paths", "RIP_PROTECTED_PATHS"),
+    ("prompt-default", "RIP_PROMPT_DEFAULT"),
+    ("type-yes", "RIP_TYPE_YES"),
+    ("chain-record", "RIP_CHAIN_RECORD"),
+    ("record-origin", "RIP_RECORD_ORIGIN"),
+    ("no-checksum", "RIP_NO_CHECKSUM"),
+    ("split-dirs", "RIP_SPLIT_DIRS"),
+    ("skip-unreadable", "RIP_SKIP_UNREADABLE"),
+    ("record-backend", "RIP_RECORD_BACKEND"),
+    ("portable", "RIP_PORTABLE"),
+    ("sandbox", "RIP_SANDBOX"),
+    ("strict", "RIP_STRICT"),
+];
+
+/// Settings written as TOML booleans rather than strings
+const BOOL_KEYS: [&str; 12] = [
+    "no-copy",
+    "force",
+    "yes",
+    "chain-record",
+    "record-origin",
+    "no-checksum",
+    "split-dirs",
+    "skip-unreadable",
+    "portable",
+    "sandbox",
+    "strict",
+    "type-yes",
+];
+
+/// Settings written as TOML lists of strings, which are kept a line each
+const LIST_KEYS: [&str; 2] = ["exclude", "protected-paths"];
+
+/// Name of the per-directory config file, whose settings override the
+/// user's config file in that directory and below
+pub const RIPRC: &str = ".riprc";
+
+/// The environment variable that overrides `key`
+pub fn var_of(key: &str) -> Option<&'static str> {
+    KEYS.iter().find(|(k, _)| *k == key).map(|(_, var)| *var)
+}
+
+/// Where the config file lives: `$RIP_CONFIG`, or else `rip/config.toml`
+/// under `$XDG_CONFIG_HOME`, the platform's config directory, or
+/// `~/.config`. An empty `$RIP_CONFIG` means there is none.
+pub fn config_path(ctx: &Context) -> Option<PathBuf> {
+    if let Some(path) = ctx.var("RIP_CONFIG") {
+        return (!path.is_empty()).then(|| ctx.cwd.join(path));
+    }
+    let in_dir = |dir: PathBuf| dir.join("rip").join("config.toml");
+    if let Some(dir) = ctx.var("XDG_CONFIG_HOME") {
+        return Some(in_dir(PathBuf::from(dir)));
+    }
+    if cfg!(windows) {
+        // %APPDATA%, e.g. C:\Users\me\AppData\Roaming
+        return ctx.var("APPDATA").map(|dir| in_dir(PathBuf::from(dir)));
+    }
+    let home = PathBuf::from(ctx.var("HOME")?);
+    if cfg!(target_os = "macos") {
+        // Used if it exists, since many tools on macOS use ~/.config too
+        let path = in_dir(home.join("Library").join("Application Support"));
+        if path.exists() {
+            return Some(path);
+        }
+    }
+    Some(in_dir(home.join(".config")))
+}
+
+/// Check that `value` is valid for `key`, using the same parsing as
+/// the corresponding flag
+pub fn validate(key: &str, value: &str) -> Result<(), Error> {
+    let invalid = |reason: String| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid {}: {}", key, reason),
+        )
+    };
+    match key {
+        "profile" if value.is_empty() => Err(invalid("must not be empty".to_string())),
+        "profile" => Ok(()),
+        "graveyard" if value.is_empty() => Err(invalid("must not be empty".to_string())),
+        "graveyard" => Ok(()),
+        "strategy" => check_enum::<util::Strategy>(value).map_err(invalid),
+        "grave-perms" => check_enum::<util::PermissionPolicy>(value).map_err(invalid),
+        "protect" => check_enum::<util::Protection>(value).map_err(invalid),
+        "path-style" => check_enum::<util::PathStyle>(value).map_err(invalid),
+        "record-backend" => check_enum::<crate::store::Backend>(value).map_err(invalid),
+        "min-free" | "big-file" => util::parse_size(value).map(drop).map_err(invalid),
+        "max-age" => util::parse_duration(value).map(drop).map_err(invalid),
+        key if BOOL_KEYS.contains(&key) => util::parse_bool(value).map(drop).map_err(invalid),
+        "prompt-default" => util::parse_bool(value).map(drop).map_err(invalid),
+        "exclude" => value.lines().try_for_each(|pattern| {
+            glob::Pattern::new(pattern)
+                .map(drop)
+                .map_err(|e| invalid(format!("{}: {}", pattern, e)))
+        }),
+        "protected-paths" => value.lines().try_for_each(|path| {
+            match path.is_empty() || Path::new(path).is_absolute() {
+                true => Ok(()),
+                false => Err(invalid(format!("{} is not an absolute path", path))),
+            }
+        }),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Unknown setting: {}", key),
+        )),
+    }
+}
+
+/// Check that `value` names a variant of `T`
+fn check_enum<T: ValueEnum>(value: &str) -> Result<(), String> {
+    T::from_str(value, true).map(drop).map_err(|_| {
+        let names: Vec<String> = T::value_variants()
+            .iter()
+            .map(|v| name_of(v.clone()))
+            .collect();
+        format!("{} (expected one of {})", value, names.join(", "))
+    })
+}
+
+/// The nearest `.riprc` to the current directory, and its settings
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Riprc {
+    pub path: PathBuf,
+    pub config: Config,
+}
+
+impl Riprc {
+    /// Load the `.riprc` in `dir` or its nearest ancestor that has one.
+    /// A relative graveyard is taken relative to the `.riprc`.
+    pub fn find(dir: &Path) -> Result<Option<Riprc>, Error> {
+        let Some(path) = dir
+            .ancestors()
+            .map(|dir| dir.join(RIPRC))
+            .find(|path| path.is_file())
+        else {
+            return Ok(None);
+        };
+        let mut riprc = Riprc {
+            config: Config::load(&path)?,
+            path,
+        };
+        // Leaving `~` for the home directory
+        if let Some(graveyard) = riprc
+            .config
+            .get("graveyard")
+            .filter(|graveyard| !Path::new(graveyard).starts_with("~"))
+        {
+            let graveyard = util::normalize_path(&riprc.dir().join(graveyard));
+            riprc
+                .config
+                .insert("graveyard", &graveyard.display().to_string())?;
+        }
+        Ok(Some(riprc))
+    }
+
+    /// The directory the `.riprc` is in
+    pub fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or(Path::new("/"))
+    }
+}
+
+/// Settings from the config file, by key, and the named profiles that
+/// can override them
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    values: BTreeMap<String, String>,
+    profiles: BTreeMap<String, Config>,
+}
+
+impl Config {
+    /// Parse and validate a config file's contents. Profiles are tables
+    /// under `profiles`, like `[profiles.work]`.
+    pub fn parse(text: &str) -> Result<Config, Error> {
+        let doc: toml_edit::DocumentMut = text
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}", e)))?;
+        let mut config = Config::default();
+        for (key, item) in doc.iter() {
+            if key != "profiles" {
+                config.parse_value(key, item)?;
+                continue;
+            }
+            let profiles = item
+                .as_table_like()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "profiles must be a table"))?;
+            for (name, profile) in profiles.iter() {
+                let items = profile.as_table_like().ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("profile {} must be a table", name),
+                    )
+                })?;
+                let mut parsed = Config::default();
+                for (key, item) in items.iter() {
+                    if key == "profile" {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("profile {} can't select another profile", name),
+                        ));
+                    }
+                    parsed.parse_value(key, item)?;
+                }
+                config.profiles.insert(name.to_string(), parsed);
+            }
+        }
+        Ok(config)
+    }
+
+    fn parse_value(&mut self, key: &str, item: &toml_edit::Item) -> Result<(), Error> {
+        let value = match item.as_value() {
+            Some(toml_edit::Value::String(s)) => s.value().clone(),
+            Some(toml_edit::Value::Boolean(b)) => b.value().to_string(),
+            Some(toml_edit::Value::Integer(i)) => i.value().to_string(),
+            Some(toml_edit::Value::Array(array)) if LIST_KEYS.contains(&key) => {
+                let items: Option<Vec<&str>> = array.iter().map(|v| v.as_str()).collect();
+                items.map(|items| items.join("\n")).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{} must be a list of strings", key),
+                    )
+                })?
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} must be a string or a boolean", key),
+                ))
+            }
+        };
+        validate(key, &value)?;
+        self.values.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    /// Read the config file at `path`, which may not exist
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => {
+                return Err(Error::new(
+                    e.kind(),
+                    format!("Failed to read config at {}: {}", path.display(), e),
+                ))
+            }
+        };
+        Config::parse(&text).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Invalid config at {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Write the config to `path` through a temporary file, so that it is
+    /// never left half-written
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("toml.tmp");
+        fs::write(&tmp, self.to_toml())?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// The value of the setting overridden by environment variable `var`
+    pub fn get_by_var(&self, var: &str) -> Option<&str> {
+        let (key, _) = KEYS.iter().find(|(_, v)| *v == var)?;
+        self.get(key)
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        validate(key, value)?;
+        self.values.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    /// Remove `key`, returning its old value
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.values.remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.profiles.is_empty()
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Config> {
+        self.profiles.get(name)
+    }
+
+    /// The profile `name`, which is added if there isn't one
+    pub fn profile_mut(&mut self, name: &str) -> &mut Config {
+        self.profiles.entry(name.to_string()).or_default()
+    }
+
+    pub fn profile_names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
+
+    /// The settings in force under profile `name`: its own, and then
+    /// those outside of any profile
+    pub fn with_profile(&self, name: &str) -> Option<Config> {
+        let mut config = self.clone();
+        for (key, value) in &self.profiles.get(name)?.values {
+            config.values.insert(key.clone(), value.clone());
+        }
+        Some(config)
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut toml: String = self
+            .values
+            .iter()
+            .map(|(key, value)| format!("{} = {}\n", key, toml_value(key, value)))
+            .collect();
+        for (name, profile) in &self.profiles {
+            let bare = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            let name = if bare {
+                name.clone()
+            } else {
+                toml_string(name)
+            };
+            if !toml.is_empty() {
+                toml.push('\n');
+            }
+            toml.push_str(&format!("[profiles.{}]\n", name));
+            toml.push_str(&profile.to_toml());
+        }
+        toml
+    }
+}
+
+/// `value` as TOML: a bare boolean for boolean settings, a list of
+/// strings for lists, or a string
+fn toml_value(key: &str, value: &str) -> String {
+    if BOOL_KEYS.contains(&key) {
+        if let Ok(b) = util::parse_bool(value) {
+            return b.to_string();
+        }
+    }
+    if LIST_KEYS.contains(&key) {
+        let items: Vec<String> = value.lines().map(toml_string).collect();
+        return format!("[{}]", items.join(", "));
+    }
+    toml_string(value)
+}
+
+fn toml_string(value: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Where the value of a setting in force came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Flag,
+    Env(&'static str),
+    Riprc,
+    Config,
+    Default,
+}
+
+/// A setting as it is in force
+#[derive(Debug)]
+pub struct Setting {
+    pub key: &'static str,
+    pub value: Option<String>,
+    pub source: Source,
+}
+
+fn name_of(value: impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map(|v| v.get_name().to_string())
+        .unwrap_or_default()
+}
+
+/// The settings in force for `cli`, after flags, environment variables,
+/// the nearest `.riprc`, the config file and defaults are taken into account, in that order
+pub fn effective(cli: &Args, ctx: &Context) -> Result<Vec<Setting>, Error> {
+    let mut settings = Vec::new();
+    for (key, var) in KEYS {
+        let flag = match key {
+            "profile" => cli.profile.clone(),
+            "graveyard" => cli.graveyard.as_ref().map(|p| p.display().to_string()),
+            "strategy" => cli.strategy.map(name_of),
+            "grave-perms" => cli.grave_perms.map(name_of),
+            "protect" => cli.protect.map(name_of),
+            "path-style" => cli.path_style.map(name_of),
+            "min-free" => cli.min_free.map(|bytes| bytes.to_string()),
+            "max-age" => cli.max_age.map(|age| format!("{}s", age.num_seconds())),
+            "big-file" => cli.big_file.map(|bytes| bytes.to_string()),
+            "no-copy" => cli.no_copy.then(|| "true".to_string()),
+            "force" => cli.force.then(|| "true".to_string()),
+            "yes" => cli.yes.then(|| "true".to_string()),
+            "chain-record" => cli.chain_record.then(|| "true".to_string()),
+            "record-origin" => cli.record_origin.then(|| "true".to_string()),
+            "no-checksum" => cli.no_checksum.then(|| "true".to_string()),
+            "split-dirs" => cli.split_dirs.then(|| "true".to_string()),
+            "skip-unreadable" => cli.skip_unreadable.then(|| "true".to_string()),
+            "portable" => cli.portable.then(|| "true".to_string()),
+            "sandbox" => cli.sandbox.then(|| "true".to_string()),
+            "strict" => cli.strict.then(|| "true".to_string()),
+            _ => None,
+        };
+        let (value, source) = if let Some(flag) = flag {
+            (Some(flag), Source::Flag)
+        } else if let Some(value) = ctx.var(var) {
+            validate(key, value)?;
+            (Some(value.to_string()), Source::Env(var))
+        } else if let Some(value) = ctx.riprc.as_ref().and_then(|riprc| riprc.config.get(key)) {
+            (Some(value.to_string()), Source::Riprc)
+        } else if let Some(value) = ctx.config.get(key) {
+            (Some(value.to_string()), Source::Config)
+        } else {
+            let default = match key {
+                "graveyard" => Some(crate::get_graveyard(None, ctx).display().to_string()),
+                "strategy" => Some(name_of(util::Strategy::default())),
+                "grave-perms" => Some(name_of(util::PermissionPolicy::default())),
+                "protect" => Some(name_of(util::Protection::default())),
+                "path-style" => Some(name_of(util::PathStyle::default())),
+                "big-file" => Some(crate::BIG_FILE_THRESHOLD.to_string()),
+                "protected-paths" => Some(crate::PROTECTED_PATHS.join("\n")),
+                key if BOOL_KEYS.contains(&key) => Some("false".to_string()),
+                "prompt-default" => Some("no".to_string()),
+                _ => None,
+            };
+            (default, Source::Default)
+        };
+        settings.push(Setting { key, value, source });
+    }
+    Ok(settings)
+}
+
+/// Run a `rip config` subcommand
+pub fn run(
+    cli: &Args,
+    command: &ConfigCommand,
+    ctx: &Context,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    let path = config_path(ctx).ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            "No config file in use: set $RIP_CONFIG or $HOME",
+        )
+    })?;
+    match command {
+        ConfigCommand::Show { effective: false } => {
+            if path.exists() {
+                writeln!(stream, "# {}", path.display())?;
+                write!(stream, "{}", Config::load(&path)?.to_toml())?;
+            } else {
+                writeln!(stream, "# No config file at {}", path.display())?;
+            }
+        }
+        ConfigCommand::Show { effective: true } => {
+            let ctx = ctx
+                .clone()
+                .with_config(Config::load(&path)?)
+                .load_riprc()?
+                .use_profile(cli.profile.as_deref())?;
+            for setting in effective(cli, &ctx)? {
+                let source = match setting.source {
+                    Source::Flag => "flag".to_string(),
+                    Source::Env(var) => format!("${}", var),
+                    Source::Riprc => match &ctx.riprc {
+                        Some(riprc) => riprc.path.display().to_string(),
+                        None => RIPRC.to_string(),
+                    },
+                    Source::Config => path.display().to_string(),
+                    Source::Default => "default".to_string(),
+                };
+                match setting.value {
+                    Some(value) => writeln!(
+                        stream,
+                        "{} = {}  # {}",
+                        setting.key,
+                        toml_value(setting.key, &value),
+                        source
+                    )?,
+                    None => writeln!(stream, "# {} is unset", setting.key)?,
+                }
+            }
+        }
+        ConfigCommand::Export => {
+            // Only what was chosen, so that defaults that depend on
+            // the machine (like the user's name) aren't pinned
+            let ctx = ctx
+                .clone()
+                .with_config(Config::load(&path)?)
+                .load_riprc()?
+                .use_profile(cli.profile.as_deref())?;
+            let mut config = Config::default();
+            for setting in effective(cli, &ctx)? {
+                if let (Some(value), false) = (setting.value, setting.source == Source::Default) {
+                    config.insert(setting.key, &value)?;
+                }
+            }
+            write!(stream, "{}", config.to_toml())?;
+        }
+        ConfigCommand::Import { file } => {
+            let file = ctx.cwd.join(file);
+            if !file.exists() {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("No config to import at {}", file.display()),
+                ));
+            }
+            let config = Config::load(&file)?;
+            config.save(&path).map_err(|e| {
+                Error::new(
+                    e.kind(),
+                    format!("Failed to write config at {}: {}", path.display(), e),
+                )
+            })?;
+            writeln!(
+                stream,
+                "Imported {} setting(s) into {}",
+                config.len(),
+                path.display()
+            )?;
+        }
+        ConfigCommand::List => {
+            let config = Config::load(&path)?;
+            let table = match &cli.profile {
+                Some(name) => config.profile(name),
+                None => Some(&config),
+            };
+            let mut rows = vec![["key", "variable", "value"].map(str::to_string)];
+            for (key, var) in KEYS {
+                let value = table.and_then(|table| table.get(key));
+                rows.push([
+                    key.to_string(),
+                    format!("${}", var),
+                    value.map_or(String::new(), |value| toml_value(key, value)),
+                ]);
+            }
+            util::write_aligned(&rows, stream)?;
+        }
+        ConfigCommand::Get { key } => {
+            validate_key(key)?;
+            let config = Config::load(&path)?;
+            let value = match &cli.profile {
+                Some(name) => config.profile(name).and_then(|profile| profile.get(key)),
+                None => config.get(key),
+            };
+            match value {
+                Some(value) => writeln!(stream, "{}", value)?,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("{} is not set in {}", key, where_in(&path, cli)),
+                    ))
+                }
+            }
+        }
+        ConfigCommand::Set { key, value } => {
+            if key == "profile" && cli.profile.is_some() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "A profile can't select another profile",
+                ));
+            }
+            let mut config = Config::load(&path)?;
+            let table = match &cli.profile {
+                Some(name) => config.profile_mut(name),
+                None => &mut config,
+            };
+            table.insert(key, value)?;
+            save_to(&config, &path)?;
+            writeln!(stream, "{} = {}", key, toml_value(key, value))?;
+        }
+        ConfigCommand::Unset { key } => {
+            validate_key(key)?;
+            let mut config = Config::load(&path)?;
+            let table = match &cli.profile {
+                Some(name) => config.profile_mut(name),
+                None => &mut config,
+            };
+            if table.remove(key).is_none() {
+                match &cli.profile {
+                    Some(name) => writeln!(stream, "{} was not set in profile {}", key, name)?,
+                    None => writeln!(stream, "{} was not set", key)?,
+                }
+            } else {
+                save_to(&config, &path)?;
+                writeln!(stream, "Removed {}", key)?;
+            }
+        }
+        ConfigCommand::Edit => edit(&path, ctx, stream)?,
+    }
+    Ok(())
+}
+
+/// The config file, or the profile in it that `cli` selects
+fn where_in(path: &Path, cli: &Args) -> String {
+    match &cli.profile {
+        Some(name) => format!("profile {} of {}", name, path.display()),
+        None => path.display().to_string(),
+    }
+}
+
+/// Fail unless `key` is a known setting
+fn validate_key(key: &str) -> Result<(), Error> {
+    match var_of(key) {
+        Some(_) => Ok(()),
+        None => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Unknown setting: {}", key),
+        )),
+    }
+}
+
+fn save_to(config: &Config, path: &Path) -> Result<(), Error> {
+    config.save(path).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("Failed to write config at {}: {}", path.display(), e),
+        )
+    })
+}
+
+/// What `rip config edit` starts from when there is no config file yet
+fn template() -> String {
+    let mut text = String::from(
+        "# rip config: environment variables and flags take precedence\n\
+         # over the settings here. Uncomment a line to use it.\n",
+    );
+    for (key, var) in KEYS {
+        text.push_str(&format!("# {} = \"\"  # ${}\n", key, var));
+    }
+    text
+}
+
+/// Edit a copy of the config file, and only replace the real one once the
+/// copy is valid. An invalid copy is kept, so the next edit resumes from it.
+fn edit(path: &Path, ctx: &Context, stream: &mut impl Write) -> Result<(), Error> {
+    let copy = path.with_extension("toml.edit");
+    if !copy.exists() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match fs::read_to_string(path) {
+            Ok(text) => fs::write(&copy, text)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => fs::write(&copy, template())?,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let editor = ctx
+        .var("VISUAL")
+        .or_else(|| ctx.var("EDITOR"))
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or(if cfg!(windows) { "notepad" } else { "vi" });
+    // Editors are often configured with arguments, like `code --wait`
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or(editor);
+    let status = std::process::Command::new(program)
+        .args(words)
+        .arg(&copy)
+        .current_dir(&ctx.cwd)
+        .status()
+        .map_err(|e| Error::new(e.kind(), format!("Failed to run {}: {}", editor, e)))?;
+    if !status.success() {
+        return Err(Error::other(format!(
+            "{} exited with {}; {} was left unchanged",
+            editor,
+            status,
+            path.display()
+        )));
+    }
+
+    let text = fs::read_to_string(&copy)?;
+    if let Err(e) = Config::parse(&text) {
+        return Err(Error::new(
+            e.kind(),
+            format!(
+                "Invalid config: {}\n{} was left unchanged; your edits are kept in {}, \
+                 and `rip config edit` will reopen them",
+                e,
+                path.display(),
+                copy.display()
+            ),
+        ));
+    }
+    fs::rename(&copy, path)?;
+    writeln!(stream, "Saved {}", path.display())?;
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Debug;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::config::{self, Config, Riprc};
+use crate::util::IdGen;
+
+/// Source of the timestamps written to the record
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Local>;
+}
+
+/// The system's wall clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// A clock stopped at a single instant
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock(pub DateTime<Local>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Local> {
+        self.0
+    }
+}
+
+/// The parts of the process that rip depends on: the current directory,
+/// the environment, the time, and the names it makes up. Passing these explicitly, rather than
+/// reading them from the process, lets the library run concurrently with
+/// different settings.
+#[derive(Clone, Debug)]
+pub struct Context {
+    /// The physical current directory
+    pub cwd: PathBuf,
+    /// Environment variables (those which are valid unicode)
+    pub env: HashMap<String, String>,
+    /// Settings from the config file, which environment variables override
+    pub config: Config,
+    /// Settings from the nearest `.riprc`, which override the config file
+    pub riprc: Option<Riprc>,
+    pub clock: Arc<dyn Clock>,
+    pub ids: Arc<IdGen>,
+}
+
+impl Context {
+    /// A context with an empty environment, based at `cwd`
+    pub fn new(cwd: impl Into<PathBuf>) -> Context {
+        Context {
+            cwd: cwd.into(),
+            env: HashMap::new(),
+            config: Config::default(),
+            riprc: None,
+            clock: Arc::new(SystemClock),
+            ids: Arc::new(IdGen::default()),
+        }
+    }
+
+    /// Snapshot the current directory and environment of this process
+    pub fn from_process() -> Result<Context, Error> {
+        Ok(Context {
+            cwd: env::current_dir()?,
+            env: env::vars_os()
+                .filter_map(|(key, value)| {
+                    Some((key.into_string().ok()?, value.into_string().ok()?))
+                })
+                .collect(),
+            config: Config::default(),
+            riprc: None,
+            clock: Arc::new(SystemClock),
+            ids: Arc::new(IdGen::default()),
+        })
+    }
+
+    /// Set an environment variable
+    pub fn with_var(mut self, key: &str, value: impl Into<String>) -> Context {
+        self.env.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// Use the settings in `config`
+    pub fn with_config(mut self, config: Config) -> Context {
+        self.config = config;
+        self
+    }
+
+    /// Load the config file, if there is one, and the nearest `.riprc`
+    pub fn load_config(self) -> Result<Context, Error> {
+        self.load_config_for(None)
+    }
+
+    /// Like `load_config`, using profile `profile` if given
+    pub fn load_config_for(self, profile: Option<&str>) -> Result<Context, Error> {
+        let config = match config::config_path(&self) {
+            Some(path) => Config::load(&path)?,
+            None => Config::default(),
+        };
+        self.with_config(config).load_riprc()?.use_profile(profile)
+    }
+
+    /// Apply the settings of profile `profile`, or else of the one that
+    /// `$RIP_PROFILE`, the `.riprc` or the config file selects
+    pub fn use_profile(mut self, profile: Option<&str>) -> Result<Context, Error> {
+        let Some(name) = profile
+            .or_else(|| self.setting("RIP_PROFILE"))
+            .map(str::to_string)
+        else {
+            return Ok(self);
+        };
+        let riprc = self
+            .riprc
+            .as_ref()
+            .and_then(|riprc| riprc.config.with_profile(&name));
+        match (self.config.with_profile(&name), riprc.is_some()) {
+            (Some(config), _) => self.config = config,
+            (None, true) => {}
+            (None, false) => {
+                let names: Vec<&str> = self.config.profile_names().collect();
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    match names.is_empty() {
+                        true => format!("No profile named {} in the config", name),
+                        false => format!(
+                            "No profile named {} in the config (found: {})",
+                            name,
+                            names.join(", ")
+                        ),
+                    },
+                ));
+            }
+        }
+        if let (Some(riprc), Some(config)) = (&mut self.riprc, riprc) {
+            riprc.config = config;
+        }
+        Ok(self)
+    }
+
+    /// Load the `.riprc` nearest to the current directory, if there is one
+    pub fn load_riprc(mut self) -> Result<Context, Error> {
+        self.riprc = Riprc::find(&self.cwd)?;
+        Ok(self)
+    }
+
+    /// Use `clock` for timestamps instead of the system clock
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Context {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Make up the same names on every run, starting from `seed`
+    pub fn with_seed(mut self, seed: u64) -> Context {
+        self.ids = Arc::new(IdGen::seeded(seed));
+        self
+    }
+
+    pub fn var(&self, key: &str) -> Option<&str> {
+        self.env.get(key).map(String::as_str)
+    }
+
+    /// The value of a setting from its environment variable `var`,
+    /// or else from the nearest `.riprc`, or else from the config file
+    pub fn setting(&self, var: &str) -> Option<&str> {
+        self.var(var)
+            .or_else(|| self.riprc.as_ref()?.config.get_by_var(var))
+            .or_else(|| self.config.get_by_var(var))
+    }
+}
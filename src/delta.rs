@@ -0,0 +1,230 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::{collections::HashMap, fs, io};
+
+use crate::util;
+
+pub const DELTAS: &str = ".deltas";
+
+const MAGIC: &[u8] = b"RIPDELTA1\n";
+const BLOCK_SIZE: usize = 64;
+const PRIME: u64 = 1_099_511_628_211;
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+
+/// Only keep a delta if it is at most this fraction of the full file
+const MAX_DELTA_RATIO: f64 = 0.5;
+
+fn block_hash(block: &[u8]) -> u64 {
+    block
+        .iter()
+        .fold(0_u64, |h, &b| h.wrapping_mul(PRIME).wrapping_add(b as u64))
+}
+
+fn flush_insert(out: &mut Vec<u8>, literal: &mut Vec<u8>) {
+    if !literal.is_empty() {
+        out.push(OP_INSERT);
+        out.extend_from_slice(&(literal.len() as u64).to_le_bytes());
+        out.append(literal);
+    }
+}
+
+/// Encode `new` as a sequence of copies out of `base` and literal inserts.
+pub fn encode(base: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    let mut literal = Vec::new();
+
+    // Index the non-overlapping blocks of the base
+    let mut blocks: HashMap<u64, usize> = HashMap::new();
+    for (i, block) in base.chunks_exact(BLOCK_SIZE).enumerate() {
+        blocks.entry(block_hash(block)).or_insert(i * BLOCK_SIZE);
+    }
+
+    // Weight of the byte leaving the rolling window
+    let out_weight = (1..BLOCK_SIZE).fold(1_u64, |w, _| w.wrapping_mul(PRIME));
+
+    let mut i = 0;
+    let mut hash = new.get(..BLOCK_SIZE).map(block_hash);
+    while i < new.len() {
+        let matched = hash
+            .and_then(|h| blocks.get(&h))
+            .filter(|&&offset| base[offset..offset + BLOCK_SIZE] == new[i..i + BLOCK_SIZE]);
+
+        if let Some(&offset) = matched {
+            // Extend the match as far as the bytes agree
+            let mut len = BLOCK_SIZE;
+            while offset + len < base.len()
+                && i + len < new.len()
+                && base[offset + len] == new[i + len]
+            {
+                len += 1;
+            }
+            flush_insert(&mut out, &mut literal);
+            out.push(OP_COPY);
+            out.extend_from_slice(&(offset as u64).to_le_bytes());
+            out.extend_from_slice(&(len as u64).to_le_bytes());
+            i += len;
+            hash = new.get(i..i + BLOCK_SIZE).map(block_hash);
+        } else {
+            literal.push(new[i]);
+            hash = match (hash, new.get(i + BLOCK_SIZE)) {
+                (Some(h), Some(&next)) => Some(
+                    h.wrapping_sub((new[i] as u64).wrapping_mul(out_weight))
+                        .wrapping_mul(PRIME)
+                        .wrapping_add(next as u64),
+                ),
+                _ => None,
+            };
+            i += 1;
+        }
+    }
+    flush_insert(&mut out, &mut literal);
+    out
+}
+
+fn read_u64(delta: &[u8], pos: &mut usize) -> Result<u64, Error> {
+    let bytes = delta
+        .get(*pos..*pos + 8)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Truncated delta"))?;
+    *pos += 8;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reconstruct the full contents from a `base` and a `delta` made by `encode`.
+pub fn decode(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, Error> {
+    let bad = || Error::new(ErrorKind::InvalidData, "Corrupt delta");
+    if !delta.starts_with(MAGIC) {
+        return Err(bad());
+    }
+    let mut pos = MAGIC.len();
+    let mut out = Vec::new();
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        match op {
+            OP_COPY => {
+                let offset = read_u64(delta, &mut pos)? as usize;
+                let len = read_u64(delta, &mut pos)? as usize;
+                let end = offset.checked_add(len).ok_or_else(bad)?;
+                out.extend_from_slice(base.get(offset..end).ok_or_else(bad)?);
+            }
+            OP_INSERT => {
+                let len = read_u64(delta, &mut pos)? as usize;
+                let end = pos.checked_add(len).ok_or_else(bad)?;
+                out.extend_from_slice(delta.get(pos..end).ok_or_else(bad)?);
+                pos = end;
+            }
+            _ => return Err(bad()),
+        }
+    }
+    Ok(out)
+}
+
+/// Returns the delta of `new` against `base`, if it is worth storing
+/// instead of the full contents.
+pub fn try_encode(base: &[u8], new: &[u8]) -> Option<Vec<u8>> {
+    let delta = encode(base, new);
+    if (delta.len() as f64) <= new.len() as f64 * MAX_DELTA_RATIO {
+        Some(delta)
+    } else {
+        None
+    }
+}
+
+/// Index of graves stored as deltas, mapping each delta grave
+/// to the full grave it was encoded against.
+#[derive(Debug)]
+pub struct Deltas {
+    path: PathBuf,
+}
+
+impl Deltas {
+    pub fn new(graveyard: &Path) -> Deltas {
+        Deltas {
+            path: graveyard.join(DELTAS),
+        }
+    }
+
+    fn entries(&self) -> Vec<(PathBuf, PathBuf)> {
+        let Ok(file) = fs::File::open(&self.path) else {
+            return Vec::new();
+        };
+        BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| {
+                let (dest, base) = line.split_once('\t')?;
+                Some((PathBuf::from(dest), PathBuf::from(base)))
+            })
+            .collect()
+    }
+
+    fn write_entries(&self, entries: &[(PathBuf, PathBuf)]) -> io::Result<()> {
+        let mut file = fs::File::create(&self.path)?;
+        for (dest, base) in entries {
+            writeln!(file, "{}\t{}", dest.display(), base.display())?;
+        }
+        Ok(())
+    }
+
+    /// The full grave that `dest` was encoded against, if it is a delta
+    pub fn base_of(&self, dest: &Path) -> Option<PathBuf> {
+        self.entries()
+            .into_iter()
+            .find(|(d, _)| d == dest)
+            .map(|(_, base)| base)
+    }
+
+    /// The delta graves at or under `grave`, each with its base
+    pub fn within(&self, grave: &Path) -> Vec<(PathBuf, PathBuf)> {
+        self.entries()
+            .into_iter()
+            .filter(|(dest, _)| dest.starts_with(grave))
+            .collect()
+    }
+
+    pub fn add(&self, dest: &Path, base: &Path) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}\t{}", dest.display(), base.display())
+    }
+
+    /// Read the full contents of a grave, decoding it if it is a delta
+    pub fn read(&self, dest: &Path) -> io::Result<Vec<u8>> {
+        match self.base_of(dest) {
+            Some(base) => decode(&fs::read(base)?, &fs::read(dest)?),
+            None => fs::read(dest),
+        }
+    }
+
+    /// Replace a delta grave with its full contents, dropping it from the index
+    pub fn rehydrate(&self, dest: &Path) -> io::Result<()> {
+        let contents = self.read(dest)?;
+        // Keep a protected grave protected once it's rewritten
+        let protected = fs::metadata(dest)?.permissions().readonly();
+        util::unprotect(dest)?;
+        fs::write(dest, contents)?;
+        if protected {
+            util::protect(dest, util::Protection::ReadOnly)?;
+        }
+        let entries: Vec<_> = self
+            .entries()
+            .into_iter()
+            .filter(|(d, _)| d != dest)
+            .collect();
+        self.write_entries(&entries)
+    }
+
+    /// Prepare `dest` to leave the graveyard: any deltas encoded against
+    /// (or stored inside) it are rehydrated, as is `dest` if it is a delta.
+    pub fn release(&self, dest: &Path) -> io::Result<()> {
+        for (dependent, base) in self.entries() {
+            if base.starts_with(dest) || dependent.starts_with(dest) {
+                self.rehydrate(&dependent)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+//! Comparing a grave with the file that took its place, or with another
+//! grave, to help decide whether it's worth restoring
+
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::delta::Deltas;
+use crate::record::{Record, RecordItem};
+use crate::{checksum, store, util};
+
+/// Files bigger than this are only compared by hash
+const MAX_TEXT: u64 = 4 << 20;
+/// Files that differ in more places than this are only compared by hash
+const MAX_EDITS: usize = 2000;
+/// How many unchanged lines are shown around each change
+const CONTEXT: usize = 3;
+
+/// Compare the grave at `grave` with `other`, or with the file at its
+/// original path if there's no `other`. Either can be a path or the `@ID`
+/// of a grave. Text files get a unified diff, and anything else a
+/// comparison of sizes and hashes. Returns true if they're the same.
+pub fn diff(
+    graveyard: &Path,
+    cwd: &Path,
+    grave: &Path,
+    other: Option<&Path>,
+    stream: &mut impl Write,
+) -> Result<bool, Error> {
+    if !store::exists(graveyard) {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No record in {}, so nothing is buried", graveyard.display()),
+        ));
+    }
+    let record = Record::open_in(graveyard)?;
+    let grave = find_grave(&record, &resolve(&record, cwd, grave)?)?;
+    let other = match other {
+        Some(other) => resolve(&record, cwd, other)?,
+        None => grave.orig.clone(),
+    };
+
+    let deltas = Deltas::new(graveyard);
+    let (a, b) = (&grave.dest, &other);
+    let (old, new) = (load(&deltas, a)?, load(&deltas, b)?);
+    if old.hash == new.hash {
+        writeln!(stream, "{} and {} are the same", a.display(), b.display())?;
+        return Ok(true);
+    }
+    let hunks = match (&old.text, &new.text) {
+        (Some(old), Some(new)) => unified(old, new),
+        _ => None,
+    };
+    match hunks {
+        Some(hunks) => {
+            writeln!(stream, "--- {}", a.display())?;
+            writeln!(stream, "+++ {}", b.display())?;
+            write!(stream, "{}", hunks)?;
+        }
+        None => {
+            let what = match (&old.text, &new.text) {
+                (Some(_), Some(_)) => "Files",
+                _ => "Binary files",
+            };
+            writeln!(
+                stream,
+                "{} {} and {} differ",
+                what,
+                a.display(),
+                b.display()
+            )?;
+            for (path, file) in [(a, &old), (b, &new)] {
+                writeln!(
+                    stream,
+                    "  {}\t{}\t{}",
+                    util::humanize_bytes(file.len),
+                    file.hash,
+                    path.display()
+                )?;
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// The grave named by `@ID`, or else the path given, from `cwd`
+pub(crate) fn resolve(record: &Record, cwd: &Path, arg: &Path) -> Result<PathBuf, Error> {
+    match arg.to_str().and_then(|arg| arg.strip_prefix('@')) {
+        Some(id) => record.grave_by_id(id),
+        None => Ok(util::normalize_path(&cwd.join(arg))),
+    }
+}
+
+pub(crate) fn find_grave(record: &Record, dest: &Path) -> Result<RecordItem, Error> {
+    record
+        .graves_at(&[dest.to_path_buf()])?
+        .pop()
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("{} isn't a grave in the record", dest.display()),
+            )
+        })
+}
+
+/// A file to compare: its size and hash, and its contents if it's text
+/// small enough to diff
+struct Loaded {
+    len: u64,
+    hash: String,
+    text: Option<String>,
+}
+
+fn load(deltas: &Deltas, path: &Path) -> Result<Loaded, Error> {
+    let metadata = fs::metadata(path)
+        .map_err(|e| Error::new(e.kind(), format!("Can't read {}: {}", path.display(), e)))?;
+    if !metadata.is_file() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} isn't a file; rip diff compares files", path.display()),
+        ));
+    }
+    // A delta grave is only as big as its changes, so it's always decoded
+    if metadata.len() > MAX_TEXT && deltas.base_of(path).is_none() {
+        return Ok(Loaded {
+            len: metadata.len(),
+            hash: checksum::of_file(path)?,
+            text: None,
+        });
+    }
+    let contents = deltas.read(path)?;
+    Ok(Loaded {
+        len: contents.len() as u64,
+        hash: checksum::of_bytes(&contents),
+        text: (contents.len() as u64 <= MAX_TEXT && !contents.contains(&0))
+            .then(|| String::from_utf8(contents).ok())
+            .flatten(),
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Edit {
+    /// Lines of each that are the same
+    Keep(usize, usize),
+    /// A line of the old file that's gone
+    Delete(usize),
+    /// A line of the new file that's been added
+    Insert(usize),
+}
+
+/// The shortest edit script turning `old` into `new`, by Myers' algorithm,
+/// or None if they differ in too many places to be worth showing
+fn edits(old: &[&str], new: &[&str]) -> Option<Vec<Edit>> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let offset = n + m + 1;
+    let mut v = vec![0_isize; 2 * offset as usize + 1];
+    // The furthest reaching paths before each round, to trace back through.
+    // Only the diagonals a round could start from are kept.
+    let mut trace = Vec::new();
+    let at = |k: isize| (k + offset) as usize;
+    'search: for d in 0..=(n + m) {
+        if d as usize > MAX_EDITS {
+            return None;
+        }
+        trace.push(v[at(-d - 1)..=at(d + 1)].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+                v[at(k + 1)]
+            } else {
+                v[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let (mut x, mut y) = (n, m);
+    let mut script = Vec::new();
+    for (d, v) in trace.iter().enumerate().rev() {
+        let (d, k) = (d as isize, x - y);
+        let at = |k: isize| (k + d + 1) as usize;
+        let prev_k = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[at(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            script.push(Edit::Keep(x as usize, y as usize));
+        }
+        if d > 0 {
+            script.push(match x == prev_x {
+                true => Edit::Insert(prev_y as usize),
+                false => Edit::Delete(prev_x as usize),
+            });
+        }
+        (x, y) = (prev_x, prev_y);
+    }
+    script.reverse();
+    Some(script)
+}
+
+/// The lines changed between `old` and `new` as unified diff hunks, each
+/// with up to `CONTEXT` unchanged lines around it, or None if they differ
+/// in too many places to be worth showing
+pub fn unified(old: &str, new: &str) -> Option<String> {
+    let old: Vec<&str> = old.split_inclusive('\n').collect();
+    let new: Vec<&str> = new.split_inclusive('\n').collect();
+    let edits = edits(&old, &new)?;
+    let mut out = String::new();
+    let changes: Vec<usize> = (0..edits.len())
+        .filter(|&i| !matches!(edits[i], Edit::Keep(..)))
+        .collect();
+    let mut i = 0;
+    while i < changes.len() {
+        // Changes close enough for their context to meet share a hunk
+        let mut j = i;
+        while j + 1 < changes.len() && changes[j + 1] - changes[j] <= 2 * CONTEXT + 1 {
+            j += 1;
+        }
+        let start = changes[i].saturating_sub(CONTEXT);
+        let end = (changes[j] + CONTEXT + 1).min(edits.len());
+        let hunk = &edits[start..end];
+
+        // Where the hunk starts in each file is however many of its lines
+        // came before it
+        let (mut old_at, mut new_at) = (0, 0);
+        for edit in &edits[..start] {
+            if let Edit::Keep(..) | Edit::Delete(_) = edit {
+                old_at += 1;
+            }
+            if let Edit::Keep(..) | Edit::Insert(_) = edit {
+                new_at += 1;
+            }
+        }
+        let old_len = hunk
+            .iter()
+            .filter(|edit| !matches!(edit, Edit::Insert(_)))
+            .count();
+        let new_len = hunk
+            .iter()
+            .filter(|edit| !matches!(edit, Edit::Delete(_)))
+            .count();
+        out += &format!(
+            "@@ -{} +{} @@\n",
+            range(old_at, old_len),
+            range(new_at, new_len)
+        );
+        for edit in hunk {
+            let (sign, line) = match *edit {
+                Edit::Keep(x, _) => (' ', old[x]),
+                Edit::Delete(x) => ('-', old[x]),
+                Edit::Insert(y) => ('+', new[y]),
+            };
+            out.push(sign);
+            out += line;
+            if !line.ends_with('\n') {
+                out += "\n\\ No newline at end of file\n";
+            }
+        }
+        i = j + 1;
+    }
+    Some(out)
+}
+
+/// A hunk's range of lines in one file, counted from one. An empty range
+/// is given by the line before it.
+fn range(at: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", at),
+        1 => format!("{}", at + 1),
+        _ => format!("{},{}", at + 1, len),
+    }
+}
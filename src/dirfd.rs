@@ -0,0 +1,220 @@
+//! Directories held open by descriptor. What's in one is reached by name
+//! relative to it (`openat`, `linkat`, `unlinkat` and the like), never by
+//! a path from the root, so a walk stays in the directory it started in
+//! even if it, or one above it, is renamed or swapped for a symlink
+//! partway through. rsync and coreutils walk the trees they change the
+//! same way.
+
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::{self, File, Metadata};
+use std::io::Error;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::record::FileKind;
+
+/// What an entry in a directory is
+pub struct Stat {
+    pub kind: FileKind,
+    /// Whether it's a FIFO, which can be made anew in place of copying
+    pub fifo: bool,
+    /// The permission bits
+    pub mode: u32,
+    /// How many names it has
+    pub links: u64,
+}
+
+/// An open directory, and the path it was opened at, for messages
+pub struct Dir {
+    file: File,
+    path: PathBuf,
+}
+
+impl Dir {
+    /// Open the directory at `path`, refusing a symlink to one
+    pub fn open(path: &Path) -> Result<Dir, Error> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC)
+            .open(path)?;
+        Ok(Dir {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Where the directory was when it was opened, which it may have left
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The metadata of the directory itself
+    pub fn metadata(&self) -> Result<Metadata, Error> {
+        self.file.metadata()
+    }
+
+    /// Open the directory `name` in this one, refusing a symlink to one
+    pub fn open_dir(&self, name: &OsStr) -> Result<Dir, Error> {
+        let file = self.open_at(name, libc::O_DIRECTORY)?;
+        Ok(Dir {
+            file,
+            path: self.path.join(name),
+        })
+    }
+
+    /// Open `name` in this directory for reading, refusing a symlink.
+    /// It isn't waited on if it's a FIFO, so check what it is before
+    /// reading.
+    pub fn open_file(&self, name: &OsStr) -> Result<File, Error> {
+        self.open_at(name, libc::O_NONBLOCK)
+    }
+
+    fn open_at(&self, name: &OsStr, flags: libc::c_int) -> Result<File, Error> {
+        let name = CString::new(name.as_bytes())?;
+        let flags = libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC | flags;
+        // SAFETY: the descriptor is open and `name` is NUL-terminated
+        let fd = unsafe { libc::openat(self.file.as_raw_fd(), name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just opened, and nothing else owns it
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// The names of everything in this directory, sorted
+    pub fn entries(&self) -> Result<Vec<OsString>, Error> {
+        // The stream takes the descriptor it's given, so it gets a copy
+        let fd = self.file.try_clone()?.into_raw_fd();
+        // SAFETY: `fd` is open, and owned by the stream from here on
+        let stream = unsafe { libc::fdopendir(fd) };
+        if stream.is_null() {
+            let e = Error::last_os_error();
+            // SAFETY: the stream didn't take the descriptor
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        let mut names = Vec::new();
+        // SAFETY: the stream is open until `closedir`, and each entry is
+        // read before the next call to `readdir`. The copy shares the
+        // original's offset, so it's rewound in case this directory was
+        // listed before.
+        unsafe {
+            libc::rewinddir(stream);
+            loop {
+                let entry = libc::readdir(stream);
+                if entry.is_null() {
+                    break;
+                }
+                let name = CStr::from_ptr((*entry).d_name.as_ptr()).to_bytes();
+                if name != b"." && name != b".." {
+                    names.push(OsString::from_vec(name.to_vec()));
+                }
+            }
+            libc::closedir(stream);
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// What `name` in this directory is, without following a symlink
+    pub fn stat(&self, name: &OsStr) -> Result<Stat, Error> {
+        let name = CString::new(name.as_bytes())?;
+        let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+        // SAFETY: the descriptor is open, `name` is NUL-terminated and
+        // `stat` is only read once the call has filled it
+        let stat = unsafe {
+            if libc::fstatat(
+                self.file.as_raw_fd(),
+                name.as_ptr(),
+                stat.as_mut_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            ) != 0
+            {
+                return Err(Error::last_os_error());
+            }
+            stat.assume_init()
+        };
+        let format = stat.st_mode & libc::S_IFMT;
+        Ok(Stat {
+            kind: match format {
+                libc::S_IFREG => FileKind::File,
+                libc::S_IFDIR => FileKind::Dir,
+                libc::S_IFLNK => FileKind::Symlink,
+                _ => FileKind::Special,
+            },
+            fifo: format == libc::S_IFIFO,
+            #[allow(clippy::useless_conversion)] // It is narrower on some platforms
+            mode: u32::from(stat.st_mode) & 0o7777,
+            #[allow(clippy::useless_conversion)] // It is narrower on some platforms
+            links: u64::from(stat.st_nlink),
+        })
+    }
+
+    /// Where the symlink `name` in this directory points
+    pub fn read_link(&self, name: &OsStr) -> Result<PathBuf, Error> {
+        let name = CString::new(name.as_bytes())?;
+        let mut buf = vec![0_u8; libc::PATH_MAX as usize];
+        // SAFETY: the descriptor is open, `name` is NUL-terminated and
+        // `buf` is as long as is passed
+        let len = unsafe {
+            libc::readlinkat(
+                self.file.as_raw_fd(),
+                name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(Error::last_os_error());
+        }
+        buf.truncate(len as usize);
+        Ok(PathBuf::from(OsString::from_vec(buf)))
+    }
+
+    /// Hard link `name` in this directory at `dest`. A symlink is linked
+    /// itself, not what it points to.
+    pub fn hard_link(&self, name: &OsStr, dest: &Path) -> Result<(), Error> {
+        let name = CString::new(name.as_bytes())?;
+        let dest = CString::new(dest.as_os_str().as_bytes())?;
+        // SAFETY: the descriptor is open and both paths are NUL-terminated
+        let ret = unsafe {
+            libc::linkat(
+                self.file.as_raw_fd(),
+                name.as_ptr(),
+                libc::AT_FDCWD,
+                dest.as_ptr(),
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Remove `name` from this directory, which has to be empty if it is
+    /// a directory itself
+    pub fn remove(&self, name: &OsStr, dir: bool) -> Result<(), Error> {
+        let name = CString::new(name.as_bytes())?;
+        let flags = if dir { libc::AT_REMOVEDIR } else { 0 };
+        // SAFETY: the descriptor is open and `name` is NUL-terminated
+        if unsafe { libc::unlinkat(self.file.as_raw_fd(), name.as_ptr(), flags) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Remove everything in this directory, leaving it empty
+    pub fn clear(&self) -> Result<(), Error> {
+        for name in self.entries()? {
+            let dir = self.stat(&name)?.kind == FileKind::Dir;
+            if dir {
+                self.open_dir(&name)?.clear()?;
+            }
+            self.remove(&name, dir)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,551 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+
+use crate::delta::Deltas;
+use crate::manifest::Manifests;
+use crate::portable::Volume;
+use crate::record::{self, FileKind, Record, RecordItem, RECORD};
+use crate::{store, util};
+
+/// Mode given to the graveyard itself
+pub const GRAVEYARD_MODE: u32 = 0o700;
+
+/// Returns the permission bits of `path`, if other users can access it
+pub fn overly_permissive(path: &Path) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path).ok()?.permissions().mode() & 0o777;
+        (mode & 0o077 != 0).then_some(mode)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// Restrict `path` to its owner, keeping the owner's own bits
+pub fn make_private(path: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path)?.permissions().mode() & 0o700;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+    }
+    Ok(())
+}
+
+/// The graveyard and the directories between it and each grave, which
+/// mirror the original locations rather than holding buried contents.
+fn structural_dirs(graveyard: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![graveyard.to_path_buf()];
+    if let Ok(graves) = Record::new(graveyard).graves() {
+        for grave in graves {
+            for dir in grave
+                .dest
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| dir.starts_with(graveyard) && *dir != graveyard)
+            {
+                if dir.is_dir() && !dirs.iter().any(|d| d == dir) {
+                    dirs.push(dir.to_path_buf());
+                }
+            }
+        }
+    }
+    dirs
+}
+
+/// Report directories of the graveyard that other users can access,
+/// restricting them to their owner if `fix` is set.
+pub fn check_permissions(
+    graveyard: &Path,
+    fix: bool,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    if !graveyard.exists() {
+        writeln!(stream, "No graveyard at {}", graveyard.display())?;
+        return Ok(());
+    }
+
+    let mut found = 0;
+    for dir in structural_dirs(graveyard) {
+        if let Some(mode) = overly_permissive(&dir) {
+            found += 1;
+            if fix {
+                make_private(&dir)?;
+                writeln!(stream, "Fixed {} (was {:o})", dir.display(), mode)?;
+            } else {
+                writeln!(
+                    stream,
+                    "{} is accessible by other users ({:o})",
+                    dir.display(),
+                    mode
+                )?;
+            }
+        }
+    }
+
+    if found == 0 {
+        writeln!(stream, "Graveyard permissions are OK")?;
+    } else if !fix {
+        writeln!(stream, "Run `rip doctor --fix-perms` to repair")?;
+    }
+    Ok(())
+}
+
+/// Graves found by walking the graveyard instead of reading the record.
+/// Graves in `known` are taken as they are. Otherwise, a directory whose
+/// original location is still a directory is taken to mirror it, and
+/// anything else is a grave. Sidecar files like the record are skipped,
+/// as are directories with nothing in them to recover.
+pub fn find_graves(graveyard: &Path, known: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    let mut graves = Vec::new();
+    let mut dirs = vec![graveyard.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if dir == graveyard && entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            if known.contains(&path) {
+                graves.push(path);
+                continue;
+            }
+            if !entry.file_type()?.is_dir() {
+                graves.push(path);
+                continue;
+            }
+            let mirrors = known.iter().any(|grave| grave.starts_with(&path))
+                || original_of(graveyard, &path)
+                    .and_then(|orig| fs::symlink_metadata(orig).ok())
+                    .is_some_and(|metadata| metadata.is_dir());
+            if mirrors {
+                dirs.push(path);
+            } else if fs::read_dir(&path)?.next().is_some() {
+                graves.push(path);
+            }
+        }
+    }
+    graves.sort();
+    Ok(graves)
+}
+
+/// Remove the directories in the graveyard left empty by graves that are
+/// gone, other than graves that are empty directories themselves
+pub fn prune_empty(graveyard: &Path, stream: &mut impl Write) -> Result<(), Error> {
+    if !graveyard.exists() {
+        writeln!(stream, "No graveyard at {}", graveyard.display())?;
+        return Ok(());
+    }
+    let known: HashSet<PathBuf> = Record::new(graveyard)
+        .graves()?
+        .map(|grave| grave.dest)
+        .collect();
+    // Every directory outside of a grave, each after those above it
+    let mut found = Vec::new();
+    let mut dirs = vec![graveyard.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if dir == graveyard && entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            if entry.file_type()?.is_dir() && !known.contains(&path) {
+                dirs.push(path.clone());
+                found.push(path);
+            }
+        }
+    }
+    let removed: usize = found
+        .iter()
+        .rev()
+        .map(|dir| util::prune_empty_dirs(dir, graveyard, |dir| known.contains(dir)))
+        .sum();
+    writeln!(stream, "Removed {} empty director(ies)", removed)?;
+    Ok(())
+}
+
+/// Where the grave at `grave` was buried from, going by its path in the
+/// graveyard. A `~N` suffix is taken to be from a rename on conflict if
+/// the grave it conflicted with is still there.
+fn original_of(graveyard: &Path, grave: &Path) -> Option<PathBuf> {
+    let orig = match Volume::of(graveyard) {
+        Some(volume) => volume.original_of(grave)?,
+        None => util::original_of_grave(graveyard, grave)?,
+    };
+    if let Some((base, _)) = rename_suffix(grave) {
+        if util::symlink_exists(grave.with_file_name(base)) {
+            return Some(orig.with_file_name(base));
+        }
+    }
+    Some(orig)
+}
+
+/// The name `grave` would have without the `~N` suffix of a rename on
+/// conflict, and N
+fn rename_suffix(grave: &Path) -> Option<(&str, u64)> {
+    let (base, n) = grave.file_name()?.to_str()?.rsplit_once('~')?;
+    let digits = !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit());
+    match !base.is_empty() && digits {
+        true => Some((base, n.parse().ok()?)),
+        false => None,
+    }
+}
+
+/// Graves in the graveyard that the record doesn't know about, like ones
+/// copied in by hand or left behind by a lost record
+pub fn orphans(graveyard: &Path, record: &Record) -> Result<Vec<PathBuf>, Error> {
+    let known: Vec<PathBuf> = record.graves()?.map(|grave| grave.dest).collect();
+    Ok(find_graves(graveyard, &known)?
+        .into_iter()
+        .filter(|grave| !known.contains(grave))
+        .collect())
+}
+
+/// Record entries for `orphans`, marked as adopted and dated by when they
+/// were last modified, oldest first
+fn adoptees(graveyard: &Path, orphans: Vec<PathBuf>) -> Result<Vec<RecordItem>, Error> {
+    let mut adoptees = Vec::new();
+    for grave in orphans {
+        let Some(orig) = original_of(graveyard, &grave) else {
+            continue;
+        };
+        let metadata = fs::symlink_metadata(&grave)?;
+        adoptees.push((metadata.modified()?, orig, grave, FileKind::of(&metadata)));
+    }
+    adoptees.sort();
+    Ok(adoptees
+        .into_iter()
+        .map(|(modified, orig, dest, kind)| RecordItem {
+            status: Some("adopted".to_string()),
+            kind: Some(kind),
+            ..RecordItem::buried(DateTime::<Local>::from(modified), orig, dest)
+        })
+        .collect())
+}
+
+/// Report graves that are missing from the record, and so invisible to
+/// seance and unbury
+pub fn check_record(graveyard: &Path, stream: &mut impl Write) -> Result<(), Error> {
+    if !graveyard.exists() {
+        return Ok(());
+    }
+    let orphans = orphans(graveyard, &Record::new(graveyard))?;
+    if orphans.is_empty() {
+        writeln!(stream, "Every grave is in the record")?;
+    } else {
+        for grave in &orphans {
+            writeln!(stream, "{} is missing from the record", grave.display())?;
+        }
+        writeln!(
+            stream,
+            "Run `rip doctor --adopt` to add {} grave(s) to the record, \
+             or `rip doctor --orphans` to list them for deletion",
+            orphans.len()
+        )?;
+    }
+    Ok(())
+}
+
+/// Print the path of each grave missing from the record, one per line
+pub fn list_orphans(graveyard: &Path, stream: &mut impl Write) -> Result<(), Error> {
+    if !graveyard.exists() {
+        return Ok(());
+    }
+    for grave in orphans(graveyard, &Record::new(graveyard))? {
+        writeln!(stream, "{}", grave.display())?;
+    }
+    Ok(())
+}
+
+/// Add the graves missing from the record to it, as the oldest graves.
+/// Their original paths are taken from where they are in the graveyard,
+/// and their times from when they were last modified.
+pub fn adopt_orphans(graveyard: &Path, chain: bool, stream: &mut impl Write) -> Result<(), Error> {
+    if !graveyard.exists() {
+        writeln!(stream, "No graveyard at {}", graveyard.display())?;
+        return Ok(());
+    }
+    let record = Record::new(graveyard).with_chain(chain);
+    let adoptees = adoptees(graveyard, orphans(graveyard, &record)?)?;
+    for adoptee in &adoptees {
+        writeln!(
+            stream,
+            "Adopted {} from {}",
+            adoptee.dest.display(),
+            adoptee.orig.display()
+        )?;
+    }
+    let count = adoptees.len();
+    record.prepend(adoptees)?;
+    writeln!(stream, "Adopted {} grave(s) into the record", count)?;
+    Ok(())
+}
+
+/// Rebuild the record from the graveyard: entries for graves that are gone
+/// or unreadable are dropped, and graves missing from it are adopted.
+pub fn rebuild_record(graveyard: &Path, chain: bool, stream: &mut impl Write) -> Result<(), Error> {
+    if !graveyard.exists() {
+        writeln!(stream, "No graveyard at {}", graveyard.display())?;
+        return Ok(());
+    }
+    let record = Record::new(graveyard).with_chain(chain);
+    let unreadable = record.malformed_lines()?.len();
+    let (kept, gone): (Vec<RecordItem>, Vec<RecordItem>) = record
+        .graves()?
+        .partition(|grave| util::symlink_exists(&grave.dest));
+
+    let recovered = adoptees(graveyard, orphans(graveyard, &record)?)?;
+    let count = recovered.len();
+    record.replace(recovered.into_iter().chain(kept))?;
+
+    writeln!(stream, "Recovered {} grave(s) into the record", count)?;
+    if !gone.is_empty() {
+        writeln!(
+            stream,
+            "Dropped {} entries for graves that are gone",
+            gone.len()
+        )?;
+    }
+    if unreadable > 0 {
+        writeln!(stream, "Dropped {} unreadable line(s)", unreadable)?;
+    }
+    Ok(())
+}
+
+/// Cross-check the record against the graveyard: entries for graves that
+/// are gone, graves missing from the record, unreadable lines and entries
+/// sharing a grave. With `repair`, the record is rewritten without the
+/// bad entries, keeping the newest of each shared grave, and the missing
+/// graves are adopted. Returns whether everything was consistent or fixed.
+pub fn fsck(
+    graveyard: &Path,
+    repair: bool,
+    chain: bool,
+    stream: &mut impl Write,
+) -> Result<bool, Error> {
+    if !graveyard.exists() {
+        writeln!(stream, "No graveyard at {}", graveyard.display())?;
+        return Ok(true);
+    }
+    let record = Record::new(graveyard).with_chain(chain);
+    let malformed = record.malformed_lines()?;
+    for line in &malformed {
+        writeln!(stream, "Line {}: unreadable", line)?;
+    }
+
+    let mut kept = Vec::new();
+    let mut gone = Vec::new();
+    let mut duplicates: Vec<(PathBuf, usize)> = Vec::new();
+    // Walk newest first, so the newest entry of a shared grave is kept
+    let graves: Vec<RecordItem> = record.graves()?.collect();
+    for grave in graves.into_iter().rev() {
+        if !util::symlink_exists(&grave.dest) {
+            writeln!(
+                stream,
+                "{} is in the record, but not in the graveyard",
+                grave.dest.display()
+            )?;
+            gone.push(grave);
+        } else if kept.iter().any(|kept: &RecordItem| kept.dest == grave.dest) {
+            match duplicates.iter_mut().find(|(dest, _)| *dest == grave.dest) {
+                Some((_, count)) => *count += 1,
+                None => duplicates.push((grave.dest.clone(), 2)),
+            }
+        } else {
+            kept.push(grave);
+        }
+    }
+    kept.reverse();
+    for (dest, count) in &duplicates {
+        writeln!(
+            stream,
+            "{} is in the record {} times",
+            dest.display(),
+            count
+        )?;
+    }
+
+    let orphans = orphans(graveyard, &record)?;
+    for grave in &orphans {
+        writeln!(stream, "{} is missing from the record", grave.display())?;
+    }
+
+    let problems = malformed.len() + gone.len() + duplicates.len() + orphans.len();
+    if problems == 0 {
+        writeln!(stream, "The record and the graveyard agree")?;
+        return Ok(true);
+    }
+    if !repair {
+        writeln!(
+            stream,
+            "Found {} problem(s); run `rip fsck --repair` to fix them",
+            problems
+        )?;
+        return Ok(false);
+    }
+
+    let adopted = adoptees(graveyard, orphans)?;
+    let count = adopted.len();
+    record.replace(adopted.into_iter().chain(kept))?;
+    if !malformed.is_empty() {
+        writeln!(stream, "Dropped {} unreadable line(s)", malformed.len())?;
+    }
+    if !gone.is_empty() {
+        writeln!(
+            stream,
+            "Dropped {} entries for graves that are gone",
+            gone.len()
+        )?;
+    }
+    if !duplicates.is_empty() {
+        writeln!(
+            stream,
+            "Kept the newest entry of {} shared grave(s)",
+            duplicates.len()
+        )?;
+    }
+    if count > 0 {
+        writeln!(stream, "Adopted {} grave(s) into the record", count)?;
+    }
+    Ok(true)
+}
+
+/// Rewrite the record without the entries for graves that are gone, or
+/// the repeats of an entry
+pub fn compact(graveyard: &Path, chain: bool, stream: &mut impl Write) -> Result<(), Error> {
+    if !store::exists(graveyard) {
+        writeln!(stream, "No record at {}", graveyard.display())?;
+        return Ok(());
+    }
+    let (gone, repeats) = Record::open_in(graveyard)?.with_chain(chain).compact()?;
+    if gone == 0 && repeats == 0 {
+        writeln!(stream, "The record is already compact")?;
+    }
+    if gone > 0 {
+        writeln!(stream, "Dropped {} entries for graves that are gone", gone)?;
+    }
+    if repeats > 0 {
+        writeln!(stream, "Dropped {} repeated entries", repeats)?;
+    }
+    Ok(())
+}
+
+/// Close the gaps in the `~N` suffixes of graves renamed on conflict,
+/// left when the graves before them were unburied or purged. `dir~3`
+/// becomes `dir~1` once `dir~1` and `dir~2` are gone, or `dir` once that
+/// is gone too. Only suffixes that the grave's original name lacks count.
+pub fn renumber(graveyard: &Path, chain: bool, stream: &mut impl Write) -> Result<(), Error> {
+    if !store::exists(graveyard) {
+        writeln!(stream, "No record at {}", graveyard.display())?;
+        return Ok(());
+    }
+    let record = Record::open_in(graveyard)?.with_chain(chain);
+    let mut renamed: Vec<(PathBuf, u64)> = record
+        .graves()?
+        .filter_map(|grave| {
+            let (base, n) = rename_suffix(&grave.dest)?;
+            let original = grave.orig.file_name()?.to_str()? == base;
+            (original && util::symlink_exists(&grave.dest)).then_some((grave.dest, n))
+        })
+        .collect();
+    // Graves inside a directory go before it, while their paths still
+    // hold, and each chain in order, so it closes up from the start
+    renamed.sort_by_key(|(grave, n)| (Reverse(grave.components().count()), *n));
+    renamed.dedup();
+
+    let deltas = Deltas::new(graveyard);
+    let manifests = Manifests::new(graveyard);
+    let mut moves = Vec::new();
+    for (grave, n) in renamed {
+        let Some((base, _)) = rename_suffix(&grave) else {
+            continue;
+        };
+        let base = grave.with_file_name(base);
+        let slots = (1..n).map(|i| {
+            let mut name = base.as_os_str().to_owned();
+            name.push(format!("~{}", i));
+            PathBuf::from(name)
+        });
+        let Some(slot) = std::iter::once(base.clone())
+            .chain(slots)
+            .find(|slot| !util::symlink_exists(slot))
+        else {
+            continue;
+        };
+        // Deltas are indexed by path, so they're made whole first
+        deltas.release(&grave)?;
+        if let Err(e) = fs::rename(&grave, &slot) {
+            writeln!(stream, "Couldn't rename {}: {}", grave.display(), e)?;
+            continue;
+        }
+        manifests.rename(&grave, &slot)?;
+        writeln!(stream, "{} -> {}", grave.display(), slot.display())?;
+        moves.push((grave, slot));
+    }
+    record.move_graves(&moves)?;
+    writeln!(stream, "Renumbered {} grave(s)", moves.len())?;
+    Ok(())
+}
+
+/// Rewrite a record left by the original rip in rip2's format: with a
+/// header, RFC 3339 times and grave IDs. Entries that rip2 added to it
+/// since are kept as they are. Nothing is changed if a line is in neither
+/// format.
+pub fn migrate(graveyard: &Path, chain: bool, stream: &mut impl Write) -> Result<(), Error> {
+    if !record::is_legacy(graveyard) {
+        match store::exists(graveyard) {
+            true => writeln!(stream, "The record is already in rip2's format")?,
+            false => writeln!(stream, "No record at {}", graveyard.display())?,
+        }
+        return Ok(());
+    }
+    let volume = Volume::of(graveyard);
+    let file = fs::File::open(graveyard.join(RECORD))?;
+    let mut graves = Vec::new();
+    let mut migrated = 0;
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(grave) = RecordItem::from_legacy(&line) {
+            migrated += 1;
+            graves.push(grave);
+        } else if let Some(grave) = RecordItem::new(&line).filter(|item| item.age().is_some()) {
+            graves.push(match &volume {
+                Some(volume) => volume.load(grave),
+                None => grave,
+            });
+        } else {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Line {} of the record is in neither the original rip's format nor \
+                     rip2's; fix or remove it, then run `rip migrate` again",
+                    i + 1
+                ),
+            ));
+        }
+    }
+    Record::new(graveyard).with_chain(chain).replace(graves)?;
+    writeln!(
+        stream,
+        "Migrated {} grave(s) from the original rip's record",
+        migrated
+    )?;
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use std::io::{Error, Write};
+use std::path::Path;
+
+use crate::record::{FileKind, Record, RecordItem};
+use crate::schema::Versioned;
+use crate::store;
+
+const CSV_HEADER: &str = "time,orig,dest,id,kind,note,status,checksum,op,user,host,cwd";
+
+/// Formats the record can be exported in
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// The graves, tagged with the schema version
+    Json,
+    /// A row per grave, under a header
+    Csv,
+}
+
+/// Write the graves in the record, oldest first, to `stream` in `format`.
+/// With `since`, only graves buried within that long are written. A
+/// graveyard without a record exports as empty.
+pub fn export(
+    graveyard: &Path,
+    format: Format,
+    since: Option<chrono::Duration>,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    let graves: Vec<RecordItem> = if store::exists(graveyard) {
+        Record::open_in(graveyard)?
+            .graves()?
+            .filter(|grave| since.is_none_or(|since| grave.age().is_some_and(|age| age <= since)))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *stream, &Versioned::new(graves))?;
+            writeln!(stream)?;
+        }
+        Format::Csv => {
+            writeln!(stream, "{}", CSV_HEADER)?;
+            for grave in graves {
+                let origin = grave.origin.as_ref();
+                let fields = [
+                    grave.time.as_str(),
+                    &grave.orig.to_string_lossy(),
+                    &grave.dest.to_string_lossy(),
+                    grave.id.as_deref().unwrap_or_default(),
+                    grave.kind.map_or("", FileKind::as_str),
+                    grave.note.as_deref().unwrap_or_default(),
+                    grave.status.as_deref().unwrap_or_default(),
+                    grave.checksum.as_deref().unwrap_or_default(),
+                    grave.op.as_deref().unwrap_or_default(),
+                    origin.map_or("", |origin| &origin.user),
+                    origin.map_or("", |origin| &origin.host),
+                    &origin.map_or_else(String::new, |origin| {
+                        origin.cwd.to_string_lossy().into_owned()
+                    }),
+                ]
+                .map(csv_field);
+                writeln!(stream, "{}", fields.join(","))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Quote `field` if it has a comma, quote or line break in it, doubling
+/// any quotes
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
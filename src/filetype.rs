@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use crate::record::RecordItem;
+
+/// Broad kinds of file, each a group of extensions
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Group {
+    Image,
+    Video,
+    Audio,
+    Document,
+    Archive,
+    Code,
+    Text,
+}
+
+impl Group {
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Group::Image => &[
+                "png", "jpg", "jpeg", "gif", "webp", "bmp", "tif", "tiff", "svg", "heic", "heif",
+                "avif", "ico", "raw", "cr2", "nef", "psd",
+            ],
+            Group::Video => &[
+                "mp4", "m4v", "mov", "mkv", "webm", "avi", "wmv", "flv", "mpg", "mpeg",
+            ],
+            Group::Audio => &[
+                "mp3", "wav", "flac", "ogg", "oga", "opus", "m4a", "aac", "wma", "aiff",
+            ],
+            Group::Document => &[
+                "pdf", "doc", "docx", "odt", "rtf", "xls", "xlsx", "ods", "ppt", "pptx", "odp",
+                "epub", "tex",
+            ],
+            Group::Archive => &[
+                "zip", "tar", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar", "iso", "dmg",
+            ],
+            Group::Code => &[
+                "rs", "py", "js", "ts", "jsx", "tsx", "c", "h", "cpp", "hpp", "cc", "go", "java",
+                "kt", "rb", "php", "swift", "sh", "bash", "zsh", "fish", "lua", "jl", "r", "cs",
+                "scala", "hs", "ml", "html", "css", "sql",
+            ],
+            Group::Text => &[
+                "txt", "md", "rst", "org", "csv", "tsv", "json", "toml", "yaml", "yml", "xml",
+                "ini", "cfg", "conf", "log",
+            ],
+        }
+    }
+}
+
+/// What `--type` selects: a group of extensions, or a single one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TypeFilter {
+    Group(Group),
+    Extension(String),
+}
+
+impl TypeFilter {
+    pub fn matches(&self, extension: &str) -> bool {
+        match self {
+            TypeFilter::Group(group) => group.extensions().contains(&extension),
+            TypeFilter::Extension(wanted) => wanted == extension,
+        }
+    }
+}
+
+/// Parse a `--type`: the name of a group, like `image`, or an extension,
+/// like `pdf` or `.pdf`
+pub fn parse_type_filter(value: &str) -> Result<TypeFilter, String> {
+    if let Ok(group) = Group::from_str(value, true) {
+        return Ok(TypeFilter::Group(group));
+    }
+    let extension = value.strip_prefix('.').unwrap_or(value);
+    if extension.is_empty() || !extension.chars().all(|c| c.is_ascii_alphanumeric()) {
+        let groups: Vec<String> = Group::value_variants()
+            .iter()
+            .filter_map(|g| Some(g.to_possible_value()?.get_name().to_string()))
+            .collect();
+        return Err(format!(
+            "{} is neither an extension nor one of {}",
+            value,
+            groups.join(", ")
+        ));
+    }
+    Ok(TypeFilter::Extension(extension.to_ascii_lowercase()))
+}
+
+/// The extension that a file's contents start like, for the common
+/// formats that are recognizable from their first bytes
+pub fn sniff(path: &Path) -> Option<&'static str> {
+    let mut head = [0; 12];
+    let mut file = File::open(path).ok()?;
+    let len = file.read(&mut head).ok()?;
+    let head = &head[..len];
+    let signatures: [(&[u8], &str); 13] = [
+        (b"\x89PNG\r\n\x1a\n", "png"),
+        (b"\xff\xd8\xff", "jpg"),
+        (b"GIF8", "gif"),
+        (b"%PDF", "pdf"),
+        (b"PK\x03\x04", "zip"),
+        (b"\x1f\x8b", "gz"),
+        (b"\xfd7zXZ\x00", "xz"),
+        (b"7z\xbc\xaf\x27\x1c", "7z"),
+        (b"BZh", "bz2"),
+        (b"ID3", "mp3"),
+        (b"fLaC", "flac"),
+        (b"OggS", "ogg"),
+        (b"\x1aE\xdf\xa3", "mkv"),
+    ];
+    if let Some((_, extension)) = signatures.iter().find(|(sig, _)| head.starts_with(sig)) {
+        return Some(extension);
+    }
+    match (head.get(..4), head.get(4..8), head.get(8..12)) {
+        (Some(b"RIFF"), _, Some(b"WEBP")) => Some("webp"),
+        (Some(b"RIFF"), _, Some(b"WAVE")) => Some("wav"),
+        (Some(b"RIFF"), _, Some(b"AVI ")) => Some("avi"),
+        (_, Some(b"ftyp"), Some(b"qt  ")) => Some("mov"),
+        (_, Some(b"ftyp"), Some(b"M4A ")) => Some("m4a"),
+        (_, Some(b"ftyp"), Some(b"heic")) => Some("heic"),
+        (_, Some(b"ftyp"), Some(b"avif")) => Some("avif"),
+        (_, Some(b"ftyp"), _) => Some("mp4"),
+        _ => None,
+    }
+}
+
+/// The lowercase extension of a grave's original name, or, if it had
+/// none, the one that its contents look like
+pub fn extension_of(grave: &RecordItem) -> Option<String> {
+    if let Some(extension) = grave.orig.extension() {
+        return Some(extension.to_string_lossy().to_ascii_lowercase());
+    }
+    if !grave.dest.is_file() {
+        return None;
+    }
+    sniff(&grave.dest).map(str::to_string)
+}
+
+/// Whether `grave` is of any of the types in `filters`, or there are none
+pub fn grave_matches(grave: &RecordItem, filters: &[TypeFilter]) -> bool {
+    if filters.is_empty() {
+        return true;
+    }
+    let Some(extension) = extension_of(grave) else {
+        return false;
+    };
+    filters.iter().any(|filter| filter.matches(&extension))
+}
@@ -0,0 +1,197 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
+
+use walkdir::WalkDir;
+
+#[cfg(unix)]
+use std::os::unix::fs::symlink;
+#[cfg(target_os = "windows")]
+use std::os::windows::fs::symlink_file as symlink;
+
+use crate::delta::{self, Deltas};
+use crate::meta::FileMeta;
+use crate::record::{Record, RecordItem};
+use crate::util::{self, PermissionPolicy};
+use crate::{make_graveyard, store};
+
+/// Copy the graves of the graveyard at `from` into `graveyard`, and merge
+/// their entries into its record in order of burial. A grave whose place
+/// is taken is renamed as a duplicate would be when burying. `from` is
+/// left as it was.
+pub fn import(
+    graveyard: &Path,
+    from: &Path,
+    chain: bool,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    if !store::exists(from) {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No record at {}, so it isn't a graveyard", from.display()),
+        ));
+    }
+    let from = &dunce::canonicalize(from)?;
+    if dunce::canonicalize(graveyard).is_ok_and(|graveyard| graveyard == *from) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Can't import a graveyard into itself",
+        ));
+    }
+    make_graveyard(graveyard)?;
+    let record = Record::open_in(graveyard)?.with_chain(chain);
+    let unreadable = record.malformed_lines()?.len();
+    if unreadable > 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "The record has {} unreadable line(s), which merging would drop; \
+                 run `rip fsck --repair` first",
+                unreadable
+            ),
+        ));
+    }
+
+    let (mut imported, mut renamed, mut missing) = (Vec::new(), 0, 0);
+    let mut cache = util::DirCache::default();
+    let deltas = Deltas::new(from);
+    for grave in Record::open_in(from)?.graves()? {
+        let Some(source) = grave_in(from, &grave).filter(|source| util::symlink_exists(source))
+        else {
+            missing += 1;
+            continue;
+        };
+        let mut dest = graveyard.join(source.strip_prefix(from).unwrap_or(&source));
+        if util::symlink_exists(&dest) {
+            dest = util::rename_grave(&dest);
+            renamed += 1;
+        }
+        if let Some(parent) = dest.parent() {
+            util::create_dirs_with_permissions(
+                parent,
+                source.parent(),
+                PermissionPolicy::Mirror,
+                &mut cache,
+            )?;
+        }
+        copy_grave(&source, &dest)?;
+        // A delta is only readable against its base in `from`, so it's
+        // brought over whole. The index has the paths the graves were
+        // buried to, like the record.
+        let inner = source.strip_prefix(from).unwrap_or(&source);
+        let buried_in = grave.dest.ancestors().nth(inner.components().count());
+        let moved = |path: &Path| match buried_in.and_then(|root| path.strip_prefix(root).ok()) {
+            Some(inner) => from.join(inner),
+            None => path.to_path_buf(),
+        };
+        for (delta, base) in deltas.within(&grave.dest) {
+            let (delta, base) = (moved(&delta), moved(&base));
+            let contents = delta::decode(&fs::read(&base)?, &fs::read(&delta)?).map_err(|e| {
+                Error::new(
+                    e.kind(),
+                    format!("Failed to decode {}: {}", delta.display(), e),
+                )
+            })?;
+            let to = match delta.strip_prefix(&source) {
+                Ok(inner) if inner != Path::new("") => dest.join(inner),
+                _ => dest.clone(),
+            };
+            util::unprotect(&to)?;
+            fs::write(&to, contents)?;
+            if let Some(meta) = FileMeta::of(&delta) {
+                meta.apply(&to).ok();
+            }
+        }
+        imported.push(RecordItem {
+            dest,
+            prev: None,
+            ..grave
+        });
+    }
+
+    let count = imported.len();
+    let mut graves: Vec<RecordItem> = record.graves()?.chain(imported).collect();
+    // Stable, so graves buried at the same time keep their order
+    graves.sort_by_key(|grave| chrono::DateTime::parse_from_rfc3339(&grave.time).ok());
+    record.replace(graves)?;
+
+    writeln!(
+        stream,
+        "Imported {} grave(s) from {}",
+        count,
+        from.display()
+    )?;
+    if renamed > 0 {
+        writeln!(stream, "Renamed {} grave(s) whose place was taken", renamed)?;
+    }
+    if missing > 0 {
+        writeln!(
+            stream,
+            "Skipped {} entries for graves missing from {}",
+            missing,
+            from.display()
+        )?;
+    }
+    Ok(())
+}
+
+/// Where `grave` is in the graveyard at `from`, which may have been
+/// somewhere else when it was buried, like on an old machine. Graves sit
+/// at their original path under the graveyard, so outside of `from`,
+/// the grave's path is taken to be its last as many components.
+fn grave_in(from: &Path, grave: &RecordItem) -> Option<PathBuf> {
+    if grave.dest.starts_with(from) {
+        return Some(grave.dest.clone());
+    }
+    let depth = util::join_absolute(from, &grave.orig)
+        .strip_prefix(from)
+        .ok()?
+        .components()
+        .count();
+    let components: Vec<Component> = grave.dest.components().collect();
+    let inner: PathBuf = components[components.len().checked_sub(depth)?..]
+        .iter()
+        .collect();
+    Some(from.join(inner))
+}
+
+/// Copy the grave at `source` to `dest`, keeping timestamps and the
+/// targets of symlinks
+pub fn copy_grave(source: &Path, dest: &Path) -> Result<(), Error> {
+    let mut dirs = Vec::new();
+    for entry in WalkDir::new(source) {
+        let entry = entry?;
+        let to = match entry.path().strip_prefix(source) {
+            Ok(inner) if inner != Path::new("") => dest.join(inner),
+            _ => dest.to_path_buf(),
+        };
+        let filetype = entry.file_type();
+        if filetype.is_dir() {
+            fs::create_dir_all(&to)?;
+            // Set once their contents are in, which changes their times
+            dirs.push((to, FileMeta::of(entry.path())));
+            continue;
+        } else if filetype.is_symlink() {
+            symlink(fs::read_link(entry.path())?, &to)?;
+        } else if filetype.is_file() {
+            fs::copy(entry.path(), &to).map_err(|e| {
+                Error::new(
+                    e.kind(),
+                    format!("Failed to copy {}: {}", entry.path().display(), e),
+                )
+            })?;
+        } else {
+            // Sockets and the like only stand in for what they were
+            continue;
+        }
+        if let Some(meta) = FileMeta::of(entry.path()) {
+            meta.apply(&to).ok();
+        }
+    }
+    for (dir, meta) in dirs.iter().rev() {
+        if let Some(meta) = meta {
+            meta.apply(dir).ok();
+        }
+    }
+    Ok(())
+}
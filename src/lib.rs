@@ -1,54 +1,369 @@
 use clap::CommandFactory;
 use fs_extra::dir::get_size;
+use std::collections::HashSet;
 use std::fs::Metadata;
 use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
 use std::path::{Path, PathBuf};
 use std::{env, fs};
-use walkdir::WalkDir;
+use walkdir::{WalkDir, WalkDirIterator};
 
 // Platform-specific imports
 #[cfg(unix)]
+use std::ffi::{OsStr, OsString};
+#[cfg(unix)]
 use std::os::unix::fs::{symlink, FileTypeExt, PermissionsExt};
 
 #[cfg(target_os = "windows")]
 use std::os::windows::fs::symlink_file as symlink;
 
 pub mod args;
+pub mod audit;
+pub mod checksum;
 pub mod completions;
+pub mod config;
+pub mod context;
+pub mod delta;
+pub mod diff;
+#[cfg(unix)]
+pub mod dirfd;
+pub mod doctor;
+pub mod export;
+pub mod filetype;
+pub mod import;
+pub mod manifest;
+pub mod media;
+pub mod meta;
+pub mod parents;
+pub mod portable;
 pub mod record;
+pub mod sandbox;
+pub mod schema;
+pub mod stats;
+pub mod store;
+#[cfg(feature = "testkit")]
+pub mod testkit;
 pub mod util;
+pub mod warnings;
 
-use args::Args;
-use record::{Record, RecordItem};
+use args::{Args, Commands};
+use audit::{AuditLog, Reason};
+use context::Context;
+use delta::Deltas;
+use manifest::Manifests;
+use meta::FileMeta;
+use parents::Parents;
+#[cfg(unix)]
+use record::FileKind;
+pub use record::{Record, RecordItem};
+use stats::{Snapshot, Stats};
+use util::{PermissionPolicy, Strategy};
+use warnings::Warnings;
 
 const LINES_TO_INSPECT: usize = 6;
 const FILES_TO_INSPECT: usize = 6;
 pub const BIG_FILE_THRESHOLD: u64 = 500000000; // 500 MB
+/// Burying more targets than this asks for confirmation first
+pub const MANY_TARGETS: usize = 20;
+const LARGEST_TO_SHOW: usize = 3;
+/// Paths that are refused as targets unless the config says otherwise
+#[cfg(not(windows))]
+pub const PROTECTED_PATHS: [&str; 2] = ["/", "/home"];
+#[cfg(windows)]
+pub const PROTECTED_PATHS: [&str; 2] = ["C:\\", "C:\\Windows"];
 
+/// Run with the process's current directory and environment, and the
+/// settings of the config file and nearest `.riprc`
 pub fn run(cli: Args, mode: impl util::TestingMode, stream: &mut impl Write) -> Result<(), Error> {
-    args::validate_args(&cli)?;
-    let graveyard: &PathBuf = &get_graveyard(cli.graveyard);
+    let ctx = Context::from_process()?.load_config_for(cli.profile.as_deref())?;
+    run_with(cli, &ctx, mode, stream)
+}
 
-    if !graveyard.exists() {
-        fs::create_dir_all(graveyard)?;
+/// Like `run`, but taking the current directory and environment from
+/// `ctx` instead of the process
+pub fn run_with(
+    cli: Args,
+    ctx: &Context,
+    mode: impl util::TestingMode,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    run_burying(cli, ctx, mode, stream, &mut Vec::new())
+}
 
-        #[cfg(unix)]
-        {
-            let metadata = graveyard.metadata()?;
-            let mut permissions = metadata.permissions();
-            permissions.set_mode(0o700);
+/// Bury `cli.targets` as `rip TARGET...` would, returning the record
+/// entry of each target buried, in order, so that tools built on rip
+/// know where the graves are without reading the record. Targets that
+/// weren't buried, e.g. ones excluded or declined at a prompt, have no
+/// entry.
+pub fn bury(
+    cli: Args,
+    ctx: &Context,
+    mode: impl util::TestingMode,
+    stream: &mut impl Write,
+) -> Result<Vec<RecordItem>, Error> {
+    if cli.seance || cli.unbury.is_some() || cli.decompose || cli.command.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "bury only buries targets; use run for other actions",
+        ));
+    }
+    let mut buried = Vec::new();
+    if !cli.targets.is_empty() {
+        run_burying(cli, ctx, mode, stream, &mut buried)?;
+    }
+    Ok(buried)
+}
+
+/// Like `run_with`, collecting the record entries of buried targets
+/// into `buried`
+fn run_burying(
+    cli: Args,
+    ctx: &Context,
+    mode: impl util::TestingMode,
+    stream: &mut impl Write,
+    buried: &mut Vec<RecordItem>,
+) -> Result<(), Error> {
+    let strict = util::flag_or_env_bool(cli.strict, "RIP_STRICT", ctx);
+    let warnings = Warnings::new();
+    let result = run_warning(cli, ctx, mode, stream, &warnings, buried);
+    // Warnings are still worth seeing when the run failed partway,
+    // but the failure is what to report
+    warnings.finish(strict && result.is_ok(), stream)?;
+    result
+}
+
+fn run_warning(
+    cli: Args,
+    ctx: &Context,
+    mode: impl util::TestingMode,
+    stream: &mut impl Write,
+    warnings: &Warnings,
+    buried: &mut Vec<RecordItem>,
+) -> Result<(), Error> {
+    let cli = args::resolve_args(cli, ctx)?;
+    let (graveyard, source) = &locate_graveyard(cli.graveyard.clone(), ctx);
+    check_graveyard(graveyard).map_err(|problem| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Can't use {} from {} as the graveyard: {} (see `rip graveyard --explain`)",
+                graveyard.display(),
+                graveyard_source(*source, ctx),
+                problem
+            ),
+        )
+    })?;
+    let strategy = util::get_strategy(cli.strategy, ctx)?;
+    let max_age = get_max_age(cli.max_age, ctx)?;
+    let force = cli.force;
+    let big_file = get_big_file(cli.big_file, force, ctx)?;
+    let prompts = util::get_prompt_policy(ctx)?;
+    if cli.expiring.is_some() && max_age.is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--expiring requires a retention policy (--max-age or RIP_MAX_AGE)",
+        ));
+    }
+    // Whether a grave was buried between --since and --before
+    let in_window = |grave: &RecordItem| match (cli.since, cli.before) {
+        (None, None) => true,
+        (since, before) => grave.age_at(ctx.clock.now()).is_some_and(|age| {
+            since.is_none_or(|since| age <= since) && before.is_none_or(|before| age >= before)
+        }),
+    };
+    let time_filtered = cli.since.is_some() || cli.before.is_some();
+    // Whether a grave will be purged within the --expiring window, is of
+    // a --type asked for, and was buried in the window asked for
+    let wanted = |grave: &RecordItem| {
+        let expiring = match (cli.expiring, max_age) {
+            (Some(window), Some(max_age)) => {
+                time_to_expiry(grave, max_age, ctx.clock.now()) <= window
+            }
+            _ => true,
+        };
+        expiring && filetype::grave_matches(grave, &cli.file_type) && in_window(grave)
+    };
+
+    // Only burying needs a graveyard; everything else reads what's there
+    let burying = cli.command.is_none()
+        && !cli.explain
+        && !cli.seance
+        && cli.unbury.is_none()
+        && !cli.decompose
+        && !cli.targets.is_empty();
+    let sandboxed = util::flag_or_env_bool(cli.sandbox, "RIP_SANDBOX", ctx);
+    let portable = util::flag_or_env_bool(cli.portable, "RIP_PORTABLE", ctx);
+    let backend = util::get_record_backend(ctx)?;
+    if !graveyard.exists() {
+        // The first grave makes it, unless it has to be there beforehand
+        // to be marked, to hold a record in another store, or to be allowed
+        // by the sandbox
+        if burying && (sandboxed || portable || backend.is_some()) {
+            make_graveyard(graveyard)?;
         }
-        // TODO: Default permissions on windows should be good, but need to double-check.
+    } else if let Some(mode) = doctor::overly_permissive(graveyard) {
+        warnings.warn(format!(
+            "graveyard {} is accessible by other users ({:o}); \
+             run `rip doctor --fix-perms` to repair",
+            graveyard.display(),
+            mode
+        ));
     }
 
-    // Stores the deleted files
-    let record = Record::new(graveyard);
-    let cwd = &env::current_dir()?;
+    // Stores the deleted files, moved into the store asked for first
+    if let Some(backend) = backend {
+        if graveyard.exists() {
+            store::convert(graveyard, backend)?;
+        }
+    }
+    if portable && graveyard.exists() && portable::Volume::of(graveyard).is_none() {
+        portable::Volume::mark(graveyard)?;
+    }
+    let record = Record::open_in(graveyard)?
+        .with_clock(ctx.clock.clone())
+        .with_ids(util::get_ids(cli.seed, ctx)?)
+        .with_chain(util::flag_or_env_bool(
+            cli.chain_record,
+            "RIP_CHAIN_RECORD",
+            ctx,
+        ));
+    let path_style = util::get_path_style(cli.path_style, ctx)?;
+    let cwd = &get_cwd(cli.cwd.clone(), path_style, ctx)?;
+    let record = record.with_origin(
+        util::flag_or_env_bool(cli.record_origin, "RIP_RECORD_ORIGIN", ctx)
+            .then(|| record::Origin::current(ctx, cwd)),
+    );
+    if sandboxed {
+        let writable = sandbox_paths(&cli, graveyard, &record, cwd);
+        for warning in sandbox::enter(&writable)? {
+            warnings.warn(warning);
+        }
+    }
+    if record::is_legacy(graveyard) {
+        warnings.warn(
+            "the record was written by the original rip, so its first grave \
+             is hidden and times aren't understood; run `rip migrate` to convert it",
+        );
+    }
+    let reading_record = cli.seance
+        || cli.unbury.is_some()
+        || matches!(cli.command, Some(Commands::RestoreSession { .. }));
+    if reading_record {
+        let malformed = record.malformed_lines().unwrap_or_default();
+        if !malformed.is_empty() {
+            let lines: Vec<String> = malformed.iter().map(usize::to_string).collect();
+            warnings.warn(format!(
+                "skipped unreadable line(s) {} of the record",
+                lines.join(", ")
+            ));
+        }
+        let empty = record
+            .graves()
+            .map_or(true, |mut graves| graves.next().is_none());
+        if empty && has_graves(graveyard) {
+            warnings.warn(
+                "the record is empty, but the graveyard isn't; \
+                 run `rip doctor --rebuild-record` to recover its graves",
+            );
+        }
+    }
+    let unbury_options = UnburyOptions {
+        // Restored files mirror their graves
+        moving: MoveOptions {
+            strategy,
+            perms: PermissionPolicy::Mirror,
+            big_file,
+            prompts,
+            no_copy: false,
+            skip_unreadable: false,
+            warnings: Some(warnings),
+        },
+        retarget: cli.retarget_symlinks,
+        warnings,
+        verbose: cli.verbose,
+        dry_run: cli.dry_run,
+        yes: cli.yes || force,
+        remaps: cli
+            .remap
+            .iter()
+            .map(|(from, to)| {
+                (
+                    util::normalize_path(&cwd.join(from)),
+                    util::normalize_path(&cwd.join(to)),
+                )
+            })
+            .collect(),
+        to: cli
+            .to
+            .as_ref()
+            .map(|to| util::normalize_path(&cwd.join(to))),
+        only: cli
+            .only
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern).map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid pattern {}: {}", pattern, e),
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?,
+    };
 
-    // If the user wishes to restore everything
-    if cli.decompose {
-        if util::prompt_yes("Really unlink the entire graveyard?", &mode, stream)? {
-            fs::remove_dir_all(graveyard)?;
+    if let Some(Commands::Note { grave, text }) = cli.command {
+        let grave = cwd.join(grave);
+        if !record.annotate(&grave, &text)? {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No grave at {} in the record", grave.display()),
+            ));
+        }
+    } else if let Some(Commands::RestoreSession { within }) = cli.command {
+        let graves_to_exhume: Vec<PathBuf> = record
+            .graves()?
+            .filter(|grave| grave.age_at(record.now()).is_some_and(|age| age <= within))
+            .map(|grave| grave.dest)
+            .collect();
+        if graves_to_exhume.is_empty() {
+            writeln!(
+                stream,
+                "Nothing was buried in the last {}",
+                util::humanize_duration(within)
+            )?;
+        }
+        unbury_graves(
+            &graves_to_exhume,
+            graveyard,
+            &record,
+            &unbury_options,
+            &mode,
+            stream,
+        )?;
+    } else if let Some(Commands::Purge {
+        graves,
+        ids,
+        patterns,
+    }) = cli.command
+    {
+        let selected = select_graves(&record, cwd, &graves, &ids, &patterns)?;
+        purge_selected(
+            graveyard,
+            &record,
+            &selected,
+            cli.yes || force,
+            &prompts,
+            &mode,
+            stream,
+        )?;
+    } else if cli.decompose {
+        // If the user wishes to restore everything
+        if util::prompt_destructive(
+            "Really unlink the entire graveyard?",
+            &prompts,
+            &mode,
+            stream,
+        )? {
+            decompose(graveyard)?;
         }
     } else if let Some(mut graves_to_exhume) = cli.unbury {
         // Vector to hold the grave path of items we want to unbury.
@@ -57,142 +372,2244 @@ pub fn run(cli: Args, mode: impl util::TestingMode, stream: &mut impl Write) ->
         // Initialize it with the targets passed to -r
 
         // If -s is also passed, push all files found by seance onto
-        // the graves_to_exhume.
-        if cli.seance && record.open().is_ok() {
-            let gravepath = util::join_absolute(graveyard, dunce::canonicalize(cwd)?);
-            for grave in record.seance(&gravepath)? {
+        // the graves_to_exhume. A time window with nothing else named
+        // picks from them too.
+        if cli.seance || (time_filtered && cli.targets.is_empty()) {
+            let gravepath = portable::grave_of(
+                graveyard,
+                record.volume(),
+                &util::absolute_path(cwd, cwd, path_style)?,
+            );
+            for grave in record.seance(&gravepath)?.filter(wanted) {
                 graves_to_exhume.push(grave.dest);
             }
         }
 
-        // Otherwise, add the last deleted file
-        if graves_to_exhume.is_empty() {
-            if let Ok(s) = record.get_last_bury() {
-                graves_to_exhume.push(s);
+        // Graves named on the command line, by path or as @ID
+        for target in &cli.targets {
+            match target.to_str().and_then(|target| target.strip_prefix('@')) {
+                Some(id) => graves_to_exhume.extend(record.graves_by_id(id)?),
+                None => {
+                    let grave = cwd.join(target);
+                    let pattern = target.to_string_lossy();
+                    // A glob, unless it's the name of a grave, matches
+                    // where graves were buried from
+                    if pattern.contains(['*', '?', '[']) && !util::symlink_exists(&grave) {
+                        let matched = graves_matching(&record, cwd, &[pattern.to_string()])?;
+                        if matched.is_empty() {
+                            return Err(Error::new(
+                                ErrorKind::NotFound,
+                                format!("No grave was buried from a path matching {}", pattern),
+                            ));
+                        }
+                        graves_to_exhume.extend(matched);
+                        continue;
+                    }
+                    match graves_in(&record, &grave)? {
+                        inside if inside.is_empty() => graves_to_exhume.push(grave),
+                        inside => graves_to_exhume.extend(inside),
+                    }
+                }
+            }
+        }
+
+        if let Some(n) = cli.last {
+            graves_to_exhume.extend(match cli.dry_run {
+                true => record.peek_last_n_buries(n as usize)?,
+                false => record.get_last_n_buries(n as usize)?,
+            });
+        }
+
+        if time_filtered {
+            let within: HashSet<PathBuf> = record
+                .graves_at(&graves_to_exhume)?
+                .into_iter()
+                .filter(in_window)
+                .map(|grave| grave.dest)
+                .collect();
+            graves_to_exhume.retain(|grave| within.contains(grave));
+            if graves_to_exhume.is_empty() {
+                let what = match cli.targets.is_empty() {
+                    true => "Nothing here was",
+                    false => "None of those graves were",
+                };
+                writeln!(
+                    stream,
+                    "{} buried {}",
+                    what,
+                    describe_window(cli.since, cli.before)
+                )?;
             }
         }
 
-        // Go through the graveyard and exhume all the graves
-        for line in record.lines_of_graves(&graves_to_exhume) {
-            let entry = RecordItem::new(&line);
-            let orig: PathBuf = match util::symlink_exists(&entry.orig) {
-                true => util::rename_grave(&entry.orig),
-                false => PathBuf::from(&entry.orig),
+        // Otherwise, add the last deleted file, or directory
+        if graves_to_exhume.is_empty() && !time_filtered {
+            let last = match cli.dry_run {
+                true => record.peek_last_n_buries(1)?.into_iter().next(),
+                false => record.get_last_bury().ok(),
             };
-            move_target(&entry.dest, &orig, &mode, stream).map_err(|e| {
-                Error::new(
-                    e.kind(),
-                    format!(
-                        "Unbury failed: couldn't copy files from {} to {}",
-                        entry.dest.display(),
-                        orig.display()
-                    ),
-                )
-            })?;
-            writeln!(
-                stream,
-                "Returned {} to {}",
-                entry.dest.display(),
-                orig.display()
-            )?;
+            if let Some(s) = last {
+                graves_to_exhume.extend(record.burial_of(&s)?);
+            }
         }
-        record.log_exhumed_graves(&graves_to_exhume)?;
+
+        unbury_graves(
+            &graves_to_exhume,
+            graveyard,
+            &record,
+            &unbury_options,
+            &mode,
+            stream,
+        )?;
     } else if cli.seance {
-        let gravepath = util::join_absolute(graveyard, dunce::canonicalize(cwd)?);
-        writeln!(stream, "{: <19}\tpath", "deletion_time")?;
-        for grave in record.seance(&gravepath)? {
-            let parsed_time = chrono::DateTime::parse_from_rfc3339(&grave.time)
-                .expect("Failed to parse time from RFC3339 format")
-                .format("%Y-%m-%dT%H:%M:%S")
-                .to_string();
-            // Get the path separator:
-            writeln!(stream, "{}\t{}", parsed_time, grave.dest.display())?;
-        }
+        let base = util::absolute_path(cwd, cwd, path_style)?;
+        let gravepath = portable::grave_of(graveyard, record.volume(), &base);
+        let graves: Vec<RecordItem> = record.seance(&gravepath)?.filter(wanted).collect();
+        let all: Vec<RecordItem> = record.graves()?.collect();
+        let columns = SeanceColumns {
+            id_length: record::id_length(&all),
+            max_age,
+            relative_to: (!cli.absolute).then_some(base.as_path()),
+            verbose: cli.verbose,
+            preview: cli.preview,
+        };
+        print_seance(&graves, &columns, record.now(), stream)?;
     } else if cli.targets.is_empty() {
         Args::command().print_help()?;
     } else {
+        let mut options = BuryOptions {
+            inspect: cli.inspect,
+            moving: MoveOptions {
+                strategy,
+                perms: util::get_permission_policy(cli.grave_perms, ctx)?,
+                big_file,
+                prompts,
+                no_copy: util::flag_or_env_bool(cli.no_copy, "RIP_NO_COPY", ctx),
+                skip_unreadable: util::flag_or_env_bool(
+                    cli.skip_unreadable,
+                    "RIP_SKIP_UNREADABLE",
+                    ctx,
+                ),
+                warnings: Some(warnings),
+            },
+            delta: cli.delta,
+            follow_symlinks: cli.follow_symlinks,
+            path_style,
+            verbose: cli.verbose,
+            min_free: get_min_free(cli.min_free, ctx)?,
+            max_age,
+            protection: util::get_protection(cli.protect, ctx)?,
+            parents: Parents::new(graveyard),
+            protected: match cli.allow_protected {
+                true => Vec::new(),
+                false => get_protected_paths(graveyard, ctx),
+            },
+            print_grave_path: match (cli.print_grave_path, cli.print0) {
+                (_, true) => Some('\0'),
+                (true, false) => Some('\n'),
+                (false, false) => None,
+            },
+            excludes: Excludes::from_settings(cli.no_exclude, ctx)?,
+            checksum: !util::flag_or_env_bool(cli.no_checksum, "RIP_NO_CHECKSUM", ctx),
+            note: cli
+                .message
+                .as_deref()
+                .map(record::clean_note)
+                .filter(|note| !note.is_empty()),
+            split_dirs: util::flag_or_env_bool(cli.split_dirs, "RIP_SPLIT_DIRS", ctx),
+            warnings,
+        };
+        if cli.explain {
+            let from = graveyard_source(*source, ctx);
+            for target in &cli.targets {
+                explain_target(
+                    target, graveyard, &from, &record, cwd, &options, force, stream,
+                )?;
+            }
+            return Ok(());
+        }
+        let mut targets = Vec::new();
         for target in cli.targets {
-            bury_target(&target, graveyard, &record, cwd, cli.inspect, &mode, stream)?;
+            match options.excludes.matching(&cwd.join(&target)) {
+                Some(pattern) => writeln!(
+                    stream,
+                    "Skipped {}: matches exclude pattern {}",
+                    target.display(),
+                    pattern
+                )?,
+                None => targets.push(target),
+            }
+        }
+        if targets.len() > MANY_TARGETS && !cli.yes && !force {
+            if !confirm_targets(&targets, cwd, &prompts, &mode, stream)? {
+                return Ok(());
+            }
+            // One answer covers every target
+            options.inspect = false;
+        }
+        // Once for all the targets, as each pass reads the whole record
+        if graveyard.exists() {
+            if let Some(max_age) = options.max_age {
+                purge_expired(graveyard, &record, max_age, stream)?;
+            }
+            if let Some(min_free) = options.min_free {
+                let incoming = targets
+                    .iter()
+                    .map(|target| util::normalize_path(&cwd.join(target)))
+                    .filter(|source| util::symlink_exists(source))
+                    .map(|source| space_needed(&source, graveyard, &record, &options.moving))
+                    .sum();
+                make_room(
+                    graveyard,
+                    &record,
+                    min_free,
+                    incoming,
+                    options.warnings,
+                    stream,
+                )?;
+            }
+        }
+        for target in targets {
+            if force && !util::symlink_exists(cwd.join(&target)) {
+                continue;
+            }
+            buried.extend(bury_target(
+                &target, graveyard, &record, cwd, &options, &mode, stream,
+            )?);
+        }
+        // Once a day, for `rip stats --history`, if anything was buried
+        let snapshot = match graveyard.exists() {
+            true => Stats::new(graveyard)
+                .snapshot_daily(graveyard, &record)
+                .err(),
+            false => None,
+        };
+        if let Some(e) = snapshot {
+            warnings.warn(format!("couldn't update the graveyard's stats: {}", e));
         }
     }
 
     Ok(())
 }
 
-fn bury_target(
-    target: &PathBuf,
-    graveyard: &PathBuf,
-    record: &Record,
+/// Glob patterns for targets that are never buried, from the `exclude`
+/// setting. A pattern without a `/` matches a target's name, and one with
+/// a `/` matches its path relative to the `.riprc` it came from, or else
+/// to the current directory, or to a directory being buried for what's
+/// inside it.
+#[derive(Default)]
+struct Excludes {
+    base: PathBuf,
+    patterns: Vec<glob::Pattern>,
+}
+
+impl Excludes {
+    fn from_settings(no_exclude: bool, ctx: &Context) -> Result<Excludes, Error> {
+        if no_exclude {
+            return Ok(Excludes::default());
+        }
+        let patterns = util::setting_list("RIP_EXCLUDE", ctx).unwrap_or_default();
+        let from_riprc = ctx.var("RIP_EXCLUDE").is_none()
+            && ctx
+                .riprc
+                .as_ref()
+                .is_some_and(|riprc| riprc.config.get("exclude").is_some());
+        let base = match &ctx.riprc {
+            Some(riprc) if from_riprc => riprc.dir().to_path_buf(),
+            _ => ctx.cwd.clone(),
+        };
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern).map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid exclude: {}: {}", pattern, e),
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Excludes { base, patterns })
+    }
+
+    /// The first pattern that `path` matches
+    fn matching(&self, path: &Path) -> Option<&str> {
+        self.matching_under(path, None)
+    }
+
+    /// Like `matching`, but for `path` inside the directory `root` that is
+    /// being buried
+    fn matching_under(&self, path: &Path, root: Option<&Path>) -> Option<&str> {
+        let path = util::normalize_path(path);
+        let name = path.file_name()?;
+        let relative: Vec<&Path> = [Some(self.base.as_path()), root]
+            .into_iter()
+            .flatten()
+            .filter_map(|base| path.strip_prefix(base).ok())
+            .collect();
+        self.patterns
+            .iter()
+            .find(|pattern| {
+                if pattern.as_str().contains('/') {
+                    relative
+                        .iter()
+                        .any(|relative| pattern.matches_path(relative))
+                } else {
+                    pattern.matches(&name.to_string_lossy())
+                }
+            })
+            .map(glob::Pattern::as_str)
+    }
+
+    /// Whether anything inside the directory `dir` is excluded
+    fn any_within(&self, dir: &Path) -> bool {
+        if self.patterns.is_empty() || !fs::symlink_metadata(dir).is_ok_and(|m| m.is_dir()) {
+            return false;
+        }
+        WalkDir::new(dir)
+            .min_depth(1)
+            .follow_links(false)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .any(|entry| self.matching_under(entry.path(), Some(dir)).is_some())
+    }
+}
+
+/// Summarize the targets about to be buried, and ask once whether to go
+/// ahead with all of them
+fn confirm_targets(
+    targets: &[PathBuf],
     cwd: &Path,
-    inspect: bool,
+    prompts: &util::PromptPolicy,
     mode: &impl util::TestingMode,
     stream: &mut impl Write,
-) -> Result<(), Error> {
-    // Check if source exists
-    let metadata = &fs::symlink_metadata(target).map_err(|_| {
-        Error::new(
-            ErrorKind::NotFound,
-            format!(
-                "Cannot remove {}: no such file or directory",
-                target.to_str().unwrap()
-            ),
-        )
-    })?;
-    // Canonicalize the path unless it's a symlink
-    let source = &if !metadata.file_type().is_symlink() {
-        dunce::canonicalize(cwd.join(target))
-            .map_err(|e| Error::new(e.kind(), "Failed to canonicalize path"))?
-    } else {
-        cwd.join(target)
-    };
-
-    if inspect && !should_we_bury_this(target, source, metadata, mode, stream)? {
-        // User chose to not bury the file
-    } else if source.starts_with(graveyard) {
-        // If rip is called on a file already in the graveyard, prompt
-        // to permanently delete it instead.
-        writeln!(stream, "{} is already in the graveyard.", source.display())?;
-        if util::prompt_yes("Permanently unlink it?", mode, stream)? {
-            if fs::remove_dir_all(source).is_err() {
-                fs::remove_file(source).map_err(|e| {
-                    Error::new(e.kind(), format!("Couldn't unlink {}", source.display()))
-                })?;
-            }
+) -> Result<bool, Error> {
+    let (mut files, mut dirs, mut missing, mut total) = (0, 0, 0, 0);
+    let mut sizes = Vec::new();
+    for target in targets {
+        let path = cwd.join(target);
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            missing += 1;
+            continue;
+        };
+        let size = if metadata.is_dir() {
+            dirs += 1;
+            get_size(&path).unwrap_or(0)
         } else {
-            writeln!(stream, "Skipping {}", source.display())?;
-            // TODO: In the original code, this was a hard return from the entire
-            // method (i.e., `run`). I think it should just be a return from the bury
-            // (meaning a `continue` in the original code's loop). But I'm not sure.
+            files += 1;
+            metadata.len()
+        };
+        total += size;
+        sizes.push((size, target));
+    }
+    sizes.sort_by_key(|(size, _)| std::cmp::Reverse(*size));
+
+    write!(
+        stream,
+        "About to bury {} targets: {} files and {} directories, {} in total",
+        targets.len(),
+        files,
+        dirs,
+        util::humanize_bytes(total)
+    )?;
+    match missing {
+        0 => writeln!(stream)?,
+        _ => writeln!(stream, " ({} don't exist)", missing)?,
+    }
+    writeln!(stream, "Largest:")?;
+    for (size, target) in sizes.iter().take(LARGEST_TO_SHOW) {
+        writeln!(
+            stream,
+            "  {}\t{}",
+            util::humanize_bytes(*size),
+            target.display()
+        )?;
+    }
+    util::prompt_yes("Send them all to the graveyard?", prompts, mode, stream)
+}
+
+/// Whether the graveyard holds any files besides its own sidecars. The
+/// directories left behind once graves are unburied don't count.
+fn has_graves(graveyard: &Path) -> bool {
+    WalkDir::new(graveyard)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|e| e.depth() > 1 || !e.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|e| e.ok())
+        .any(|e| !e.file_type().is_dir())
+}
+
+/// Directories that a sandboxed run may write beneath: the graveyard,
+/// and wherever targets are buried from or graves restored to
+fn sandbox_paths(cli: &Args, graveyard: &Path, record: &Record, cwd: &Path) -> Vec<PathBuf> {
+    // A graveyard that isn't there won't be written to
+    let mut paths: Vec<PathBuf> = graveyard
+        .exists()
+        .then(|| graveyard.to_path_buf())
+        .into_iter()
+        .collect();
+    let restoring =
+        cli.unbury.is_some() || matches!(cli.command, Some(Commands::RestoreSession { .. }));
+    if restoring {
+        // Missing directories get recreated, so allow the closest that exists
+        let nearest = |path: &Path| path.ancestors().find(|dir| dir.is_dir()).map(PathBuf::from);
+        if let Ok(graves) = record.graves() {
+            paths.extend(graves.filter_map(|grave| nearest(grave.orig.parent()?)));
+        }
+        for to in cli.remap.iter().map(|(_, to)| to).chain(&cli.to) {
+            paths.extend(nearest(&util::normalize_path(&cwd.join(to))));
         }
     } else {
-        let dest: &Path = &{
-            let dest = util::join_absolute(graveyard, source);
-            // Resolve a name conflict if necessary
-            if util::symlink_exists(&dest) {
-                util::rename_grave(dest)
-            } else {
-                dest
+        for target in &cli.targets {
+            let path = util::normalize_path(&cwd.join(target));
+            paths.extend(path.parent().map(PathBuf::from));
+            if cli.follow_symlinks {
+                if let Ok(resolved) = util::resolve_symlink(&path) {
+                    paths.extend(resolved.parent().map(PathBuf::from));
+                }
             }
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// What `print_seance` shows besides each grave's time and path
+struct SeanceColumns<'a> {
+    /// How many digits of each grave's ID to show
+    id_length: usize,
+    /// How long until each grave is purged, under this retention policy
+    max_age: Option<chrono::Duration>,
+    /// Show original paths relative to this directory, rather than in full
+    relative_to: Option<&'a Path>,
+    /// Each grave's size and where it is in the graveyard, with the
+    /// columns aligned for reading rather than tab-separated
+    verbose: bool,
+    preview: bool,
+}
+
+/// Print a table of graves, with a column for time-to-expiry under a
+/// retention policy, and columns for status and notes if any grave has one.
+/// Under `preview`, media graves are described as each row is printed.
+fn print_seance(
+    graves: &[RecordItem],
+    columns: &SeanceColumns<'_>,
+    now: chrono::DateTime<chrono::Local>,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    let has_status = graves.iter().any(|grave| grave.status.is_some());
+    let has_notes = graves.iter().any(|grave| grave.note.is_some());
+    let has_ids = graves.iter().any(|grave| grave.id.is_some());
+    let mut header = Vec::new();
+    if has_ids {
+        header.push(format!("{: <1$}", "id", columns.id_length));
+    }
+    header.push(format!("{: <19}", "deletion_time"));
+    if columns.max_age.is_some() {
+        header.push("expiry".to_string());
+    }
+    if columns.verbose {
+        header.push("size".to_string());
+        header.push("type".to_string());
+    }
+    header.push("path".to_string());
+    if columns.verbose {
+        header.push("grave".to_string());
+    }
+    if columns.preview {
+        header.push("preview".to_string());
+    }
+    if has_status {
+        header.push("status".to_string());
+    }
+    if has_notes {
+        header.push("note".to_string());
+    }
+    let mut rows = vec![header];
+
+    for grave in graves {
+        let mut row = Vec::new();
+        if has_ids {
+            let id = grave.id.as_deref().unwrap_or_default();
+            row.push(format!(
+                "{: <1$}",
+                &id[..columns.id_length.min(id.len())],
+                columns.id_length
+            ));
+        }
+        row.push(burial_time(grave));
+        if let Some(max_age) = columns.max_age {
+            let left = time_to_expiry(grave, max_age, now);
+            row.push(if left > chrono::Duration::zero() {
+                format!("in {}", util::humanize_duration(left))
+            } else {
+                "overdue".to_string()
+            });
+        }
+        if columns.verbose {
+            row.push(util::humanize_bytes(get_size(&grave.dest).unwrap_or(0)));
+            row.push(grave.kind.map_or("", record::FileKind::as_str).to_string());
+        }
+        let path = match columns.relative_to {
+            Some(base) => match grave.orig.strip_prefix(base) {
+                Ok(rest) if rest.as_os_str().is_empty() => PathBuf::from("."),
+                Ok(rest) => rest.to_path_buf(),
+                Err(_) => grave.orig.clone(),
+            },
+            None => grave.orig.clone(),
         };
+        row.push(path.display().to_string());
+        if columns.verbose {
+            row.push(grave.dest.display().to_string());
+        }
+        if columns.preview {
+            row.push(media::describe(&grave.dest).unwrap_or_default());
+        }
+        if has_status {
+            row.push(grave.status.clone().unwrap_or_default());
+        }
+        if has_notes {
+            row.push(grave.note.clone().unwrap_or_default());
+        }
+        rows.push(row);
+    }
+    match columns.verbose {
+        true => util::write_aligned(&rows, stream),
+        false => rows
+            .iter()
+            .try_for_each(|row| writeln!(stream, "{}", row.join("\t"))),
+    }
+}
 
-        let moved = move_target(source, dest, mode, stream).map_err(|e| {
-            fs::remove_dir_all(dest).ok();
-            Error::new(e.kind(), "Failed to bury file")
-        })?;
+/// When a grave was buried, to the second, in local time
+fn burial_time(grave: &RecordItem) -> String {
+    chrono::DateTime::parse_from_rfc3339(&grave.time)
+        .map(|t| t.format("%Y-%m-%dT%H:%M:%S").to_string())
+        .unwrap_or_else(|_| grave.time.clone())
+}
 
-        if moved {
-            // Clean up any partial buries due to permission error
-            record.write_log(source, dest)?;
+/// Settings shared by every grave restored in a single run
+struct UnburyOptions<'a> {
+    moving: MoveOptions<'a>,
+    /// Rewrite relative symlinks of graves restored elsewhere
+    retarget: bool,
+    warnings: &'a Warnings,
+    /// Directories to restore into instead of the original ones
+    remaps: Vec<(PathBuf, PathBuf)>,
+    /// A directory to restore every grave into instead
+    to: Option<PathBuf>,
+    /// Finish with a table of what was restored, and from where
+    verbose: bool,
+    /// Only print what would be restored where
+    dry_run: bool,
+    /// Restore into other users' directories without asking
+    yes: bool,
+    /// Only copy out the files of directory graves that match one of
+    /// these, by name, or by path inside the grave if it has a `/`
+    only: Vec<glob::Pattern>,
+}
+
+/// Where a grave buried from `orig` should be restored, following the
+/// first of `remaps` whose original directory contains it
+fn remapped(orig: &Path, remaps: &[(PathBuf, PathBuf)]) -> Option<PathBuf> {
+    remaps.iter().find_map(|(from, to)| {
+        orig.strip_prefix(from)
+            .ok()
+            .map(|rest| util::normalize_path(&to.join(rest)))
+    })
+}
+
+/// If the directory that `orig` was buried from is gone (renamed, say),
+/// ask where to restore into instead. Returns the topmost missing
+/// directory and its replacement, if the user gave one.
+fn ask_for_missing_parent(
+    orig: &Path,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
+) -> Result<Option<(PathBuf, PathBuf)>, Error> {
+    let Some(missing) = orig
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| !dir.exists())
+        .last()
+    else {
+        return Ok(None);
+    };
+    let base = missing.parent().unwrap_or(missing);
+    let answer = util::prompt_path(
+        format!(
+            "Original directory {} is missing. Restore into (relative to {}, empty to recreate it):",
+            missing.display(),
+            base.display()
+        ),
+        mode,
+        stream,
+    )?;
+    Ok(answer.map(|dir| (missing.to_path_buf(), util::normalize_path(&base.join(dir)))))
+}
+
+/// Move each of the given graves back to where it was buried from,
+/// renaming on conflict, and drop them from the record.
+fn unbury_graves(
+    graves_to_exhume: &[PathBuf],
+    graveyard: &Path,
+    record: &Record,
+    options: &UnburyOptions<'_>,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    let deltas = Deltas::new(graveyard);
+    let audit = AuditLog::new(graveyard);
+    let parents = Parents::new(graveyard);
+    let manifests = Manifests::new(graveyard);
+    // Answers to prompts about missing directories apply to later graves too
+    let mut remaps = options.remaps.clone();
+    // Graves renamed because something was in the way of where they'd go
+    let mut conflicts = Vec::new();
+    let mut restored = vec![["deletion_time", "size", "path", "grave"].map(str::to_string)];
+    // Graves left in the graveyard when asked
+    let mut kept = Vec::new();
+    // Where a dry run would have restored graves to so far
+    let mut planned = HashSet::new();
+    let row = |entry: &RecordItem, orig: &Path| {
+        [
+            burial_time(entry),
+            util::humanize_bytes(get_size(&entry.dest).unwrap_or(0)),
+            orig.display().to_string(),
+            entry.dest.display().to_string(),
+        ]
+    };
+    // Go through the graveyard and exhume all the graves
+    for line in record.lines_of_graves(graves_to_exhume) {
+        let Some(entry) = RecordItem::new(&line) else {
+            continue;
+        };
+        let requested = match &options.to {
+            Some(to) => to.join(entry.orig.file_name().unwrap_or(entry.orig.as_os_str())),
+            None => {
+                if remapped(&entry.orig, &remaps).is_none() && !options.dry_run {
+                    if let Some(remap) = ask_for_missing_parent(&entry.orig, mode, stream)? {
+                        remaps.push(remap);
+                    }
+                }
+                remapped(&entry.orig, &remaps).unwrap_or_else(|| entry.orig.clone())
+            }
+        };
+        if !options.only.is_empty() {
+            restore_only(&entry, &requested, options, &manifests, &audit, stream)?;
+            kept.push(entry.dest);
+            continue;
+        }
+        let orig = restore_path(&requested, &planned);
+        if options.dry_run {
+            writeln!(
+                stream,
+                "Would return {} to {}",
+                entry.dest.display(),
+                orig.display()
+            )?;
+            if options.verbose {
+                restored.push(row(&entry, &orig));
+            }
+            planned.insert(orig.clone());
+            if orig != requested {
+                conflicts.push((requested, orig));
+            }
+            continue;
+        }
+        if let Some(owner) = util::foreign_owner(&orig).filter(|_| !options.yes) {
+            let prompt = format!(
+                "{} would be restored among {}'s files. Restore it anyway?",
+                orig.display(),
+                owner
+            );
+            if !util::prompt_yes(prompt, &options.moving.prompts, mode, stream)? {
+                writeln!(stream, "Left {} in the graveyard", entry.dest.display())?;
+                kept.push(entry.dest);
+                continue;
+            }
+        }
+        deltas.release(&entry.dest)?;
+        util::unprotect(&entry.dest)?;
+        if let Some(kind) = entry.kind {
+            let now =
+                fs::symlink_metadata(&entry.dest).map(|metadata| record::FileKind::of(&metadata));
+            if now.is_ok_and(|now| now != kind) {
+                options.warnings.warn(format!(
+                    "{} was buried as a {}, but is now something else",
+                    entry.dest.display(),
+                    kind.as_str()
+                ));
+            }
+        }
+        if let Some(expected) = &entry.checksum {
+            if !checksum::matches(&entry.dest, expected)? {
+                options.warnings.warn(format!(
+                    "{} doesn't match the checksum it was buried with; \
+                     it may have been corrupted or tampered with",
+                    entry.dest.display()
+                ));
+            }
+        }
+        if let Some(parent) = orig.parent() {
+            parents.recreate(parent, graveyard)?;
+        }
+        if options.verbose {
+            restored.push(row(&entry, &orig));
+        }
+        move_target(&entry.dest, &orig, &options.moving, mode, stream).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!(
+                    "Unbury failed: couldn't copy files from {} to {}",
+                    entry.dest.display(),
+                    orig.display()
+                ),
+            )
+        })?;
+        if let Some(meta) = &entry.meta {
+            meta.apply(&orig).map_err(|e| {
+                Error::new(
+                    e.kind(),
+                    format!(
+                        "Failed to restore the metadata of {}: {}",
+                        orig.display(),
+                        e
+                    ),
+                )
+            })?;
+        }
+        writeln!(
+            stream,
+            "Returned {} to {}",
+            entry.dest.display(),
+            orig.display()
+        )?;
+        audit.write_unbury(&entry.dest, &requested, &orig)?;
+        manifests.remove(&entry.dest)?;
+        if orig != entry.orig {
+            retarget_symlinks(
+                &orig,
+                &entry.orig,
+                options.retarget,
+                options.warnings,
+                stream,
+            )?;
         }
+        if orig != requested {
+            conflicts.push((requested, orig));
+        }
+    }
+    let exhumed: Vec<PathBuf> = graves_to_exhume
+        .iter()
+        .filter(|grave| !kept.contains(grave))
+        .cloned()
+        .collect();
+    if !options.dry_run {
+        record.log_exhumed_graves(&exhumed)?;
+        prune_above(&exhumed, graveyard, record);
+    }
+    if restored.len() > 1 {
+        writeln!(stream)?;
+        util::write_aligned(&restored, stream)?;
     }
 
+    if !conflicts.is_empty() {
+        writeln!(
+            stream,
+            "\n{} files {} restored under different names:",
+            conflicts.len(),
+            if options.dry_run { "would be" } else { "were" }
+        )?;
+        writeln!(stream, "requested\trestored")?;
+        for (requested, restored) in conflicts {
+            writeln!(stream, "{}\t{}", requested.display(), restored.display())?;
+        }
+    }
     Ok(())
 }
 
+/// Copy the files in the directory grave `entry` that match one of `only`
+/// back under `dest`, renaming on conflict, and leave the grave as it is
+fn restore_only(
+    entry: &RecordItem,
+    dest: &Path,
+    options: &UnburyOptions<'_>,
+    manifests: &Manifests,
+    audit: &AuditLog,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    if !fs::symlink_metadata(&entry.dest).is_ok_and(|m| m.is_dir()) {
+        writeln!(
+            stream,
+            "Skipped {}: --only picks files out of directory graves",
+            entry.dest.display()
+        )?;
+        return Ok(());
+    }
+    let mut cache = util::DirCache::default();
+    let (mut restored, mut total) = (0, 0);
+    // Where each file copied out of the grave went
+    let mut copied = Vec::new();
+    for file in WalkDir::new(&entry.dest).sort_by(|a, b| a.cmp(b)) {
+        let file = file?;
+        if file.file_type().is_dir() {
+            continue;
+        }
+        total += 1;
+        let inner = file.path().strip_prefix(&entry.dest).unwrap_or(file.path());
+        if !matches_only(inner, &options.only) {
+            continue;
+        }
+        let requested = dest.join(inner);
+        let restore = match util::symlink_exists(&requested) {
+            true => util::rename_grave(&requested),
+            false => requested.clone(),
+        };
+        restored += 1;
+        if options.dry_run {
+            writeln!(
+                stream,
+                "Would return {} to {}",
+                file.path().display(),
+                restore.display()
+            )?;
+            continue;
+        }
+        if let Some(parent) = restore.parent() {
+            util::create_dirs_with_permissions(
+                parent,
+                file.path().parent(),
+                PermissionPolicy::Mirror,
+                &mut cache,
+            )?;
+        }
+        import::copy_grave(file.path(), &restore)?;
+        writeln!(
+            stream,
+            "Returned {} to {}",
+            file.path().display(),
+            restore.display()
+        )?;
+        audit.write_unbury(file.path(), &requested, &restore)?;
+        copied.push((inner.to_path_buf(), restore));
+    }
+    // Going by the manifest, check that nothing asked for was missing
+    // from the grave, and that what was copied is what was buried
+    let expected = match options.dry_run {
+        true => None,
+        false => manifests.read(&entry.dest)?,
+    };
+    if let Some(expected) = expected {
+        for item in expected {
+            if item.kind == record::FileKind::Dir || !matches_only(&item.path, &options.only) {
+                continue;
+            }
+            let Some((_, restore)) = copied.iter().find(|(inner, _)| *inner == item.path) else {
+                options.warnings.warn(format!(
+                    "{} was buried, but is missing from the grave",
+                    entry.dest.join(&item.path).display()
+                ));
+                continue;
+            };
+            if item.kind != record::FileKind::File {
+                continue;
+            }
+            let changed = match &item.checksum {
+                Some(expected) => checksum::of_file(restore)? != *expected,
+                None => fs::metadata(restore)?.len() != item.size,
+            };
+            if changed {
+                options.warnings.warn(format!(
+                    "{} isn't what was buried at {}",
+                    restore.display(),
+                    entry.dest.join(&item.path).display()
+                ));
+            }
+        }
+    }
+    writeln!(
+        stream,
+        "{} {} of the {} files in {}; the grave stays buried",
+        if options.dry_run {
+            "Would restore"
+        } else {
+            "Restored"
+        },
+        restored,
+        total,
+        entry.dest.display()
+    )?;
+    Ok(())
+}
+
+/// When graves were buried between `since` and `before` ago, in words
+fn describe_window(since: Option<chrono::Duration>, before: Option<chrono::Duration>) -> String {
+    match (since, before) {
+        (Some(since), Some(before)) => format!(
+            "between {} and {} ago",
+            util::humanize_duration(since),
+            util::humanize_duration(before)
+        ),
+        (Some(since), None) => format!("in the last {}", util::humanize_duration(since)),
+        (None, Some(before)) => format!("more than {} ago", util::humanize_duration(before)),
+        (None, None) => "ever".to_string(),
+    }
+}
+
+/// Where a grave asked back to `requested` goes: there, or else the first
+/// `~N` name after it that's free, counting those `planned` as taken
+fn restore_path(requested: &Path, planned: &HashSet<PathBuf>) -> PathBuf {
+    let taken = |path: &PathBuf| util::symlink_exists(path) || planned.contains(path);
+    let requested = requested.to_path_buf();
+    if !taken(&requested) {
+        return requested;
+    }
+    (1_u64..)
+        .map(|i| {
+            let mut name = requested.as_os_str().to_owned();
+            name.push(format!("~{}", i));
+            PathBuf::from(name)
+        })
+        .find(|path| !taken(path))
+        .expect("Failed to rename duplicate file or directory")
+}
+
+/// Whether the file at `inner` in a grave matches one of `only`, by name,
+/// or by its path in the grave for patterns with a `/`
+fn matches_only(inner: &Path, only: &[glob::Pattern]) -> bool {
+    let name = inner.file_name().unwrap_or_default().to_string_lossy();
+    only.iter()
+        .any(|pattern| match pattern.as_str().contains('/') {
+            true => pattern.matches_path(inner),
+            false => pattern.matches(&name),
+        })
+}
+
+/// Check the relative symlinks in `restored`, which was originally at
+/// `orig`, for ones that no longer point where they used to. These are
+/// rewritten to point at the same place if `rewrite` is set, and
+/// otherwise added to `warnings`.
+pub fn retarget_symlinks(
+    restored: &Path,
+    orig: &Path,
+    rewrite: bool,
+    warnings: &Warnings,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    for entry in WalkDir::new(restored).into_iter().filter_map(|e| e.ok()) {
+        if !entry.file_type().is_symlink() {
+            continue;
+        }
+        let target = fs::read_link(entry.path())?;
+        if target.is_absolute() {
+            continue;
+        }
+        let Ok(inner) = entry.path().strip_prefix(restored) else {
+            continue;
+        };
+        let (old_link, link) = (orig.join(inner), entry.path());
+        let (Some(old_dir), Some(dir)) = (old_link.parent(), link.parent()) else {
+            continue;
+        };
+
+        // Where the link used to point, carried along if it was inside the tree
+        let pointed = util::normalize_path(&old_dir.join(&target));
+        let expected = match pointed.strip_prefix(orig) {
+            Ok(within) if !within.as_os_str().is_empty() => restored.join(within),
+            Ok(_) => restored.to_path_buf(),
+            Err(_) => pointed,
+        };
+        if util::normalize_path(&dir.join(&target)) == expected {
+            continue;
+        }
+
+        if rewrite {
+            let new_target = util::relative_path(&util::normalize_path(dir), &expected);
+            fs::remove_file(link)?;
+            symlink(&new_target, link)?;
+            writeln!(
+                stream,
+                "Retargeted symlink {} to {}",
+                link.display(),
+                new_target.display()
+            )?;
+        } else {
+            warnings.warn(format!(
+                "symlink {} no longer points to {}; \
+                 pass --retarget-symlinks to rewrite it",
+                link.display(),
+                expected.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn protect_grave(dest: &Path, options: &BuryOptions<'_>) -> Result<(), Error> {
+    if !util::protect(dest, options.protection)? {
+        options.warnings.warn(format!(
+            "couldn't make {} immutable, so it is only read-only",
+            dest.display()
+        ));
+    }
+    Ok(())
+}
+
+/// How targets are moved between the graveyard and the rest of the filesystem
+#[derive(Clone, Copy, Debug)]
+pub struct MoveOptions<'a> {
+    pub strategy: Strategy,
+    /// Permissions for files and directories created at the destination
+    pub perms: PermissionPolicy,
+    /// Size above which copying a file asks whether to delete it instead,
+    /// or `None` to never ask
+    pub big_file: Option<u64>,
+    pub prompts: util::PromptPolicy,
+    /// Fail rather than copy when the target can't simply be renamed
+    pub no_copy: bool,
+    /// Leave the entries of a directory that can't be read where they
+    /// are without asking, rather than asking whether to
+    pub skip_unreadable: bool,
+    /// Where to warn about files that kept changing while they were
+    /// copied, or `None` to print the warning straight away
+    pub warnings: Option<&'a Warnings>,
+}
+
+impl Default for MoveOptions<'_> {
+    fn default() -> Self {
+        MoveOptions {
+            strategy: Strategy::default(),
+            perms: PermissionPolicy::default(),
+            big_file: Some(BIG_FILE_THRESHOLD),
+            prompts: util::PromptPolicy::default(),
+            no_copy: false,
+            skip_unreadable: false,
+            warnings: None,
+        }
+    }
+}
+
+/// Times a file is copied before giving up on a copy that it didn't
+/// change during
+const COPY_ATTEMPTS: usize = 3;
+
+/// What changes about a file as it's written to
+fn write_stamp(metadata: &Metadata) -> (u64, Option<std::time::SystemTime>) {
+    (metadata.len(), metadata.modified().ok())
+}
+
+/// Warn that `source` changed during every attempt to copy it
+fn warn_changed(
+    source: &Path,
+    options: &MoveOptions,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    let message = format!(
+        "{} changed while it was being copied, {} times over, so its copy may be \
+         torn; check it was finished with before relying on it",
+        source.display(),
+        COPY_ATTEMPTS
+    );
+    warn_moving(message, options, stream)
+}
+
+/// Warn at the end of the run if `options` collects warnings, and
+/// straight away otherwise
+fn warn_moving(
+    message: String,
+    options: &MoveOptions,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    match options.warnings {
+        Some(warnings) => warnings.warn(message),
+        None => writeln!(stream, "Warning: {}", message)?,
+    }
+    Ok(())
+}
+
+/// How many entries there are under the directory at `dir`, without
+/// following symlinks
+fn count_entries(dir: &Path) -> Result<usize, Error> {
+    let mut count = 0;
+    for entry in WalkDir::new(dir).min_depth(1) {
+        entry?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// How many entries there are under the open directory `dir`
+#[cfg(unix)]
+fn count_entries_in(dir: &dirfd::Dir) -> Result<usize, Error> {
+    let mut count = 0;
+    for name in dir.entries()? {
+        count += 1;
+        if dir.stat(&name)?.kind == FileKind::Dir {
+            count += count_entries_in(&dir.open_dir(&name)?)?;
+        }
+    }
+    Ok(count)
+}
+
+/// Check that the grave at `dest` holds as many entries as `target`, which
+/// is about to be removed, holds now, less the `dropped` ones deleted
+/// rather than copied. A copy that came up short, or a target that gained
+/// something while it was being copied, keeps the target where it is.
+/// Sizes aren't compared, as files still being written to are buried as
+/// they were when copied.
+fn check_copied(target: &Path, dest: &Path, source: usize, dropped: usize) -> Result<(), Error> {
+    let copied = count_entries(dest)?;
+    let expected = source.saturating_sub(dropped);
+    if copied == expected {
+        return Ok(());
+    }
+    Err(Error::other(format!(
+        "{} holds {} entries, but {} has {} to be buried, so it was left where it was",
+        dest.display(),
+        copied,
+        target.display(),
+        expected
+    )))
+}
+
+/// Whether to leave `path`, inside a directory being buried, where it is
+/// since it can't be read, and bury the rest. Asks unless the options say
+/// to without asking.
+fn skip_unreadable(
+    path: &Path,
+    options: &MoveOptions,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
+) -> Result<bool, Error> {
+    if options.skip_unreadable {
+        return Ok(true);
+    }
+    writeln!(stream, "Cannot read {}", path.display())?;
+    util::prompt_yes(
+        "Leave it where it is and bury the rest?",
+        &options.prompts,
+        mode,
+        stream,
+    )
+}
+
+/// Warn of each entry left behind by `skip_unreadable`
+fn warn_unreadable(
+    unreadable: &[PathBuf],
+    options: &MoveOptions,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    for path in unreadable {
+        warn_moving(
+            format!("couldn't read {}, so left it where it was", path.display()),
+            options,
+            stream,
+        )?;
+    }
+    Ok(())
+}
+
+/// How a file that can't be renamed into place gets there
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Transfer {
+    Copy,
+    /// Hard link, falling back to a copy
+    HardLink,
+    /// Clone copy-on-write extents, falling back to a copy
+    Reflink,
+}
+
+impl Transfer {
+    /// How files get into place under `strategy` once renaming is out:
+    /// hard linked if it allows and they're on the same filesystem, unless
+    /// the grave is to get permissions of its own
+    fn under(strategy: Strategy, same_device: bool, perms: PermissionPolicy) -> Transfer {
+        if strategy == Strategy::Reflink {
+            Transfer::Reflink
+        } else if strategy.allows_hard_link() && same_device && perms == PermissionPolicy::Mirror {
+            Transfer::HardLink
+        } else {
+            Transfer::Copy
+        }
+    }
+
+    /// Returns false if the file was not transferred (due to user input)
+    fn run(
+        self,
+        source: &Path,
+        dest: &Path,
+        options: &MoveOptions,
+        mode: &impl util::TestingMode,
+        stream: &mut impl Write,
+    ) -> Result<bool, Error> {
+        match self {
+            Transfer::HardLink if sole_link(source) && fs::hard_link(source, dest).is_ok() => {
+                Ok(true)
+            }
+            Transfer::Reflink
+                if fs::symlink_metadata(source)?.is_file()
+                    && util::reflink(source, dest).is_ok() =>
+            {
+                Ok(true)
+            }
+            _ => copy_file_with(source, dest, options, mode, stream),
+        }
+    }
+}
+
+/// Whether `path` is the only name of its file, so that a hard link to it
+/// isn't shared with names left outside the graveyard, through which the
+/// grave could be changed
+fn sole_link(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        fs::symlink_metadata(path).is_ok_and(|metadata| metadata.nlink() == 1)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// Settings shared by every target buried in a single run
+struct BuryOptions<'a> {
+    inspect: bool,
+    moving: MoveOptions<'a>,
+    delta: bool,
+    follow_symlinks: bool,
+    path_style: util::PathStyle,
+    verbose: bool,
+    min_free: Option<u64>,
+    max_age: Option<chrono::Duration>,
+    protection: util::Protection,
+    parents: Parents,
+    /// Paths that are never buried, unless --allow-protected
+    protected: Vec<PathBuf>,
+    /// What to end each grave's path with when printing it once buried,
+    /// for pipelines
+    print_grave_path: Option<char>,
+    /// Targets, and entries of directory targets, that are left alone
+    excludes: Excludes,
+    /// Record a checksum of each grave, to check on unbury
+    checksum: bool,
+    /// Note attached to each grave, from --message
+    note: Option<String>,
+    /// Give each file in a directory its own entry in the record
+    split_dirs: bool,
+    warnings: &'a Warnings,
+}
+
+/// Where `source` is in the graveyard, if it is a grave or inside one.
+/// Besides comparing paths, with symlinks above it resolved, the
+/// directories leading to it are matched by device and inode against the
+/// graveyard and the directories of the graves in the record, so that
+/// graves reached through a bind mount are found too. Files aren't
+/// matched themselves, as a hard link elsewhere would share a grave's
+/// inode.
+fn grave_at(graveyard: &Path, record: &Record, source: &Path) -> Option<PathBuf> {
+    if source.starts_with(graveyard) {
+        return Some(source.to_path_buf());
+    }
+    let resolved = dunce::canonicalize(graveyard).ok()?;
+    // The target itself may be a symlink, which is buried as-is
+    let real = match (source.parent(), source.file_name()) {
+        (Some(parent), Some(name)) => dunce::canonicalize(parent)
+            .map(|parent| parent.join(name))
+            .unwrap_or_else(|_| source.to_path_buf()),
+        _ => source.to_path_buf(),
+    };
+    for path in [source, &real] {
+        if let Ok(rel) = path.strip_prefix(&resolved) {
+            return Some(graveyard.join(rel));
+        }
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let id = |metadata: &fs::Metadata| (metadata.dev(), metadata.ino());
+        let graveyard_id = id(&fs::metadata(graveyard).ok()?);
+        // Nearest first, and the target itself only if it's a directory
+        // rather than a link to one
+        let dirs: Vec<(&Path, (u64, u64))> = source
+            .ancestors()
+            .filter_map(|dir| {
+                let metadata = match dir == source {
+                    true => fs::symlink_metadata(dir),
+                    false => fs::metadata(dir),
+                }
+                .ok()?;
+                metadata.is_dir().then(|| (dir, id(&metadata)))
+            })
+            .collect();
+        if !dirs.iter().any(|(_, (dev, _))| *dev == graveyard_id.0) {
+            return None;
+        }
+        let mut known = vec![graveyard.to_path_buf()];
+        for grave in record.graves().ok()? {
+            for dir in grave
+                .dest
+                .ancestors()
+                .take_while(|dir| dir.starts_with(graveyard) && *dir != graveyard)
+            {
+                if !known.iter().any(|known| known == dir) {
+                    known.push(dir.to_path_buf());
+                }
+            }
+        }
+        let roots: Vec<(PathBuf, (u64, u64))> = known
+            .into_iter()
+            .filter_map(|dir| {
+                let metadata = fs::symlink_metadata(&dir).ok()?;
+                metadata.is_dir().then(|| (dir, id(&metadata)))
+            })
+            .collect();
+        for (dir, dir_id) in dirs {
+            if let Some((root, _)) = roots.iter().find(|(_, root_id)| *root_id == dir_id) {
+                let rel = source.strip_prefix(dir).ok()?;
+                return Some(match rel.as_os_str().is_empty() {
+                    true => root.clone(),
+                    false => root.join(rel),
+                });
+            }
+        }
+        None
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = record;
+        None
+    }
+}
+
+/// Create the graveyard, readable only by its owner
+pub(crate) fn make_graveyard(graveyard: &Path) -> Result<(), Error> {
+    fs::create_dir_all(graveyard)?;
+
+    #[cfg(unix)]
+    fs::set_permissions(
+        graveyard,
+        fs::Permissions::from_mode(doctor::GRAVEYARD_MODE),
+    )?;
+    // TODO: Default permissions on windows should be good, but need to double-check.
+    Ok(())
+}
+
+/// The path `target` is buried from, and its metadata: canonicalized,
+/// unless it's a symlink, which is buried as-is unless we were asked to
+/// follow it
+fn source_of(
+    target: &Path,
+    cwd: &Path,
+    link_metadata: fs::Metadata,
+    options: &BuryOptions<'_>,
+) -> Result<(PathBuf, fs::Metadata), Error> {
+    if !link_metadata.file_type().is_symlink() {
+        let source = util::absolute_path(cwd, target, options.path_style)
+            .map_err(|e| Error::new(e.kind(), "Failed to canonicalize path"))?;
+        Ok((source, link_metadata))
+    } else if options.follow_symlinks {
+        let source = util::resolve_symlink(&cwd.join(target)).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Cannot follow symlink {}: {}", target.display(), e),
+            )
+        })?;
+        let metadata = fs::symlink_metadata(&source)?;
+        Ok((source, metadata))
+    } else {
+        Ok((cwd.join(target), link_metadata))
+    }
+}
+
+/// Print what burying `target` would do, for `rip --explain`, without
+/// doing any of it
+#[allow(clippy::too_many_arguments)]
+fn explain_target(
+    target: &Path,
+    graveyard: &Path,
+    graveyard_from: &str,
+    record: &Record,
+    cwd: &Path,
+    options: &BuryOptions<'_>,
+    force: bool,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    writeln!(stream, "{}", target.display())?;
+    if let Some(pattern) = options.excludes.matching(&cwd.join(target)) {
+        writeln!(stream, "  skipped: matches exclude pattern {}", pattern)?;
+        return Ok(());
+    }
+    let Ok(link_metadata) = fs::symlink_metadata(cwd.join(target)) else {
+        let outcome = match force {
+            true => "it would be ignored (--force)",
+            false => "burying it would fail",
+        };
+        writeln!(stream, "  source: doesn't exist, so {}", outcome)?;
+        return Ok(());
+    };
+    let is_symlink = link_metadata.file_type().is_symlink();
+    let (source, metadata) = source_of(target, cwd, link_metadata, options)?;
+    let kind = if is_symlink && !options.follow_symlinks {
+        "symlink"
+    } else if metadata.is_dir() {
+        "directory"
+    } else {
+        "file"
+    };
+    writeln!(stream, "  source: {} ({})", source.display(), kind)?;
+    writeln!(
+        stream,
+        "  graveyard: {} (from {}{})",
+        graveyard.display(),
+        graveyard_from,
+        if graveyard.exists() {
+            ""
+        } else {
+            "; to be created"
+        }
+    )?;
+
+    if options.protected.contains(&source) {
+        writeln!(
+            stream,
+            "  protected: yes, so it would be refused (pass --allow-protected to bury it anyway)"
+        )?;
+        return Ok(());
+    }
+    writeln!(stream, "  protected: no")?;
+    if contains_graveyard(&source, graveyard) {
+        writeln!(stream, "  refused: it contains the graveyard")?;
+        return Ok(());
+    }
+    if let Some(grave) = grave_at(graveyard, record, &source) {
+        writeln!(
+            stream,
+            "  grave: already in the graveyard, as {}, so it would be offered for permanent deletion",
+            grave.display()
+        )?;
+        return Ok(());
+    }
+
+    let dest = portable::grave_of(graveyard, record.volume(), &source);
+    let dest = match util::symlink_exists(&dest) {
+        true => util::rename_grave(dest),
+        false => dest,
+    };
+    writeln!(stream, "  grave: {}", dest.display())?;
+    let (how, copied) = planned_move(&source, &dest, &options.moving);
+    let strategy = clap::ValueEnum::to_possible_value(&options.moving.strategy);
+    writeln!(
+        stream,
+        "  strategy: {}, so it would be {}",
+        strategy.as_ref().map_or("auto", |value| value.get_name()),
+        how
+    )?;
+    let big_file = match options.moving.big_file {
+        None => "never asked about".to_string(),
+        Some(_) if !copied => "doesn't apply, as nothing is copied".to_string(),
+        Some(limit) if metadata.is_dir() => format!(
+            "any file inside over {} would be asked about",
+            util::humanize_bytes(limit)
+        ),
+        Some(limit) if metadata.len() > limit => format!(
+            "yes, {} is over {}, so it would ask to delete it permanently instead of copying",
+            util::humanize_bytes(metadata.len()),
+            util::humanize_bytes(limit)
+        ),
+        Some(limit) => format!(
+            "no, {} is under {}",
+            util::humanize_bytes(metadata.len()),
+            util::humanize_bytes(limit)
+        ),
+    };
+    writeln!(stream, "  big file: {}", big_file)?;
+    let mut retention = Vec::new();
+    if let Some(max_age) = options.max_age {
+        retention.push(format!(
+            "graves older than {} are purged first",
+            util::humanize_duration(max_age)
+        ));
+    }
+    if let Some(min_free) = options.min_free {
+        retention.push(format!(
+            "the oldest graves are purged while less than {} is free",
+            util::humanize_bytes(min_free)
+        ));
+    }
+    if retention.is_empty() {
+        retention.push("none".to_string());
+    }
+    writeln!(stream, "  retention: {}", retention.join("; "))?;
+    Ok(())
+}
+
+/// How `move_target_with` would get `source` to `dest`, and whether that
+/// means copying it
+fn planned_move(source: &Path, dest: &Path, options: &MoveOptions) -> (&'static str, bool) {
+    // The directory the grave would go in, or the nearest above it that exists
+    let existing = dest.ancestors().skip(1).find(|dir| dir.exists());
+    let same_device = existing.is_some_and(|dir| util::same_device(source, dir));
+    let strategy = options.strategy;
+    if options.no_copy && same_device {
+        ("renamed into it (--no-copy)", false)
+    } else if options.no_copy {
+        (
+            "refused, as it's on another filesystem and --no-copy won't copy it",
+            false,
+        )
+    } else if strategy.allows_rename() && same_device {
+        ("renamed into it", false)
+    } else {
+        let transfer = match Transfer::under(strategy, same_device, options.perms) {
+            Transfer::HardLink if !source.is_dir() && !sole_link(source) => Transfer::Copy,
+            transfer => transfer,
+        };
+        match transfer {
+            Transfer::Reflink => (
+                "cloned copy-on-write where supported, otherwise copied, and removed",
+                true,
+            ),
+            Transfer::HardLink => ("hard linked, then unlinked", false),
+            Transfer::Copy if same_device => ("copied, then removed", true),
+            Transfer::Copy => ("copied to another filesystem, then removed", true),
+        }
+    }
+}
+
+/// Whether `source` is a directory above the graveyard
+fn contains_graveyard(source: &Path, graveyard: &Path) -> bool {
+    let resolved_graveyard = dunce::canonicalize(graveyard).unwrap_or(graveyard.to_path_buf());
+    source != resolved_graveyard && resolved_graveyard.starts_with(source)
+}
+
+/// Bury `target`, returning a record item for each grave made, or none if
+/// nothing was buried
+fn bury_target(
+    target: &PathBuf,
+    graveyard: &Path,
+    record: &Record,
+    cwd: &Path,
+    options: &BuryOptions<'_>,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
+) -> Result<Vec<RecordItem>, Error> {
+    // Check if source exists
+    let link_metadata = fs::symlink_metadata(cwd.join(target)).map_err(|_| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "Cannot remove {}: no such file or directory",
+                target.display()
+            ),
+        )
+    })?;
+    let is_symlink = link_metadata.file_type().is_symlink();
+    let (source, metadata) = &source_of(target, cwd, link_metadata, options)?;
+    if is_symlink && options.verbose {
+        match options.follow_symlinks {
+            true => writeln!(
+                stream,
+                "Following symlink {} to {}",
+                target.display(),
+                source.display()
+            )?,
+            false => writeln!(
+                stream,
+                "Burying symlink {} itself, not its target",
+                target.display()
+            )?,
+        }
+    }
+
+    if options.protected.iter().any(|p| p == source) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Refusing to bury {}: it is a protected path \
+                 (pass --allow-protected to bury it anyway)",
+                target.display()
+            ),
+        ));
+    }
+    if contains_graveyard(source, graveyard) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Cannot bury {}: it contains the graveyard",
+                target.display()
+            ),
+        ));
+    }
+
+    if options.inspect
+        && !should_we_bury_this(
+            target,
+            source,
+            metadata,
+            &options.moving.prompts,
+            mode,
+            stream,
+        )?
+    {
+        // User chose to not bury the file
+    } else if let Some(grave) = grave_at(graveyard, record, source) {
+        // If rip is called on a file already in the graveyard, prompt
+        // to permanently delete it instead.
+        match grave == *source {
+            true => writeln!(stream, "{} is already in the graveyard.", source.display())?,
+            false => writeln!(
+                stream,
+                "{} is already in the graveyard, as {}.",
+                source.display(),
+                grave.display()
+            )?,
+        }
+        if util::prompt_destructive(
+            "Permanently unlink it?",
+            &options.moving.prompts,
+            mode,
+            stream,
+        )? {
+            Deltas::new(graveyard).release(&grave)?;
+            util::unprotect(&grave)?;
+            let size = get_size(&grave).unwrap_or(0);
+            if fs::remove_dir_all(&grave).is_err() {
+                fs::remove_file(&grave).map_err(|e| {
+                    Error::new(e.kind(), format!("Couldn't unlink {}", source.display()))
+                })?;
+            }
+            AuditLog::new(graveyard).write("purge", Reason::Manual, size, &grave)?;
+            // It's gone from the record too, with any graves inside it
+            let gone: Vec<PathBuf> = record
+                .graves()?
+                .map(|item| item.dest)
+                .filter(|dest| dest.starts_with(&grave))
+                .collect();
+            let manifests = Manifests::new(graveyard);
+            for grave in gone.iter().chain([&grave]) {
+                manifests.remove(grave)?;
+            }
+            if !gone.is_empty() {
+                record.log_exhumed_graves(&gone)?;
+            }
+            prune_above(&[grave], graveyard, record);
+        } else {
+            writeln!(stream, "Skipping {}", source.display())?;
+            // TODO: In the original code, this was a hard return from the entire
+            // method (i.e., `run`). I think it should just be a return from the bury
+            // (meaning a `continue` in the original code's loop). But I'm not sure.
+        }
+    } else {
+        if !graveyard.exists() {
+            make_graveyard(graveyard)?;
+        }
+
+        // So the directories above can be recreated as they were on unbury
+        options.parents.remember(source)?;
+        // And the target itself, which copying may not preserve
+        let meta = FileMeta::of(source);
+        let inside = match options.split_dirs && metadata.is_dir() {
+            true => split_dir(source, &options.parents)?,
+            false => Vec::new(),
+        };
+
+        let dest: &Path = &{
+            let dest = portable::grave_of(graveyard, record.volume(), source);
+            if let Some(parent) = dest.parent() {
+                util::create_dirs_with_permissions(
+                    parent,
+                    source.parent(),
+                    options.moving.perms,
+                    &mut util::DirCache::default(),
+                )?;
+            }
+            // Taking the next free name if another grave has it
+            util::claim_path(&dest, source, options.moving.perms)?
+        };
+
+        let buried_as_delta = match options.delta && metadata.is_file() {
+            true => bury_as_delta(
+                source,
+                dest,
+                graveyard,
+                record,
+                options.moving.perms,
+                options.checksum && metadata.len() <= BIG_FILE_THRESHOLD,
+            )
+            .inspect_err(|_| util::release_claim(dest))?,
+            false => None,
+        };
+        if let Some(checksum) = buried_as_delta {
+            protect_grave(dest, options)?;
+            let item = record.write_item(RecordItem {
+                meta,
+                kind: Some(record::FileKind::of(metadata)),
+                checksum,
+                note: options.note.clone(),
+                ..RecordItem::buried(record.now(), source, dest)
+            })?;
+            print_grave_path(dest, options, stream)?;
+            return Ok(vec![item]);
+        }
+
+        let moved = move_target_with(
+            source,
+            dest,
+            &options.moving,
+            &options.excludes,
+            mode,
+            stream,
+        )
+        .map_err(|e| {
+            // Clean up any partial buries due to permission error
+            util::release_claim(dest);
+            Error::new(e.kind(), format!("Failed to bury file: {}", e))
+        })?;
+
+        if moved {
+            // Big graves would take too long to read through again
+            let checksum_of = |grave: &Path| match options.checksum
+                && get_size(grave).is_ok_and(|size| size <= BIG_FILE_THRESHOLD)
+            {
+                true => checksum::of(grave).map(Some),
+                false => Ok(None),
+            };
+            protect_grave(dest, options)?;
+            let mut items = Vec::new();
+            if inside.is_empty() {
+                if metadata.is_dir() {
+                    let hash = options.checksum
+                        && get_size(dest).is_ok_and(|size| size <= BIG_FILE_THRESHOLD);
+                    if let Err(e) = Manifests::new(graveyard).write(dest, hash) {
+                        options.warnings.warn(format!(
+                            "couldn't write the manifest of {}: {}",
+                            dest.display(),
+                            e
+                        ));
+                    }
+                }
+                items.push(record.write_item(RecordItem {
+                    meta,
+                    kind: Some(record::FileKind::of(metadata)),
+                    checksum: checksum_of(dest)?,
+                    note: options.note.clone(),
+                    ..RecordItem::buried(record.now(), source, dest)
+                })?);
+            } else {
+                // Their entries share the ID the directory would have had
+                let op = RecordItem::buried(record.now(), source, dest).id;
+                for (inner, meta, kind) in inside {
+                    let grave = dest.join(&inner);
+                    // Excluded files were left where they were
+                    if !util::symlink_exists(&grave) {
+                        continue;
+                    }
+                    items.push(record.write_item(RecordItem {
+                        meta,
+                        kind: Some(kind),
+                        checksum: checksum_of(&grave)?,
+                        note: options.note.clone(),
+                        op: op.clone(),
+                        ..RecordItem::buried(record.now(), source.join(&inner), &grave)
+                    })?);
+                }
+            }
+            print_grave_path(dest, options, stream)?;
+            return Ok(items);
+        }
+        util::release_claim(dest);
+    }
+
+    Ok(Vec::new())
+}
+
+/// The files in `dir`, and the directories in it with nothing inside, by
+/// their paths inside it, with their metadata and what they are, to give
+/// each its own entry in the record. The directories above them are
+/// remembered, to be recreated as they were when they come back.
+fn split_dir(
+    dir: &Path,
+    parents: &Parents,
+) -> Result<Vec<(PathBuf, Option<FileMeta>, record::FileKind)>, Error> {
+    let mut inside = Vec::new();
+    for entry in WalkDir::new(dir).min_depth(1).sort_by(|a, b| a.cmp(b)) {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type().is_dir() && fs::read_dir(path)?.next().is_some() {
+            continue;
+        }
+        parents.remember(path)?;
+        inside.push((
+            path.strip_prefix(dir).unwrap_or(path).to_path_buf(),
+            FileMeta::of(path),
+            record::FileKind::of(&fs::symlink_metadata(path)?),
+        ));
+    }
+    Ok(inside)
+}
+
+fn print_grave_path(
+    dest: &Path,
+    options: &BuryOptions<'_>,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    if let Some(end) = options.print_grave_path {
+        write!(stream, "{}{}", dest.display(), end)?;
+    }
+    Ok(())
+}
+
+/// Store `source` as a delta against the last grave of the same path,
+/// if there is one and the delta is small enough to be worth it.
+/// Returns None if the source wasn't buried, and otherwise its checksum
+/// if `hash` is set, taken of the full contents the grave stands for.
+fn bury_as_delta(
+    source: &Path,
+    dest: &Path,
+    graveyard: &Path,
+    record: &Record,
+    perms: PermissionPolicy,
+    hash: bool,
+) -> Result<Option<Option<String>>, Error> {
+    let deltas = Deltas::new(graveyard);
+    let Some(base) = record
+        .last_grave_of(source)
+        .filter(|base| base.is_file() && deltas.base_of(base).is_none())
+    else {
+        return Ok(None);
+    };
+    let Some(encoded) = delta::try_encode(&fs::read(&base)?, &fs::read(source)?) else {
+        return Ok(None);
+    };
+    let checksum = match hash {
+        true => Some(checksum::of(source)?),
+        false => None,
+    };
+
+    util::create_dirs_with_permissions(
+        dest.parent()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Could not get parent of dest!"))?,
+        source.parent(),
+        perms,
+        &mut util::DirCache::default(),
+    )?;
+    fs::write(dest, encoded)?;
+    util::set_file_permissions(source, dest, perms)?;
+    deltas.add(dest, &base)?;
+    fs::remove_file(source).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("Failed to remove file: {}", source.display()),
+        )
+    })?;
+    Ok(Some(checksum))
+}
+
+/// Check the hash chain of the record, printing any broken links.
+/// Returns false if the record has been tampered with.
+pub fn verify_record(graveyard: &Path, stream: &mut impl Write) -> Result<bool, Error> {
+    let record = Record::open_in(graveyard)?;
+    let path = record.path();
+    let report = record.verify_chain().map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("Failed to read record at {}: {}", path.display(), e),
+        )
+    })?;
+    if report.chained == 0 {
+        writeln!(
+            stream,
+            "The record at {} is not hash-chained; bury with --chain-record \
+             (or set $RIP_CHAIN_RECORD) to start a chain",
+            path.display()
+        )?;
+        return Ok(true);
+    }
+    for line in &report.broken {
+        writeln!(
+            stream,
+            "Line {}: hash chain broken (entry edited or a line before it removed)",
+            line
+        )?;
+    }
+    if report.broken.is_empty() {
+        writeln!(
+            stream,
+            "Record hash chain intact ({} of {} entries chained)",
+            report.chained, report.entries
+        )?;
+    } else {
+        writeln!(
+            stream,
+            "Record hash chain broken at {} line(s): the record was modified outside of rip",
+            report.broken.len()
+        )?;
+    }
+    Ok(report.broken.is_empty())
+}
+
+/// Report each time `path` was restored from the graveyard, going by the
+/// log. Returns whether it ever was.
+pub fn which(graveyard: &Path, path: &Path, stream: &mut impl Write) -> Result<bool, Error> {
+    let restores = AuditLog::new(graveyard).restores_of(path)?;
+    if restores.is_empty() {
+        writeln!(
+            stream,
+            "{} was never restored from the graveyard",
+            path.display()
+        )?;
+        return Ok(false);
+    }
+    for item in restores {
+        let time = chrono::DateTime::parse_from_rfc3339(&item.time)
+            .map(|t| t.format("%Y-%m-%dT%H:%M:%S").to_string())
+            .unwrap_or(item.time);
+        let grave = item.grave.map_or("the graveyard".to_string(), |grave| {
+            grave.display().to_string()
+        });
+        write!(stream, "{}\tRestored from {}", time, grave)?;
+        if item.restored.is_some() {
+            write!(stream, " (meant for {})", item.path.display())?;
+        }
+        writeln!(stream)?;
+    }
+    Ok(true)
+}
+
+/// Permanently delete everything in the graveyard except the audit log,
+/// which gains an entry recording how much was reclaimed.
+fn decompose(graveyard: &Path) -> Result<(), Error> {
+    let mut reclaimed = 0;
+    for entry in fs::read_dir(graveyard)? {
+        let path = entry?.path();
+        if path.file_name() == Some(audit::LOG.as_ref()) {
+            continue;
+        }
+        reclaimed += get_size(&path).unwrap_or(0);
+        util::unprotect(&path)?;
+        if fs::remove_dir_all(&path).is_err() {
+            fs::remove_file(&path)?;
+        }
+    }
+    AuditLog::new(graveyard).write("decompose", Reason::Manual, reclaimed, graveyard)
+}
+
+/// Time left before `grave` is purged under a `max_age` retention policy
+fn time_to_expiry(
+    grave: &RecordItem,
+    max_age: chrono::Duration,
+    now: chrono::DateTime<chrono::Local>,
+) -> chrono::Duration {
+    grave
+        .age_at(now)
+        .map_or(chrono::Duration::zero(), |age| max_age - age)
+}
+
+/// Permanently delete a single grave and log why it was removed,
+/// returning the number of bytes reclaimed.
+fn purge_grave(
+    graveyard: &Path,
+    grave: &Path,
+    reason: Reason,
+    stream: &mut impl Write,
+) -> Result<u64, Error> {
+    Deltas::new(graveyard).release(grave)?;
+    util::unprotect(grave)?;
+    let size = get_size(grave).unwrap_or(0);
+    if fs::remove_dir_all(grave).is_err() {
+        fs::remove_file(grave)?;
+    }
+    AuditLog::new(graveyard).write("purge", reason, size, grave)?;
+    Manifests::new(graveyard).remove(grave)?;
+    writeln!(
+        stream,
+        "Evicted {} ({}) [{}]",
+        grave.display(),
+        util::humanize_bytes(size),
+        reason
+    )?;
+    Ok(size)
+}
+
+/// Remove the purged graves from the record and summarize what was reclaimed
+fn finish_purge(
+    graveyard: &Path,
+    record: &Record,
+    evicted: &[PathBuf],
+    reclaimed: u64,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    if !evicted.is_empty() {
+        record.log_exhumed_graves(evicted)?;
+        prune_above(evicted, graveyard, record);
+        writeln!(
+            stream,
+            "Evicted {} graves, reclaiming {}",
+            evicted.len(),
+            util::humanize_bytes(reclaimed)
+        )?;
+    }
+    Ok(())
+}
+
+/// Remove the directories mirroring the paths of `graves`, once they're
+/// gone, that are left empty. Empty graves in the record are kept.
+fn prune_above(graves: &[PathBuf], graveyard: &Path, record: &Record) {
+    // Read once, rather than for every directory; if it can't be read,
+    // nothing is pruned
+    let dests: Option<HashSet<PathBuf>> = record
+        .graves()
+        .ok()
+        .map(|graves| graves.map(|grave| grave.dest).collect());
+    let is_grave = |dir: &Path| dests.as_ref().is_none_or(|dests| dests.contains(dir));
+    for grave in graves {
+        if let Some(parent) = grave.parent() {
+            util::prune_empty_dirs(parent, graveyard, is_grave);
+        }
+    }
+}
+
+/// Graves buried from paths matching any of `patterns`. Like exclude
+/// patterns, a glob without a `/` matches the name, and one with a `/`
+/// the path, relative to `cwd`.
+fn graves_matching(
+    record: &Record,
+    cwd: &Path,
+    patterns: &[String],
+) -> Result<Vec<PathBuf>, Error> {
+    let patterns = patterns
+        .iter()
+        .map(|pattern| {
+            let by_path = pattern.contains('/');
+            let full = match by_path && !Path::new(pattern).is_absolute() {
+                true => format!(
+                    "{}/{}",
+                    glob::Pattern::escape(&cwd.to_string_lossy()),
+                    pattern
+                ),
+                false => pattern.clone(),
+            };
+            glob::Pattern::new(&full)
+                .map(|glob| (glob, by_path))
+                .map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid pattern {}: {}", pattern, e),
+                    )
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if patterns.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(record
+        .graves()?
+        .filter(|item| {
+            let name = item.orig.file_name().map(Path::new).unwrap_or(&item.orig);
+            patterns.iter().any(|(glob, by_path)| match by_path {
+                true => glob.matches_path(&item.orig),
+                false => glob.matches_path(name),
+            })
+        })
+        .map(|item| item.dest)
+        .collect())
+}
+
+/// The grave at `path` in the record, or else the graves under it, as a
+/// directory buried file by file leaves them
+fn graves_in(record: &Record, path: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !record.graves_at(&[path.to_path_buf()])?.is_empty() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    Ok(record.seance(path)?.map(|item| item.dest).collect())
+}
+
+/// Graves chosen by path in the graveyard, by ID, or by glob patterns
+/// matched against where they were buried from, in the order they were
+/// buried
+fn select_graves(
+    record: &Record,
+    cwd: &Path,
+    graves: &[PathBuf],
+    ids: &[String],
+    patterns: &[String],
+) -> Result<Vec<PathBuf>, Error> {
+    if graves.is_empty() && ids.is_empty() && patterns.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Nothing to purge: name graves by path, --id or --match",
+        ));
+    }
+    let mut selected = Vec::new();
+    let graves: Vec<PathBuf> = graves.iter().map(|grave| cwd.join(grave)).collect();
+    let found: HashSet<PathBuf> = record
+        .graves_at(&graves)?
+        .into_iter()
+        .map(|item| item.dest)
+        .collect();
+    for grave in graves {
+        if found.contains(&grave) {
+            selected.push(grave);
+            continue;
+        }
+        let inside = graves_in(record, &grave)?;
+        if inside.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No grave at {} in the record", grave.display()),
+            ));
+        }
+        selected.extend(inside);
+    }
+    for id in ids {
+        selected.extend(record.graves_by_id(id.trim_start_matches('@'))?);
+    }
+    selected.extend(graves_matching(record, cwd, patterns)?);
+    let order: Vec<PathBuf> = record.graves()?.map(|item| item.dest).collect();
+    selected.sort_by_key(|grave| order.iter().position(|dest| dest == grave));
+    selected.dedup();
+    Ok(selected)
+}
+
+/// Permanently delete `graves`, after asking unless `yes`, and drop them
+/// from the record in one go
+fn purge_selected(
+    graveyard: &Path,
+    record: &Record,
+    graves: &[PathBuf],
+    yes: bool,
+    prompts: &util::PromptPolicy,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    if graves.is_empty() {
+        writeln!(stream, "No graves matched")?;
+        return Ok(());
+    }
+    if !yes {
+        writeln!(
+            stream,
+            "About to permanently delete {} grave(s):",
+            graves.len()
+        )?;
+        for grave in graves {
+            writeln!(stream, "  {}", grave.display())?;
+        }
+        if !util::prompt_destructive("Delete them?", prompts, mode, stream)? {
+            return Ok(());
+        }
+    }
+    let mut evicted = Vec::new();
+    let mut reclaimed = 0;
+    for grave in graves {
+        if util::symlink_exists(grave) {
+            match purge_grave(graveyard, grave, Reason::Manual, stream) {
+                Ok(size) => reclaimed += size,
+                Err(e) => {
+                    // Keep the record in step with what was deleted
+                    finish_purge(graveyard, record, &evicted, reclaimed, stream)?;
+                    return Err(e);
+                }
+            }
+        }
+        evicted.push(grave.clone());
+    }
+    finish_purge(graveyard, record, &evicted, reclaimed, stream)
+}
+
+/// Purge every grave older than `max_age`
+fn purge_expired(
+    graveyard: &Path,
+    record: &Record,
+    max_age: chrono::Duration,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    let mut evicted = Vec::new();
+    let mut reclaimed = 0;
+    // Read up front, so other rips can write to the record while graves go
+    let graves: Vec<RecordItem> = record.graves()?.collect();
+    for grave in graves {
+        if time_to_expiry(&grave, max_age, record.now()) > chrono::Duration::zero()
+            || !util::symlink_exists(&grave.dest)
+        {
+            continue;
+        }
+        reclaimed += purge_grave(graveyard, &grave.dest, Reason::Age, stream)?;
+        evicted.push(grave.dest);
+    }
+    finish_purge(graveyard, record, &evicted, reclaimed, stream)
+}
+
+/// Purge the oldest graves until the graveyard's filesystem would have at
+/// least `min_free` bytes available once `incoming` more are buried,
+/// reporting each eviction. If purging every grave couldn't get there,
+/// none are.
+fn make_room(
+    graveyard: &Path,
+    record: &Record,
+    min_free: u64,
+    incoming: u64,
+    warnings: &Warnings,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    let needed = min_free.saturating_add(incoming);
+    let Some(free) = util::free_space(graveyard).filter(|free| *free < needed) else {
+        return Ok(());
+    };
+
+    let graves: Vec<(PathBuf, u64)> = record
+        .graves()?
+        .filter(|grave| util::symlink_exists(&grave.dest))
+        .map(|grave| {
+            let size = get_size(&grave.dest).unwrap_or(0);
+            (grave.dest, size)
+        })
+        .collect();
+    let reclaimable: u64 = graves.iter().map(|(_, size)| size).sum();
+    if free.saturating_add(reclaimable) < needed {
+        warnings.warn(format!(
+            "--min-free asks for {} free, but purging every grave would leave {}, \
+             so none were",
+            util::humanize_bytes(min_free),
+            util::humanize_bytes((free + reclaimable).saturating_sub(incoming))
+        ));
+        return Ok(());
+    }
+
+    let mut evicted = Vec::new();
+    let mut reclaimed = 0;
+    for (grave, _) in graves {
+        // Going by what was purged too, in case the space isn't freed at once
+        if free + reclaimed >= needed
+            || util::free_space(graveyard).is_none_or(|free| free >= needed)
+        {
+            break;
+        }
+        reclaimed += purge_grave(graveyard, &grave, Reason::Space, stream)?;
+        evicted.push(grave);
+    }
+    finish_purge(graveyard, record, &evicted, reclaimed, stream)
+}
+
+/// How much more of the graveyard's filesystem burying `source` would take:
+/// its size if it would be copied there, and nothing if it's renamed
+fn space_needed(source: &Path, graveyard: &Path, record: &Record, options: &MoveOptions) -> u64 {
+    let dest = portable::grave_of(graveyard, record.volume(), source);
+    match planned_move(source, &dest, options) {
+        (_, true) => get_size(source).unwrap_or(0),
+        (_, false) => 0,
+    }
+}
+
 fn should_we_bury_this(
     target: &Path,
     source: &PathBuf,
     metadata: &Metadata,
+    prompts: &util::PromptPolicy,
     mode: &impl util::TestingMode,
     stream: &mut impl Write,
 ) -> Result<bool, Error> {
@@ -200,15 +2617,15 @@ fn should_we_bury_this(
         // Get the size of the directory and all its contents
         {
             let num_bytes = get_size(source).map_err(|_| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to get size of directory: {}", source.display()),
-                )
+                Error::other(format!(
+                    "Failed to get size of directory: {}",
+                    source.display()
+                ))
             })?;
             writeln!(
                 stream,
                 "{}: directory, {} including:",
-                target.to_str().unwrap(),
+                target.display(),
                 util::humanize_bytes(num_bytes)
             )?;
         }
@@ -228,7 +2645,7 @@ fn should_we_bury_this(
         writeln!(
             stream,
             "{}: file, {}",
-            &target.to_str().unwrap(),
+            target.display(),
             util::humanize_bytes(metadata.len())
         )?;
         // Read the file and print the first few lines
@@ -245,7 +2662,8 @@ fn should_we_bury_this(
         }
     }
     util::prompt_yes(
-        format!("Send {} to the graveyard?", target.to_str().unwrap()),
+        format!("Send {} to the graveyard?", target.display()),
+        prompts,
         mode,
         stream,
     )
@@ -257,34 +2675,105 @@ fn should_we_bury_this(
 pub fn move_target(
     target: &Path,
     dest: &Path,
+    options: &MoveOptions,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
+) -> Result<bool, Error> {
+    move_target_with(target, dest, options, &Excludes::default(), mode, stream)
+}
+
+/// Like `move_target`, but leaving the entries of a directory that match
+/// `excludes` where they are
+fn move_target_with(
+    target: &Path,
+    dest: &Path,
+    options: &MoveOptions,
+    excludes: &Excludes,
     mode: &impl util::TestingMode,
     stream: &mut impl Write,
 ) -> Result<bool, Error> {
+    let strategy = options.strategy;
+    // A directory with excluded entries has to be moved piece by piece
+    let whole = !excludes.any_within(target);
     // Try a simple rename, which will only work within the same mount point.
     // Trying to rename across filesystems will throw errno 18.
-    if util::allow_rename() && fs::rename(target, dest).is_ok() {
+    if whole
+        && strategy.allows_rename()
+        && !options.no_copy
+        && util::rename_into_claim(target, dest).is_ok()
+    {
         return Ok(true);
     }
 
     // If that didn't work, then we need to copy and rm.
-    fs::create_dir_all(
-        dest.parent()
-            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Could not get parent of dest!"))?,
-    )?;
+    let dest_parent = dest
+        .parent()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Could not get parent of dest!"))?;
+    let mut cache = util::DirCache::default();
+    util::create_dirs_with_permissions(dest_parent, target.parent(), options.perms, &mut cache)?;
+    if options.no_copy && !whole {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Cannot move {} without copying (--no-copy): it holds excluded \
+                 entries, which would be left behind",
+                target.display()
+            ),
+        ));
+    }
+    if options.no_copy {
+        // Now that the directories above exist, a rename can only fail
+        // for a reason like being across filesystems
+        return util::rename_into_claim(target, dest)
+            .map(|()| true)
+            .map_err(|e| {
+                Error::new(
+                    e.kind(),
+                    format!(
+                        "Cannot move {} to {} without copying (--no-copy): {}",
+                        target.display(),
+                        dest.display(),
+                        e
+                    ),
+                )
+            });
+    }
+
+    let transfer = Transfer::under(
+        strategy,
+        util::same_device(target, dest_parent),
+        options.perms,
+    );
 
     if fs::symlink_metadata(target)?.is_dir() {
-        move_dir(target, dest, mode, stream)
+        move_dir_with(
+            target, dest, options, excludes, transfer, mode, stream, &mut cache,
+        )
     } else {
-        let moved = copy_file(target, dest, mode, stream).map_err(|e| {
-            Error::new(
-                e.kind(),
-                format!(
-                    "Failed to copy file from {} to {}",
-                    target.display(),
-                    dest.display()
-                ),
-            )
-        })?;
+        // Links and the like can't be made over a claim on `dest`, so are
+        // made beside it and renamed over it
+        let staged = match util::symlink_exists(dest) {
+            true => util::staging_path(dest),
+            false => dest.to_path_buf(),
+        };
+        let moved = transfer
+            .run(target, &staged, options, mode, stream)
+            .and_then(|moved| match moved && staged != dest {
+                true => fs::rename(&staged, dest).map(|()| moved),
+                false => Ok(moved),
+            })
+            .map_err(|e| {
+                fs::remove_file(&staged).ok();
+                Error::new(
+                    e.kind(),
+                    format!(
+                        "Failed to copy file from {} to {}",
+                        target.display(),
+                        dest.display()
+                    ),
+                )
+            })?;
+        util::set_file_permissions(target, dest, options.perms)?;
         fs::remove_file(target).map_err(|e| {
             Error::new(
                 e.kind(),
@@ -303,84 +2792,519 @@ pub fn move_dir(
     mode: &impl util::TestingMode,
     stream: &mut impl Write,
 ) -> Result<bool, Error> {
-    // Walk the source, creating directories and copying files as needed
-    for entry in WalkDir::new(target).into_iter().filter_map(|e| e.ok()) {
+    let mut cache = util::DirCache::default();
+    move_dir_with(
+        target,
+        dest,
+        &MoveOptions::default(),
+        &Excludes::default(),
+        Transfer::Copy,
+        mode,
+        stream,
+        &mut cache,
+    )
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::too_many_arguments)]
+fn move_dir_with(
+    target: &Path,
+    dest: &Path,
+    options: &MoveOptions,
+    excludes: &Excludes,
+    transfer: Transfer,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
+    cache: &mut util::DirCache,
+) -> Result<bool, Error> {
+    let perms = options.perms;
+    let mut excluded = 0;
+    // Entries that couldn't be read, and were left where they were
+    let mut unreadable = Vec::new();
+    // Entries deleted rather than copied, when asked to
+    let mut dropped = 0;
+    // What was moved, for removing once everything is in the grave, if
+    // excluded entries mean the directory can't be removed whole
+    let mut moved: Vec<(PathBuf, bool)> = Vec::new();
+    // Walk the source, creating directories and copying files as needed.
+    // Symlinks are never followed, so they are copied as links and can't
+    // send the walk around a loop.
+    for entry in WalkDir::new(target)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| {
+            let keep = entry.depth() == 0
+                || excludes
+                    .matching_under(entry.path(), Some(target))
+                    .is_none();
+            excluded += usize::from(!keep);
+            keep
+        })
+    {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let path = e.path().map(Path::to_path_buf);
+                let e = Error::from(e);
+                // A directory that can't be listed is left as it is, with
+                // everything in it
+                if let Some(path) = path.filter(|_| e.kind() == ErrorKind::PermissionDenied) {
+                    if skip_unreadable(&path, options, mode, stream)? {
+                        if let Ok(orphan) = path.strip_prefix(target) {
+                            fs::remove_dir(dest.join(orphan)).ok();
+                        }
+                        moved.retain(|(done, _)| *done != path);
+                        unreadable.push(path);
+                    }
+                }
+                continue;
+            }
+        };
         // Path without the top-level directory
-        let orphan = entry.path().strip_prefix(target).map_err(|_| {
-            Error::new(
-                ErrorKind::Other,
-                "Parent directory isn't a prefix of child directories?",
-            )
-        })?;
+        let orphan = entry
+            .path()
+            .strip_prefix(target)
+            .map_err(|_| Error::other("Parent directory isn't a prefix of child directories?"))?;
+        let out = &dest.join(orphan);
+        // Nothing under the grave may lead outside of it
+        util::ensure_within(dest, out)?;
 
         if entry.file_type().is_dir() {
-            fs::create_dir_all(dest.join(orphan)).map_err(|e| {
+            util::create_dirs_with_permissions(out, Some(entry.path()), perms, cache).map_err(
+                |e| {
+                    Error::new(
+                        e.kind(),
+                        format!(
+                            "Failed to create dir: {} in {}",
+                            entry.path().display(),
+                            out.display()
+                        ),
+                    )
+                },
+            )?;
+        } else {
+            let transferred = transfer.run(entry.path(), out, options, mode, stream);
+            if let Err(e) = &transferred {
+                let unreadable_file = e.kind() == ErrorKind::PermissionDenied
+                    && fs::File::open(entry.path())
+                        .is_err_and(|e| e.kind() == ErrorKind::PermissionDenied);
+                if unreadable_file && skip_unreadable(entry.path(), options, mode, stream)? {
+                    fs::remove_file(out).ok();
+                    unreadable.push(entry.path().to_path_buf());
+                    continue;
+                }
+            }
+            let transferred = transferred.map_err(|e| {
                 Error::new(
                     e.kind(),
                     format!(
-                        "Failed to create dir: {} in {}",
+                        "Failed to copy file from {} to {}",
                         entry.path().display(),
-                        dest.join(orphan).display()
+                        out.display()
                     ),
                 )
             })?;
+            match transferred {
+                true => util::set_file_permissions(entry.path(), out, perms)?,
+                false => dropped += 1,
+            }
+        }
+        moved.push((entry.path().to_path_buf(), entry.file_type().is_dir()));
+    }
+    if excluded == 0 && unreadable.is_empty() {
+        check_copied(target, dest, count_entries(target)?, dropped)?;
+        fs::remove_dir_all(target).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Failed to remove dir: {}", target.display()),
+            )
+        })?;
+        return Ok(true);
+    }
+
+    // Directories are walked before what's in them, so the deepest go first
+    for (path, is_dir) in moved.iter().rev() {
+        if *is_dir {
+            // Those holding excluded entries stay
+            fs::remove_dir(path).ok();
         } else {
-            copy_file(entry.path(), &dest.join(orphan), mode, stream).map_err(|e| {
+            fs::remove_file(path).map_err(|e| {
                 Error::new(
                     e.kind(),
-                    format!(
-                        "Failed to copy file from {} to {}",
-                        entry.path().display(),
-                        dest.join(orphan).display()
-                    ),
+                    format!("Failed to remove file: {}", path.display()),
                 )
             })?;
         }
     }
-    fs::remove_dir_all(target).map_err(|e| {
+    if excluded > 0 {
+        writeln!(
+            stream,
+            "Left {} excluded {} in {}",
+            excluded,
+            if excluded == 1 { "entry" } else { "entries" },
+            target.display()
+        )?;
+    }
+    warn_unreadable(&unreadable, options, stream)?;
+
+    Ok(true)
+}
+
+/// Like the version for other platforms, but the target is walked by
+/// descriptor, so that nothing outside of it can be copied or removed if
+/// a directory in it is renamed or swapped for a symlink partway through
+#[cfg(unix)]
+#[allow(clippy::too_many_arguments)]
+fn move_dir_with(
+    target: &Path,
+    dest: &Path,
+    options: &MoveOptions,
+    excludes: &Excludes,
+    transfer: Transfer,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
+    cache: &mut util::DirCache,
+) -> Result<bool, Error> {
+    let (Some(parent), Some(name)) = (target.parent(), target.file_name()) else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Cannot move {}: it has no parent", target.display()),
+        ));
+    };
+    let removing = |e: Error| {
         Error::new(
             e.kind(),
             format!("Failed to remove dir: {}", target.display()),
         )
-    })?;
+    };
+    // Opened before anything is copied, so that it's what gets removed
+    let parent = dirfd::Dir::open(parent).map_err(removing)?;
+    let root = parent.open_dir(name).map_err(removing)?;
+    util::create_dirs_with_permissions(dest, Some(target), options.perms, cache)?;
+    let mut walk = DirMove {
+        target,
+        dest,
+        options,
+        excludes,
+        transfer,
+        mode,
+        stream,
+        excluded: 0,
+        unreadable: Vec::new(),
+        dropped: 0,
+        moved: HashSet::new(),
+    };
+    walk.copy(&root, root.entries()?, Path::new(""))?;
+    let excluded = walk.excluded;
+    let unreadable = std::mem::take(&mut walk.unreadable);
+    if excluded == 0 && unreadable.is_empty() {
+        check_copied(target, dest, count_entries_in(&root)?, walk.dropped)?;
+        // The name is only removed once it's an empty directory, so
+        // whatever has taken the target's place is left alone
+        return root
+            .clear()
+            .and_then(|()| parent.remove(name, true))
+            .map(|()| true)
+            .map_err(removing);
+    }
+
+    walk.remove(&root, Path::new(""))?;
+    if excluded > 0 {
+        writeln!(
+            stream,
+            "Left {} excluded {} in {}",
+            excluded,
+            if excluded == 1 { "entry" } else { "entries" },
+            target.display()
+        )?;
+    }
+    warn_unreadable(&unreadable, options, stream)?;
 
     Ok(true)
 }
 
+/// A directory target being moved into its grave, entry by entry
+#[cfg(unix)]
+struct DirMove<'a, M, W> {
+    target: &'a Path,
+    dest: &'a Path,
+    options: &'a MoveOptions<'a>,
+    excludes: &'a Excludes,
+    transfer: Transfer,
+    mode: &'a M,
+    stream: &'a mut W,
+    excluded: usize,
+    /// Entries that couldn't be read, and were left where they were
+    unreadable: Vec<PathBuf>,
+    /// Entries deleted rather than copied, when asked to
+    dropped: usize,
+    /// What was moved, relative to the target, for removing once
+    /// everything is in the grave, if excluded entries mean the directory
+    /// can't be removed whole
+    moved: HashSet<PathBuf>,
+}
+
+#[cfg(unix)]
+impl<M: util::TestingMode, W: Write> DirMove<'_, M, W> {
+    /// Move `entries`, the names in `dir`, which is `rel` under the target,
+    /// to the same place under the grave. Symlinks are never followed, so
+    /// they are copied as links and can't send the walk around a loop.
+    fn copy(&mut self, dir: &dirfd::Dir, entries: Vec<OsString>, rel: &Path) -> Result<(), Error> {
+        for name in entries {
+            let rel = rel.join(&name);
+            let path = self.target.join(&rel);
+            if self
+                .excludes
+                .matching_under(&path, Some(self.target))
+                .is_some()
+            {
+                self.excluded += 1;
+                continue;
+            }
+            let out = &self.dest.join(&rel);
+            // Nothing under the grave may lead outside of it
+            util::ensure_within(self.dest, out)?;
+
+            let stat = dir.stat(&name)?;
+            if stat.kind == FileKind::Dir {
+                // One that can't be listed is left as it is, with
+                // everything in it
+                let listed = dir
+                    .open_dir(&name)
+                    .and_then(|inner| Ok((inner.entries()?, inner)));
+                let (entries, inner) = match listed {
+                    Err(e) if e.kind() == ErrorKind::PermissionDenied && self.skip(&path)? => {
+                        continue;
+                    }
+                    listed => listed?,
+                };
+                let metadata = inner.metadata()?;
+                util::create_dir_with_permissions(out, Some(&metadata), self.options.perms)
+                    .map_err(|e| {
+                        Error::new(
+                            e.kind(),
+                            format!(
+                                "Failed to create dir: {} in {}",
+                                path.display(),
+                                out.display()
+                            ),
+                        )
+                    })?;
+                self.copy(&inner, entries, &rel)?;
+            } else {
+                let transferred = self.transfer(dir, &name, &stat, out);
+                if let Err(e) = &transferred {
+                    let unreadable = e.kind() == ErrorKind::PermissionDenied
+                        && dir
+                            .open_file(&name)
+                            .is_err_and(|e| e.kind() == ErrorKind::PermissionDenied);
+                    if unreadable && self.skip(&path)? {
+                        continue;
+                    }
+                }
+                let transferred = transferred.map_err(|e| {
+                    Error::new(
+                        e.kind(),
+                        format!(
+                            "Failed to copy file from {} to {}",
+                            path.display(),
+                            out.display()
+                        ),
+                    )
+                })?;
+                self.dropped += usize::from(!transferred);
+            }
+            self.moved.insert(rel);
+        }
+        Ok(())
+    }
+
+    /// Whether to leave `path`, which can't be read, where it is
+    fn skip(&mut self, path: &Path) -> Result<bool, Error> {
+        let skip = skip_unreadable(path, self.options, self.mode, self.stream)?;
+        if skip {
+            self.unreadable.push(path.to_path_buf());
+        }
+        Ok(skip)
+    }
+
+    /// Transfer the non-directory `name` in `dir` to `dest`. Returns false
+    /// if it was not transferred (due to user input).
+    fn transfer(
+        &mut self,
+        dir: &dirfd::Dir,
+        name: &OsStr,
+        stat: &dirfd::Stat,
+        dest: &Path,
+    ) -> Result<bool, Error> {
+        if self.transfer == Transfer::HardLink
+            && stat.links == 1
+            && dir.hard_link(name, dest).is_ok()
+        {
+            return Ok(true);
+        }
+        let source = dir.path().join(name);
+        match stat.kind {
+            FileKind::File => {
+                let mut file = dir.open_file(name)?;
+                let metadata = file.metadata()?;
+                if !metadata.is_file() {
+                    return Err(Error::other(format!(
+                        "{} was replaced while it was being moved",
+                        source.display()
+                    )));
+                }
+                if !(self.transfer == Transfer::Reflink && util::reflink_from(&file, dest).is_ok())
+                {
+                    if !confirm_big_copy(
+                        &source,
+                        metadata.len(),
+                        self.options,
+                        self.mode,
+                        self.stream,
+                    )? {
+                        return Ok(false);
+                    }
+                    let mut before = metadata;
+                    for attempt in 1..=COPY_ATTEMPTS {
+                        std::io::Seek::rewind(&mut file)?;
+                        std::io::copy(&mut file, &mut fs::File::create(dest)?)?;
+                        let after = file.metadata()?;
+                        if write_stamp(&after) == write_stamp(&before) {
+                            break;
+                        } else if attempt == COPY_ATTEMPTS {
+                            warn_changed(&source, self.options, self.stream)?;
+                        }
+                        before = after;
+                    }
+                    // Keep the mode and timestamps, which copying doesn't;
+                    // the timestamps are only a nicety
+                    fs::set_permissions(dest, before.permissions())?;
+                    if let Some(meta) = FileMeta::from_metadata(&before) {
+                        meta.apply(dest).ok();
+                    }
+                }
+                util::set_copied_file_permissions(stat.mode, dest, self.options.perms)?;
+                Ok(true)
+            }
+            FileKind::Symlink => {
+                symlink(dir.read_link(name)?, dest)?;
+                Ok(true)
+            }
+            FileKind::Special if stat.fifo => {
+                make_fifo(dest, stat.mode)?;
+                Ok(true)
+            }
+            _ => special_file(
+                &source,
+                Error::new(
+                    ErrorKind::Unsupported,
+                    format!("Cannot copy special file {}", source.display()),
+                ),
+                self.options,
+                self.mode,
+                self.stream,
+            ),
+        }
+    }
+
+    /// Remove what was moved from `dir`, which is `rel` under the target.
+    /// Directories still holding excluded entries stay.
+    fn remove(&self, dir: &dirfd::Dir, rel: &Path) -> Result<(), Error> {
+        for name in dir.entries()? {
+            let rel = rel.join(&name);
+            if !self.moved.contains(&rel) {
+                continue;
+            }
+            if dir.stat(&name)?.kind == FileKind::Dir {
+                self.remove(&dir.open_dir(&name)?, &rel)?;
+                dir.remove(&name, true).ok();
+            } else {
+                dir.remove(&name, false).map_err(|e| {
+                    Error::new(
+                        e.kind(),
+                        format!(
+                            "Failed to remove file: {}",
+                            dir.path().join(&name).display()
+                        ),
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hard link a non-directory into the graveyard, falling back to a copy
+/// if the link is refused (e.g., by `fs.protected_hardlinks`).
+pub fn link_file(
+    source: &Path,
+    dest: &Path,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
+) -> Result<bool, Error> {
+    Transfer::HardLink.run(source, dest, &MoveOptions::default(), mode, stream)
+}
+
+/// Clone a regular file into the graveyard, falling back to a copy
+/// for other file types or filesystems without reflink support.
+pub fn reflink_file(
+    source: &Path,
+    dest: &Path,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
+) -> Result<bool, Error> {
+    Transfer::Reflink.run(source, dest, &MoveOptions::default(), mode, stream)
+}
+
 pub fn copy_file(
     source: &Path,
     dest: &Path,
     mode: &impl util::TestingMode,
     stream: &mut impl Write,
+) -> Result<bool, Error> {
+    copy_file_with(source, dest, &MoveOptions::default(), mode, stream)
+}
+
+fn copy_file_with(
+    source: &Path,
+    dest: &Path,
+    options: &MoveOptions,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
 ) -> Result<bool, Error> {
     let metadata = fs::symlink_metadata(source)?;
     let filetype = metadata.file_type();
 
-    if metadata.len() > BIG_FILE_THRESHOLD {
-        writeln!(
-            stream,
-            "About to copy a big file ({} is {})",
-            source.display(),
-            util::humanize_bytes(metadata.len())
-        )?;
-        if util::prompt_yes("Permanently delete this file instead?", mode, stream)? {
-            return Ok(false);
-        }
+    // Links and special files have no contents of their own to copy
+    if filetype.is_file() && !confirm_big_copy(source, metadata.len(), options, mode, stream)? {
+        return Ok(false);
     }
 
     if filetype.is_file() {
-        fs::copy(source, dest)?;
+        // Copied again if it's written to meanwhile, so that the copy
+        // isn't half old and half new
+        let mut before = metadata;
+        for attempt in 1..=COPY_ATTEMPTS {
+            fs::copy(source, dest)?;
+            let after = fs::symlink_metadata(source)?;
+            if write_stamp(&after) == write_stamp(&before) {
+                break;
+            } else if attempt == COPY_ATTEMPTS {
+                warn_changed(source, options, stream)?;
+            }
+            before = after;
+        }
+        // Keep the timestamps, which copying doesn't; they're only a nicety
+        if let Some(meta) = FileMeta::of(source) {
+            meta.apply(dest).ok();
+        }
         return Ok(true);
     }
 
     #[cfg(unix)]
     if filetype.is_fifo() {
-        let metadata_mode = metadata.permissions().mode();
-        std::process::Command::new("mkfifo")
-            .arg(dest)
-            .arg("-m")
-            .arg(metadata_mode.to_string())
-            .output()?;
+        make_fifo(dest, metadata.permissions().mode())?;
         return Ok(true);
     }
 
@@ -390,38 +3314,341 @@ pub fn copy_file(
         return Ok(true);
     }
 
+    // Special file: Try copying it as normal, but this probably won't work
     match fs::copy(source, dest) {
-        Err(e) => {
-            // Special file: Try copying it as normal, but this probably won't work
+        Err(e) => special_file(source, e, options, mode, stream),
+        Ok(_) => Ok(true),
+    }
+}
+
+/// Whether to go ahead and copy `source`, which is `len` bytes, asking
+/// if it's big enough that it might rather be deleted for good
+fn confirm_big_copy(
+    source: &Path,
+    len: u64,
+    options: &MoveOptions,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
+) -> Result<bool, Error> {
+    if options.big_file.is_none_or(|big_file| len <= big_file) {
+        return Ok(true);
+    }
+    writeln!(
+        stream,
+        "About to copy a big file ({} is {})",
+        source.display(),
+        util::humanize_bytes(len)
+    )?;
+    Ok(!util::prompt_destructive(
+        "Permanently delete this file instead?",
+        &options.prompts,
+        mode,
+        stream,
+    )?)
+}
+
+#[cfg(unix)]
+fn make_fifo(dest: &Path, mode: u32) -> Result<(), Error> {
+    std::process::Command::new("mkfifo")
+        .arg(dest)
+        .arg("-m")
+        .arg(mode.to_string())
+        .output()?;
+    Ok(())
+}
+
+/// Ask whether to delete the special file `source`, which couldn't be
+/// copied with `e`, for good. Returns false if so.
+fn special_file(
+    source: &Path,
+    e: Error,
+    options: &MoveOptions,
+    mode: &impl util::TestingMode,
+    stream: &mut impl Write,
+) -> Result<bool, Error> {
+    writeln!(
+        stream,
+        "Non-regular file or directory: {}",
+        source.display()
+    )?;
+    if util::prompt_destructive(
+        "Permanently delete the file?",
+        &options.prompts,
+        mode,
+        stream,
+    )? {
+        Ok(false)
+    } else {
+        Err(e)
+    }
+}
+
+/// Print the size of the graveyard, taking today's snapshot if there
+/// isn't one yet, and under `history` chart the last `days` of them
+pub fn stats(
+    graveyard: &Path,
+    history: bool,
+    days: u32,
+    ctx: &Context,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    if !graveyard.exists() {
+        writeln!(stream, "No graveyard at {}", graveyard.display())?;
+        return Ok(());
+    }
+    let record = Record::new(graveyard).with_clock(ctx.clock.clone());
+    let stats = Stats::new(graveyard);
+    stats.snapshot_daily(graveyard, &record)?;
+    if history {
+        stats::print_history(&stats.snapshots()?, days, stream)
+    } else {
+        stats::print_summary(graveyard, &Snapshot::take(graveyard, &record)?, stream)
+    }
+}
+
+/// Print the `limit` most recent graves buried from under `dir`, newest
+/// first. This never creates the graveyard, so it is cheap enough to run
+/// from a shell prompt hook.
+pub fn recent(
+    graveyard: &Path,
+    dir: &Path,
+    limit: usize,
+    within: Option<chrono::Duration>,
+    porcelain: bool,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    if !store::exists(graveyard) {
+        return Ok(());
+    }
+    let record = Record::open_in(graveyard)?;
+    let gravepath = portable::grave_of(graveyard, record.volume(), &dunce::canonicalize(dir)?);
+    let mut graves: Vec<RecordItem> = record
+        .seance(&gravepath)?
+        .filter(|grave| within.is_none_or(|within| grave.age().is_some_and(|age| age <= within)))
+        .collect();
+    graves.reverse();
+    for grave in graves.into_iter().take(limit) {
+        if porcelain {
             writeln!(
                 stream,
-                "Non-regular file or directory: {}",
-                source.display()
+                "{}\t{}\t{}",
+                grave.time,
+                grave.orig.display(),
+                grave.dest.display()
+            )?;
+        } else {
+            let age = grave.age().unwrap_or_else(chrono::Duration::zero);
+            writeln!(
+                stream,
+                "{} ago\t{}",
+                util::humanize_duration(age),
+                grave.orig.display()
             )?;
-
-            if util::prompt_yes("Permanently delete the file?", mode, stream)? {
-                Ok(false)
-            } else {
-                Err(e)
-            }
         }
-        Ok(_) => Ok(true),
     }
+    Ok(())
+}
+
+/// Free space threshold from the flag, falling back to `RIP_MIN_FREE`
+pub fn get_min_free(min_free: Option<u64>, ctx: &Context) -> Result<Option<u64>, Error> {
+    if min_free.is_some() {
+        return Ok(min_free);
+    }
+    ctx.setting("RIP_MIN_FREE")
+        .map(|size| util::parse_size(size).map_err(|e| Error::new(ErrorKind::InvalidInput, e)))
+        .transpose()
+}
+
+/// Big file threshold from the flag, falling back to `RIP_BIG_FILE`.
+/// Under `force`, big files are never asked about.
+pub fn get_big_file(
+    big_file: Option<u64>,
+    force: bool,
+    ctx: &Context,
+) -> Result<Option<u64>, Error> {
+    if force {
+        return Ok(None);
+    }
+    if big_file.is_some() {
+        return Ok(big_file);
+    }
+    let setting = ctx
+        .setting("RIP_BIG_FILE")
+        .map(|size| util::parse_size(size).map_err(|e| Error::new(ErrorKind::InvalidInput, e)))
+        .transpose()?;
+    Ok(Some(setting.unwrap_or(BIG_FILE_THRESHOLD)))
+}
+
+/// Retention policy from the flag, falling back to `RIP_MAX_AGE`
+pub fn get_max_age(
+    max_age: Option<chrono::Duration>,
+    ctx: &Context,
+) -> Result<Option<chrono::Duration>, Error> {
+    if max_age.is_some() {
+        return Ok(max_age);
+    }
+    ctx.setting("RIP_MAX_AGE")
+        .map(|age| util::parse_duration(age).map_err(|e| Error::new(ErrorKind::InvalidInput, e)))
+        .transpose()
 }
 
-pub fn get_graveyard(graveyard: Option<PathBuf>) -> PathBuf {
+/// The directory that relative targets and seance are based on: the
+/// flag (itself relative to the current directory) if given, or else
+/// the current directory.
+pub fn get_cwd(
+    cwd: Option<PathBuf>,
+    style: util::PathStyle,
+    ctx: &Context,
+) -> Result<PathBuf, Error> {
+    let current = util::current_dir(style, ctx)?;
+    let Some(dir) = cwd else {
+        return Ok(current);
+    };
+    let dir = util::normalize_path(&current.join(dir));
+    if !dir.is_dir() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("--cwd {} is not a directory", dir.display()),
+        ));
+    }
+    Ok(dir)
+}
+
+/// Paths that are refused as targets, from the `protected-paths` setting
+/// or else the defaults, along with the graveyard itself. Each is taken
+/// both as it is and with symlinks resolved.
+pub fn get_protected_paths(graveyard: &Path, ctx: &Context) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match util::setting_list("RIP_PROTECTED_PATHS", ctx) {
+        Some(paths) => paths.into_iter().map(PathBuf::from).collect(),
+        None => PROTECTED_PATHS.iter().map(PathBuf::from).collect(),
+    };
+    paths.push(graveyard.to_path_buf());
+    let resolved: Vec<PathBuf> = paths
+        .iter()
+        .filter_map(|path| dunce::canonicalize(path).ok())
+        .collect();
+    paths.extend(resolved);
+    paths
+        .iter()
+        .map(|path| util::normalize_path(path))
+        .collect()
+}
+
+pub fn get_graveyard(graveyard: Option<PathBuf>, ctx: &Context) -> PathBuf {
+    locate_graveyard(graveyard, ctx).0
+}
+
+/// The graveyard, and where its location came from. A relative location
+/// is resolved against the current directory when given by
+/// `--graveyard`, the directory of the `.riprc` or config file it was set
+/// in, or the home directory when given by `$RIP_GRAVEYARD`. A leading
+/// `~` is the home directory wherever it was given.
+pub fn locate_graveyard(graveyard: Option<PathBuf>, ctx: &Context) -> (PathBuf, config::Source) {
+    let home = ctx.var("HOME").map(PathBuf::from);
+    let resolve = |path: PathBuf, base: Option<PathBuf>| {
+        let path = match (path.strip_prefix("~"), &home) {
+            (Ok(rest), Some(home)) => home.join(rest),
+            _ => path,
+        };
+        match base {
+            Some(base) if path.is_relative() => util::normalize_path(&base.join(path)),
+            _ => path,
+        }
+    };
     if let Some(flag) = graveyard {
-        flag
-    } else if let Ok(env_graveyard) = env::var("RIP_GRAVEYARD") {
-        PathBuf::from(env_graveyard)
-    } else if let Ok(mut env_graveyard) = env::var("XDG_DATA_HOME") {
+        (resolve(flag, Some(ctx.cwd.clone())), config::Source::Flag)
+    } else if let Some(env_graveyard) = ctx.var("RIP_GRAVEYARD") {
+        (
+            resolve(PathBuf::from(env_graveyard), home.clone()),
+            config::Source::Env("RIP_GRAVEYARD"),
+        )
+    } else if let Some(set) = ctx.setting("RIP_GRAVEYARD") {
+        // The `.riprc` has already resolved its own
+        let source = match ctx
+            .riprc
+            .as_ref()
+            .and_then(|riprc| riprc.config.get("graveyard"))
+        {
+            Some(_) => config::Source::Riprc,
+            None => config::Source::Config,
+        };
+        let config_dir =
+            config::config_path(ctx).and_then(|path| path.parent().map(Path::to_path_buf));
+        (resolve(PathBuf::from(set), config_dir), source)
+    } else if let Some(env_graveyard) = ctx.var("XDG_DATA_HOME") {
+        let mut env_graveyard = env_graveyard.to_string();
         if !env_graveyard.ends_with(std::path::MAIN_SEPARATOR) {
             env_graveyard.push(std::path::MAIN_SEPARATOR);
         }
         env_graveyard.push_str("graveyard");
-        PathBuf::from(env_graveyard)
+        (
+            PathBuf::from(env_graveyard),
+            config::Source::Env("XDG_DATA_HOME"),
+        )
     } else {
-        let user = util::get_user();
-        env::temp_dir().join(format!("graveyard-{}", user))
+        let user = util::get_user(ctx);
+        (
+            env::temp_dir().join(format!("graveyard-{}", user)),
+            config::Source::Default,
+        )
+    }
+}
+
+/// Describe where the graveyard's location came from
+fn graveyard_source(source: config::Source, ctx: &Context) -> String {
+    match source {
+        config::Source::Flag => "--graveyard".to_string(),
+        config::Source::Env("XDG_DATA_HOME") => "$XDG_DATA_HOME/graveyard".to_string(),
+        config::Source::Env(var) => format!("${}", var),
+        config::Source::Riprc => match &ctx.riprc {
+            Some(riprc) => riprc.path.display().to_string(),
+            None => config::RIPRC.to_string(),
+        },
+        config::Source::Config => "the config file".to_string(),
+        config::Source::Default => "the default, graveyard-$USER in the temp directory".to_string(),
+    }
+}
+
+/// Check that `graveyard` is somewhere a graveyard can be: an absolute
+/// path to a directory that is writable, or can be created
+pub fn check_graveyard(graveyard: &Path) -> Result<(), String> {
+    if graveyard.is_relative() {
+        return Err("it is a relative path, and there is no $HOME to resolve it against".into());
     }
+    // The graveyard, or the nearest directory above that it'd be created in
+    let existing = graveyard
+        .ancestors()
+        .find(|dir| fs::symlink_metadata(dir).is_ok())
+        .unwrap_or(graveyard);
+    if !existing.is_dir() {
+        return Err(match existing == graveyard {
+            true => "it is a file, not a directory".to_string(),
+            false => format!("{} is a file, not a directory", existing.display()),
+        });
+    }
+    if !util::is_writable(existing) {
+        return Err(match existing == graveyard {
+            true => "it isn't writable".to_string(),
+            false => format!(
+                "it can't be created, as {} isn't writable",
+                existing.display()
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Print the graveyard, where its location came from, and whether it's
+/// usable, for `rip graveyard --explain`
+pub fn explain_graveyard(ctx: &Context, stream: &mut impl Write) -> Result<(), Error> {
+    let (graveyard, source) = locate_graveyard(None, ctx);
+    writeln!(stream, "{}", graveyard.display())?;
+    writeln!(stream, "  from: {}", graveyard_source(source, ctx))?;
+    let status = match check_graveyard(&graveyard) {
+        Err(problem) => format!("unusable: {}", problem),
+        Ok(()) if graveyard.exists() => "exists and is writable".to_string(),
+        Ok(()) => "doesn't exist yet; will be created on the first bury".to_string(),
+    };
+    writeln!(stream, "  status: {}", status)?;
+    Ok(())
 }
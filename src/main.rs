@@ -1,44 +1,269 @@
 use clap::{Args as _, Command, FromArgMatches as _};
-use std::env;
 use std::io;
 use std::process::ExitCode;
 
 use rip2::args::Commands;
-use rip2::{args, completions, util};
+use rip2::context::Context;
+use rip2::{args, completions, portable, util};
 
 fn main() -> ExitCode {
     let base_cmd = Command::new("rip");
     let cmd = args::Args::augment_args(base_cmd);
     let cli = args::Args::from_arg_matches(&cmd.get_matches()).unwrap();
+    let ctx = match &cli.command {
+        Some(Commands::Config { .. }) => Context::from_process(),
+        _ => Context::from_process().and_then(|ctx| ctx.load_config_for(cli.profile.as_deref())),
+    };
+    let ctx = match ctx {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
 
     match &cli.command {
         Some(Commands::Completions { shell }) => {
             let result = completions::generate_shell_completions(shell, &mut io::stdout());
-            if result.is_err() {
-                eprintln!("{}", result.unwrap_err());
+            if let Err(e) = result {
+                eprintln!("{}", e);
                 return ExitCode::FAILURE;
             }
         }
-        Some(Commands::Graveyard { seance }) => {
-            let graveyard = rip2::get_graveyard(None);
-            if *seance {
-                let cwd = env::current_dir().expect("Failed to get current directory");
-                let gravepath = util::join_absolute(
-                    graveyard,
-                    dunce::canonicalize(cwd).expect("Failed to get current directory"),
-                );
-                print!("{}", gravepath.display());
+        Some(Commands::Graveyard { seance, explain }) => {
+            let graveyard = rip2::get_graveyard(None, &ctx);
+            if *explain {
+                if let Err(e) = rip2::explain_graveyard(&ctx, &mut io::stdout()) {
+                    eprintln!("{}", e);
+                    return ExitCode::FAILURE;
+                }
+            } else if *seance {
+                let style = util::get_path_style(cli.path_style, &ctx).unwrap_or_default();
+                let gravepath = rip2::get_cwd(cli.cwd.clone(), style, &ctx)
+                    .and_then(|cwd| util::absolute_path(&cwd, &cwd, style))
+                    .map(|cwd| {
+                        let volume = portable::Volume::of(&graveyard);
+                        portable::grave_of(&graveyard, volume.as_ref(), &cwd)
+                    });
+                match gravepath {
+                    Ok(gravepath) => print!("{}", gravepath.display()),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return ExitCode::FAILURE;
+                    }
+                }
             } else {
                 print!("{}", graveyard.display());
             }
         }
-        None => {
+        Some(Commands::Log) => {
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            let result = rip2::audit::AuditLog::new(&graveyard).print(&mut io::stdout());
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Some(Commands::Which { path }) => {
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            let style = util::get_path_style(cli.path_style, &ctx).unwrap_or_default();
+            let result = rip2::get_cwd(cli.cwd.clone(), style, &ctx).and_then(|cwd| {
+                let path = cwd.join(path);
+                let path =
+                    dunce::canonicalize(&path).unwrap_or_else(|_| util::normalize_path(&path));
+                rip2::which(&graveyard, &path, &mut io::stdout())
+            });
+            match result {
+                Ok(true) => {}
+                Ok(false) => return ExitCode::FAILURE,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        Some(Commands::Diff { grave, other }) => {
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            let style = util::get_path_style(cli.path_style, &ctx).unwrap_or_default();
+            let result = rip2::get_cwd(cli.cwd.clone(), style, &ctx).and_then(|cwd| {
+                rip2::diff::diff(&graveyard, &cwd, grave, other.as_deref(), &mut io::stdout())
+            });
+            match result {
+                Ok(true) => {}
+                Ok(false) => return ExitCode::FAILURE,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        Some(Commands::Peek { grave }) => {
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            let style = util::get_path_style(cli.path_style, &ctx).unwrap_or_default();
+            let result = rip2::get_cwd(cli.cwd.clone(), style, &ctx)
+                .and_then(|cwd| rip2::manifest::peek(&graveyard, &cwd, grave, &mut io::stdout()));
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Some(Commands::Recent {
+            dir,
+            limit,
+            within,
+            porcelain,
+        }) => {
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            let style = util::get_path_style(cli.path_style, &ctx).unwrap_or_default();
+            let result = rip2::get_cwd(cli.cwd.clone(), style, &ctx).and_then(|cwd| {
+                let dir = match dir {
+                    Some(dir) => cwd.join(dir),
+                    None => cwd,
+                };
+                rip2::recent(
+                    &graveyard,
+                    &dir,
+                    *limit,
+                    *within,
+                    *porcelain,
+                    &mut io::stdout(),
+                )
+            });
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Some(Commands::Stats { history, days }) => {
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            let result = rip2::stats(&graveyard, *history, *days, &ctx, &mut io::stdout());
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Some(Commands::Doctor {
+            fix_perms,
+            rebuild_record,
+            adopt,
+            prune_empty,
+            renumber,
+            orphans,
+        }) => {
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            let mut stdout = io::stdout();
+            let chain = util::flag_or_env_bool(cli.chain_record, "RIP_CHAIN_RECORD", &ctx);
+            let result = if *orphans {
+                rip2::doctor::list_orphans(&graveyard, &mut stdout)
+            } else {
+                if *rebuild_record {
+                    rip2::doctor::rebuild_record(&graveyard, chain, &mut stdout)
+                } else if *adopt {
+                    rip2::doctor::adopt_orphans(&graveyard, chain, &mut stdout)
+                } else {
+                    rip2::doctor::check_record(&graveyard, &mut stdout)
+                }
+                .and_then(|()| match renumber {
+                    true => rip2::doctor::renumber(&graveyard, chain, &mut stdout),
+                    false => Ok(()),
+                })
+                .and_then(|()| match prune_empty {
+                    true => rip2::doctor::prune_empty(&graveyard, &mut stdout),
+                    false => Ok(()),
+                })
+                .and_then(|()| rip2::doctor::check_permissions(&graveyard, *fix_perms, &mut stdout))
+            };
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Some(Commands::Fsck { repair }) => {
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            let chain = util::flag_or_env_bool(cli.chain_record, "RIP_CHAIN_RECORD", &ctx);
+            match rip2::doctor::fsck(&graveyard, *repair, chain, &mut io::stdout()) {
+                Ok(true) => {}
+                Ok(false) => return ExitCode::FAILURE,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        Some(Commands::Compact) => {
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            let chain = util::flag_or_env_bool(cli.chain_record, "RIP_CHAIN_RECORD", &ctx);
+            if let Err(e) = rip2::doctor::compact(&graveyard, chain, &mut io::stdout()) {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Some(Commands::Migrate) => {
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            let chain = util::flag_or_env_bool(cli.chain_record, "RIP_CHAIN_RECORD", &ctx);
+            if let Err(e) = rip2::doctor::migrate(&graveyard, chain, &mut io::stdout()) {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Some(Commands::Export { format, since }) => {
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            if let Err(e) = rip2::export::export(&graveyard, *format, *since, &mut io::stdout()) {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Some(Commands::Import { from }) => {
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            let chain = util::flag_or_env_bool(cli.chain_record, "RIP_CHAIN_RECORD", &ctx);
+            if let Err(e) = rip2::import::import(&graveyard, from, chain, &mut io::stdout()) {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Some(Commands::Verify { record, manifests }) => {
+            // With nothing selected, run every check
+            let all = !record && !manifests;
+            let graveyard = rip2::get_graveyard(cli.graveyard.clone(), &ctx);
+            if *record || all {
+                match rip2::verify_record(&graveyard, &mut io::stdout()) {
+                    Ok(true) => {}
+                    Ok(false) => return ExitCode::FAILURE,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+            if *manifests || all {
+                match rip2::manifest::verify(&graveyard, &mut io::stdout()) {
+                    Ok(true) => {}
+                    Ok(false) => return ExitCode::FAILURE,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+        }
+        Some(Commands::Config { command }) => {
+            // The config loads itself here, so that a broken one can be replaced
+            let result = rip2::config::run(&cli, command, &ctx, &mut io::stdout());
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+        Some(Commands::RestoreSession { .. })
+        | Some(Commands::Note { .. })
+        | Some(Commands::Purge { .. })
+        | None => {
             let mut stream = io::stdout();
             let mode = util::ProductionMode;
 
             ////////////////////////////////////////////////////////////
             // Main code ///////////////////////////////////////////////
-            let result = rip2::run(cli, mode, &mut stream);
+            let result = rip2::run_with(cli, &ctx, mode, &mut stream);
             ////////////////////////////////////////////////////////////
 
             if let Err(ref e) = result {
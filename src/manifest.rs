@@ -0,0 +1,293 @@
+//! Manifests of directory graves: what was in each when it was buried,
+//! with sizes, modes and hashes, so that a grave can be listed without
+//! walking it, and checked against what it should hold.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::meta::FileMeta;
+use crate::record::{self, FileKind, Record};
+use crate::{checksum, diff, store, util};
+
+/// Directory in the graveyard that manifests are kept in
+pub const MANIFESTS: &str = ".manifests";
+
+/// Something inside a directory grave
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Where it is in the grave
+    pub path: PathBuf,
+    pub kind: FileKind,
+    /// The length of a file or of a symlink's target, and 0 otherwise
+    pub size: u64,
+    /// The permission bits, where there are any
+    pub mode: u32,
+    /// Checksum of a file, if it was hashed
+    pub checksum: Option<String>,
+}
+
+impl Entry {
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{:o}\t{}\t{}",
+            self.kind.as_str(),
+            self.size,
+            self.mode,
+            self.checksum.as_deref().unwrap_or("-"),
+            record::escape(&self.path.to_string_lossy())
+        )
+    }
+
+    fn parse(line: &str) -> Option<Entry> {
+        let mut fields = line.splitn(5, '\t');
+        Some(Entry {
+            kind: FileKind::parse(fields.next()?)?,
+            size: fields.next()?.parse().ok()?,
+            mode: u32::from_str_radix(fields.next()?, 8).ok()?,
+            checksum: Some(fields.next()?)
+                .filter(|checksum| *checksum != "-")
+                .map(str::to_string),
+            path: PathBuf::from(record::unescape(fields.next()?)),
+        })
+    }
+}
+
+/// What's in the directory at `dir` now, in order. Files are hashed if
+/// `hash` is set.
+pub fn entries_of(dir: &Path, hash: bool) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(dir).min_depth(1).sort_by(|a, b| a.cmp(b)) {
+        let entry = entry?;
+        let metadata = fs::symlink_metadata(entry.path())?;
+        let kind = FileKind::of(&metadata);
+        entries.push(Entry {
+            path: entry
+                .path()
+                .strip_prefix(dir)
+                .unwrap_or(entry.path())
+                .to_path_buf(),
+            kind,
+            size: match kind {
+                FileKind::File | FileKind::Symlink => metadata.len(),
+                _ => 0,
+            },
+            mode: FileMeta::from_metadata(&metadata).map_or(0, |meta| meta.mode),
+            checksum: match kind == FileKind::File && hash {
+                true => Some(checksum::of_file(entry.path())?),
+                false => None,
+            },
+        });
+    }
+    Ok(entries)
+}
+
+/// The manifests of the graves in a graveyard, each named for a hash of
+/// its grave's path in the graveyard
+pub struct Manifests {
+    graveyard: PathBuf,
+}
+
+impl Manifests {
+    pub fn new(graveyard: &Path) -> Manifests {
+        Manifests {
+            graveyard: graveyard.to_path_buf(),
+        }
+    }
+
+    fn path_of(&self, grave: &Path) -> PathBuf {
+        let inner = grave.strip_prefix(&self.graveyard).unwrap_or(grave);
+        let name = xxhash_rust::xxh3::xxh3_64(inner.to_string_lossy().as_bytes());
+        self.graveyard
+            .join(MANIFESTS)
+            .join(format!("{:016x}", name))
+    }
+
+    /// Write the manifest of the directory grave at `grave`, hashing its
+    /// files if `hash` is set
+    pub fn write(&self, grave: &Path, hash: bool) -> io::Result<()> {
+        let path = self.path_of(grave);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        // The grave it's for, so that it can be told apart by eye
+        writeln!(file, "#\t{}", record::escape(&grave.to_string_lossy()))?;
+        for entry in entries_of(grave, hash)? {
+            writeln!(file, "{}", entry.to_line())?;
+        }
+        file.flush()
+    }
+
+    /// The manifest of `grave`, if it has one
+    pub fn read(&self, grave: &Path) -> io::Result<Option<Vec<Entry>>> {
+        let file = match fs::File::open(self.path_of(grave)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if !line.starts_with('#') {
+                entries.extend(Entry::parse(&line));
+            }
+        }
+        Ok(Some(entries))
+    }
+
+    /// Drop the manifest of `grave`, if it has one, once it has left the
+    /// graveyard
+    pub fn remove(&self, grave: &Path) -> io::Result<()> {
+        match fs::remove_file(self.path_of(grave)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Keep the manifest of a grave that was renamed from `from` to `to`
+    pub fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match fs::rename(self.path_of(from), self.path_of(to)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// How `grave` differs from its manifest, a line for each thing
+    /// missing, added or changed; None if it has no manifest
+    pub fn check(&self, grave: &Path) -> io::Result<Option<Vec<String>>> {
+        let Some(expected) = self.read(grave)? else {
+            return Ok(None);
+        };
+        let hash = expected.iter().any(|entry| entry.checksum.is_some());
+        let mut found: HashMap<PathBuf, Entry> = entries_of(grave, hash)?
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+        let mut problems = Vec::new();
+        for entry in expected {
+            let path = grave.join(&entry.path);
+            let Some(now) = found.remove(&entry.path) else {
+                problems.push(format!("{} is missing", path.display()));
+                continue;
+            };
+            if now.kind != entry.kind {
+                problems.push(format!(
+                    "{} was buried as a {}, but is now a {}",
+                    path.display(),
+                    entry.kind.as_str(),
+                    now.kind.as_str()
+                ));
+            } else if now.size != entry.size
+                || entry.checksum.is_some() && now.checksum != entry.checksum
+            {
+                problems.push(format!("{} has changed", path.display()));
+            }
+        }
+        let mut added: Vec<PathBuf> = found.into_keys().collect();
+        added.sort();
+        for path in added {
+            problems.push(format!(
+                "{} wasn't there when it was buried",
+                grave.join(path).display()
+            ));
+        }
+        Ok(Some(problems))
+    }
+}
+
+/// List what's in the directory grave at `grave` (a path or `@ID`), from
+/// its manifest if it has one, and otherwise by walking it
+pub fn peek(
+    graveyard: &Path,
+    cwd: &Path,
+    grave: &Path,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    if !store::exists(graveyard) {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No record in {}, so nothing is buried", graveyard.display()),
+        ));
+    }
+    let record = Record::open_in(graveyard)?;
+    let grave = diff::find_grave(&record, &diff::resolve(&record, cwd, grave)?)?.dest;
+    if !fs::symlink_metadata(&grave).is_ok_and(|m| m.is_dir()) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} isn't a directory; rip peek lists directory graves",
+                grave.display()
+            ),
+        ));
+    }
+    let entries = match Manifests::new(graveyard).read(&grave)? {
+        Some(entries) => entries,
+        None => {
+            writeln!(
+                stream,
+                "{} has no manifest; listing what's in it now",
+                grave.display()
+            )?;
+            entries_of(&grave, false)?
+        }
+    };
+    let mut rows = vec![["type", "size", "mode", "path"].map(str::to_string)];
+    let (mut files, mut total) = (0, 0);
+    for entry in &entries {
+        if entry.kind == FileKind::File {
+            files += 1;
+            total += entry.size;
+        }
+        rows.push([
+            entry.kind.as_str().to_string(),
+            match entry.kind {
+                FileKind::Dir => "-".to_string(),
+                _ => util::humanize_bytes(entry.size),
+            },
+            format!("{:o}", entry.mode & 0o7777),
+            entry.path.display().to_string(),
+        ]);
+    }
+    util::write_aligned(&rows, stream)?;
+    writeln!(
+        stream,
+        "{} files, {} in all",
+        files,
+        util::humanize_bytes(total)
+    )?;
+    Ok(())
+}
+
+/// Check every grave in `graveyard` that has a manifest against it,
+/// reporting what has gone missing, changed or appeared since it was
+/// buried. Returns true if nothing has.
+pub fn verify(graveyard: &Path, stream: &mut impl Write) -> Result<bool, Error> {
+    let manifests = Manifests::new(graveyard);
+    let (mut checked, mut damaged) = (0, 0);
+    for grave in Record::open_in(graveyard)?.graves()? {
+        let problems = match manifests.check(&grave.dest) {
+            Ok(Some(problems)) => problems,
+            Ok(None) => continue,
+            Err(e) => vec![format!("Can't check {}: {}", grave.dest.display(), e)],
+        };
+        checked += 1;
+        if !problems.is_empty() {
+            damaged += 1;
+        }
+        for problem in problems {
+            writeln!(stream, "{}", problem)?;
+        }
+    }
+    match damaged {
+        0 => writeln!(stream, "{} directory graves match their manifests", checked)?,
+        _ => writeln!(
+            stream,
+            "{} of {} directory graves don't match their manifests",
+            damaged, checked
+        )?,
+    }
+    Ok(damaged == 0)
+}
@@ -0,0 +1,231 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::filetype;
+
+/// How much of a PDF is searched for pages
+const PDF_LIMIT: u64 = 64 << 20;
+/// How much of a JPEG is searched for its size and EXIF date
+const JPEG_LIMIT: u64 = 1 << 20;
+
+/// A short description of a media file's contents, like `4032x3024,
+/// taken 2023:06:01 12:00:00`, `3:25` or `12 pages`, for telling graves
+/// apart when their names aren't enough. Only the parts of the file
+/// needed are read.
+pub fn describe(path: &Path) -> Option<String> {
+    if !path.is_file() {
+        return None;
+    }
+    let kind = filetype::sniff(path)?;
+    let mut file = File::open(path).ok()?;
+    match kind {
+        "png" => {
+            let head = read_at(&mut file, 16, 8)?;
+            Some(dimensions(be32(&head[0..])?, be32(&head[4..])?))
+        }
+        "gif" => {
+            let head = read_at(&mut file, 6, 4)?;
+            Some(dimensions(
+                le16(&head[0..])?.into(),
+                le16(&head[2..])?.into(),
+            ))
+        }
+        "jpg" => describe_jpeg(&mut file),
+        "wav" => describe_wav(&mut file),
+        "flac" => describe_flac(&mut file),
+        "mp4" | "mov" | "m4a" => describe_mp4(&mut file),
+        "pdf" => describe_pdf(&mut file),
+        _ => None,
+    }
+}
+
+fn dimensions(width: u32, height: u32) -> String {
+    format!("{}x{}", width, height)
+}
+
+/// A duration in seconds like a media player shows it, e.g. `1:02:03`
+fn playing_time(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> Option<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    let mut buf = vec![0; len];
+    file.read_exact(&mut buf).ok()?;
+    Some(buf)
+}
+
+fn be16(bytes: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(..2)?.try_into().ok()?))
+}
+
+fn be32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+fn be64(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(..8)?.try_into().ok()?))
+}
+
+fn le16(bytes: &[u8]) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(..2)?.try_into().ok()?))
+}
+
+fn le32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+/// The size from the frame header, and the date taken from the EXIF data
+fn describe_jpeg(file: &mut File) -> Option<String> {
+    let mut data = Vec::new();
+    file.take(JPEG_LIMIT).read_to_end(&mut data).ok()?;
+    let (mut size, mut taken) = (None, None);
+    let mut i = 2;
+    while size.is_none() && i + 4 <= data.len() && data[i] == 0xff {
+        let marker = data[i + 1];
+        let len = usize::from(be16(&data[i + 2..])?);
+        let segment = data.get(i + 4..i + 2 + len)?;
+        match marker {
+            // Start of frame, other than the DHT, JPG and DAC markers
+            0xc0..=0xcf if ![0xc4, 0xc8, 0xcc].contains(&marker) => {
+                size = Some(dimensions(
+                    be16(segment.get(3..)?)?.into(),
+                    be16(segment.get(1..)?)?.into(),
+                ));
+            }
+            0xe1 if segment.starts_with(b"Exif\0\0") => taken = exif_date(&segment[6..]),
+            _ => {}
+        }
+        i += 2 + len;
+    }
+    match (size, taken) {
+        (Some(size), Some(taken)) => Some(format!("{}, taken {}", size, taken)),
+        (size, taken) => size.or(taken.map(|taken| format!("taken {}", taken))),
+    }
+}
+
+/// When the photo in the TIFF structure `tiff` was taken, or else last
+/// changed
+fn exif_date(tiff: &[u8]) -> Option<String> {
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| match big_endian {
+        true => be16(tiff.get(at..)?),
+        false => le16(tiff.get(at..)?),
+    };
+    let u32_at = |at: usize| match big_endian {
+        true => be32(tiff.get(at..)?),
+        false => le32(tiff.get(at..)?),
+    };
+    // The value of `tag` in the directory at `ifd`
+    let find = |ifd: usize, tag: u16| {
+        let count = usize::from(u16_at(ifd)?);
+        (0..count)
+            .map(|n| ifd + 2 + n * 12)
+            .find(|&entry| u16_at(entry) == Some(tag))
+            .and_then(|entry| u32_at(entry + 8))
+    };
+    let text_at = |at: u32| {
+        let at = at as usize;
+        let text = tiff.get(at..at + 19)?;
+        Some(String::from_utf8_lossy(text).into_owned())
+    };
+
+    let ifd0 = u32_at(4)? as usize;
+    let original = find(ifd0, 0x8769)
+        .and_then(|exif| find(exif as usize, 0x9003))
+        .and_then(text_at);
+    original.or_else(|| find(ifd0, 0x0132).and_then(text_at))
+}
+
+/// The duration from the byte rate and the size of the samples
+fn describe_wav(file: &mut File) -> Option<String> {
+    let mut offset = 12;
+    let mut byte_rate = None;
+    loop {
+        let header = read_at(file, offset, 8)?;
+        let len = u64::from(le32(&header[4..])?);
+        match &header[..4] {
+            b"fmt " => byte_rate = le32(&read_at(file, offset + 16, 4)?),
+            b"data" => {
+                let byte_rate = u64::from(byte_rate.filter(|&rate| rate > 0)?);
+                return Some(playing_time(len / byte_rate));
+            }
+            _ => {}
+        }
+        offset += 8 + len + len % 2;
+    }
+}
+
+/// The duration from the sample rate and count in the STREAMINFO block
+fn describe_flac(file: &mut File) -> Option<String> {
+    let info = read_at(file, 8, 18)?;
+    let rate = u64::from(be32(&info[10..])? >> 12);
+    let samples = be64(&info[10..])? & 0xf_ffff_ffff;
+    (rate > 0).then(|| playing_time(samples / rate))
+}
+
+/// The duration from the movie header, which may be at either end
+fn describe_mp4(file: &mut File) -> Option<String> {
+    let moov = find_box(file, 0, file.metadata().ok()?.len(), b"moov")?;
+    let mvhd = find_box(file, moov.0, moov.1, b"mvhd")?;
+    let version = read_at(file, mvhd.0, 1)?[0];
+    let header = read_at(file, mvhd.0, if version == 1 { 32 } else { 20 })?;
+    let (timescale, duration) = match version {
+        1 => (u64::from(be32(&header[20..])?), be64(&header[24..])?),
+        _ => (
+            u64::from(be32(&header[12..])?),
+            u64::from(be32(&header[16..])?),
+        ),
+    };
+    (timescale > 0).then(|| playing_time(duration / timescale))
+}
+
+/// The start and end of the contents of the first box named `name`
+/// between `start` and `end`
+fn find_box(file: &mut File, start: u64, end: u64, name: &[u8]) -> Option<(u64, u64)> {
+    let mut offset = start;
+    while offset.saturating_add(8) <= end {
+        let header = read_at(file, offset, 16).or_else(|| read_at(file, offset, 8))?;
+        let (len, skip) = match be32(&header)? {
+            1 => (be64(&header[8..])?, 16),
+            0 => (end - offset, 8),
+            len => (u64::from(len), 8),
+        };
+        if len < skip {
+            return None;
+        }
+        if &header[4..8] == name {
+            return Some((offset + skip, offset.checked_add(len)?));
+        }
+        offset = offset.checked_add(len)?;
+    }
+    None
+}
+
+/// The number of page objects, which is right for all but unusual PDFs
+fn describe_pdf(file: &mut File) -> Option<String> {
+    let mut data = Vec::new();
+    file.take(PDF_LIMIT).read_to_end(&mut data).ok()?;
+    let mut pages = 0;
+    for (i, _) in data.windows(5).enumerate().filter(|(_, w)| w == b"/Type") {
+        let rest = &data[i + 5..];
+        let rest = &rest[rest.iter().take_while(|b| b.is_ascii_whitespace()).count()..];
+        if rest.starts_with(b"/Page") && !rest[5..].first().is_some_and(u8::is_ascii_alphabetic) {
+            pages += 1;
+        }
+    }
+    (pages > 0).then(|| match pages {
+        1 => "1 page".to_string(),
+        n => format!("{} pages", n),
+    })
+}
@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Mode, ownership and timestamps of a buried path, kept in the record so
+/// that they can be put back on unbury
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMeta {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Nanoseconds since the epoch
+    pub mtime: i64,
+    pub atime: i64,
+}
+
+impl FileMeta {
+    /// The metadata of `path`, without following a symlink
+    pub fn of(path: &Path) -> Option<FileMeta> {
+        FileMeta::from_metadata(&fs::symlink_metadata(path).ok()?)
+    }
+
+    /// The parts of `metadata` that are kept
+    pub fn from_metadata(metadata: &fs::Metadata) -> Option<FileMeta> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let nanos = |secs: i64, nsecs: i64| secs * 1_000_000_000 + nsecs;
+            Some(FileMeta {
+                mode: metadata.mode() & 0o7777,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                mtime: nanos(metadata.mtime(), metadata.mtime_nsec()),
+                atime: nanos(metadata.atime(), metadata.atime_nsec()),
+            })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = metadata;
+            None
+        }
+    }
+
+    /// Parse the `meta=` field of the record: `mode:uid:gid:mtime:atime`,
+    /// with the mode in octal
+    pub fn parse(field: &str) -> Option<FileMeta> {
+        let mut parts = field.split(':');
+        let meta = FileMeta {
+            mode: u32::from_str_radix(parts.next()?, 8).ok()?,
+            uid: parts.next()?.parse().ok()?,
+            gid: parts.next()?.parse().ok()?,
+            mtime: parts.next()?.parse().ok()?,
+            atime: parts.next()?.parse().ok()?,
+        };
+        parts.next().is_none().then_some(meta)
+    }
+
+    /// Format as the `meta=` field of the record
+    pub fn to_field(&self) -> String {
+        format!(
+            "{:o}:{}:{}:{}:{}",
+            self.mode, self.uid, self.gid, self.mtime, self.atime
+        )
+    }
+
+    /// Put the metadata back on `path`. Ownership can only be given away
+    /// by root, so that is best-effort.
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            use std::os::unix::fs::PermissionsExt;
+            std::os::unix::fs::lchown(path, Some(self.uid), Some(self.gid)).ok();
+            if !fs::symlink_metadata(path)?.file_type().is_symlink() {
+                fs::set_permissions(path, fs::Permissions::from_mode(self.mode))?;
+            }
+            let timespec = |nanos: i64| libc::timespec {
+                tv_sec: nanos.div_euclid(1_000_000_000) as libc::time_t,
+                tv_nsec: nanos.rem_euclid(1_000_000_000) as _,
+            };
+            let times = [timespec(self.atime), timespec(self.mtime)];
+            let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+            // SAFETY: `path` is a valid C string and `times` holds two timespecs
+            let result = unsafe {
+                libc::utimensat(
+                    libc::AT_FDCWD,
+                    path.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = path;
+        }
+        Ok(())
+    }
+}
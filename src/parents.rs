@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::{fs, io::ErrorKind};
+
+use crate::util;
+
+pub const PARENTS: &str = ".parents";
+
+/// Mode and ownership of a directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirMeta {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl DirMeta {
+    fn of(dir: &Path) -> Option<DirMeta> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = fs::metadata(dir).ok()?;
+            Some(DirMeta {
+                mode: metadata.mode() & 0o7777,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = dir;
+            None
+        }
+    }
+
+    fn apply(&self, dir: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // Only root can give directories away, so this is best-effort
+            std::os::unix::fs::chown(dir, Some(self.uid), Some(self.gid)).ok();
+            fs::set_permissions(dir, fs::Permissions::from_mode(self.mode))?;
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = dir;
+        }
+        Ok(())
+    }
+}
+
+/// Metadata of the directories that graves were buried from, so that
+/// they can be recreated faithfully if they're gone by the time of unbury.
+/// Later lines for the same directory take precedence.
+#[derive(Debug)]
+pub struct Parents {
+    path: PathBuf,
+    known: RefCell<Option<HashMap<PathBuf, DirMeta>>>,
+}
+
+impl Parents {
+    pub fn new(graveyard: &Path) -> Parents {
+        Parents {
+            path: graveyard.join(PARENTS),
+            known: RefCell::new(None),
+        }
+    }
+
+    fn read(&self) -> HashMap<PathBuf, DirMeta> {
+        let Ok(file) = fs::File::open(&self.path) else {
+            return HashMap::new();
+        };
+        BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| {
+                let mut tokens = line.split('\t');
+                let dir = PathBuf::from(tokens.next()?);
+                let mode = u32::from_str_radix(tokens.next()?, 8).ok()?;
+                let uid = tokens.next()?.parse().ok()?;
+                let gid = tokens.next()?.parse().ok()?;
+                Some((dir, DirMeta { mode, uid, gid }))
+            })
+            .collect()
+    }
+
+    /// The recorded metadata of `dir`, if any
+    pub fn get(&self, dir: &Path) -> Option<DirMeta> {
+        self.known
+            .borrow_mut()
+            .get_or_insert_with(|| self.read())
+            .get(dir)
+            .copied()
+    }
+
+    /// Record the metadata of each directory above `path`
+    pub fn remember(&self, path: &Path) -> io::Result<()> {
+        let mut known = self.known.borrow_mut();
+        let known = known.get_or_insert_with(|| self.read());
+        let mut file = None;
+        for dir in path
+            .ancestors()
+            .skip(1)
+            .filter(|dir| dir.parent().is_some())
+        {
+            let Some(meta) = DirMeta::of(dir) else {
+                continue;
+            };
+            if known.get(dir) == Some(&meta) {
+                continue;
+            }
+            if file.is_none() {
+                file = Some(
+                    fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&self.path)?,
+                );
+            }
+            if let Some(file) = file.as_mut() {
+                writeln!(
+                    file,
+                    "{}\t{:o}\t{}\t{}",
+                    dir.display(),
+                    meta.mode,
+                    meta.uid,
+                    meta.gid
+                )?;
+            }
+            known.insert(dir.to_path_buf(), meta);
+        }
+        Ok(())
+    }
+
+    /// Create `dir` and its missing ancestors, top-down, with the recorded
+    /// metadata where known, or else mirroring their counterparts in the
+    /// graveyard.
+    pub fn recreate(&self, dir: &Path, graveyard: &Path) -> io::Result<()> {
+        let missing: Vec<&Path> = dir.ancestors().take_while(|d| !d.exists()).collect();
+        for d in missing.into_iter().rev() {
+            match self.get(d) {
+                Some(meta) => {
+                    match fs::create_dir(d) {
+                        Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e),
+                        _ => {}
+                    }
+                    meta.apply(d)?;
+                }
+                None => util::create_dirs_with_permissions(
+                    d,
+                    Some(&util::join_absolute(graveyard, d)),
+                    util::PermissionPolicy::Mirror,
+                    &mut util::DirCache::default(),
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::record::RecordItem;
+use crate::util;
+
+/// Marks a graveyard as portable
+pub const MARKER: &str = ".portable";
+
+/// A portable graveyard, kept on a removable drive. Graves of files on
+/// the same drive are buried under their path from the root of the
+/// drive, and the record holds that path rather than the absolute one,
+/// so seance and unbury keep working wherever the drive is mounted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Volume {
+    pub graveyard: PathBuf,
+    /// Where the drive the graveyard is on is mounted
+    pub root: PathBuf,
+}
+
+impl Volume {
+    /// The volume of `graveyard`, if it has been made portable
+    pub fn of(graveyard: &Path) -> Option<Volume> {
+        if !graveyard.join(MARKER).exists() {
+            return None;
+        }
+        Some(Volume {
+            graveyard: graveyard.to_path_buf(),
+            root: volume_root(graveyard),
+        })
+    }
+
+    /// Make `graveyard` portable from now on. Graves already buried keep
+    /// their absolute paths.
+    pub fn mark(graveyard: &Path) -> io::Result<Volume> {
+        fs::create_dir_all(graveyard)?;
+        fs::write(graveyard.join(MARKER), "")?;
+        Ok(Volume {
+            graveyard: graveyard.to_path_buf(),
+            root: volume_root(graveyard),
+        })
+    }
+
+    /// Where `path` is buried. Paths off the drive are buried as usual.
+    pub fn grave_of(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.root) {
+            Ok(rel) => self.graveyard.join(rel),
+            Err(_) => util::join_absolute(&self.graveyard, path),
+        }
+    }
+
+    /// Where the grave at `grave` was buried from, going by its path in
+    /// the graveyard, taking it to be from the drive
+    pub fn original_of(&self, grave: &Path) -> Option<PathBuf> {
+        let rel = grave.strip_prefix(&self.graveyard).ok()?;
+        (!rel.as_os_str().is_empty()).then(|| self.root.join(rel))
+    }
+
+    /// `item` as it is written to the record, with paths on the drive
+    /// made relative
+    pub fn store(&self, item: RecordItem) -> RecordItem {
+        match (
+            item.orig.strip_prefix(&self.root),
+            item.dest.strip_prefix(&self.graveyard),
+        ) {
+            (Ok(orig), Ok(dest)) => RecordItem {
+                orig: orig.to_path_buf(),
+                dest: dest.to_path_buf(),
+                ..item.clone()
+            },
+            _ => item,
+        }
+    }
+
+    /// `item` as read from the record, with relative paths resolved
+    /// against where the drive is mounted now
+    pub fn load(&self, item: RecordItem) -> RecordItem {
+        if item.orig.is_absolute() {
+            return item;
+        }
+        RecordItem {
+            orig: self.root.join(&item.orig),
+            dest: self.graveyard.join(&item.dest),
+            ..item
+        }
+    }
+}
+
+/// Where `path` is buried in `graveyard`, which may be portable
+pub fn grave_of(graveyard: &Path, volume: Option<&Volume>, path: &Path) -> PathBuf {
+    match volume {
+        Some(volume) => volume.grave_of(path),
+        None => util::join_absolute(graveyard, path),
+    }
+}
+
+/// The mount point of the filesystem `path` is on: its highest ancestor
+/// on the same device
+#[cfg(unix)]
+fn volume_root(path: &Path) -> PathBuf {
+    use std::os::unix::fs::MetadataExt;
+    let existing = path
+        .ancestors()
+        .find_map(|dir| dunce::canonicalize(dir).ok())
+        .unwrap_or_else(|| path.to_path_buf());
+    let Ok(dev) = fs::metadata(&existing).map(|m| m.dev()) else {
+        return existing;
+    };
+    existing
+        .ancestors()
+        .take_while(|dir| fs::metadata(dir).is_ok_and(|m| m.dev() == dev))
+        .last()
+        .unwrap_or(&existing)
+        .to_path_buf()
+}
+
+/// The drive `path` is on, e.g. `E:\`
+#[cfg(not(unix))]
+fn volume_root(path: &Path) -> PathBuf {
+    let path = dunce::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    path.ancestors().last().unwrap_or(&path).to_path_buf()
+}
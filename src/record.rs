@@ -1,178 +1,947 @@
-use chrono::Local;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, Error, ErrorKind};
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::sync::Arc;
 
-use crate::util;
+use crate::context::{Clock, Context, SystemClock};
+use crate::meta::FileMeta;
+use crate::portable::Volume;
+use crate::store::{self, RecordStore};
+use crate::util::{self, IdGen};
 
 pub const RECORD: &str = ".record";
+/// The start of the record's header. Hash chains start from it, so it
+/// stays the same as the format changes.
+pub(crate) const HEADER: &str = "Time\tOriginal\tDestination";
+/// Version of the record's format, which ends its header as `version=N`.
+/// Headers without one are of version 1.
+pub const FORMAT_VERSION: u32 = 2;
+/// Upgrades of a line of the record from each version of the format to
+/// the next, starting from version 1
+const UPGRADES: [fn(String) -> String; FORMAT_VERSION as usize - 1] = [
+    // 2 only added the version to the header
+    |line| line,
+];
+/// How the original rip wrote the time of a grave, like
+/// `Sun Jun 10 12:30:45 2018`, in local time
+const LEGACY_TIME: &str = "%a %b %e %H:%M:%S %Y";
 
-#[derive(Debug)]
+/// An entry of the record: a grave, and the path it was buried from
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RecordItem {
     pub time: String,
     pub orig: PathBuf,
     pub dest: PathBuf,
+    /// Short ID to unbury the grave by, as `rip -u @ID`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// How the grave came to be in the record, if not by being buried
+    /// (`adopted` for graves found in the graveyard by `rip doctor`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// Mode, ownership and timestamps of the original, to put back on unbury
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<FileMeta>,
+    /// What the original was: a file, directory, symlink or something else
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<FileKind>,
+    /// Checksum of the grave as it was buried, checked on unbury
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// ID shared by the entries of a directory buried file by file,
+    /// which is the ID the directory would have had as a single grave
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub op: Option<String>,
+    /// Who buried the grave, if asked to record it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<Origin>,
+    /// Hash of the previous line, if the record is hash-chained
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev: Option<String>,
+}
+
+/// What a grave was when it was buried
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    /// A FIFO, socket or device
+    Special,
+}
+
+impl FileKind {
+    /// The kind of what `metadata`, from `symlink_metadata`, describes
+    pub fn of(metadata: &fs::Metadata) -> FileKind {
+        let filetype = metadata.file_type();
+        if filetype.is_symlink() {
+            FileKind::Symlink
+        } else if filetype.is_dir() {
+            FileKind::Dir
+        } else if filetype.is_file() {
+            FileKind::File
+        } else {
+            FileKind::Special
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FileKind::File => "file",
+            FileKind::Dir => "dir",
+            FileKind::Symlink => "symlink",
+            FileKind::Special => "special",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<FileKind> {
+        [
+            FileKind::File,
+            FileKind::Dir,
+            FileKind::Symlink,
+            FileKind::Special,
+        ]
+        .into_iter()
+        .find(|known| known.as_str() == kind)
+    }
+}
+
+/// Who buried a grave: the user, the host they were on, and the directory
+/// they ran `rip` from, for graveyards that several people share
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Origin {
+    pub user: String,
+    pub host: String,
+    pub cwd: PathBuf,
+}
+
+impl Origin {
+    /// The current user and host, running from `cwd`
+    pub fn current(ctx: &Context, cwd: &Path) -> Origin {
+        Origin {
+            user: clean_note(&util::get_user(ctx)),
+            host: clean_note(&util::get_host(ctx)),
+            cwd: cwd.to_path_buf(),
+        }
+    }
 }
 
 impl RecordItem {
-    /// Parse a line in the record into a `RecordItem`
-    pub fn new(line: &str) -> RecordItem {
-        let mut tokens = line.split('\t');
-        let time = tokens.next().expect("Bad format: column 1").to_string();
-        let orig = tokens.next().expect("Bad format: column 2").to_string();
-        let dest = tokens.next().expect("Bad format: column 3").to_string();
+    /// An entry for `orig` buried at `dest` at `time`
+    pub fn buried(
+        time: DateTime<Local>,
+        orig: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+    ) -> RecordItem {
+        let time = time.to_rfc3339();
+        let id = grave_id(&time, orig.as_ref(), dest.as_ref());
         RecordItem {
+            time,
+            orig: orig.as_ref().to_path_buf(),
+            dest: dest.as_ref().to_path_buf(),
+            id: Some(id),
+            note: None,
+            status: None,
+            meta: None,
+            kind: None,
+            checksum: None,
+            op: None,
+            origin: None,
+            prev: None,
+        }
+    }
+
+    /// Parse a line in the record into a `RecordItem`, or `None` if it is
+    /// malformed. Columns after the first three are optional `key=value` fields.
+    pub fn new(line: &str) -> Option<RecordItem> {
+        let mut tokens = line.split('\t');
+        let time = tokens.next()?.to_string();
+        let mut orig = tokens.next()?.to_string();
+        let mut dest = tokens.next()?.to_string();
+        let (mut id, mut note, mut status, mut meta, mut kind, mut checksum, mut op, mut prev) =
+            (None, None, None, None, None, None, None, None);
+        let (mut user, mut host, mut cwd) = (None, None, None);
+        let mut escaped = false;
+        for (key, value) in tokens.filter_map(|field| field.split_once('=')) {
+            match key {
+                "escaped" => escaped = true,
+                "id" => id = Some(value.to_string()),
+                "note" => note = Some(value.to_string()),
+                "status" => status = Some(value.to_string()),
+                "meta" => meta = FileMeta::parse(value),
+                "kind" => kind = FileKind::parse(value),
+                "checksum" => checksum = Some(value.to_string()),
+                "op" => op = Some(value.to_string()),
+                "user" => user = Some(value.to_string()),
+                "host" => host = Some(value.to_string()),
+                "cwd" => cwd = Some(value.to_string()),
+                "prev" => prev = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        if escaped {
+            orig = unescape(&orig);
+            dest = unescape(&dest);
+            cwd = cwd.map(|cwd| unescape(&cwd));
+        }
+        let origin = (user.is_some() || host.is_some() || cwd.is_some()).then(|| Origin {
+            user: user.unwrap_or_default(),
+            host: host.unwrap_or_default(),
+            cwd: PathBuf::from(cwd.unwrap_or_default()),
+        });
+        Some(RecordItem {
             time,
             orig: PathBuf::from(orig),
             dest: PathBuf::from(dest),
+            id,
+            note,
+            status,
+            meta,
+            kind,
+            checksum,
+            op,
+            origin,
+            prev,
+        })
+    }
+
+    /// Format the item as a line of the record. Paths with tabs or line
+    /// breaks in them are escaped, and the line marked as such; others are
+    /// written as they are, as they always were.
+    pub fn to_line(&self) -> String {
+        let orig = self.orig.display().to_string();
+        let dest = self.dest.display().to_string();
+        let cwd = self
+            .origin
+            .as_ref()
+            .map(|origin| origin.cwd.display().to_string());
+        let escaped = [Some(&orig), Some(&dest), cwd.as_ref()]
+            .iter()
+            .flatten()
+            .any(|path| path.contains(['\t', '\n', '\r']));
+        let mut line = match escaped {
+            true => format!(
+                "{}\t{}\t{}\tescaped=1",
+                self.time,
+                escape(&orig),
+                escape(&dest)
+            ),
+            false => format!("{}\t{}\t{}", self.time, orig, dest),
+        };
+        if let Some(id) = &self.id {
+            line.push_str(&format!("\tid={}", id));
+        }
+        if let Some(note) = &self.note {
+            line.push_str(&format!("\tnote={}", note));
+        }
+        if let Some(status) = &self.status {
+            line.push_str(&format!("\tstatus={}", status));
+        }
+        if let Some(meta) = &self.meta {
+            line.push_str(&format!("\tmeta={}", meta.to_field()));
+        }
+        if let Some(kind) = self.kind {
+            line.push_str(&format!("\tkind={}", kind.as_str()));
+        }
+        if let Some(checksum) = &self.checksum {
+            line.push_str(&format!("\tchecksum={}", checksum));
+        }
+        if let Some(op) = &self.op {
+            line.push_str(&format!("\top={}", op));
+        }
+        if let (Some(origin), Some(cwd)) = (&self.origin, cwd) {
+            let cwd = match escaped {
+                true => escape(&cwd),
+                false => cwd,
+            };
+            line.push_str(&format!(
+                "\tuser={}\thost={}\tcwd={}",
+                origin.user, origin.host, cwd
+            ));
         }
+        // The hash comes last, so that it can be replaced without parsing
+        if let Some(prev) = &self.prev {
+            line.push_str(&format!("\tprev={}", prev));
+        }
+        line
+    }
+
+    /// Parse a line of a record written by the original rip, which has
+    /// just the three columns, with the time in `LEGACY_TIME`
+    pub fn from_legacy(line: &str) -> Option<RecordItem> {
+        let mut tokens = line.split('\t');
+        let (time, orig, dest) = (tokens.next()?, tokens.next()?, tokens.next()?);
+        if tokens.next().is_some() {
+            return None;
+        }
+        let time = NaiveDateTime::parse_from_str(time.trim(), LEGACY_TIME).ok()?;
+        let time = Local.from_local_datetime(&time).earliest()?;
+        Some(RecordItem::buried(time, orig, dest))
+    }
+
+    /// How long ago the grave was buried
+    pub fn age(&self) -> Option<chrono::Duration> {
+        self.age_at(Local::now())
+    }
+
+    /// How long before `now` the grave was buried
+    pub fn age_at(&self, now: DateTime<Local>) -> Option<chrono::Duration> {
+        let time = chrono::DateTime::parse_from_rfc3339(&self.time).ok()?;
+        Some(now.signed_duration_since(time))
+    }
+}
+
+/// Whether the record file of `graveyard` was written by the original rip,
+/// which didn't start it with a header
+pub fn is_legacy(graveyard: &Path) -> bool {
+    let Ok(file) = fs::File::open(graveyard.join(RECORD)) else {
+        return false;
+    };
+    match io::BufReader::new(file).lines().next() {
+        Some(Ok(first)) => !first.is_empty() && format_version(&first).is_none(),
+        _ => false,
     }
 }
 
+/// The header of a record in the current format
+pub(crate) fn header() -> String {
+    format!("{}\tversion={}", HEADER, FORMAT_VERSION)
+}
+
+/// The version of the format of a record whose first line is `first`, or
+/// `None` if that isn't a header
+pub(crate) fn format_version(first: &str) -> Option<u32> {
+    match first.strip_prefix(HEADER)? {
+        "" => Some(1),
+        rest => rest.strip_prefix("\tversion=")?.parse().ok(),
+    }
+}
+
+/// `line`, from a record of format `version`, brought up to the current
+/// format
+pub(crate) fn upgrade_line(version: u32, line: String) -> String {
+    UPGRADES
+        .iter()
+        .skip(version.saturating_sub(1) as usize)
+        .fold(line, |line, upgrade| upgrade(line))
+}
+
+/// `path` with backslashes, tabs and line breaks escaped, for a line
+/// marked `escaped`
+pub(crate) fn escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undo `escape`
+pub(crate) fn unescape(field: &str) -> String {
+    let mut path = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            path.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => path.push('\t'),
+            Some('n') => path.push('\n'),
+            Some('r') => path.push('\r'),
+            Some(c) => path.push(c),
+            None => path.push('\\'),
+        }
+    }
+    path
+}
+
+/// ID of the grave of `orig` buried at `dest` at `time`: eight hex digits,
+/// which seance shortens to as few as stay unique
+fn grave_id(time: &str, orig: &Path, dest: &Path) -> String {
+    let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+    for part in [
+        time.as_bytes(),
+        orig.as_os_str().as_encoded_bytes(),
+        dest.as_os_str().as_encoded_bytes(),
+    ] {
+        hasher.update(part);
+        hasher.update(b"\0");
+    }
+    format!("{:08x}", hasher.digest() as u32)
+}
+
+/// `note` as it can be kept in the record, on a single line
+pub fn clean_note(note: &str) -> String {
+    note.replace(['\t', '\n', '\r'], " ")
+}
+
+/// How many digits of the grave IDs in `graves` it takes to tell them
+/// apart, and no fewer than four
+pub fn id_length(graves: &[RecordItem]) -> usize {
+    let ids: Vec<&str> = graves
+        .iter()
+        .filter_map(|grave| grave.id.as_deref())
+        .collect();
+    let longest = ids.iter().map(|id| id.len()).max().unwrap_or(0);
+    (4..longest)
+        .find(|&len| {
+            let mut prefixes: Vec<&str> = ids.iter().map(|id| &id[..len.min(id.len())]).collect();
+            prefixes.sort();
+            prefixes.windows(2).all(|pair| pair[0] != pair[1])
+        })
+        .unwrap_or(longest.max(4))
+}
+
+/// Hash linking an entry to the line before it
+fn link_to(prev_line: &str) -> String {
+    format!("{:x}", Sha256::digest(prev_line.as_bytes()))
+}
+
+/// The hash an entry holds of the line before it, if any
+fn link_of(line: &str) -> Option<&str> {
+    line.rfind("\tprev=").map(|i| &line[i + "\tprev=".len()..])
+}
+
+/// Replace the hash in `line` with one of `prev_line`
+fn chain_line(line: &str, prev_line: &str) -> String {
+    let base = line.rfind("\tprev=").map_or(line, |i| &line[..i]);
+    format!("{}\tprev={}", base, link_to(prev_line))
+}
+
+/// Result of checking the record's hash chain
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainReport {
+    pub entries: usize,
+    pub chained: usize,
+    /// Line numbers of entries whose link to the line before doesn't hold
+    pub broken: Vec<usize>,
+}
+
 #[derive(Debug)]
 pub struct Record {
-    path: PathBuf,
+    store: Box<dyn RecordStore>,
+    clock: Arc<dyn Clock>,
+    chain: bool,
+    volume: Option<Volume>,
+    origin: Option<Origin>,
 }
 
 impl Record {
     pub fn new(graveyard: &Path) -> Record {
-        let path = graveyard.join(RECORD);
-        // Create the record file if it doesn't exist
-        if !path.exists() {
-            // Write a header to the record file
-            let mut record_file = fs::OpenOptions::new()
-                .truncate(true)
-                .create(true)
-                .write(true)
-                .open(&path)
-                .expect("Failed to open record file");
-            record_file
-                .write_all(b"Time\tOriginal\tDestination\n")
-                .expect("Failed to write header to record file");
+        Record::open_in(graveyard).expect("Failed to open record")
+    }
+
+    /// The record of `graveyard`, in whichever store it is kept
+    pub fn open_in(graveyard: &Path) -> io::Result<Record> {
+        Ok(Record {
+            store: store::open(graveyard)?,
+            clock: Arc::new(SystemClock),
+            chain: false,
+            volume: Volume::of(graveyard),
+            origin: None,
+        })
+    }
+
+    /// Start a hash chain with the next entry, if the record doesn't have
+    /// one already. Once started, the chain is kept up regardless.
+    pub fn with_chain(mut self, chain: bool) -> Record {
+        self.chain = chain;
+        self
+    }
+
+    /// Use `clock` for the timestamps of new graves and their ages
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Record {
+        self.clock = clock;
+        self
+    }
+
+    /// Record `origin` as who buried each new grave
+    pub fn with_origin(mut self, origin: Option<Origin>) -> Record {
+        self.origin = origin;
+        self
+    }
+
+    /// Name temporary files, like the new record as it is written, with `ids`
+    pub fn with_ids(mut self, ids: Arc<IdGen>) -> Record {
+        self.store.use_ids(ids);
+        self
+    }
+
+    pub fn now(&self) -> DateTime<Local> {
+        self.clock.now()
+    }
+
+    /// The drive of the graveyard, if it is portable
+    pub fn volume(&self) -> Option<&Volume> {
+        self.volume.as_ref()
+    }
+
+    /// Where the record is kept
+    pub fn path(&self) -> &Path {
+        self.store.path()
+    }
+
+    /// Whether any entry of the record is hash-chained
+    fn chained(&self) -> io::Result<bool> {
+        for line in self.store.lines()? {
+            if link_of(&line?).is_some() {
+                return Ok(true);
+            }
         }
-        Record { path }
+        Ok(false)
+    }
+
+    /// Parse a line of the record, resolving paths kept relative to a
+    /// portable graveyard's drive
+    fn parse(&self, line: &str) -> Option<RecordItem> {
+        let item = RecordItem::new(line)?;
+        Some(match &self.volume {
+            Some(volume) => volume.load(item),
+            None => item,
+        })
     }
 
-    pub fn open(&self) -> Result<fs::File, Error> {
-        fs::File::open(&self.path)
-            .map_err(|_| Error::new(ErrorKind::NotFound, "Failed to read record!"))
+    /// Format `item` as a line of the record, with paths relative to a
+    /// portable graveyard's drive
+    fn format(&self, item: RecordItem) -> String {
+        match &self.volume {
+            Some(volume) => volume.store(item).to_line(),
+            None => item.to_line(),
+        }
     }
 
     /// Return the path in the graveyard of the last file to be buried.
     /// As a side effect, any valid last files that are found in the record but
     /// not on the filesystem are removed from the record.
     pub fn get_last_bury(&self) -> Result<PathBuf, Error> {
-        // record: impl AsRef<Path>
-        let record_file = self.open()?;
-        let contents = {
-            let path_f = PathBuf::from(&self.path);
-            fs::read_to_string(path_f)?
-        };
+        self.get_last_n_buries(1)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No files in graveyard"))
+    }
+
+    /// Return the graves of the last `n` buries, newest first. The entries
+    /// of a directory buried with split-dirs make up one bury. Like
+    /// `get_last_bury`, drops the entries it passes whose graves are gone.
+    pub fn get_last_n_buries(&self, n: usize) -> Result<Vec<PathBuf>, Error> {
+        self.last_n_buries(n, true)
+    }
 
-        // This will be None if there is nothing, or Some
-        // if there is items in the vector
+    /// Like `get_last_n_buries`, but leaving the record as it is
+    pub fn peek_last_n_buries(&self, n: usize) -> Result<Vec<PathBuf>, Error> {
+        self.last_n_buries(n, false)
+    }
+
+    fn last_n_buries(&self, n: usize, prune: bool) -> Result<Vec<PathBuf>, Error> {
+        // Read from the end, so only as far back as the graves asked for
         let mut graves_to_exhume: Vec<PathBuf> = Vec::new();
-        let mut lines = contents.lines();
-        lines.next();
-        for entry in lines.rev().map(RecordItem::new) {
-            // Check that the file is still in the graveyard.
-            // If it is, return the corresponding line.
-            if util::symlink_exists(&entry.dest) {
-                if !graves_to_exhume.is_empty() {
-                    self.delete_lines(record_file, &graves_to_exhume)?;
-                }
-                return Ok(entry.dest);
-            } else {
+        let mut graves = Vec::new();
+        let mut buries = 0;
+        let mut last_op = None;
+        for line in self.store.lines_rev()? {
+            let Some(entry) = self.parse(&line?) else {
+                continue;
+            };
+            let op = entry
+                .op
+                .clone()
+                .unwrap_or_else(|| entry.dest.display().to_string());
+            let new_bury = last_op.as_ref() != Some(&op);
+            if new_bury && buries == n {
+                break;
+            }
+            // Check that the file is still in the graveyard
+            if !util::symlink_exists(&entry.dest) {
                 // File is gone, mark the grave to be removed from the record
                 graves_to_exhume.push(entry.dest);
+                continue;
+            }
+            if new_bury {
+                buries += 1;
             }
+            graves.push(entry.dest);
+            last_op = Some(op);
         }
 
-        if !graves_to_exhume.is_empty() {
-            self.delete_lines(record_file, &graves_to_exhume)?;
+        if prune && !graves_to_exhume.is_empty() {
+            self.delete_lines(&graves_to_exhume)?;
         }
-        Err(Error::new(ErrorKind::NotFound, "No files in graveyard"))
+        Ok(graves)
+    }
+
+    /// Rewrite each entry of the record with `edit`, dropping those it
+    /// returns `None` for, after inserting `first` at the top. Entries whose
+    /// hash link held are re-linked to their new predecessor, and broken
+    /// links are left broken, so that tampering stays evident.
+    fn rewrite(
+        &self,
+        first: Vec<RecordItem>,
+        mut edit: impl FnMut(&str) -> Option<String>,
+    ) -> Result<(), Error> {
+        let chain = self.chain || (!first.is_empty() && self.chained()?);
+        let mut first = Some(first);
+        self.store.update(&mut |lines, write| {
+            let mut old_prev = HEADER.to_string();
+            let mut new_prev = HEADER.to_string();
+            for item in first.take().unwrap_or_default() {
+                let mut new_line = self.format(RecordItem { prev: None, ..item });
+                if chain {
+                    new_line = chain_line(&new_line, &new_prev);
+                }
+                new_prev.clone_from(&new_line);
+                write(new_line)?;
+            }
+            for line in lines {
+                let line = line?;
+                let intact = link_of(&line) == Some(link_to(&old_prev).as_str());
+                let edited = edit(&line);
+                old_prev = line;
+                let Some(new_line) = edited else {
+                    continue;
+                };
+                let new_line = match intact {
+                    true => chain_line(&new_line, &new_prev),
+                    false => new_line,
+                };
+                new_prev.clone_from(&new_line);
+                write(new_line)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Replace every entry of the record with `items`. They are hash-chained
+    /// if the record was, or if a chain was asked for.
+    pub fn replace(&self, items: impl IntoIterator<Item = RecordItem>) -> Result<(), Error> {
+        let chain = self.chain || self.chained()?;
+        let mut items = Some(items);
+        self.store.update(&mut |_, write| {
+            let mut prev_line = HEADER.to_string();
+            for item in items.take().into_iter().flatten() {
+                let mut line = self.format(RecordItem { prev: None, ..item });
+                if chain {
+                    line = chain_line(&line, &prev_line);
+                }
+                prev_line.clone_from(&line);
+                write(line)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Add `items` above every other entry, as the oldest graves
+    pub fn prepend(&self, items: Vec<RecordItem>) -> Result<(), Error> {
+        self.rewrite(items, |line| Some(line.to_string()))
+    }
+
+    /// Drop the entries whose graves are gone, and repeats of an entry,
+    /// keeping the first. Unreadable lines are left for `rip fsck`. Returns
+    /// how many entries were dropped for each.
+    pub fn compact(&self) -> Result<(usize, usize), Error> {
+        let (mut gone, mut repeats) = (0, 0);
+        let mut seen = HashSet::new();
+        self.rewrite(Vec::new(), |line| {
+            let Some(item) = self.parse(line) else {
+                return Some(line.to_string());
+            };
+            if !util::symlink_exists(&item.dest) {
+                gone += 1;
+                return None;
+            }
+            // Repeats can differ in their hash links
+            if !seen.insert(RecordItem { prev: None, ..item }.to_line()) {
+                repeats += 1;
+                return None;
+            }
+            Some(line.to_string())
+        })?;
+        Ok((gone, repeats))
     }
 
     /// Takes a vector of grave paths and removes the respective lines from the record
-    fn delete_lines(&self, record_file: fs::File, graves: &[PathBuf]) -> Result<(), Error> {
-        let record_path = &self.path;
-        // Get the lines to write back to the record, which is every line except
-        // the ones matching the exhumed graves.  Store them in a vector
-        // since we'll be overwriting the record in-place.
-        let mut reader = BufReader::new(record_file).lines();
-        reader.next();
-        let lines_to_write: Vec<String> = reader
-            .map_while(Result::ok)
-            .filter(|line| !graves.iter().any(|y| *y == RecordItem::new(line).dest))
-            .collect();
-        let mut mutable_record_file = fs::File::create(record_path)?;
-        for line in lines_to_write {
-            writeln!(mutable_record_file, "{}", line)?;
-        }
-        Ok(())
+    fn delete_lines(&self, graves: &[PathBuf]) -> Result<(), Error> {
+        let graves: HashSet<&PathBuf> = graves.iter().collect();
+        self.rewrite(Vec::new(), |line| {
+            match self
+                .parse(line)
+                .is_some_and(|item| graves.contains(&item.dest))
+            {
+                true => None,
+                false => Some(line.to_string()),
+            }
+        })
     }
 
     pub fn log_exhumed_graves(&self, graves_to_exhume: &[PathBuf]) -> Result<(), Error> {
-        // Reopen the record and then delete lines corresponding to exhumed graves
-        let record_file = self.open()?;
-        self.delete_lines(record_file, graves_to_exhume)
-            .map_err(|e| {
-                Error::new(
-                    e.kind(),
-                    format!("Failed to remove unburied files from record: {}", e),
-                )
-            })
+        self.delete_lines(graves_to_exhume).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Failed to remove unburied files from record: {}", e),
+            )
+        })
     }
 
-    /// Takes a vector of grave paths and returns the respective lines in the record
+    /// Takes a vector of grave paths and returns the respective lines in the
+    /// record, with any relative paths resolved. They are read before the
+    /// graves are exhumed, so the record is free to change meanwhile.
     pub fn lines_of_graves<'a>(
         &'a self,
         graves: &'a [PathBuf],
     ) -> impl Iterator<Item = String> + 'a {
-        let record_file = self.open().unwrap();
-        let mut reader = BufReader::new(record_file).lines();
-        reader.next();
-        reader
-            .map_while(Result::ok)
-            .filter(move |line| graves.iter().any(|y| *y == RecordItem::new(line).dest))
+        let lines: Vec<String> = self
+            .graves_at(graves)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|item| item.to_line())
+            .collect();
+        lines.into_iter()
     }
 
-    /// Returns an iterator over all graves in the record that are under gravepath
-    pub fn seance<'a>(
-        &'a self,
-        gravepath: &'a PathBuf,
-    ) -> io::Result<impl Iterator<Item = RecordItem> + 'a> {
-        let record_file = self.open()?;
-        let mut reader = BufReader::new(record_file).lines();
-        reader.next();
-        Ok(reader
+    /// The graves at any of `dests`, oldest first. The record is read
+    /// once for all of them, or not at all when it's a database, which
+    /// looks each up in its index.
+    pub fn graves_at(&self, dests: &[PathBuf]) -> io::Result<Vec<RecordItem>> {
+        let dests: HashSet<PathBuf> = dests.iter().cloned().collect();
+        Ok(match &self.volume {
+            // Relative paths can't be looked up as they are
+            Some(_) => self
+                .graves()?
+                .filter(|item| dests.contains(&item.dest))
+                .collect(),
+            None => self.store.at(&dests)?,
+        })
+    }
+
+    /// Returns an iterator over every grave in the record, oldest first,
+    /// reading it as it goes. A failure to read ends it early.
+    pub fn graves(&self) -> io::Result<impl Iterator<Item = RecordItem> + '_> {
+        Ok(self
+            .store
+            .lines()?
             .map_while(Result::ok)
-            .map(|line| RecordItem::new(&line))
-            .filter(move |record_item| record_item.dest.starts_with(gravepath)))
-    }
-
-    /// Write deletion history to record
-    pub fn write_log(&self, source: impl AsRef<Path>, dest: impl AsRef<Path>) -> io::Result<()> {
-        let (source, dest) = (source.as_ref(), dest.as_ref());
-        let mut record_file = fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.path)?;
-        writeln!(
-            record_file,
-            "{}\t{}\t{}",
-            Local::now().to_rfc3339(),
-            source.display(),
-            dest.display()
-        )
-        .map_err(|e| {
-            Error::new(
-                e.kind(),
-                format!("Failed to write record at {}", &self.path.display()),
-            )
+            .filter_map(|line| self.parse(&line)))
+    }
+
+    /// Line numbers of the lines that aren't graves, which everything
+    /// else skips over
+    pub fn malformed_lines(&self) -> io::Result<Vec<usize>> {
+        let mut malformed = Vec::new();
+        for (i, line) in self.store.lines()?.enumerate() {
+            let line = line?;
+            if !line.is_empty() && RecordItem::new(&line).is_none() {
+                // Counting from one, after the header
+                malformed.push(i + 2);
+            }
+        }
+        Ok(malformed)
+    }
+
+    /// Attach a note to the grave at `dest`, replacing any existing note.
+    /// An empty note removes it. Returns false if there is no such grave.
+    pub fn annotate(&self, dest: &Path, note: &str) -> Result<bool, Error> {
+        let note = clean_note(note);
+        let mut found = false;
+        self.rewrite(Vec::new(), |line| match self.parse(line) {
+            Some(mut item) if item.dest == dest => {
+                found = true;
+                item.note = Some(note.clone()).filter(|note| !note.is_empty());
+                Some(self.format(item))
+            }
+            _ => Some(line.to_string()),
         })?;
+        Ok(found)
+    }
+
+    /// Point the entries of graves that were moved within the graveyard,
+    /// and of graves inside them, at their new paths. Each move is applied
+    /// in turn, so a grave can be moved and then the directory it is in.
+    pub fn move_graves(&self, moves: &[(PathBuf, PathBuf)]) -> Result<(), Error> {
+        self.rewrite(Vec::new(), |line| match self.parse(line) {
+            Some(mut item) if moves.iter().any(|(from, _)| item.dest.starts_with(from)) => {
+                for (from, to) in moves {
+                    if let Ok(inner) = item.dest.strip_prefix(from) {
+                        item.dest = match inner.as_os_str().is_empty() {
+                            true => to.clone(),
+                            false => to.join(inner),
+                        };
+                    }
+                }
+                Some(self.format(item))
+            }
+            _ => Some(line.to_string()),
+        })
+    }
+
+    /// Return the most recent grave of `orig` that is still in the graveyard
+    pub fn last_grave_of(&self, orig: &Path) -> Option<PathBuf> {
+        self.graves()
+            .ok()?
+            .filter(|item| item.orig == orig && util::symlink_exists(&item.dest))
+            .last()
+            .map(|item| item.dest)
+    }
+
+    /// The grave whose ID starts with `prefix`, which must be the only one
+    pub fn grave_by_id(&self, prefix: &str) -> Result<PathBuf, Error> {
+        let prefix = prefix.to_lowercase();
+        let mut graves: Vec<RecordItem> = match prefix.is_empty() {
+            true => Vec::new(),
+            false => self
+                .graves()?
+                .filter(|item| item.id.as_ref().is_some_and(|id| id.starts_with(&prefix)))
+                .collect(),
+        };
+        match graves.len() {
+            0 => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No grave has the ID @{}", prefix),
+            )),
+            1 => Ok(graves.remove(0).dest),
+            n => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "The ID @{} is ambiguous: {} graves start with it; give more of it",
+                    prefix, n
+                ),
+            )),
+        }
+    }
+
+    /// The graves whose ID starts with `prefix`: the one grave it picks
+    /// out, or else the entries of the directory buried file by file whose
+    /// operation ID starts with it
+    pub fn graves_by_id(&self, prefix: &str) -> Result<Vec<PathBuf>, Error> {
+        match self.grave_by_id(prefix) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            grave => return grave.map(|grave| vec![grave]),
+        }
+        let prefix = prefix.to_lowercase();
+        let graves: Vec<RecordItem> = match prefix.is_empty() {
+            true => Vec::new(),
+            false => self
+                .graves()?
+                .filter(|item| item.op.as_ref().is_some_and(|op| op.starts_with(&prefix)))
+                .collect(),
+        };
+        let ops: HashSet<&str> = graves
+            .iter()
+            .filter_map(|item| item.op.as_deref())
+            .collect();
+        match ops.len() {
+            0 => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No grave has the ID @{}", prefix),
+            )),
+            1 => Ok(graves.into_iter().map(|item| item.dest).collect()),
+            n => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "The ID @{} is ambiguous: {} directories start with it; give more of it",
+                    prefix, n
+                ),
+            )),
+        }
+    }
+
+    /// The graves still in the graveyard that were buried along with
+    /// `dest`, as entries of the same directory, or else just `dest`
+    pub fn burial_of(&self, dest: &Path) -> io::Result<Vec<PathBuf>> {
+        let op = self
+            .graves_at(&[dest.to_path_buf()])?
+            .pop()
+            .and_then(|item| item.op);
+        Ok(match op {
+            Some(op) => self
+                .graves()?
+                .filter(|item| item.op.as_ref() == Some(&op) && util::symlink_exists(&item.dest))
+                .map(|item| item.dest)
+                .collect(),
+            None => vec![dest.to_path_buf()],
+        })
+    }
+
+    /// Returns an iterator over all graves in the record that are under gravepath
+    pub fn seance(&self, gravepath: &Path) -> io::Result<impl Iterator<Item = RecordItem>> {
+        let graves = match &self.volume {
+            // Relative paths can't be looked up by prefix
+            Some(_) => self
+                .graves()?
+                .filter(|item| item.dest.starts_with(gravepath))
+                .collect(),
+            None => self.store.under(gravepath)?,
+        };
+        Ok(graves.into_iter())
+    }
+
+    /// Write deletion history to record, returning the entry written, with
+    /// who wrote it if the record was given an origin
+    pub fn write_log(
+        &self,
+        source: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+    ) -> io::Result<RecordItem> {
+        self.write_item(RecordItem::buried(self.now(), source, dest))
+    }
+
+    /// Add `item` to the record, linking it to the last entry if the record
+    /// is hash-chained, and return it. It gets the record's origin, if it
+    /// has none of its own.
+    pub fn write_item(&self, mut item: RecordItem) -> io::Result<RecordItem> {
+        if item.origin.is_none() {
+            item.origin.clone_from(&self.origin);
+        }
+        self.store
+            .append(&mut |last_line| {
+                if self.chain || link_of(last_line).is_some() {
+                    item.prev = Some(link_to(last_line));
+                }
+                self.format(item.clone())
+            })
+            .map_err(|e| {
+                Error::new(
+                    e.kind(),
+                    format!("Failed to write record at {}", self.path().display()),
+                )
+            })?;
+
+        Ok(item)
+    }
 
-        Ok(())
+    /// Check that each hash-chained entry links to the line before it,
+    /// and that no entry after the start of the chain is missing its link
+    pub fn verify_chain(&self) -> io::Result<ChainReport> {
+        let mut report = ChainReport::default();
+        let mut prev_line = HEADER.to_string();
+        for (i, line) in self.store.lines()?.enumerate() {
+            let line = line?;
+            report.entries += 1;
+            match link_of(&line) {
+                Some(link) => {
+                    report.chained += 1;
+                    if link != link_to(&prev_line) {
+                        // Counting from one, after the header
+                        report.broken.push(i + 2);
+                    }
+                }
+                None if report.chained > 0 => report.broken.push(i + 2),
+                None => {}
+            }
+            prev_line = line;
+        }
+        Ok(report)
     }
 }
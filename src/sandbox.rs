@@ -0,0 +1,286 @@
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+/// Restrict this process for the rest of its life: writes are only allowed
+/// beneath the directories in `writable`, and syscalls that rip has no
+/// business making are refused. Returns warnings for any part of the
+/// sandbox the kernel doesn't support.
+pub fn enter(writable: &[PathBuf]) -> Result<Vec<String>, Error> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut warnings = Vec::new();
+        if let Err(e) = linux::no_new_privs() {
+            return Err(Error::new(
+                e.kind(),
+                format!("Failed to enter sandbox: {}", e),
+            ));
+        }
+        match linux::landlock(writable) {
+            Ok(()) => {}
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EOPNOTSUPP)) => {
+                warnings
+                    .push("Landlock is not available, so writes are not restricted".to_string());
+            }
+            Err(e) => {
+                return Err(Error::new(
+                    e.kind(),
+                    format!("Failed to restrict writes: {}", e),
+                ))
+            }
+        }
+        match linux::seccomp() {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::Unsupported => warnings.push(e.to_string()),
+            Err(e) => {
+                return Err(Error::new(
+                    e.kind(),
+                    format!("Failed to install syscall filter: {}", e),
+                ))
+            }
+        }
+        Ok(warnings)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = writable;
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "--sandbox is only supported on Linux",
+        ))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs;
+    use std::io::Error;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::PathBuf;
+
+    const CREATE_RULESET_VERSION: u32 = 1;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+    const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+    const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+    const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+    const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+    /// Moving files between directories, from ABI 2
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    /// From ABI 3
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    /// Everything that modifies the filesystem; reads are left alone
+    const ACCESS_FS_WRITE: u64 = ACCESS_FS_WRITE_FILE
+        | ACCESS_FS_REMOVE_DIR
+        | ACCESS_FS_REMOVE_FILE
+        | ACCESS_FS_MAKE_CHAR
+        | ACCESS_FS_MAKE_DIR
+        | ACCESS_FS_MAKE_REG
+        | ACCESS_FS_MAKE_SOCK
+        | ACCESS_FS_MAKE_FIFO
+        | ACCESS_FS_MAKE_BLOCK
+        | ACCESS_FS_MAKE_SYM;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Stop this process and its children from gaining privileges,
+    /// which both Landlock and seccomp require of unprivileged users
+    pub fn no_new_privs() -> Result<(), Error> {
+        // SAFETY: prctl with integer arguments only
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn landlock(writable: &[PathBuf]) -> Result<(), Error> {
+        // SAFETY: asking for the ABI version takes no attribute
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0_usize,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 0 {
+            return Err(Error::last_os_error());
+        }
+        // Before ABI 2, moving files between directories is always
+        // denied, which rip copes with as if crossing filesystems
+        let mut handled = ACCESS_FS_WRITE;
+        if abi >= 2 {
+            handled |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= ACCESS_FS_TRUNCATE;
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // SAFETY: `attr` is a valid ruleset attribute of the given size
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0_u32,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        // SAFETY: the kernel just handed us this descriptor
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        for path in writable {
+            let dir = fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+                .open(path)
+                .map_err(|e| {
+                    Error::new(
+                        e.kind(),
+                        format!("Couldn't open {} for the sandbox: {}", path.display(), e),
+                    )
+                })?;
+            let rule = PathBeneathAttr {
+                allowed_access: handled,
+                parent_fd: dir.as_raw_fd(),
+            };
+            // SAFETY: both descriptors are open and `rule` outlives the call
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    RULE_PATH_BENEATH,
+                    &rule as *const PathBeneathAttr,
+                    0_u32,
+                )
+            };
+            if ret != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        // SAFETY: the ruleset descriptor is open
+        if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0_u32) }
+            != 0
+        {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// Syscalls that have nothing to do with moving files around
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_setns,
+        libc::SYS_unshare,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_personality,
+    ];
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn seccomp() -> Result<(), Error> {
+        const LD_W_ABS: u16 = 0x20;
+        const JEQ_K: u16 = 0x15;
+        const JGE_K: u16 = 0x35;
+        const RET_K: u16 = 0x06;
+        const RET_ALLOW: u32 = 0x7fff_0000;
+        // Offsets into struct seccomp_data
+        const NR: u32 = 0;
+        const ARCH: u32 = 4;
+        // Set on syscalls made through the x32 ABI
+        const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+        let stmt = |code, k| libc::sock_filter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        };
+        let jump = |code, k, jt, jf| libc::sock_filter { code, jt, jf, k };
+        let deny = stmt(RET_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+
+        let mut filter = vec![
+            stmt(LD_W_ABS, ARCH),
+            jump(JEQ_K, AUDIT_ARCH, 1, 0),
+            stmt(RET_K, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(LD_W_ABS, NR),
+            jump(JGE_K, X32_SYSCALL_BIT, 0, 1),
+            deny,
+        ];
+        for &nr in DENIED {
+            filter.push(jump(JEQ_K, nr as u32, 0, 1));
+            filter.push(deny);
+        }
+        filter.push(stmt(RET_K, RET_ALLOW));
+
+        let prog = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        // SAFETY: `prog` points at a filter that outlives the call,
+        // which the kernel copies
+        let ret = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn seccomp() -> Result<(), Error> {
+        Err(Error::new(
+            std::io::ErrorKind::Unsupported,
+            "The syscall filter is not available on this architecture",
+        ))
+    }
+}
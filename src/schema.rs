@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+
+/// Version of the serialized form of rip's types, like `RecordItem`,
+/// `LogItem` and `Snapshot`. Bumped whenever a field is renamed or
+/// removed, or changes meaning; new optional fields don't bump it.
+pub const VERSION: u32 = 1;
+
+/// Data tagged with the schema version it was written with, so that
+/// readers can tell whether they understand it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    pub version: u32,
+    pub data: T,
+}
+
+impl<T> Versioned<T> {
+    /// Tag `data` with the current version
+    pub fn new(data: T) -> Versioned<T> {
+        Versioned {
+            version: VERSION,
+            data,
+        }
+    }
+
+    /// The data, if it was written with a version this build of rip
+    /// understands
+    pub fn into_data(self) -> Result<T, Error> {
+        if self.version > VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Data is of schema version {}, but this rip only knows up to {}",
+                    self.version, VERSION
+                ),
+            ));
+        }
+        Ok(self.data)
+    }
+}
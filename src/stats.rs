@@ -0,0 +1,169 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::record::Record;
+use crate::util;
+
+pub const STATS: &str = ".stats";
+const HEADER: &str = "date,graves,bytes";
+/// Width of the longest bar in the history chart
+const CHART_WIDTH: u64 = 40;
+
+/// The size of the graveyard on one day
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub date: NaiveDate,
+    pub graves: u64,
+    pub bytes: u64,
+}
+
+impl Snapshot {
+    /// Parse a line of the stats file
+    pub fn new(line: &str) -> Option<Snapshot> {
+        let mut fields = line.split(',');
+        Some(Snapshot {
+            date: fields.next()?.parse().ok()?,
+            graves: fields.next()?.parse().ok()?,
+            bytes: fields.next()?.parse().ok()?,
+        })
+    }
+
+    /// The graveyard as it is now
+    pub fn take(graveyard: &Path, record: &Record) -> io::Result<Snapshot> {
+        let graves = match record.graves() {
+            Ok(graves) => graves.count() as u64,
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(Snapshot {
+            date: record.now().date_naive(),
+            graves,
+            bytes: fs_extra::dir::get_size(graveyard).unwrap_or(0),
+        })
+    }
+}
+
+/// Daily snapshots of the graveyard's size, kept in a small CSV file in
+/// the graveyard, for seeing how it grows over time
+#[derive(Debug)]
+pub struct Stats {
+    path: PathBuf,
+}
+
+impl Stats {
+    pub fn new(graveyard: &Path) -> Stats {
+        Stats {
+            path: graveyard.join(STATS),
+        }
+    }
+
+    /// Every snapshot, oldest first
+    pub fn snapshots(&self) -> io::Result<Vec<Snapshot>> {
+        let Ok(file) = fs::File::open(&self.path) else {
+            return Ok(Vec::new());
+        };
+        let mut lines = BufReader::new(file).lines();
+        lines.next();
+        Ok(lines
+            .map_while(Result::ok)
+            .filter_map(|line| Snapshot::new(&line))
+            .collect())
+    }
+
+    /// Add a snapshot of the graveyard, unless there is one for today
+    /// already. Returns today's snapshot.
+    pub fn snapshot_daily(&self, graveyard: &Path, record: &Record) -> io::Result<Snapshot> {
+        let today = record.now().date_naive();
+        if let Some(last) = self.snapshots()?.pop().filter(|last| last.date == today) {
+            return Ok(last);
+        }
+        let snapshot = Snapshot::take(graveyard, record)?;
+        let exists = self.path.exists();
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if !exists {
+            writeln!(file, "{}", HEADER)?;
+        }
+        writeln!(
+            file,
+            "{},{},{}",
+            snapshot.date, snapshot.graves, snapshot.bytes
+        )
+        .map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Failed to write stats at {}", self.path.display()),
+            )
+        })?;
+        Ok(snapshot)
+    }
+}
+
+/// Print how many graves there are and how much space they take
+pub fn print_summary(
+    graveyard: &Path,
+    snapshot: &Snapshot,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    writeln!(
+        stream,
+        "{} graves, {} in {}",
+        snapshot.graves,
+        util::humanize_bytes(snapshot.bytes),
+        graveyard.display()
+    )?;
+    Ok(())
+}
+
+/// Chart the size of the graveyard over the snapshots within `days` of
+/// the last one, with a bar for each
+pub fn print_history(
+    snapshots: &[Snapshot],
+    days: u32,
+    stream: &mut impl Write,
+) -> Result<(), Error> {
+    let Some(last) = snapshots.last() else {
+        writeln!(stream, "No history yet")?;
+        return Ok(());
+    };
+    let since = last.date - chrono::Duration::days(days.into());
+    let shown: Vec<&Snapshot> = snapshots.iter().filter(|s| s.date > since).collect();
+    let largest = shown.iter().map(|s| s.bytes).max().unwrap_or(0).max(1);
+
+    writeln!(stream, "{: <10}  {: >6}  {: >10}", "date", "graves", "size")?;
+    for snapshot in &shown {
+        let bar = (snapshot.bytes * CHART_WIDTH).div_ceil(largest) as usize;
+        writeln!(
+            stream,
+            "{}  {: >6}  {: >10}  {}",
+            snapshot.date,
+            snapshot.graves,
+            util::humanize_bytes(snapshot.bytes),
+            "#".repeat(bar)
+        )?;
+    }
+
+    let first = shown[0];
+    let elapsed = (last.date - first.date).num_days();
+    if elapsed > 0 {
+        let change = last.bytes.abs_diff(first.bytes);
+        writeln!(
+            stream,
+            "{} {} over {} days, about {} a day",
+            if last.bytes >= first.bytes {
+                "Grew"
+            } else {
+                "Shrank"
+            },
+            util::humanize_bytes(change),
+            elapsed,
+            util::humanize_bytes(change / elapsed as u64)
+        )?;
+    }
+    Ok(())
+}
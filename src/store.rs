@@ -0,0 +1,778 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, TryLockError};
+use std::io::{self, BufRead, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "sqlite")]
+use crate::meta::FileMeta;
+use crate::record::{self, RecordItem, FORMAT_VERSION, HEADER, RECORD};
+#[cfg(feature = "sqlite")]
+use crate::record::{FileKind, Origin};
+use crate::util::IdGen;
+
+/// Name of the record when it is kept in an SQLite database
+pub const DATABASE: &str = ".record.db";
+
+/// Name of the file that rips writing to the record take turns locking
+pub const LOCK: &str = ".record.lock";
+
+/// How the record is stored in the graveyard
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// A tab-separated file, a line per grave
+    #[default]
+    Tsv,
+    /// An SQLite database, which is safe to write from several rips at
+    /// once and quick to search (needs the `sqlite` feature)
+    Sqlite,
+}
+
+/// Lines of the record, read as they are asked for, so that a record of
+/// any length is gone through in bounded memory
+pub type Lines<'a> = Box<dyn Iterator<Item = io::Result<String>> + 'a>;
+
+/// Where `update` writes each line of the new record
+pub type WriteLine<'a> = dyn FnMut(String) -> io::Result<()> + 'a;
+
+/// Where the lines of the record are kept. Each line is a grave as
+/// written by `RecordItem::to_line`, oldest first, without the header.
+pub trait RecordStore: fmt::Debug {
+    /// Where the record is
+    fn path(&self) -> &Path;
+
+    /// Every line of the record, oldest first
+    fn lines(&self) -> io::Result<Lines<'_>>;
+
+    /// Every line of the record, newest first
+    fn lines_rev(&self) -> io::Result<Lines<'_>>;
+
+    /// Add the line that `make` builds from the last line, or from the
+    /// header if there are none
+    fn append(&self, make: &mut dyn FnMut(&str) -> String) -> io::Result<()>;
+
+    /// Replace the lines with those that `edit` writes, as it reads the
+    /// old ones
+    fn update(
+        &self,
+        edit: &mut dyn FnMut(Lines<'_>, &mut WriteLine<'_>) -> io::Result<()>,
+    ) -> io::Result<()>;
+
+    /// Name temporary files with `ids`
+    fn use_ids(&mut self, _ids: Arc<IdGen>) {}
+
+    /// The graves at any of `dests`, oldest first
+    fn at(&self, dests: &HashSet<PathBuf>) -> io::Result<Vec<RecordItem>> {
+        let mut graves = Vec::new();
+        for line in self.lines()? {
+            graves.extend(RecordItem::new(&line?).filter(|item| dests.contains(&item.dest)));
+        }
+        Ok(graves)
+    }
+
+    /// The graves buried from under the directory whose grave is `gravepath`
+    fn under(&self, gravepath: &Path) -> io::Result<Vec<RecordItem>> {
+        let mut graves = Vec::new();
+        for line in self.lines()? {
+            graves.extend(RecordItem::new(&line?).filter(|item| item.dest.starts_with(gravepath)));
+        }
+        Ok(graves)
+    }
+}
+
+/// Whether `graveyard` has a record, in either store
+pub fn exists(graveyard: &Path) -> bool {
+    graveyard.join(RECORD).exists() || graveyard.join(DATABASE).exists()
+}
+
+/// Open the record of `graveyard`: the database if there is one, and
+/// otherwise the tab-separated file, which is only created once written to
+pub fn open(graveyard: &Path) -> io::Result<Box<dyn RecordStore>> {
+    if graveyard.join(DATABASE).exists() {
+        return open_database(graveyard);
+    }
+    Ok(Box::new(TsvStore::new(graveyard)?))
+}
+
+#[cfg(feature = "sqlite")]
+fn open_database(graveyard: &Path) -> io::Result<Box<dyn RecordStore>> {
+    Ok(Box::new(SqliteStore::open(&graveyard.join(DATABASE))?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_database(graveyard: &Path) -> io::Result<Box<dyn RecordStore>> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        format!(
+            "The record at {} is an SQLite database, but this rip was built \
+             without the sqlite feature",
+            graveyard.join(DATABASE).display()
+        ),
+    ))
+}
+
+/// Move the record of `graveyard` into `backend`, if it isn't there
+/// already. Returns true if it was moved.
+pub fn convert(graveyard: &Path, backend: Backend) -> io::Result<bool> {
+    let database = graveyard.join(DATABASE);
+    match backend {
+        Backend::Sqlite if !database.exists() => {
+            create_database(&database, &TsvStore::new(graveyard)?)?;
+            fs::remove_file(graveyard.join(RECORD)).or_else(|e| match e.kind() {
+                ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            })?;
+            Ok(true)
+        }
+        Backend::Tsv if database.exists() => {
+            let from = open_database(graveyard)?;
+            TsvStore::new(graveyard)?.update(&mut |_, write| copy_lines(&*from, write))?;
+            // Closed before its files go
+            drop(from);
+            for suffix in ["", "-wal", "-shm"] {
+                let mut path = database.clone().into_os_string();
+                path.push(suffix);
+                fs::remove_file(path).or_else(|e| match e.kind() {
+                    ErrorKind::NotFound => Ok(()),
+                    _ => Err(e),
+                })?;
+            }
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Write every line of `from` with `write`
+fn copy_lines(from: &dyn RecordStore, write: &mut WriteLine<'_>) -> io::Result<()> {
+    from.lines()?.try_for_each(|line| write(line?))
+}
+
+#[cfg(feature = "sqlite")]
+fn create_database(database: &Path, from: &TsvStore) -> io::Result<()> {
+    SqliteStore::open(database)?.update(&mut |_, write| copy_lines(from, write))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn create_database(_: &Path, _: &TsvStore) -> io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "This rip was built without the sqlite feature, so can't keep the record in SQLite",
+    ))
+}
+
+/// The record as a tab-separated file with a header. Until something is
+/// buried there is no file, and the record reads as empty, so that merely
+/// looking at a graveyard never creates anything.
+#[derive(Debug)]
+pub struct TsvStore {
+    path: PathBuf,
+    ids: Arc<IdGen>,
+}
+
+impl TsvStore {
+    pub fn new(graveyard: &Path) -> io::Result<TsvStore> {
+        Ok(TsvStore {
+            path: graveyard.join(RECORD),
+            ids: Arc::new(IdGen::default()),
+        })
+    }
+
+    /// The record file, or `None` if nothing has been buried yet
+    fn open(&self) -> io::Result<Option<fs::File>> {
+        match fs::File::open(&self.path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            file => file.map(Some),
+        }
+    }
+
+    /// Wait for other rips to finish writing to the record, then keep them
+    /// from reading or writing it until the returned file is dropped. The
+    /// record itself is replaced on update, so a file beside it is locked
+    /// instead.
+    fn lock(&self) -> io::Result<fs::File> {
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.with_file_name(LOCK))?;
+        wait_for_lock(lock, true, &self.path)
+    }
+
+    /// Wait for other rips to finish writing to the record, then keep them
+    /// from writing it, but not from reading it, until the returned file is
+    /// dropped. Records older than the lock have nothing to lock, and are
+    /// read without.
+    fn lock_shared(&self) -> io::Result<Option<fs::File>> {
+        match fs::File::open(self.path.with_file_name(LOCK)) {
+            Ok(lock) => wait_for_lock(lock, false, &self.path).map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the header, returning the version of the format of the lines
+    /// after it, or `None` if there is no header, as in the original rip's
+    /// records. Records in a newer format than this rip knows can't be read.
+    fn read_header(&self, reader: &mut impl BufRead) -> io::Result<Option<u32>> {
+        let mut first = Vec::new();
+        reader.read_until(b'\n', &mut first)?;
+        if first.last() == Some(&b'\n') {
+            first.pop();
+        }
+        let version = record::format_version(&decode(first));
+        if let Some(version) = version.filter(|&version| version > FORMAT_VERSION) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "The record at {} is in version {} of its format, but this rip only \
+                     knows up to version {}; upgrade rip to use it",
+                    self.path.display(),
+                    version,
+                    FORMAT_VERSION
+                ),
+            ));
+        }
+        Ok(version)
+    }
+
+    /// The version of the record's format, which is the current one until
+    /// something is buried
+    fn version(&self) -> io::Result<Option<u32>> {
+        match self.open()? {
+            Some(file) => self.read_header(&mut io::BufReader::new(file)),
+            None => Ok(Some(FORMAT_VERSION)),
+        }
+    }
+
+    /// The lines after the header, oldest first, for when the lock is held
+    fn read_lines(&self) -> io::Result<Lines<'_>> {
+        let Some(file) = self.open()? else {
+            return Ok(Box::new(std::iter::empty()));
+        };
+        let mut reader = io::BufReader::new(file);
+        // The original rip's lines are only read to be migrated
+        let version = self.read_header(&mut reader)?.unwrap_or(1);
+        Ok(Box::new(reader.split(b'\n').map(move |line| {
+            line.map(|line| record::upgrade_line(version, decode(line)))
+        })))
+    }
+
+    /// The lines after the header, newest first, for when the lock is held
+    fn read_lines_rev(&self) -> io::Result<Lines<'_>> {
+        let Some(file) = self.open()? else {
+            return Ok(Box::new(std::iter::empty()));
+        };
+        let mut reader = io::BufReader::new(file);
+        // The original rip's lines are only read to be migrated
+        let version = self.read_header(&mut reader)?.unwrap_or(1);
+        Ok(Box::new(RevLines::new(reader.into_inner())?.map(
+            move |line| line.map(|line| record::upgrade_line(version, line)),
+        )))
+    }
+
+    /// Write the header and then the lines `edit` writes to `tmp`, with the
+    /// permissions of the record, and flush them to disk
+    fn write_to(
+        &self,
+        tmp: &Path,
+        edit: &mut dyn FnMut(Lines<'_>, &mut WriteLine<'_>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut record_file = io::BufWriter::new(fs::File::create(tmp)?);
+        writeln!(record_file, "{}", record::header())?;
+        edit(self.read_lines()?, &mut |line| {
+            writeln!(record_file, "{}", line)
+        })?;
+        let record_file = record_file.into_inner().map_err(|e| e.into_error())?;
+        if let Ok(metadata) = fs::metadata(&self.path) {
+            record_file.set_permissions(metadata.permissions())?;
+        }
+        record_file.sync_all()
+    }
+
+    /// Like `update`, for when the lock is held. The new record is written
+    /// beside the old one and renamed over it, so that a crash leaves one
+    /// or the other, never half of either.
+    fn rewrite(
+        &self,
+        edit: &mut dyn FnMut(Lines<'_>, &mut WriteLine<'_>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let tmp = self
+            .path
+            .with_file_name(format!("{}.{}.tmp", RECORD, self.ids.next_id()));
+        let written = self
+            .write_to(&tmp, edit)
+            .and_then(|()| fs::rename(&tmp, &self.path));
+        if written.is_err() {
+            fs::remove_file(&tmp).ok();
+        }
+        written
+    }
+}
+
+impl RecordStore for TsvStore {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn lines(&self) -> io::Result<Lines<'_>> {
+        let lock = self.lock_shared()?;
+        Ok(Box::new(Locked::new(self.read_lines()?, lock)))
+    }
+
+    fn lines_rev(&self) -> io::Result<Lines<'_>> {
+        let lock = self.lock_shared()?;
+        Ok(Box::new(Locked::new(self.read_lines_rev()?, lock)))
+    }
+
+    /// A record in an older format is brought up to date first, except for
+    /// the original rip's, which `rip migrate` converts
+    fn append(&self, make: &mut dyn FnMut(&str) -> String) -> io::Result<()> {
+        let _lock = self.lock()?;
+        if self
+            .version()?
+            .is_some_and(|version| version < FORMAT_VERSION)
+        {
+            self.rewrite(&mut |mut lines, write| lines.try_for_each(|line| write(line?)))?;
+        }
+        let last = self.read_lines_rev()?.next().transpose()?;
+        let line = make(last.as_deref().unwrap_or(HEADER));
+        let mut record_file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if record_file.metadata()?.len() == 0 {
+            writeln!(record_file, "{}", record::header())?;
+        }
+        writeln!(record_file, "{}", line)
+    }
+
+    fn update(
+        &self,
+        edit: &mut dyn FnMut(Lines<'_>, &mut WriteLine<'_>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let _lock = self.lock()?;
+        self.rewrite(edit)
+    }
+
+    fn use_ids(&mut self, ids: Arc<IdGen>) {
+        self.ids = ids;
+    }
+}
+
+/// How long to wait for other rips to be done with the record
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lock `lock`, exclusively or shared, trying again after longer and
+/// longer waits while another rip holds it, until `LOCK_TIMEOUT`
+fn wait_for_lock(lock: fs::File, exclusive: bool, record: &Path) -> io::Result<fs::File> {
+    let start = Instant::now();
+    let mut wait = Duration::from_millis(5);
+    loop {
+        let locked = match exclusive {
+            true => lock.try_lock(),
+            false => lock.try_lock_shared(),
+        };
+        match locked {
+            Ok(()) => return Ok(lock),
+            Err(TryLockError::WouldBlock) if start.elapsed() < LOCK_TIMEOUT => {
+                thread::sleep(wait);
+                wait = (wait * 2).min(Duration::from_millis(250));
+            }
+            Err(TryLockError::WouldBlock) => {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "The record at {} has been locked by another rip for {}s; \
+                         try again once it's done",
+                        record.display(),
+                        LOCK_TIMEOUT.as_secs()
+                    ),
+                ))
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+    }
+}
+
+/// Lines of the record, read under a lock that is held until they're
+/// dropped
+struct Locked<'a> {
+    lines: Lines<'a>,
+    _lock: Option<fs::File>,
+}
+
+impl<'a> Locked<'a> {
+    fn new(lines: Lines<'a>, lock: Option<fs::File>) -> Locked<'a> {
+        Locked { lines, _lock: lock }
+    }
+}
+
+impl Iterator for Locked<'_> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.lines.next()
+    }
+}
+
+/// A line of the record file without its line ending. Paths are written
+/// as text, so anything else is replaced rather than failing the read.
+fn decode(mut line: Vec<u8>) -> String {
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+/// The lines of a file after the first, read backwards a block at a time
+struct RevLines {
+    file: fs::File,
+    /// Where in the file the bytes not yet read end
+    pos: u64,
+    /// Bytes read but not yet returned, starting partway through a line
+    tail: Vec<u8>,
+}
+
+impl RevLines {
+    const BLOCK: u64 = 64 * 1024;
+
+    fn new(mut file: fs::File) -> io::Result<RevLines> {
+        let mut pos = file.metadata()?.len();
+        // The last line ends with a newline, which doesn't start another
+        if pos > 0 {
+            let mut last = [0];
+            file.seek(SeekFrom::Start(pos - 1))?;
+            file.read_exact(&mut last)?;
+            if last[0] == b'\n' {
+                pos -= 1;
+            }
+        }
+        Ok(RevLines {
+            file,
+            pos,
+            tail: Vec::new(),
+        })
+    }
+
+    /// Read the block before what has been read so far
+    fn read_block(&mut self) -> io::Result<()> {
+        let len = self.pos.min(Self::BLOCK);
+        let mut block = vec![0; len as usize];
+        self.file.seek(SeekFrom::Start(self.pos - len))?;
+        self.file.read_exact(&mut block)?;
+        self.pos -= len;
+        block.append(&mut self.tail);
+        self.tail = block;
+        Ok(())
+    }
+}
+
+impl Iterator for RevLines {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        loop {
+            if let Some(newline) = self.tail.iter().rposition(|&byte| byte == b'\n') {
+                let line = self.tail.split_off(newline + 1);
+                self.tail.truncate(newline);
+                return Some(Ok(decode(line)));
+            }
+            // What's left is the first line, the header
+            if self.pos == 0 {
+                return None;
+            }
+            if let Err(e) = self.read_block() {
+                self.pos = 0;
+                self.tail.clear();
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// The record as an SQLite database, a row per grave. Every change is a
+/// transaction, so rips running at once can't corrupt it, and graves are
+/// indexed by path and time.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+pub struct SqliteStore {
+    path: PathBuf,
+    conn: rusqlite::Connection,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    const COLUMNS: &'static str =
+        "time, orig, dest, grave_id, note, status, meta, checksum, prev, user, host, cwd, kind, op";
+
+    pub fn open(path: &Path) -> io::Result<SqliteStore> {
+        let conn = rusqlite::Connection::open(path).map_err(sql_error)?;
+        conn.busy_timeout(std::time::Duration::from_secs(10))
+            .map_err(sql_error)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS graves (
+                 id INTEGER PRIMARY KEY,
+                 time TEXT NOT NULL,
+                 orig TEXT NOT NULL,
+                 dest TEXT NOT NULL,
+                 grave_id TEXT,
+                 note TEXT,
+                 status TEXT,
+                 meta TEXT,
+                 checksum TEXT,
+                 prev TEXT,
+                 user TEXT,
+                 host TEXT,
+                 cwd TEXT,
+                 kind TEXT,
+                 op TEXT
+             );
+             CREATE INDEX IF NOT EXISTS graves_dest ON graves (dest);
+             CREATE INDEX IF NOT EXISTS graves_orig ON graves (orig);
+             CREATE INDEX IF NOT EXISTS graves_time ON graves (time);",
+        )
+        .map_err(sql_error)?;
+        // Databases from before graves had IDs, origins, kinds or operations
+        for column in ["grave_id", "user", "host", "cwd", "kind", "op"] {
+            let has_column: bool = conn
+                .query_row(
+                    "SELECT COUNT(*) > 0 FROM pragma_table_info('graves') WHERE name = ?1",
+                    [column],
+                    |row| row.get(0),
+                )
+                .map_err(sql_error)?;
+            if !has_column {
+                conn.execute(
+                    &format!("ALTER TABLE graves ADD COLUMN {} TEXT", column),
+                    [],
+                )
+                .map_err(sql_error)?;
+            }
+        }
+        Ok(SqliteStore {
+            path: path.to_path_buf(),
+            conn,
+        })
+    }
+
+    /// Rows read at a time when going through the whole record
+    const PAGE: i64 = 1000;
+
+    /// The grave in `COLUMNS` of `row`, starting at column `first`
+    fn item(row: &rusqlite::Row, first: usize) -> rusqlite::Result<RecordItem> {
+        Ok(RecordItem {
+            time: row.get(first)?,
+            orig: PathBuf::from(row.get::<_, String>(first + 1)?),
+            dest: PathBuf::from(row.get::<_, String>(first + 2)?),
+            id: row.get(first + 3)?,
+            note: row.get(first + 4)?,
+            status: row.get(first + 5)?,
+            meta: row
+                .get::<_, Option<String>>(first + 6)?
+                .and_then(|meta| FileMeta::parse(&meta)),
+            kind: row
+                .get::<_, Option<String>>(first + 12)?
+                .and_then(|kind| FileKind::parse(&kind)),
+            checksum: row.get(first + 7)?,
+            op: row.get(first + 13)?,
+            origin: match row.get::<_, Option<String>>(first + 11)? {
+                Some(cwd) => Some(Origin {
+                    user: row.get::<_, Option<String>>(first + 9)?.unwrap_or_default(),
+                    host: row
+                        .get::<_, Option<String>>(first + 10)?
+                        .unwrap_or_default(),
+                    cwd: PathBuf::from(cwd),
+                }),
+                None => None,
+            },
+            prev: row.get(first + 8)?,
+        })
+    }
+
+    fn query(&self, sql: &str, params: impl rusqlite::Params) -> io::Result<Vec<RecordItem>> {
+        let mut statement = self.conn.prepare(sql).map_err(sql_error)?;
+        let rows = statement
+            .query_map(params, |row| SqliteStore::item(row, 0))
+            .map_err(sql_error)?;
+        rows.collect::<Result<_, _>>().map_err(sql_error)
+    }
+
+    /// The last row there is now
+    fn last_row(&self) -> io::Result<i64> {
+        self.conn
+            .query_row("SELECT COALESCE(MAX(id), 0) FROM graves", [], |row| {
+                row.get(0)
+            })
+            .map_err(sql_error)
+    }
+
+    /// The lines of the rows up to `last`, a page at a time, oldest first
+    /// or newest first
+    fn rows(&self, last: i64, newest_first: bool) -> Lines<'_> {
+        let sql = match newest_first {
+            true => "WHERE id < ?1 AND id <= ?2 ORDER BY id DESC",
+            false => "WHERE id > ?1 AND id <= ?2 ORDER BY id",
+        };
+        let sql = format!(
+            "SELECT id, {} FROM graves {} LIMIT {}",
+            Self::COLUMNS,
+            sql,
+            Self::PAGE
+        );
+        let mut next = match newest_first {
+            true => last + 1,
+            false => 0,
+        };
+        let mut page = std::collections::VecDeque::new();
+        let mut done = false;
+        Box::new(std::iter::from_fn(move || {
+            if page.is_empty() && !done {
+                let rows = self.conn.prepare_cached(&sql).and_then(|mut statement| {
+                    statement
+                        .query_map([next, last], |row| {
+                            Ok((row.get::<_, i64>(0)?, SqliteStore::item(row, 1)?))
+                        })?
+                        .collect::<rusqlite::Result<Vec<_>>>()
+                });
+                match rows {
+                    Ok(rows) => {
+                        done = (rows.len() as i64) < Self::PAGE;
+                        page.extend(rows);
+                    }
+                    Err(e) => {
+                        done = true;
+                        return Some(Err(sql_error(e)));
+                    }
+                }
+            }
+            let (id, item) = page.pop_front()?;
+            next = id;
+            Some(Ok(item.to_line()))
+        }))
+    }
+
+    fn insert(conn: &rusqlite::Connection, line: &str) -> rusqlite::Result<()> {
+        // Lines that aren't graves have no row to go in
+        let Some(item) = RecordItem::new(line) else {
+            return Ok(());
+        };
+        conn.execute(
+            &format!(
+                "INSERT INTO graves ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+                Self::COLUMNS
+            ),
+            rusqlite::params![
+                item.time,
+                item.orig.display().to_string(),
+                item.dest.display().to_string(),
+                item.id,
+                item.note,
+                item.status,
+                item.meta.map(|meta| meta.to_field()),
+                item.checksum,
+                item.prev,
+                item.origin.as_ref().map(|origin| &origin.user),
+                item.origin.as_ref().map(|origin| &origin.host),
+                item.origin.as_ref().map(|origin| origin.cwd.display().to_string()),
+                item.kind.map(FileKind::as_str),
+                item.op,
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl RecordStore for SqliteStore {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn lines(&self) -> io::Result<Lines<'_>> {
+        Ok(self.rows(self.last_row()?, false))
+    }
+
+    fn lines_rev(&self) -> io::Result<Lines<'_>> {
+        Ok(self.rows(self.last_row()?, true))
+    }
+
+    fn append(&self, make: &mut dyn FnMut(&str) -> String) -> io::Result<()> {
+        let transaction = rusqlite::Transaction::new_unchecked(
+            &self.conn,
+            rusqlite::TransactionBehavior::Immediate,
+        )
+        .map_err(sql_error)?;
+        let last = self.lines_rev()?.next().transpose()?;
+        let line = make(last.as_deref().unwrap_or(HEADER));
+        SqliteStore::insert(&transaction, &line).map_err(sql_error)?;
+        transaction.commit().map_err(sql_error)
+    }
+
+    /// The new rows go in after the old ones, which are read up to where
+    /// they ended and then deleted
+    fn update(
+        &self,
+        edit: &mut dyn FnMut(Lines<'_>, &mut WriteLine<'_>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let transaction = rusqlite::Transaction::new_unchecked(
+            &self.conn,
+            rusqlite::TransactionBehavior::Immediate,
+        )
+        .map_err(sql_error)?;
+        let last = self.last_row()?;
+        edit(self.rows(last, false), &mut |line| {
+            SqliteStore::insert(&transaction, &line).map_err(sql_error)
+        })?;
+        transaction
+            .execute("DELETE FROM graves WHERE id <= ?1", [last])
+            .map_err(sql_error)?;
+        transaction.commit().map_err(sql_error)
+    }
+
+    fn at(&self, dests: &HashSet<PathBuf>) -> io::Result<Vec<RecordItem>> {
+        let sql = format!("SELECT id, {} FROM graves WHERE dest = ?1", Self::COLUMNS);
+        let mut statement = self.conn.prepare_cached(&sql).map_err(sql_error)?;
+        let mut rows = Vec::new();
+        for dest in dests {
+            let found = statement
+                .query_map([dest.display().to_string()], |row| {
+                    Ok((row.get::<_, i64>(0)?, SqliteStore::item(row, 1)?))
+                })
+                .and_then(|found| found.collect::<rusqlite::Result<Vec<_>>>())
+                .map_err(sql_error)?;
+            rows.extend(found);
+        }
+        rows.sort_by_key(|(id, _)| *id);
+        Ok(rows.into_iter().map(|(_, item)| item).collect())
+    }
+
+    fn under(&self, gravepath: &Path) -> io::Result<Vec<RecordItem>> {
+        // Everything from `gravepath/` up to, but not including, the next
+        // character after the separator, which the index can find quickly
+        let gravepath = gravepath.display().to_string();
+        let separator = std::path::MAIN_SEPARATOR;
+        let after = char::from(separator as u8 + 1);
+        let sql = format!(
+            "SELECT {} FROM graves WHERE dest = ?1 OR (dest >= ?2 AND dest < ?3) ORDER BY id",
+            Self::COLUMNS
+        );
+        self.query(
+            &sql,
+            [
+                gravepath.clone(),
+                format!("{}{}", gravepath.trim_end_matches(separator), separator),
+                format!("{}{}", gravepath.trim_end_matches(separator), after),
+            ],
+        )
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn sql_error(e: rusqlite::Error) -> Error {
+    Error::other(format!("Record database error: {}", e))
+}
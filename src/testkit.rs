@@ -0,0 +1,181 @@
+//! Helpers for testing tools that embed rip2, against a graveyard in a
+//! temporary directory that is removed afterwards. Enabled by the
+//! `testkit` feature.
+//!
+//! ```
+//! use rip2::testkit::TestEnv;
+//!
+//! let env = TestEnv::new();
+//! env.file("notes.txt", "draft");
+//! env.run(&["notes.txt"]).unwrap();
+//! env.assert_buried("notes.txt");
+//!
+//! // Decline to delete the grave for good
+//! let grave = env.grave_of("notes.txt");
+//! let output = env.run_answering(&[grave.to_str().unwrap()], &["n"]).unwrap();
+//! assert!(output.contains("Skipping"));
+//! ```
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use tempfile::TempDir;
+
+use crate::args::Args;
+use crate::context::Context;
+use crate::record::{Record, RecordItem};
+use crate::util::{self, TestingMode};
+
+/// A source directory to bury files from, and a graveyard beside it, both
+/// in a temporary directory. The graveyard isn't created until something
+/// is buried, as with a real one.
+pub struct TestEnv {
+    _dir: TempDir,
+    /// Where `rip` is run from, and relative paths are resolved against
+    pub src: PathBuf,
+    pub graveyard: PathBuf,
+}
+
+impl Default for TestEnv {
+    fn default() -> TestEnv {
+        TestEnv::new()
+    }
+}
+
+impl TestEnv {
+    /// Panics if the temporary directory can't be made
+    pub fn new() -> TestEnv {
+        let dir = tempfile::tempdir().expect("Failed to create a temporary directory");
+        // Canonical, as graves are made from canonical paths
+        let root =
+            dunce::canonicalize(dir.path()).expect("Failed to resolve the temporary directory");
+        let src = root.join("src");
+        fs::create_dir(&src).expect("Failed to create the source directory");
+        TestEnv {
+            _dir: dir,
+            src,
+            graveyard: root.join("graveyard"),
+        }
+    }
+
+    /// Write `contents` to `path` under `src`, making the directories
+    /// above it, and return the full path
+    pub fn file(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.src.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).expect("Failed to create directories");
+        }
+        fs::write(&path, contents).expect("Failed to write file");
+        path
+    }
+
+    /// A context based at `src`, using the graveyard, with no other
+    /// environment or config, so the user's own settings don't apply, and
+    /// seeded, so temporary files get the same names on every run
+    pub fn context(&self) -> Context {
+        Context::new(&self.src)
+            .with_var("RIP_GRAVEYARD", self.graveyard.display().to_string())
+            .with_seed(0)
+    }
+
+    /// Run `rip` with `args`, returning what it printed. Prompts are
+    /// answered yes, except for paths, which are left unanswered.
+    pub fn run(&self, args: &[&str]) -> Result<String, Error> {
+        self.run_answering(args, &[])
+    }
+
+    /// Like `run`, answering prompts in turn with `answers`, like `n` or
+    /// `yes`, as if typed. Any prompts after those are answered as by `run`.
+    pub fn run_answering(&self, args: &[&str], answers: &[&str]) -> Result<String, Error> {
+        let argv = std::iter::once(OsString::from("rip")).chain(args.iter().map(OsString::from));
+        let cli = Args::try_parse_from(argv)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        let mut output = Vec::new();
+        crate::run_with(cli, &self.context(), Answers::new(answers), &mut output)?;
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    /// The record of the graveyard
+    pub fn record(&self) -> Record {
+        Record::new(&self.graveyard)
+    }
+
+    /// Every grave in the record, oldest first
+    pub fn graves(&self) -> Vec<RecordItem> {
+        self.record()
+            .graves()
+            .map(|graves| graves.collect())
+            .unwrap_or_default()
+    }
+
+    /// Where `path`, relative to `src`, is buried when it is the first
+    /// with its name
+    pub fn grave_of(&self, path: impl AsRef<Path>) -> PathBuf {
+        util::join_absolute(&self.graveyard, self.src.join(path))
+    }
+
+    /// Panics unless `path`, relative to `src`, is gone, and its latest
+    /// grave is in the graveyard and the record
+    #[track_caller]
+    pub fn assert_buried(&self, path: impl AsRef<Path>) {
+        let orig = self.src.join(path);
+        assert!(
+            !util::symlink_exists(&orig),
+            "{} is still there",
+            orig.display()
+        );
+        let Some(grave) = self
+            .graves()
+            .into_iter()
+            .rev()
+            .find(|item| item.orig == orig)
+        else {
+            panic!("{} isn't in the record", orig.display());
+        };
+        assert!(
+            util::symlink_exists(&grave.dest),
+            "The grave of {} is in the record, but not at {}",
+            orig.display(),
+            grave.dest.display()
+        );
+    }
+
+    /// Panics unless `path`, relative to `src`, is there and has no grave
+    /// in the record
+    #[track_caller]
+    pub fn assert_not_buried(&self, path: impl AsRef<Path>) {
+        let orig = self.src.join(path);
+        assert!(util::symlink_exists(&orig), "{} is gone", orig.display());
+        assert!(
+            !self.graves().iter().any(|item| item.orig == orig),
+            "{} is in the record",
+            orig.display()
+        );
+    }
+}
+
+/// Scripted answers to prompts, given in turn
+pub struct Answers(RefCell<VecDeque<String>>);
+
+impl Answers {
+    pub fn new(answers: &[&str]) -> Answers {
+        Answers(RefCell::new(
+            answers.iter().map(|answer| answer.to_string()).collect(),
+        ))
+    }
+}
+
+impl TestingMode for Answers {
+    fn is_test(&self) -> bool {
+        true
+    }
+
+    fn scripted_answer(&self) -> Option<String> {
+        self.0.borrow_mut().pop_front()
+    }
+}
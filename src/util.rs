@@ -1,41 +1,42 @@
-use std::collections::hash_map::DefaultHasher;
-use std::env;
+use std::collections::HashSet;
+use std::ffi::OsString;
 use std::fs;
-use std::hash::{Hash, Hasher};
-use std::io::{self, BufReader, Error, Read, Write};
-use std::path::Prefix::Disk;
-use std::path::{Component, Path, PathBuf};
-use std::str::from_utf8;
+use std::io::{self, BufRead, BufReader, Error, Read, Write};
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-fn hash_component(c: &Component) -> String {
-    let mut hasher = DefaultHasher::new();
-    c.hash(&mut hasher);
-    format!("{:x}", hasher.finish())
-}
-fn str_component(c: &Component) -> String {
-    match c {
-        Component::Prefix(prefix) => match prefix.kind() {
-            // C:\\ is the most common, so we just make a readable name for it.
-            Disk(disk) => format!("DISK_{}", from_utf8(&[disk]).unwrap()),
-            _ => hash_component(c),
-        },
-        _ => hash_component(c),
+use crate::context::Context;
+use crate::store::Backend;
+use walkdir::WalkDir;
+
+/// Directory under the graveyard that mirrors paths starting with `prefix`.
+/// Drives map to `DISK_C`, and network shares to `UNC/server/share`, with
+/// verbatim (`\\?\`) forms mapping to the same place as the plain ones so
+/// that a file has one grave however its path was spelled.
+fn prefix_dir(prefix: Prefix) -> PathBuf {
+    match prefix {
+        Prefix::Disk(disk) | Prefix::VerbatimDisk(disk) => {
+            PathBuf::from(format!("DISK_{}", char::from(disk).to_ascii_uppercase()))
+        }
+        Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+            Path::new("UNC").join(server).join(share)
+        }
+        Prefix::DeviceNS(device) => Path::new("DEVICE").join(device),
+        Prefix::Verbatim(name) => Path::new("VERBATIM").join(name),
     }
 }
 
 /// Concatenate two paths, even if the right argument is an absolute path.
+/// On Windows, the prefix of the right argument becomes a directory (see
+/// `prefix_dir`), which `original_of_grave` reverses.
 pub fn join_absolute<A: AsRef<Path>, B: AsRef<Path>>(left: A, right: B) -> PathBuf {
     let (left, right) = (left.as_ref(), right.as_ref());
     let mut result = left.to_path_buf();
     for c in right.components() {
         match c {
             Component::RootDir => {}
-            Component::Prefix(_) => {
-                // Hash the prefix component.
-                // We do this because there are many ways to get prefix components
-                // on Windows, so its safer to simply hash it.
-                result.push(str_component(&c));
-            }
+            Component::Prefix(prefix) => result.push(prefix_dir(prefix.kind())),
             _ => {
                 result.push(c);
             }
@@ -44,24 +45,621 @@ pub fn join_absolute<A: AsRef<Path>, B: AsRef<Path>>(left: A, right: B) -> PathB
     result
 }
 
+/// The absolute path that `grave` mirrors under `graveyard`, the reverse
+/// of `join_absolute`. Returns `None` if `grave` isn't in the graveyard.
+pub fn original_of_grave(graveyard: &Path, grave: &Path) -> Option<PathBuf> {
+    let inner = grave.strip_prefix(graveyard).ok()?;
+    #[cfg(windows)]
+    {
+        let mut components = inner.components();
+        let mut next = || {
+            components
+                .next()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        };
+        let first = next()?;
+        let root = if let Some(disk) = first.strip_prefix("DISK_") {
+            format!("{}:\\", disk)
+        } else {
+            match first.as_str() {
+                "UNC" => format!("\\\\{}\\{}\\", next()?, next()?),
+                "DEVICE" => format!("\\\\.\\{}\\", next()?),
+                "VERBATIM" => format!("\\\\?\\{}\\", next()?),
+                _ => return None,
+            }
+        };
+        Some(PathBuf::from(root).join(components.as_path()))
+    }
+    #[cfg(not(windows))]
+    {
+        Some(Path::new("/").join(inner))
+    }
+}
+
+/// How the paths of buried files are written to the record
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PathStyle {
+    /// Resolve symlinked directories, so that each file has one path
+    #[default]
+    Resolved,
+    /// Keep paths as they were typed, through symlinked directories
+    Logical,
+}
+
+/// Select the path style from the flag, falling back to `RIP_PATH_STYLE`
+pub fn get_path_style(style: Option<PathStyle>, ctx: &Context) -> Result<PathStyle, Error> {
+    flag_or_env(style, "RIP_PATH_STYLE", ctx)
+}
+
+/// The store the record should be kept in, from `RIP_RECORD_BACKEND`,
+/// or `None` to keep it wherever it already is
+pub fn get_record_backend(ctx: &Context) -> Result<Option<Backend>, Error> {
+    ctx.setting("RIP_RECORD_BACKEND")
+        .map(|_| flag_or_env(None, "RIP_RECORD_BACKEND", ctx))
+        .transpose()
+}
+
+/// The current directory. If `style` is logical, this is the path the
+/// shell used to get there (`$PWD`), if it still leads here.
+pub fn current_dir(style: PathStyle, ctx: &Context) -> Result<PathBuf, Error> {
+    let physical = ctx.cwd.clone();
+    if style == PathStyle::Logical {
+        if let Some(pwd) = ctx.var("PWD").map(PathBuf::from) {
+            if pwd.is_absolute() && same_file(&pwd, &physical) {
+                return Ok(normalize_path(&pwd));
+            }
+        }
+    }
+    Ok(physical)
+}
+
+/// The absolute path of `target` relative to `cwd`, in the given style
+pub fn absolute_path(cwd: &Path, target: &Path, style: PathStyle) -> Result<PathBuf, Error> {
+    match style {
+        PathStyle::Resolved => dunce::canonicalize(cwd.join(target)),
+        PathStyle::Logical => {
+            let path = normalize_path(&cwd.join(target));
+            fs::symlink_metadata(&path)?;
+            Ok(path)
+        }
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (fs::metadata(a), fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        matches!(
+            (dunce::canonicalize(a), dunce::canonicalize(b)),
+            (Ok(a), Ok(b)) if a == b
+        )
+    }
+}
+
+/// Resolve `.` and `..` components of an absolute path without
+/// touching the filesystem
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+/// The relative path that leads from the directory `from` to `to`,
+/// where both are absolute and normalized
+pub fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut result: PathBuf = from[common..]
+        .iter()
+        .map(|_| Component::ParentDir)
+        .collect();
+    result.extend(&to[common..]);
+    if result.as_os_str().is_empty() {
+        result.push(Component::CurDir);
+    }
+    result
+}
+
+/// Check that writing to `path` can't escape `root`, either through `..`
+/// components or through symlinks at or below `root`.
+pub fn ensure_within(root: &Path, path: &Path) -> Result<(), Error> {
+    let escape = || {
+        Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Refusing to write {} outside of {}",
+                path.display(),
+                root.display()
+            ),
+        )
+    };
+    let inner = path.strip_prefix(root).map_err(|_| escape())?;
+    let mut current = root.to_path_buf();
+    for c in inner.components() {
+        if !matches!(c, Component::Normal(_)) {
+            return Err(escape());
+        }
+        current.push(c);
+        if fs::symlink_metadata(&current).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(escape());
+        }
+    }
+    Ok(())
+}
+
+/// Directories known to exist, so that burying many files from the
+/// same directory doesn't re-stat their shared parents.
+#[derive(Debug, Default)]
+pub struct DirCache(HashSet<PathBuf>);
+
+/// Permissions given to files and directories created in the graveyard
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PermissionPolicy {
+    /// Mirror the mode (and ownership, if possible) of the source
+    #[default]
+    Mirror,
+    /// Restrict everything to the owner
+    Private,
+    /// Use the default permissions for new files under the umask
+    Umask,
+}
+
+impl PermissionPolicy {
+    /// Mode for a directory created to hold the contents of one with `source_mode`.
+    /// The owner always keeps full access so that the directory can be filled.
+    #[cfg(unix)]
+    fn dir_mode(&self, source_mode: Option<u32>) -> Option<u32> {
+        match self {
+            PermissionPolicy::Mirror => source_mode.map(|mode| (mode & 0o7777) | 0o700),
+            PermissionPolicy::Private => Some(0o700),
+            PermissionPolicy::Umask => None,
+        }
+    }
+
+    /// Mode for a file copied from one with `source_mode`
+    #[cfg(unix)]
+    fn file_mode(&self, source_mode: u32) -> Option<u32> {
+        match self {
+            PermissionPolicy::Mirror => None,
+            PermissionPolicy::Private => Some(source_mode & 0o700),
+            PermissionPolicy::Umask => {
+                let base = if source_mode & 0o111 != 0 {
+                    0o777
+                } else {
+                    0o666
+                };
+                Some(base & !umask())
+            }
+        }
+    }
+}
+
+/// Select the permission policy from the flag, falling back to `RIP_GRAVE_PERMS`
+pub fn get_permission_policy(
+    policy: Option<PermissionPolicy>,
+    ctx: &Context,
+) -> Result<PermissionPolicy, Error> {
+    flag_or_env(policy, "RIP_GRAVE_PERMS", ctx)
+}
+
+/// How graves are protected from modification while in the graveyard
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protection {
+    /// Leave graves writable
+    #[default]
+    Off,
+    /// Remove write permission from graves
+    ReadOnly,
+    /// Also set the immutable flag, where the platform and privileges allow
+    Immutable,
+}
+
+/// Select grave protection from the flag, falling back to `RIP_PROTECT`
+pub fn get_protection(protection: Option<Protection>, ctx: &Context) -> Result<Protection, Error> {
+    flag_or_env(protection, "RIP_PROTECT", ctx)
+}
+
+/// Protect everything in the grave at `path` from modification.
+/// Contents are protected before the directories holding them. Files with
+/// other hard links are left alone, as protecting them would protect the
+/// links outside the graveyard too. Returns false if the grave is only
+/// read-only because the immutable flag couldn't be set.
+pub fn protect(path: &Path, protection: Protection) -> Result<bool, Error> {
+    if protection == Protection::Off {
+        return Ok(true);
+    }
+    let mut complete = true;
+    let entries = WalkDir::new(path)
+        .follow_links(false)
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    for entry in entries.into_iter().rev() {
+        if entry.file_type().is_symlink() {
+            continue;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{MetadataExt, PermissionsExt};
+            let metadata = entry.metadata()?;
+            if !metadata.is_dir() && metadata.nlink() > 1 {
+                continue;
+            }
+            let mode = metadata.permissions().mode();
+            fs::set_permissions(entry.path(), fs::Permissions::from_mode(mode & !0o222))?;
+        }
+        #[cfg(not(unix))]
+        {
+            let mut perms = entry.metadata()?.permissions();
+            perms.set_readonly(true);
+            fs::set_permissions(entry.path(), perms)?;
+        }
+        if protection == Protection::Immutable {
+            // Setting the flag usually needs privileges, so read-only
+            // is the fallback
+            complete &= set_immutable(entry.path(), true).is_ok();
+        }
+    }
+    Ok(complete)
+}
+
+/// Lift the protection from the grave at `path` so that it can be moved
+/// or deleted. Write permission comes back wherever there is read
+/// permission, subject to the umask.
+pub fn unprotect(path: &Path) -> Result<(), Error> {
+    for entry in WalkDir::new(path).follow_links(false).into_iter().flatten() {
+        if entry.file_type().is_symlink() {
+            continue;
+        }
+        set_immutable(entry.path(), false).ok();
+        let mut perms = entry.metadata()?.permissions();
+        if !perms.readonly() {
+            continue;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = perms.mode();
+            perms = fs::Permissions::from_mode(mode | (((mode & 0o444) >> 1) & !umask()) | 0o200);
+        }
+        #[cfg(not(unix))]
+        #[allow(clippy::permissions_set_readonly_false)]
+        perms.set_readonly(false);
+        fs::set_permissions(entry.path(), perms)?;
+    }
+    Ok(())
+}
+
+/// Set or clear the immutable flag on `path`
+fn set_immutable(path: &Path, immutable: bool) -> Result<(), Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+        let file = fs::File::open(path)?;
+        let mut flags: libc::c_int = 0;
+        // SAFETY: the descriptor is open and `flags` outlives both calls
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS as _, &mut flags) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        let wanted = match immutable {
+            true => flags | FS_IMMUTABLE_FL,
+            false => flags & !FS_IMMUTABLE_FL,
+        };
+        if wanted == flags {
+            return Ok(());
+        }
+        // SAFETY: as above
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS as _, &wanted) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+    #[cfg(target_os = "macos")]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let flags = match immutable {
+            true => libc::UF_IMMUTABLE,
+            false => 0,
+        };
+        // SAFETY: the path is a valid NUL-terminated string
+        if unsafe { libc::chflags(c_path.as_ptr(), flags) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = (path, immutable);
+        Err(Error::new(
+            io::ErrorKind::Unsupported,
+            "Immutable flags are not supported on this platform",
+        ))
+    }
+}
+
+/// The process umask
+pub fn umask() -> u32 {
+    // Linux reports the umask without us having to change it,
+    // which would race with other threads creating files
+    #[cfg(target_os = "linux")]
+    if let Some(mask) = fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let line = status.lines().find(|line| line.starts_with("Umask:"))?;
+            u32::from_str_radix(line["Umask:".len()..].trim(), 8).ok()
+        })
+    {
+        return mask;
+    }
+    #[cfg(unix)]
+    {
+        // Elsewhere, there's no way to read the umask without setting
+        // it, so set it and immediately put it back.
+        // SAFETY: umask cannot fail
+        let mask = unsafe { libc::umask(0o022) };
+        unsafe { libc::umask(mask) };
+        #[allow(clippy::unnecessary_cast)]
+        {
+            mask as u32
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        0o022
+    }
+}
+
+/// Create `dest` and any missing ancestors, with permissions following
+/// `policy` given the matching ancestor of `source`.
+pub fn create_dirs_with_permissions(
+    dest: &Path,
+    source: Option<&Path>,
+    policy: PermissionPolicy,
+    cache: &mut DirCache,
+) -> Result<(), Error> {
+    // Pair each missing directory with its counterpart, leaf first.
+    // Walking up from the leaf means the pairing holds however
+    // differently the graveyard and source are nested above it.
+    let mut missing = Vec::new();
+    let (mut dir, mut counterpart) = (Some(dest), source);
+    while let Some(d) = dir {
+        if cache.0.contains(d) || d.is_dir() {
+            break;
+        }
+        missing.push((d, counterpart));
+        dir = d.parent();
+        counterpart = counterpart.and_then(Path::parent);
+    }
+
+    for (d, counterpart) in missing.into_iter().rev() {
+        let metadata = counterpart.and_then(|c| fs::metadata(c).ok());
+        create_dir_with_permissions(d, metadata.as_ref(), policy)?;
+    }
+    cache.0.extend(
+        dest.ancestors()
+            .take_while(|d| !cache.0.contains(*d))
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>(),
+    );
+    Ok(())
+}
+
+/// Create the directory `dir`, whose parent exists, with permissions
+/// following `policy` given the `metadata` of its counterpart
+pub fn create_dir_with_permissions(
+    dir: &Path,
+    metadata: Option<&fs::Metadata>,
+    policy: PermissionPolicy,
+) -> Result<(), Error> {
+    match fs::create_dir(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    set_dir_permissions(dir, metadata, policy);
+    Ok(())
+}
+
+fn set_dir_permissions(dir: &Path, metadata: Option<&fs::Metadata>, policy: PermissionPolicy) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let source_mode = metadata.map(|m| m.permissions().mode());
+        if let Some(mode) = policy.dir_mode(source_mode) {
+            fs::set_permissions(dir, fs::Permissions::from_mode(mode)).ok();
+        }
+        if let (PermissionPolicy::Mirror, Some(metadata)) = (policy, metadata) {
+            // Only root can give files away, so this is best-effort
+            std::os::unix::fs::chown(dir, Some(metadata.uid()), Some(metadata.gid())).ok();
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (dir, metadata, policy);
+    }
+}
+
+/// Apply `policy` to a regular file copied into the graveyard.
+/// Renamed and hard linked graves share the original's inode, so
+/// changing their mode would change the original, and they are skipped.
+pub fn set_file_permissions(
+    source: &Path,
+    dest: &Path,
+    policy: PermissionPolicy,
+) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let (Ok(source_meta), Ok(dest_meta)) =
+            (fs::symlink_metadata(source), fs::symlink_metadata(dest))
+        else {
+            return Ok(());
+        };
+        let shared = source_meta.dev() == dest_meta.dev() && source_meta.ino() == dest_meta.ino();
+        if !dest_meta.is_file() || shared {
+            return Ok(());
+        }
+        set_copied_file_permissions(source_meta.permissions().mode(), dest, policy)?;
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (source, dest, policy);
+    }
+    Ok(())
+}
+
+/// Apply `policy` to `dest`, a regular file copied from one with
+/// `source_mode`
+#[cfg(unix)]
+pub fn set_copied_file_permissions(
+    source_mode: u32,
+    dest: &Path,
+    policy: PermissionPolicy,
+) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(mode) = policy.file_mode(source_mode) {
+        fs::set_permissions(dest, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
 pub fn symlink_exists<P: AsRef<Path>>(path: P) -> bool {
     fs::symlink_metadata(path).is_ok()
 }
 
-pub fn get_user() -> String {
+/// Follow the chain of symlinks starting at `link` to the file it
+/// ultimately points at, failing if the chain loops or dangles.
+pub fn resolve_symlink(link: &Path) -> Result<PathBuf, Error> {
+    let mut visited = HashSet::new();
+    let mut current = link.to_path_buf();
+    while fs::symlink_metadata(&current)?.file_type().is_symlink() {
+        if !visited.insert(current.clone()) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Symlink loop at {}", link.display()),
+            ));
+        }
+        let next = fs::read_link(&current)?;
+        current = match current.parent() {
+            Some(parent) => parent.join(next),
+            None => next,
+        };
+    }
+    // Symlinks in the parent directories are resolved here
+    dunce::canonicalize(&current)
+}
+
+pub fn get_user(ctx: &Context) -> String {
+    #[cfg(unix)]
+    {
+        ctx.var("USER").unwrap_or("unknown").to_string()
+    }
+    #[cfg(target_os = "windows")]
+    {
+        ctx.var("USERNAME").unwrap_or("unknown").to_string()
+    }
+}
+
+/// The name of this machine
+pub fn get_host(ctx: &Context) -> String {
     #[cfg(unix)]
     {
-        env::var("USER").unwrap_or_else(|_| String::from("unknown"))
+        let _ = ctx;
+        let mut name = [0_u8; 256];
+        if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } != 0 {
+            return "unknown".to_string();
+        }
+        let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        String::from_utf8_lossy(&name[..end]).into_owned()
     }
     #[cfg(target_os = "windows")]
     {
-        env::var("USERNAME").unwrap_or_else(|_| String::from("unknown"))
+        ctx.var("COMPUTERNAME").unwrap_or("unknown").to_string()
     }
 }
 
+/// The other user that restoring to `path` would write into the files
+/// of: the owner of the nearest directory above it, or the user whose
+/// home it is in. Shared directories like `/tmp`, which are writable by
+/// all but sticky, don't count.
+pub fn foreign_owner(path: &Path) -> Option<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // SAFETY: geteuid cannot fail
+        let me = unsafe { libc::geteuid() };
+        let users = passwd_users();
+        let name_of = |uid: u32| {
+            users
+                .iter()
+                .find(|(_, id, _)| *id == uid)
+                .map_or_else(|| format!("uid {}", uid), |(name, _, _)| name.clone())
+        };
+        let dir = path.ancestors().skip(1).find(|dir| dir.exists())?;
+        let metadata = fs::metadata(dir).ok()?;
+        let shared = metadata.mode() & 0o1002 == 0o1002;
+        if metadata.uid() != me && !shared {
+            return Some(name_of(metadata.uid()));
+        }
+        users
+            .iter()
+            // Only homes the user owns, not the likes of /usr/sbin for daemon
+            .find(|(_, uid, home)| {
+                *uid != me
+                    && home.parent().is_some()
+                    && path.starts_with(home)
+                    && fs::metadata(home).is_ok_and(|m| m.uid() == *uid)
+            })
+            .map(|(name, _, _)| name.clone())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// The name, uid and home directory of each user in `/etc/passwd`
+#[cfg(unix)]
+fn passwd_users() -> Vec<(String, u32, PathBuf)> {
+    let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let uid = fields.get(2)?.parse().ok()?;
+            let home = fields.get(5).filter(|home| !home.is_empty())?;
+            Some((fields[0].to_string(), uid, PathBuf::from(home)))
+        })
+        .collect()
+}
+
 // Allows injection of test-specific behavior
 pub trait TestingMode {
     fn is_test(&self) -> bool;
+
+    /// The next line of scripted input to answer a prompt with. Without
+    /// one, tests take a yes (or no path), and everything else asks.
+    fn scripted_answer(&self) -> Option<String> {
+        None
+    }
 }
 
 pub struct ProductionMode;
@@ -78,36 +676,258 @@ impl TestingMode for TestMode {
     }
 }
 
-pub fn allow_rename() -> bool {
-    // Test behavior to skip simple rename
-    env::var("__RIP_ALLOW_RENAME")
-        .unwrap_or("true".to_string())
-        .parse::<bool>()
-        .unwrap()
+/// How graves are moved into (and out of) the graveyard.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Rename if possible, then hard link on the same filesystem, then copy
+    #[default]
+    Auto,
+    /// Rename if possible, otherwise copy
+    Rename,
+    /// Always copy the bytes and remove the source
+    Copy,
+    /// Hard link on the same filesystem, otherwise copy
+    #[value(name = "hardlink")]
+    HardLink,
+    /// Clone copy-on-write extents where supported, otherwise copy
+    Reflink,
+}
+
+impl Strategy {
+    pub fn allows_rename(&self) -> bool {
+        matches!(self, Strategy::Auto | Strategy::Rename)
+    }
+
+    pub fn allows_hard_link(&self) -> bool {
+        matches!(self, Strategy::Auto | Strategy::HardLink)
+    }
+}
+
+/// Select the strategy from the flag, falling back to `RIP_STRATEGY`
+pub fn get_strategy(strategy: Option<Strategy>, ctx: &Context) -> Result<Strategy, Error> {
+    flag_or_env(strategy, "RIP_STRATEGY", ctx)
+}
+
+/// Returns true if the flag is given, or the environment variable
+/// `var` (or its setting in the config file) is true, like `1` or `yes`
+pub fn flag_or_env_bool(flag: bool, var: &str, ctx: &Context) -> bool {
+    flag || ctx
+        .setting(var)
+        .is_some_and(|value| parse_bool(value) == Ok(true))
+}
+
+/// The setting of list `var`: from the environment variable, separated
+/// like `$PATH`, or else from the config, a line per item
+pub fn setting_list(var: &str, ctx: &Context) -> Option<Vec<String>> {
+    let items: Vec<String> = match ctx.var(var) {
+        Some(value) => std::env::split_paths(value)
+            .map(|item| item.display().to_string())
+            .collect(),
+        None => ctx.setting(var)?.lines().map(str::to_string).collect(),
+    };
+    Some(items.into_iter().filter(|item| !item.is_empty()).collect())
+}
+
+/// Parse a boolean setting like `true`, `1` or `no`
+pub fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" | "" => Ok(false),
+        _ => Err(format!("Invalid boolean: {}", value)),
+    }
+}
+
+/// Returns the flag if given, or else the value of the environment
+/// variable `var`, or else its setting in the config file, or else
+/// the default
+fn flag_or_env<T: clap::ValueEnum + Default>(
+    flag: Option<T>,
+    var: &str,
+    ctx: &Context,
+) -> Result<T, Error> {
+    if let Some(flag) = flag {
+        return Ok(flag);
+    }
+    match ctx.setting(var) {
+        Some(value) => T::from_str(value, true).map_err(|_| {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid {}: {}", var, value),
+            )
+        }),
+        None => Ok(T::default()),
+    }
+}
+
+/// Clone `source` to `dest` sharing copy-on-write extents, if the
+/// filesystem supports it.
+pub fn reflink(source: &Path, dest: &Path) -> Result<(), Error> {
+    reflink_from(&fs::File::open(source)?, dest)
+}
+
+/// Like `reflink`, from a file that is already open
+pub fn reflink_from(src: &fs::File, dest: &Path) -> Result<(), Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let dst = fs::File::create_new(dest)?;
+        // SAFETY: both descriptors are open for the duration of the call
+        let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE as _, src.as_raw_fd()) };
+        if ret != 0 {
+            let e = Error::last_os_error();
+            fs::remove_file(dest).ok();
+            return Err(e);
+        }
+        Ok(())
+    }
+    #[cfg(target_os = "macos")]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::io::AsRawFd;
+        let dst = CString::new(dest.as_os_str().as_bytes())?;
+        // SAFETY: the descriptor is open and `dst` is a valid
+        // NUL-terminated string
+        if unsafe { libc::fclonefileat(src.as_raw_fd(), libc::AT_FDCWD, dst.as_ptr(), 0) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = (src, dest);
+        Err(Error::new(
+            io::ErrorKind::Unsupported,
+            "Reflinks are not supported on this platform",
+        ))
+    }
+}
+
+/// Check whether two paths live on the same device, so that
+/// hard links between them are possible.
+pub fn same_device(a: impl AsRef<Path>, b: impl AsRef<Path>) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (fs::symlink_metadata(a), fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        // Volume serial numbers aren't available on stable,
+        // so we always fall back to copying.
+        let _ = (a, b);
+        false
+    }
+}
+
+/// How prompts are answered: what just pressing enter means, and how
+/// much it takes to agree to losing data for good
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PromptPolicy {
+    /// Answer yes when the user just presses enter. Prompts to delete
+    /// permanently are never answered yes by default.
+    pub default_yes: bool,
+    /// Require typing `yes` in full to delete permanently
+    pub type_yes: bool,
+}
+
+/// The prompt policy from the `prompt-default` and `type-yes` settings
+pub fn get_prompt_policy(ctx: &Context) -> Result<PromptPolicy, Error> {
+    let default_yes = match ctx.setting("RIP_PROMPT_DEFAULT") {
+        Some(value) => parse_bool(value).map_err(|e| Error::new(io::ErrorKind::InvalidInput, e))?,
+        None => false,
+    };
+    Ok(PromptPolicy {
+        default_yes,
+        type_yes: flag_or_env_bool(false, "RIP_TYPE_YES", ctx),
+    })
 }
 
-/// Prompt for user input, returning True if the first character is 'y' or 'Y'
+/// Prompt for user input, returning True if the first character is 'y' or 'Y',
+/// or if nothing is given and the policy defaults to yes.
 /// Will create an error if given a 'q' or 'Q', equivalent to if the user
 /// had passed a SIGINT.
 pub fn prompt_yes(
     prompt: impl AsRef<str>,
+    policy: &PromptPolicy,
     source: &impl TestingMode,
     stream: &mut impl Write,
 ) -> Result<bool, Error> {
-    write!(stream, "{} (y/N) ", prompt.as_ref())?;
+    let choices = if policy.default_yes { "(Y/n)" } else { "(y/N)" };
+    write!(stream, "{} {} ", prompt.as_ref(), choices)?;
     if stream.flush().is_err() {
         // If stdout wasn't flushed properly, fallback to println
-        writeln!(stream, "{} (y/N)", prompt.as_ref())?;
+        writeln!(stream, "{} {}", prompt.as_ref(), choices)?;
+    }
+
+    if let Some(answer) = source.scripted_answer() {
+        return Ok(yes_no_quit(answer.as_bytes())?.unwrap_or(policy.default_yes));
+    }
+    if source.is_test() {
+        return Ok(true);
+    }
+
+    Ok(yes_no_quit(io::stdin())?.unwrap_or(policy.default_yes))
+}
+
+/// Like `prompt_yes`, for answers that delete data for good: these default
+/// to no, and may require typing `yes` in full
+pub fn prompt_destructive(
+    prompt: impl AsRef<str>,
+    policy: &PromptPolicy,
+    source: &impl TestingMode,
+    stream: &mut impl Write,
+) -> Result<bool, Error> {
+    if !policy.type_yes {
+        let policy = PromptPolicy {
+            default_yes: false,
+            ..*policy
+        };
+        return prompt_yes(prompt, &policy, source, stream);
+    }
+    write!(stream, "{} (type yes to confirm) ", prompt.as_ref())?;
+    if stream.flush().is_err() {
+        writeln!(stream, "{} (type yes to confirm)", prompt.as_ref())?;
     }
 
+    if let Some(answer) = source.scripted_answer() {
+        return typed_yes(answer.as_bytes());
+    }
     if source.is_test() {
         return Ok(true);
     }
 
-    yes_no_quit(io::stdin())
+    typed_yes(io::stdin())
+}
+
+/// Prompt for a path, returning `None` if the answer is empty
+pub fn prompt_path(
+    prompt: impl AsRef<str>,
+    source: &impl TestingMode,
+    stream: &mut impl Write,
+) -> Result<Option<PathBuf>, Error> {
+    write!(stream, "{} ", prompt.as_ref())?;
+    if stream.flush().is_err() {
+        writeln!(stream, "{}", prompt.as_ref())?;
+    }
+
+    let mut answer = String::new();
+    match source.scripted_answer() {
+        Some(scripted) => answer = scripted,
+        None if source.is_test() => return Ok(None),
+        None => {
+            io::stdin().read_line(&mut answer)?;
+        }
+    }
+    let answer = answer.trim();
+    Ok((!answer.is_empty()).then(|| PathBuf::from(answer)))
 }
 
-pub fn yes_no_quit(in_stream: impl Read) -> Result<bool, Error> {
+/// Read a y/n answer, or `None` if nothing was given
+pub fn yes_no_quit(in_stream: impl Read) -> Result<Option<bool>, Error> {
     let buffered = BufReader::new(in_stream);
     let char_result = buffered
         .bytes()
@@ -116,8 +936,9 @@ pub fn yes_no_quit(in_stream: impl Read) -> Result<bool, Error> {
         .map(|c| c as char);
 
     match char_result {
-        Some('y') | Some('Y') => Ok(true),
-        Some('n') | Some('N') | Some('\n') | None => Ok(false),
+        Some('y') | Some('Y') => Ok(Some(true)),
+        Some('n') | Some('N') => Ok(Some(false)),
+        Some('\n') | None => Ok(None),
         Some('q') | Some('Q') => Err(Error::new(
             io::ErrorKind::Interrupted,
             "User requested to quit",
@@ -126,16 +947,155 @@ pub fn yes_no_quit(in_stream: impl Read) -> Result<bool, Error> {
     }
 }
 
+/// Read a line, returning true only if it is the word `yes`
+pub fn typed_yes(in_stream: impl Read) -> Result<bool, Error> {
+    let mut answer = String::new();
+    BufReader::new(in_stream).read_line(&mut answer)?;
+    match answer.trim() {
+        "q" | "Q" => Err(Error::new(
+            io::ErrorKind::Interrupted,
+            "User requested to quit",
+        )),
+        answer => Ok(answer.eq_ignore_ascii_case("yes")),
+    }
+}
+
 /// Add a numbered extension to duplicate filenames to avoid overwriting files.
 pub fn rename_grave(grave: impl AsRef<Path>) -> PathBuf {
     let grave = grave.as_ref();
-    let name = grave.to_str().expect("Filename must be valid unicode.");
     (1_u64..)
-        .map(|i| PathBuf::from(format!("{}~{}", name, i)))
+        .map(|i| {
+            let mut name = grave.as_os_str().to_owned();
+            name.push(format!("~{}", i));
+            PathBuf::from(name)
+        })
         .find(|p| !symlink_exists(p))
         .expect("Failed to rename duplicate file or directory")
 }
 
+/// Claim `path` for burying `source`, or the first `~N` name after it
+/// that is free, by creating it exclusively: an empty directory, with
+/// permissions following `policy`, if `source` is one, and otherwise an
+/// empty file. Checking for a free name and then moving there would leave
+/// a moment in which another rip, or anyone else, could take it first.
+/// Returns the path claimed.
+pub fn claim_path(path: &Path, source: &Path, policy: PermissionPolicy) -> Result<PathBuf, Error> {
+    let metadata = fs::symlink_metadata(source)?;
+    let mut claimed = path.to_path_buf();
+    loop {
+        let created = match metadata.is_dir() {
+            true => fs::create_dir(&claimed),
+            false => fs::File::create_new(&claimed).map(drop),
+        };
+        match created {
+            Ok(()) => break,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => claimed = rename_grave(path),
+            Err(e) => return Err(e),
+        }
+    }
+    if metadata.is_dir() {
+        set_dir_permissions(&claimed, Some(&metadata), policy);
+    }
+    Ok(claimed)
+}
+
+/// Rename `from` to `to`, which may be claimed by `claim_path`
+pub fn rename_into_claim(from: &Path, to: &Path) -> Result<(), Error> {
+    // Only Unix renames a directory over an empty one
+    #[cfg(not(unix))]
+    if to.is_dir() && fs::symlink_metadata(from)?.is_dir() {
+        fs::remove_dir(to)?;
+    }
+    fs::rename(from, to)
+}
+
+/// Where to put a file before renaming it over the claim at `path`, for
+/// ways of making it that won't replace what's there
+pub fn staging_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".rip-tmp");
+    path.with_file_name(name)
+}
+
+/// Give up the claim at `path`, with anything put there
+pub fn release_claim(path: &Path) {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path).ok(),
+        Ok(_) => fs::remove_file(path).ok(),
+        Err(_) => None,
+    };
+}
+
+/// Remove `dir` and the directories above it, up to but not including
+/// `graveyard`, for as long as they're empty and not to be kept
+pub fn prune_empty_dirs(dir: &Path, graveyard: &Path, keep: impl Fn(&Path) -> bool) -> usize {
+    let mut removed = 0;
+    for dir in dir
+        .ancestors()
+        .take_while(|dir| dir.starts_with(graveyard) && *dir != graveyard)
+    {
+        let empty = fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_none());
+        if !empty || keep(dir) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+        removed += 1;
+    }
+    removed
+}
+
+/// Source of the names rip makes up, like those of temporary files. Each
+/// is eight hex digits. Seeded, the same names come in the same order on
+/// every run, for tests and reproducible pipelines; otherwise they differ
+/// between processes.
+#[derive(Debug)]
+pub struct IdGen {
+    seed: u64,
+    count: AtomicU64,
+}
+
+impl Default for IdGen {
+    fn default() -> IdGen {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        IdGen::seeded(nanos ^ (u64::from(std::process::id()) << 32))
+    }
+}
+
+impl IdGen {
+    pub fn seeded(seed: u64) -> IdGen {
+        IdGen {
+            seed,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// The next name
+    pub fn next_id(&self) -> String {
+        let count = self.count.fetch_add(1, Ordering::Relaxed);
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.seed.to_le_bytes());
+        bytes[8..].copy_from_slice(&count.to_le_bytes());
+        format!("{:08x}", xxhash_rust::xxh3::xxh3_64(&bytes) as u32)
+    }
+}
+
+/// Names seeded by the flag, or else by `RIP_SEED`, or else those of `ctx`
+pub fn get_ids(seed: Option<u64>, ctx: &Context) -> Result<Arc<IdGen>, Error> {
+    let seed = match (seed, ctx.var("RIP_SEED")) {
+        (Some(seed), _) => seed,
+        (None, Some(value)) => value.trim().parse().map_err(|_| {
+            Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid RIP_SEED: {}", value),
+            )
+        })?,
+        (None, None) => return Ok(ctx.ids.clone()),
+    };
+    Ok(Arc::new(IdGen::seeded(seed)))
+}
+
 const UNITS: [(&str, u64); 4] = [
     ("KiB", 1_u64 << 10),
     ("MiB", 1_u64 << 20),
@@ -143,6 +1103,49 @@ const UNITS: [(&str, u64); 4] = [
     ("TiB", 1_u64 << 40),
 ];
 
+/// Whether the current user can create files in the directory `dir`
+pub fn is_writable(dir: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let Ok(path) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+            return false;
+        };
+        unsafe { libc::access(path.as_ptr(), libc::W_OK | libc::X_OK) == 0 }
+    }
+    #[cfg(not(unix))]
+    {
+        fs::metadata(dir).is_ok_and(|metadata| !metadata.permissions().readonly())
+    }
+}
+
+/// Write `rows` as columns padded to line up, two spaces apart
+pub fn write_aligned<R: AsRef<[String]>>(rows: &[R], stream: &mut impl Write) -> io::Result<()> {
+    let mut widths: Vec<usize> = Vec::new();
+    for row in rows {
+        for (i, cell) in row.as_ref().iter().enumerate() {
+            let width = cell.chars().count();
+            match widths.get_mut(i) {
+                Some(max) => *max = (*max).max(width),
+                None => widths.push(width),
+            }
+        }
+    }
+    for row in rows {
+        let row = row.as_ref();
+        let mut line = String::new();
+        for (i, cell) in row.iter().enumerate() {
+            if i + 1 < row.len() {
+                line.push_str(&format!("{: <width$}  ", cell, width = widths[i]));
+            } else {
+                line.push_str(cell);
+            }
+        }
+        writeln!(stream, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
 pub fn humanize_bytes(bytes: u64) -> String {
     for (unit, size) in UNITS.iter().rev() {
         if bytes >= *size {
@@ -151,3 +1154,197 @@ pub fn humanize_bytes(bytes: u64) -> String {
     }
     format!("{} B", bytes)
 }
+
+/// Parse a human-readable size like `500M`, `2GiB` or `1024` into bytes.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number.parse().map_err(|_| {
+        format!(
+            "Invalid size {:?}; expected something like 1024, 500M or 1.5GiB",
+            size
+        )
+    })?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1_u64 << 10,
+        "M" | "MB" | "MIB" => 1_u64 << 20,
+        "G" | "GB" | "GIB" => 1_u64 << 30,
+        "T" | "TB" | "TIB" => 1_u64 << 40,
+        _ => {
+            return Err(format!(
+                "Invalid size {:?}: unknown unit {:?}; use B, K, M, G or T",
+                size, unit
+            ))
+        }
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`
+pub fn free_space(path: &Path) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        // SAFETY: c_path is NUL-terminated and stat is a valid out-pointer
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+            return None;
+        }
+        #[allow(clippy::unnecessary_cast)]
+        Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+const DURATION_UNITS: [(&str, i64); 5] = [
+    ("w", 7 * 24 * 60 * 60),
+    ("d", 24 * 60 * 60),
+    ("h", 60 * 60),
+    ("m", 60),
+    ("s", 1),
+];
+
+/// Parse a `FROM=TO` pair of directories
+pub fn parse_remap(s: &str) -> Result<(PathBuf, PathBuf), String> {
+    match s.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((PathBuf::from(from), PathBuf::from(to)))
+        }
+        _ => Err(format!("expected FROM=TO, got {}", s)),
+    }
+}
+
+/// Parse a duration like `90s`, `10m`, `2h30m`, `3d` or `1w`, with units
+/// also spelled out (`2 hours`), or in ISO 8601 (`P1DT12H`)
+pub fn parse_duration(duration: &str) -> Result<chrono::Duration, String> {
+    let duration = duration.trim();
+    let seconds = match duration.strip_prefix(['P', 'p']) {
+        Some(iso) => iso_duration_seconds(iso),
+        None => duration_seconds(duration),
+    }
+    .map_err(|e| {
+        format!(
+            "Invalid duration {:?}: {}; expected something like 90s, 2h30m, 3d, 1w or P1DT12H",
+            duration, e
+        )
+    })?;
+    Ok(chrono::Duration::seconds(seconds))
+}
+
+/// Seconds in a run of numbers and units, like `2h30m` or `1 day 6 hours`
+fn duration_seconds(duration: &str) -> Result<i64, String> {
+    if duration.is_empty() {
+        return Err("it's empty".to_string());
+    }
+    let mut total: i64 = 0;
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(format!("expected a number at {:?}", rest));
+        }
+        let (number, tail) = rest.split_at(digits);
+        let tail = tail.trim_start();
+        let letters = tail
+            .find(|c: char| !c.is_alphabetic())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(letters);
+        let size = duration_unit(unit)?;
+        total = number
+            .parse::<i64>()
+            .ok()
+            .and_then(|number| number.checked_mul(size))
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or("it's too long")?;
+        rest = tail.trim_start_matches([' ', ',']);
+    }
+    Ok(total)
+}
+
+/// Seconds in an ISO 8601 duration after its `P`, like `3D` or `T2H30M`.
+/// Years and months aren't accepted, as their length varies.
+fn iso_duration_seconds(iso: &str) -> Result<i64, String> {
+    let (date, time) = match iso.split_once(['T', 't']) {
+        Some((date, time)) if !time.is_empty() => (date, Some(time)),
+        Some(_) => return Err("nothing follows the T".to_string()),
+        None => (iso, None),
+    };
+    if date.is_empty() && time.is_none() {
+        return Err("nothing follows the P".to_string());
+    }
+    let mut total: i64 = 0;
+    for (part, units) in [(date, "WD"), (time.unwrap_or_default(), "HMS")] {
+        let mut rest = part;
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let (number, tail) = rest.split_at(digits);
+            let Some(designator) = tail.chars().next().map(|c| c.to_ascii_uppercase()) else {
+                return Err(format!("{} has no unit", number));
+            };
+            if digits == 0 {
+                return Err(format!("expected a number before {}", designator));
+            }
+            if !units.contains(designator) {
+                return Err(match designator {
+                    'Y' | 'M' if units == "WD" => {
+                        "years and months vary in length; use days or weeks".to_string()
+                    }
+                    _ => format!("unknown unit {}", designator),
+                });
+            }
+            let size = duration_unit(&designator.to_ascii_lowercase().to_string())?;
+            total = number
+                .parse::<i64>()
+                .ok()
+                .and_then(|number| number.checked_mul(size))
+                .and_then(|seconds| total.checked_add(seconds))
+                .ok_or("it's too long")?;
+            rest = &tail[1..];
+        }
+    }
+    Ok(total)
+}
+
+/// Seconds in a unit of duration, by its letter or name
+fn duration_unit(unit: &str) -> Result<i64, String> {
+    let unit = unit.to_lowercase();
+    let letter = match unit.as_str() {
+        "" => return Err("a number has no unit; use s, m, h, d or w".to_string()),
+        "sec" | "secs" | "second" | "seconds" => "s",
+        "min" | "mins" | "minute" | "minutes" => "m",
+        "hr" | "hrs" | "hour" | "hours" => "h",
+        "day" | "days" => "d",
+        "wk" | "wks" | "week" | "weeks" => "w",
+        unit => unit,
+    };
+    DURATION_UNITS
+        .iter()
+        .find(|(name, _)| *name == letter)
+        .map(|(_, seconds)| *seconds)
+        .ok_or_else(|| format!("unknown unit {:?}; use s, m, h, d or w", unit))
+}
+
+/// Format a duration using its largest whole unit, e.g. `2d`
+pub fn humanize_duration(duration: chrono::Duration) -> String {
+    let seconds = duration.num_seconds();
+    for (unit, size) in DURATION_UNITS.iter() {
+        if seconds >= *size {
+            return format!("{}{}", seconds / size, unit);
+        }
+    }
+    format!("{}s", seconds)
+}
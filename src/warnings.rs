@@ -0,0 +1,48 @@
+use std::cell::RefCell;
+use std::io::{Error, Write};
+
+/// Non-fatal problems found during a run, which are printed together at
+/// the end instead of getting lost among the rest of the output
+#[derive(Debug, Default)]
+pub struct Warnings {
+    messages: RefCell<Vec<String>>,
+}
+
+impl Warnings {
+    pub fn new() -> Warnings {
+        Warnings::default()
+    }
+
+    pub fn warn(&self, message: impl Into<String>) {
+        self.messages.borrow_mut().push(message.into());
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.borrow().is_empty()
+    }
+
+    /// Print every warning so far, then forget them
+    pub fn report(&self, stream: &mut impl Write) -> Result<(), Error> {
+        for message in self.messages.borrow_mut().drain(..) {
+            writeln!(stream, "Warning: {}", message)?;
+        }
+        Ok(())
+    }
+
+    /// Print every warning so far, and under `strict` fail if there were any
+    pub fn finish(&self, strict: bool, stream: &mut impl Write) -> Result<(), Error> {
+        let count = self.len();
+        self.report(stream)?;
+        if strict && count > 0 {
+            return Err(Error::other(format!(
+                "{} warning(s), which --strict treats as errors",
+                count
+            )));
+        }
+        Ok(())
+    }
+}
@@ -1,29 +1,20 @@
-use lazy_static::lazy_static;
 use predicates::str::is_match;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use rip2::args::Args;
-use rip2::record;
-use rip2::util::TestMode;
+use rip2::context::Context;
+use rip2::util::{Strategy, TestMode};
 use rip2::{self, util};
+use rip2::{audit, record};
 use rstest::rstest;
 use std::fs;
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::{BufReader, ErrorKind, Read, Write};
 use std::path::PathBuf;
-use std::sync::{Mutex, MutexGuard};
 use std::{env, ffi, iter};
 use tempfile::{tempdir, TempDir};
 use walkdir::WalkDir;
 
-lazy_static! {
-    static ref GLOBAL_LOCK: Mutex<()> = Mutex::new(());
-}
-
-fn aquire_lock() -> MutexGuard<'static, ()> {
-    GLOBAL_LOCK.lock().unwrap()
-}
-
 struct TestEnv {
     _tmpdir: TempDir,
     graveyard: PathBuf,
@@ -78,9 +69,8 @@ impl TestData {
 /// Also checks that the graveyard is deleted when decompose is true
 #[rstest]
 fn test_bury_unbury(#[values(false, true)] decompose: bool, #[values(false, true)] inspect: bool) {
-    let _env_lock = aquire_lock();
-
     let test_env = TestEnv::new();
+    let ctx = Context::new(&test_env.src);
     let test_data = TestData::new(&test_env, None);
     // And is now in the graveyard
     let expected_graveyard_path = util::join_absolute(
@@ -89,13 +79,14 @@ fn test_bury_unbury(#[values(false, true)] decompose: bool, #[values(false, true
     );
 
     let mut log = Vec::new();
-    rip2::run(
+    rip2::run_with(
         Args {
             targets: [test_data.path.clone()].to_vec(),
             graveyard: Some(test_env.graveyard.clone()),
             inspect,
             ..Args::default()
         },
+        &ctx,
         TestMode,
         &mut log,
     )
@@ -119,13 +110,14 @@ fn test_bury_unbury(#[values(false, true)] decompose: bool, #[values(false, true
     assert_eq!(restored_data_from_grave, test_data.data);
 
     let mut log = Vec::new();
-    rip2::run(
+    rip2::run_with(
         Args {
             graveyard: Some(test_env.graveyard.clone()),
             decompose,
             unbury: if decompose { None } else { Some(Vec::new()) },
             ..Args::default()
         },
+        &ctx,
         TestMode,
         &mut log,
     )
@@ -138,8 +130,17 @@ fn test_bury_unbury(#[values(false, true)] decompose: bool, #[values(false, true
     }
 
     if decompose {
-        // Verify that the graveyard is completely deleted
-        assert!(!test_env.graveyard.exists());
+        // Verify that the graveyard is emptied, except for the log
+        let remaining: Vec<_> = fs::read_dir(&test_env.graveyard)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(remaining, [audit::LOG]);
+        let entries = audit::AuditLog::new(&test_env.graveyard).entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation, "decompose");
+        assert_eq!(entries[0].reason, "manual");
+        assert!(entries[0].bytes >= 100);
         // And that the file was not restored
         assert!(!test_data.path.exists());
     } else {
@@ -150,39 +151,9 @@ fn test_bury_unbury(#[values(false, true)] decompose: bool, #[values(false, true
     }
 }
 
-const ENV_VARS: [&str; 2] = ["RIP_GRAVEYARD", "XDG_DATA_HOME"];
-
-// Delete env vars and return them
-// so we can restore them later
-fn cache_and_remove_env_vars() -> [Option<String>; 2] {
-    // This should be the same size as ENV_VARS
-    ENV_VARS.map(|key| {
-        // Check if env var exists
-        let value = env::var(key).ok();
-        env::remove_var(key);
-        value
-    })
-}
-
-fn restore_env_vars(default_env_vars: [Option<String>; 2]) {
-    // Iterate over the default env vars and restore them
-    ENV_VARS
-        .iter()
-        .zip(default_env_vars.iter())
-        .for_each(|(key, value)| {
-            env::remove_var(key);
-            if let Some(value) = value {
-                env::set_var(key, value);
-            }
-        });
-}
-
 /// Test that we can set the graveyard from different env variables
 #[rstest]
 fn test_env(#[values("RIP_GRAVEYARD", "XDG_DATA_HOME")] env_var: &str) {
-    let _env_lock = aquire_lock();
-
-    let default_env_vars = cache_and_remove_env_vars();
     let test_env = TestEnv::new();
     let test_data = TestData::new(&test_env, None);
     let modified_graveyard = if env_var == "XDG_DATA_HOME" {
@@ -196,16 +167,18 @@ fn test_env(#[values("RIP_GRAVEYARD", "XDG_DATA_HOME")] env_var: &str) {
         dunce::canonicalize(&test_data.path).unwrap(),
     );
 
-    let graveyard = test_env.graveyard.clone();
-    env::set_var(env_var, graveyard);
+    // The environment is given explicitly, rather than set on the process
+    let ctx = Context::new(env::current_dir().unwrap())
+        .with_var(env_var, test_env.graveyard.to_str().unwrap());
 
     let mut log = Vec::new();
-    rip2::run(
+    rip2::run_with(
         Args {
             targets: [test_data.path.clone()].to_vec(),
             // We don't set the graveyard here!
             ..Args::default()
         },
+        &ctx,
         TestMode,
         &mut log,
     )
@@ -216,8 +189,6 @@ fn test_env(#[values("RIP_GRAVEYARD", "XDG_DATA_HOME")] env_var: &str) {
 
     let restored_data = fs::read_to_string(expected_graveyard_path).unwrap();
     assert_eq!(restored_data, test_data.data);
-
-    restore_env_vars(default_env_vars);
 }
 
 #[rstest]
@@ -225,9 +196,8 @@ fn test_duplicate_file(
     #[values(false, true)] in_folder: bool,
     #[values(false, true)] inspect: bool,
 ) {
-    let _env_lock = aquire_lock();
-
     let test_env = TestEnv::new();
+    let ctx = Context::new(&test_env.src);
 
     // Bury the first file
     let test_data1 = if in_folder {
@@ -242,7 +212,7 @@ fn test_duplicate_file(
     );
 
     let mut log = Vec::new();
-    rip2::run(
+    rip2::run_with(
         Args {
             targets: [if in_folder {
                 test_data1.path.parent().unwrap().to_path_buf()
@@ -254,6 +224,7 @@ fn test_duplicate_file(
             inspect,
             ..Args::default()
         },
+        &ctx,
         TestMode,
         &mut log,
     )
@@ -295,7 +266,7 @@ fn test_duplicate_file(
 
     let mut log = Vec::new();
 
-    rip2::run(
+    rip2::run_with(
         Args {
             targets: [if in_folder {
                 test_data2.path.parent().unwrap().to_path_buf()
@@ -306,6 +277,7 @@ fn test_duplicate_file(
             graveyard: Some(test_env.graveyard.clone()),
             ..Args::default()
         },
+        &ctx,
         TestMode,
         &mut log,
     )
@@ -314,18 +286,16 @@ fn test_duplicate_file(
     // The second file will be in the same folder, but with '~1' appended
     assert!(expected_graveyard_path2.exists());
 
-    // Navigate to the test_env.src directory
-    let cur_dir = env::current_dir().unwrap();
-    env::set_current_dir(&test_env.src).unwrap();
     let mut log = Vec::new();
     // Unbury using seance
-    rip2::run(
+    rip2::run_with(
         Args {
             graveyard: Some(test_env.graveyard.clone()),
             unbury: Some(Vec::new()),
             seance: true,
             ..Args::default()
         },
+        &ctx,
         TestMode,
         &mut log,
     )
@@ -342,16 +312,14 @@ fn test_duplicate_file(
     } else {
         assert!(test_env.src.join("dir~1/file.txt").exists());
     }
-    env::set_current_dir(cur_dir).unwrap();
 }
 
 /// Test that big files trigger special behavior.
 /// In this test, we simply delete it automatically.
 #[rstest]
 fn test_big_file() {
-    let _env_lock = aquire_lock();
-
     let test_env = TestEnv::new();
+    let ctx = Context::new(&test_env.src);
     // Access constant BIG_FILE_THRESHOLD from rip2's lib.rs:
     let size = rip2::BIG_FILE_THRESHOLD + 1;
 
@@ -366,12 +334,14 @@ fn test_big_file() {
     );
 
     let mut log = Vec::new();
-    rip2::run(
+    rip2::run_with(
         Args {
             targets: [test_env.src.join("big_file.txt")].to_vec(),
             graveyard: Some(test_env.graveyard.clone()),
+            strategy: Some(Strategy::Copy),
             ..Args::default()
         },
+        &ctx,
         TestMode,
         &mut log,
     )
@@ -388,18 +358,18 @@ fn test_big_file() {
 /// throws an error
 #[rstest]
 fn test_same_file_twice() {
-    let _env_lock = aquire_lock();
-
     let test_env = TestEnv::new();
+    let ctx = Context::new(&test_env.src);
     let test_data = TestData::new(&test_env, None);
 
     let mut log = Vec::new();
-    let result = rip2::run(
+    let result = rip2::run_with(
         Args {
             targets: [test_data.path.clone(), test_data.path.clone()].to_vec(),
             graveyard: Some(test_env.graveyard.clone()),
             ..Args::default()
         },
+        &ctx,
         TestMode,
         &mut log,
     );
@@ -428,7 +398,9 @@ where
     for arg in args {
         cmd.arg(arg);
     }
-    cmd.env("__RIP_ALLOW_RENAME", "false");
+    cmd.env("RIP_STRATEGY", "copy");
+    // Keep the user's own config out of it
+    cmd.env("RIP_CONFIG", "");
     cmd
 }
 
@@ -450,7 +422,6 @@ fn test_cli(
     )]
     scenario: &str,
 ) {
-    let _env_lock = aquire_lock();
     let test_env = TestEnv::new();
 
     // Early exit for some tests
@@ -566,7 +537,6 @@ fn test_cli(
 
 #[rstest]
 fn issue_0018() {
-    let _env_lock = aquire_lock();
     let test_env = TestEnv::new();
 
     // Make a big file
@@ -681,9 +651,7 @@ fn issue_0018() {
 
 #[rstest]
 fn test_graveyard_subcommand(#[values(false, true)] seance: bool) {
-    let _env_lock = aquire_lock();
-
-    let expected_graveyard = rip2::get_graveyard(None);
+    let expected_graveyard = rip2::get_graveyard(None, &Context::from_process().unwrap());
     let cwd = &env::current_dir().unwrap();
     let expected_gravepath =
         util::join_absolute(&expected_graveyard, dunce::canonicalize(cwd).unwrap());
@@ -704,7 +672,6 @@ fn test_graveyard_subcommand(#[values(false, true)] seance: bool) {
 
 #[rstest]
 fn read_empty_record() {
-    let _env_lock = aquire_lock();
     let test_env = TestEnv::new();
     let cwd = env::current_dir().unwrap();
     fs::create_dir(&test_env.graveyard).unwrap();
@@ -742,6 +709,7 @@ fn _hash_dir(dir: &PathBuf) -> String {
 #[rstest]
 fn many_nest() {
     let test_env = TestEnv::new();
+    let ctx = Context::new(&test_env.src);
 
     // Random generators
     let pathname_len_range = 3..10;
@@ -840,13 +808,14 @@ fn many_nest() {
 
     // Bury the files interactively
     let mut log = Vec::new();
-    let result = rip2::run(
+    let result = rip2::run_with(
         Args {
             targets: [test_env.src.clone()].to_vec(),
             graveyard: Some(test_env.graveyard.clone()),
             inspect: true,
             ..Args::default()
         },
+        &ctx,
         TestMode,
         &mut log,
     );
@@ -861,12 +830,13 @@ fn many_nest() {
 
     // Unbury everything
     let mut log = Vec::new();
-    let result = rip2::run(
+    let result = rip2::run_with(
         Args {
             graveyard: Some(test_env.graveyard.clone()),
             unbury: Some(Vec::new()),
             ..Args::default()
         },
+        &ctx,
         TestMode,
         &mut log,
     );
@@ -876,3 +846,4250 @@ fn many_nest() {
     let new_hash = _hash_dir(&test_env.src);
     assert_eq!(original_hash, new_hash);
 }
+
+/// Test that graves on the same filesystem are hard linked
+/// rather than copied, unless copying is requested
+#[cfg(unix)]
+#[rstest]
+fn test_hard_link_strategy(
+    #[values(false, true)] in_folder: bool,
+    #[values(Strategy::HardLink, Strategy::Copy, Strategy::Reflink)] strategy: Strategy,
+) {
+    use std::os::unix::fs::MetadataExt;
+    let test_env = TestEnv::new();
+    let ctx = Context::new(&test_env.src);
+    let test_data = if in_folder {
+        fs::create_dir(test_env.src.join("dir")).unwrap();
+        TestData::new(&test_env, Some(&PathBuf::from("dir").join("file.txt")))
+    } else {
+        TestData::new(&test_env, None)
+    };
+    let inode = fs::metadata(&test_data.path).unwrap().ino();
+    let target = if in_folder {
+        test_data.path.parent().unwrap().to_path_buf()
+    } else {
+        test_data.path.clone()
+    };
+    let expected_graveyard_path = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_data.path).unwrap(),
+    );
+
+    rip2::run_with(
+        Args {
+            targets: vec![target.clone()],
+            graveyard: Some(test_env.graveyard.clone()),
+            strategy: Some(strategy),
+            ..Args::default()
+        },
+        &ctx,
+        TestMode,
+        &mut Vec::new(),
+    )
+    .unwrap();
+
+    assert!(!target.exists());
+    let metadata = fs::metadata(&expected_graveyard_path).unwrap();
+    assert_eq!(metadata.ino() == inode, strategy == Strategy::HardLink);
+    assert_eq!(
+        fs::read_to_string(&expected_graveyard_path).unwrap(),
+        test_data.data
+    );
+}
+
+/// Test that a file with other names is copied rather than hard linked,
+/// so writes through them don't reach the grave, and that a grave given
+/// permissions of its own is copied too
+#[cfg(unix)]
+#[rstest]
+fn test_hard_link_fallbacks(#[values(false, true)] in_folder: bool) {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    fs::create_dir(src.join("dir")).unwrap();
+    let shared = TestData::new(&test_env, Some(&PathBuf::from("dir/shared.txt")));
+    let link = src.join("link.txt");
+    fs::hard_link(&shared.path, &link).unwrap();
+    let target = match in_folder {
+        true => "dir",
+        false => "dir/shared.txt",
+    };
+    cli_runner(
+        ["--graveyard", graveyard, "--strategy", "hardlink", target],
+        Some(&src),
+    )
+    .assert()
+    .success();
+
+    let grave = util::join_absolute(&test_env.graveyard, src.join("dir/shared.txt"));
+    assert_ne!(
+        fs::metadata(&grave).unwrap().ino(),
+        fs::metadata(&link).unwrap().ino()
+    );
+    fs::write(&link, "changed").unwrap();
+    assert_eq!(fs::read_to_string(&grave).unwrap(), shared.data);
+
+    let private = TestData::new(&test_env, Some(&PathBuf::from("private.txt")));
+    fs::set_permissions(&private.path, fs::Permissions::from_mode(0o644)).unwrap();
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "--strategy",
+            "hardlink",
+            "--grave-perms",
+            "private",
+            "private.txt",
+        ],
+        Some(&src),
+    )
+    .assert()
+    .success();
+    let grave = util::join_absolute(&test_env.graveyard, src.join("private.txt"));
+    assert_eq!(
+        fs::metadata(&grave).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+}
+
+/// Test that repeated buries of a file are stored as deltas,
+/// and that every version can still be restored
+#[rstest]
+fn test_delta_storage(#[values(false, true)] unbury_base_first: bool) {
+    let test_env = TestEnv::new();
+    let ctx = Context::new(&test_env.src);
+    let path = test_env.src.join("config.txt");
+    let first: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(4096)
+        .map(char::from)
+        .collect();
+    let second = format!("{}edited{}", &first[..2000], &first[2000..]);
+    let bury = |data: &str| {
+        fs::write(&path, data).unwrap();
+        rip2::run_with(
+            Args {
+                targets: vec![path.clone()],
+                graveyard: Some(test_env.graveyard.clone()),
+                delta: true,
+                ..Args::default()
+            },
+            &ctx,
+            TestMode,
+            &mut Vec::new(),
+        )
+        .unwrap();
+    };
+    bury(&first);
+    bury(&second);
+
+    let grave1 = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    )
+    .join("config.txt");
+    let grave2 = PathBuf::from(format!("{}~1", grave1.display()));
+    assert!(grave2.exists());
+    assert!(fs::metadata(&grave2).unwrap().len() < 1000);
+    // The checksum is of what the delta stands for, not the delta
+    let checksum = record::Record::new(&test_env.graveyard)
+        .graves()
+        .unwrap()
+        .find(|grave| grave.dest == grave2)
+        .and_then(|grave| grave.checksum)
+        .unwrap();
+
+    let unbury = |grave: &PathBuf| {
+        rip2::run_with(
+            Args {
+                graveyard: Some(test_env.graveyard.clone()),
+                unbury: Some(vec![grave.clone()]),
+                ..Args::default()
+            },
+            &ctx,
+            TestMode,
+            &mut Vec::new(),
+        )
+        .unwrap();
+    };
+    let renamed = test_env.src.join("config.txt~1");
+    let (restored1, restored2) = if unbury_base_first {
+        unbury(&grave1);
+        unbury(&grave2);
+        (path.clone(), renamed)
+    } else {
+        unbury(&grave2);
+        unbury(&grave1);
+        (renamed, path.clone())
+    };
+    assert_eq!(fs::read_to_string(restored1).unwrap(), first);
+    assert_eq!(rip2::checksum::of(&restored2).unwrap(), checksum);
+    assert_eq!(fs::read_to_string(restored2).unwrap(), second);
+}
+
+/// Test that the oldest graves are evicted when the graveyard runs low on
+/// space, only as many as it takes, and none if it can't be done
+#[cfg(unix)]
+#[rstest]
+fn test_min_free_eviction() {
+    let test_env = TestEnv::new();
+    let ctx = Context::new(&test_env.src);
+    let old = TestData::new(&test_env, Some(&PathBuf::from("old.txt")));
+    let middle = TestData::new(&test_env, Some(&PathBuf::from("middle.txt")));
+    let new = TestData::new(&test_env, Some(&PathBuf::from("new.txt")));
+    let newest = TestData::new(&test_env, Some(&PathBuf::from("newest.txt")));
+    // Big enough that purging it alone makes the room asked for below
+    fs::write(&old.path, vec![b'x'; 4 << 20]).unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let grave = |data: &TestData| {
+        util::join_absolute(
+            &test_env.graveyard,
+            src.join(data.path.file_name().unwrap()),
+        )
+    };
+
+    let bury = |data: &TestData, min_free: Option<u64>| {
+        let mut log = Vec::new();
+        rip2::run_with(
+            Args {
+                targets: vec![data.path.clone()],
+                graveyard: Some(test_env.graveyard.clone()),
+                min_free,
+                ..Args::default()
+            },
+            &ctx,
+            TestMode,
+            &mut log,
+        )
+        .unwrap();
+        String::from_utf8(log).unwrap()
+    };
+    assert!(bury(&old, None).is_empty());
+    assert!(bury(&middle, None).is_empty());
+
+    // No filesystem has this much space, so nothing is evicted for it
+    let log_s = bury(&new, Some(u64::MAX));
+    assert!(!log_s.contains("Evicted"));
+    assert!(log_s.contains("Warning: --min-free asks for"));
+    assert!(grave(&old).exists());
+    assert!(grave(&middle).exists());
+
+    // A little more than is free now takes only the oldest grave
+    let free = util::free_space(&test_env.graveyard).unwrap();
+    let log_s = bury(&newest, Some(free + (1 << 20)));
+    assert!(log_s.contains(&format!(
+        "Evicted {} (4.0 MiB) [space]",
+        grave(&old).display()
+    )));
+    assert!(log_s.contains("Evicted 1 graves, reclaiming 4.0 MiB"));
+    assert!(!grave(&old).exists());
+    for data in [&middle, &new, &newest] {
+        assert!(grave(data).exists());
+    }
+    let record_contents = fs::read_to_string(test_env.graveyard.join(record::RECORD)).unwrap();
+    assert!(!record_contents.contains("old.txt"));
+
+    // And the eviction is logged
+    cli_runner(
+        ["--graveyard", test_env.graveyard.to_str().unwrap(), "log"],
+        None,
+    )
+    .assert()
+    .success()
+    .stdout(is_match("purge\tspace\t4.0 MiB\t.*old.txt").unwrap());
+}
+
+/// Test that seance shows time-to-expiry under a retention policy,
+/// and that expired graves are purged on the next bury
+#[rstest]
+fn test_retention(#[values("1h", "2d")] expiring: &str) {
+    let test_env = TestEnv::new();
+    let ctx = Context::new(&test_env.src);
+    TestData::new(&test_env, Some(&PathBuf::from("old.txt")));
+    let new = TestData::new(&test_env, Some(&PathBuf::from("new.txt")));
+    let graveyard = test_env.graveyard.to_str().unwrap();
+
+    cli_runner(["--graveyard", graveyard, "old.txt"], Some(&test_env.src))
+        .assert()
+        .success();
+
+    let seance = quick_cmd_output(&mut cli_runner(
+        ["--graveyard", graveyard, "--max-age", "1d", "-s"],
+        Some(&test_env.src),
+    ));
+    assert!(seance.contains("expiry"));
+    assert!(seance.contains("in 23h"));
+
+    let seance = quick_cmd_output(&mut cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "--max-age",
+            "1d",
+            "-s",
+            "--expiring",
+            expiring,
+        ],
+        Some(&test_env.src),
+    ));
+    assert_eq!(seance.contains("old.txt"), expiring == "2d");
+
+    // --expiring needs a retention policy
+    cli_runner(
+        ["--graveyard", graveyard, "-s", "--expiring", expiring],
+        Some(&test_env.src),
+    )
+    .assert()
+    .failure();
+
+    // Expired graves are purged once, before any of the targets are buried
+    let another = TestData::new(&test_env, Some(&PathBuf::from("another.txt")));
+    let mut log = Vec::new();
+    rip2::run_with(
+        Args {
+            targets: vec![new.path.clone(), another.path.clone()],
+            graveyard: Some(test_env.graveyard.clone()),
+            max_age: Some(util::parse_duration("0s").unwrap()),
+            ..Args::default()
+        },
+        &ctx,
+        TestMode,
+        &mut log,
+    )
+    .unwrap();
+    let log_s = String::from_utf8(log).unwrap();
+    assert!(log_s.contains("old.txt (100 B) [age]"));
+    assert_eq!(log_s.matches("Evicted").count(), 2, "{}", log_s);
+    assert!(!util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap()
+    )
+    .join("old.txt")
+    .exists());
+    assert!(!new.path.exists());
+    assert!(util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap()
+    )
+    .join("new.txt")
+    .exists());
+}
+
+/// Test that restore-session brings back everything recent,
+/// regardless of which directory it was buried from
+#[rstest]
+fn test_restore_session() {
+    let test_env = TestEnv::new();
+    fs::create_dir(test_env.src.join("dir")).unwrap();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let test_data = [
+        TestData::new(&test_env, Some(&PathBuf::from("a.txt"))),
+        TestData::new(&test_env, Some(&PathBuf::from("dir").join("b.txt"))),
+    ];
+    cli_runner(
+        ["--graveyard", graveyard, "a.txt", "dir/b.txt"],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "restore-session",
+            "--within",
+            "10m",
+        ],
+        Some(&test_env.graveyard),
+    )
+    .assert()
+    .success()
+    .stdout(is_match("Returned .*a.txt").unwrap())
+    .stdout(is_match("Returned .*b.txt").unwrap());
+
+    for data in test_data {
+        assert_eq!(fs::read_to_string(&data.path).unwrap(), data.data);
+    }
+
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "restore-session",
+            "--within",
+            "10m",
+        ],
+        None,
+    )
+    .assert()
+    .success()
+    .stdout("Nothing was buried in the last 10m\n");
+}
+
+/// Test the cheap listing of recent graves meant for shell hooks
+#[rstest]
+fn test_recent(#[values(false, true)] porcelain: bool) {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = test_env.src.to_str().unwrap();
+
+    // Nothing has been buried, and the graveyard isn't created
+    let mut args = vec!["--graveyard", graveyard, "recent", "--dir", src];
+    if porcelain {
+        args.push("--porcelain");
+    }
+    cli_runner(&args, None).assert().success().stdout("");
+    assert!(!test_env.graveyard.exists());
+
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+        cli_runner(["--graveyard", graveyard, name], Some(&test_env.src))
+            .assert()
+            .success();
+    }
+
+    args.extend(["-n", "2", "--within", "1h"]);
+    let output = quick_cmd_output(&mut cli_runner(&args, None));
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("c.txt"));
+    assert!(lines[1].contains("b.txt"));
+    if porcelain {
+        assert_eq!(lines[0].split('\t').count(), 3);
+    } else {
+        assert!(lines[0].contains("s ago\t"));
+    }
+}
+
+/// Test attaching, editing and removing notes on graves
+#[rstest]
+fn test_note() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    TestData::new(&test_env, Some(&PathBuf::from("a.txt")));
+    TestData::new(&test_env, Some(&PathBuf::from("b.txt")));
+    cli_runner(
+        ["--graveyard", graveyard, "a.txt", "b.txt"],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+    let gravepath = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    );
+    let grave = gravepath.join("a.txt");
+
+    let seance = || {
+        quick_cmd_output(&mut cli_runner(
+            ["--graveyard", graveyard, "-s"],
+            Some(&test_env.src),
+        ))
+    };
+    assert!(!seance().contains("note"));
+
+    for note in ["verified obsolete", "safe to purge\tafter release"] {
+        cli_runner(
+            [
+                "--graveyard",
+                graveyard,
+                "note",
+                grave.to_str().unwrap(),
+                note,
+            ],
+            None,
+        )
+        .assert()
+        .success();
+    }
+    let output = seance();
+    assert!(output.lines().next().unwrap().ends_with("path\tnote"));
+    assert!(output.contains("\ta.txt\tsafe to purge after release"));
+    assert!(output.contains("\tb.txt\t\n"));
+
+    // Notes survive other graves being unburied
+    cli_runner(["--graveyard", graveyard, "-u"], None)
+        .assert()
+        .success();
+    assert!(seance().contains("safe to purge after release"));
+
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "note",
+            grave.to_str().unwrap(),
+            "",
+        ],
+        None,
+    )
+    .assert()
+    .success();
+    assert!(!seance().contains("note"));
+
+    cli_runner(
+        ["--graveyard", graveyard, "note", "/no/such/grave", "text"],
+        None,
+    )
+    .assert()
+    .failure();
+}
+
+/// Test that a message given when burying becomes the note of each grave
+#[test]
+fn test_bury_message() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "-m",
+            "cleaning\told builds",
+            "a.txt",
+            "b.txt",
+        ],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+    cli_runner(["--graveyard", graveyard, "c.txt"], Some(&test_env.src))
+        .assert()
+        .success();
+
+    let output = quick_cmd_output(&mut cli_runner(
+        ["--graveyard", graveyard, "-s"],
+        Some(&test_env.src),
+    ));
+    assert!(output.lines().next().unwrap().ends_with("path\tnote"));
+    assert!(output.contains("\ta.txt\tcleaning old builds\n"));
+    assert!(output.contains("\tb.txt\tcleaning old builds\n"));
+    assert!(output.contains("\tc.txt\t\n"));
+}
+
+/// Test that restores which conflict with existing files are
+/// summarized and logged
+#[rstest]
+fn test_unbury_conflict_log() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let test_data = TestData::new(&test_env, Some(&PathBuf::from("a.txt")));
+    cli_runner(["--graveyard", graveyard, "a.txt"], Some(&test_env.src))
+        .assert()
+        .success();
+    fs::write(&test_data.path, "replacement").unwrap();
+
+    let renamed = format!("{}~1", test_data.path.display());
+    let output = quick_cmd_output(&mut cli_runner(["--graveyard", graveyard, "-u"], None));
+    assert!(output.contains("1 files were restored under different names:"));
+    assert!(output.contains(&format!("{}\t{}", test_data.path.display(), renamed)));
+    assert_eq!(fs::read_to_string(&renamed).unwrap(), test_data.data);
+
+    let entries = audit::AuditLog::new(&test_env.graveyard).entries().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].operation, "unbury");
+    assert_eq!(entries[0].reason, "conflict");
+    assert_eq!(entries[0].path, test_data.path);
+    assert_eq!(entries[0].restored, Some(PathBuf::from(&renamed)));
+    assert_eq!(entries[0].bytes, 100);
+}
+
+/// Test that which reports where a restored path came back from
+#[test]
+fn test_which() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    TestData::new(&test_env, Some(&PathBuf::from("a.txt")));
+    cli_runner(["--graveyard", graveyard, "which", "a.txt"], Some(&src))
+        .assert()
+        .failure()
+        .stdout(format!(
+            "{} was never restored from the graveyard\n",
+            src.join("a.txt").display()
+        ));
+
+    cli_runner(["--graveyard", graveyard, "a.txt"], Some(&src))
+        .assert()
+        .success();
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&src))
+        .assert()
+        .success();
+    let grave = util::join_absolute(&test_env.graveyard, src.join("a.txt"));
+    cli_runner(["--graveyard", graveyard, "which", "a.txt"], Some(&src))
+        .assert()
+        .success()
+        .stdout(predicates::str::ends_with(format!(
+            "\tRestored from {}\n",
+            grave.display()
+        )));
+
+    // A restore renamed on conflict is found under its new name
+    cli_runner(["--graveyard", graveyard, "a.txt"], Some(&src))
+        .assert()
+        .success();
+    fs::write(src.join("a.txt"), "replacement").unwrap();
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&src))
+        .assert()
+        .success();
+    cli_runner(["--graveyard", graveyard, "which", "a.txt~1"], Some(&src))
+        .assert()
+        .success()
+        .stdout(is_match("Restored from .*a.txt \\(meant for .*a.txt\\)\n$").unwrap());
+    let entries = audit::AuditLog::new(&test_env.graveyard).entries().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].reason, "manual");
+    assert_eq!(entries[0].grave, Some(grave));
+}
+
+/// Test that diff compares a grave with its original path, another path,
+/// or another grave, falling back to hashes for binary files
+#[test]
+fn test_diff() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    let notes = src.join("notes.txt");
+    fs::write(&notes, "shopping\nmilk\neggs\n").unwrap();
+    rip(&["notes.txt"]).assert().success();
+    fs::write(&notes, "shopping\noat milk\neggs\n").unwrap();
+    rip(&["notes.txt"]).assert().success();
+    fs::write(&notes, "shopping\noat milk\neggs\n").unwrap();
+
+    let record = record::Record::new(&test_env.graveyard);
+    let graves: Vec<_> = record.graves().unwrap().collect();
+    let (first, second) = (&graves[0], &graves[1]);
+    let id = |grave: &record::RecordItem| format!("@{}", grave.id.as_ref().unwrap());
+
+    rip(&["diff", &id(first)])
+        .assert()
+        .failure()
+        .stdout(format!(
+            "--- {}\n+++ {}\n@@ -1,3 +1,3 @@\n shopping\n-milk\n+oat milk\n eggs\n",
+            first.dest.display(),
+            notes.display()
+        ));
+    rip(&["diff", second.dest.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(format!(
+            "{} and {} are the same\n",
+            second.dest.display(),
+            notes.display()
+        ));
+    rip(&["diff", &id(second), &id(first)])
+        .assert()
+        .failure()
+        .stdout(predicates::str::contains("-oat milk\n+milk\n"));
+
+    fs::write(src.join("blob"), b"\0\x01\x02").unwrap();
+    rip(&["diff", &id(first), "blob"])
+        .assert()
+        .failure()
+        .stdout(predicates::str::starts_with(format!(
+            "Binary files {} and {} differ\n",
+            first.dest.display(),
+            src.join("blob").display()
+        )))
+        .stdout(predicates::str::contains("xxh3:"));
+
+    rip(&["diff", src.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("isn't a grave in the record"));
+    fs::create_dir(src.join("dir")).unwrap();
+    rip(&["diff", &id(first), "dir"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("rip diff compares files"));
+}
+
+/// Test that a grave reached through a symlink is still recognized as one
+#[cfg(unix)]
+#[test]
+fn test_grave_through_symlink() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    TestData::new(&test_env, Some(&PathBuf::from("a.txt")));
+    cli_runner(["--graveyard", graveyard, "a.txt"], Some(&src))
+        .assert()
+        .success();
+    let grave = util::join_absolute(&test_env.graveyard, src.join("a.txt"));
+    std::os::unix::fs::symlink(grave.parent().unwrap(), src.join("link")).unwrap();
+
+    let rip = || {
+        let mut cmd = cli_runner(
+            [
+                "--graveyard",
+                graveyard,
+                "--path-style",
+                "logical",
+                "link/a.txt",
+            ],
+            Some(&src),
+        );
+        cmd.env("PWD", &src);
+        cmd
+    };
+    rip()
+        .write_stdin("n\n")
+        .assert()
+        .success()
+        .stdout(is_match("link/a.txt is already in the graveyard, as .*a.txt.\n").unwrap());
+    assert!(grave.exists());
+    rip().write_stdin("y\n").assert().success();
+    assert!(!grave.exists());
+    let entries = audit::AuditLog::new(&test_env.graveyard).entries().unwrap();
+    assert_eq!(entries[0].operation, "purge");
+    assert_eq!(entries[0].path, grave);
+}
+
+/// Test that graves can be unburied by the IDs shown in seance
+#[test]
+fn test_unbury_by_id() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    for name in ["a.txt", "b.txt"] {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    cli_runner(["--graveyard", graveyard, "a.txt", "b.txt"], Some(&src))
+        .assert()
+        .success();
+
+    let output = quick_cmd_output(&mut cli_runner(
+        ["--graveyard", graveyard, "-s"],
+        Some(&src),
+    ));
+    let mut lines = output.lines();
+    assert!(lines.next().unwrap().starts_with("id  \tdeletion_time"));
+    let id_of = |name: &str| {
+        output
+            .lines()
+            .find(|line| line.ends_with(name))
+            .and_then(|line| line.split('\t').next())
+            .unwrap()
+            .to_string()
+    };
+    let id = id_of("a.txt");
+    assert_eq!(id.len(), 4);
+    assert_ne!(id, id_of("b.txt"));
+
+    // The oldest grave, not the last, comes back
+    cli_runner(
+        ["--graveyard", graveyard, "-u", &format!("@{}", id)],
+        Some(&src),
+    )
+    .assert()
+    .success()
+    .stdout(is_match("^Returned .*a.txt to .*a.txt\n$").unwrap());
+    assert!(src.join("a.txt").exists());
+    assert!(!src.join("b.txt").exists());
+
+    cli_runner(
+        ["--graveyard", graveyard, "-u", &format!("@{}", id)],
+        Some(&src),
+    )
+    .assert()
+    .failure()
+    .stdout(format!("Exception: No grave has the ID @{}\n", id));
+    assert!(!src.join("b.txt").exists());
+
+    // Graves can still be named by path
+    let grave = util::join_absolute(&test_env.graveyard, src.join("b.txt"));
+    cli_runner(
+        ["--graveyard", graveyard, "-u", grave.to_str().unwrap()],
+        None,
+    )
+    .assert()
+    .success();
+    assert!(src.join("b.txt").exists());
+}
+
+/// Test unburying every grave buried from a path matching a glob
+#[test]
+fn test_unbury_by_glob() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let names = ["src/main.rs", "src/lib.rs", "src/deep/mod.rs", "notes.txt"];
+    fs::create_dir_all(src.join("src/deep")).unwrap();
+    for name in names {
+        fs::write(src.join(name), name).unwrap();
+    }
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    rip(&names).assert().success();
+
+    rip(&["-u", "**/*.rs"]).assert().success();
+    for name in &names[..3] {
+        assert_eq!(fs::read_to_string(src.join(name)).unwrap(), *name);
+    }
+    assert!(!src.join("notes.txt").exists());
+
+    // Without a /, it matches names
+    rip(&["-u", "*.txt"]).assert().success();
+    assert!(src.join("notes.txt").exists());
+
+    rip(&["-u", "*.md"])
+        .assert()
+        .failure()
+        .stdout("Exception: No grave was buried from a path matching *.md\n");
+}
+
+/// Test unburying the last few things buried, counting a directory
+/// buried with --split-dirs once
+#[test]
+fn test_unbury_last() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    fs::create_dir(src.join("proj")).unwrap();
+    for name in ["a.txt", "b.txt", "proj/x.txt", "proj/y.txt", "c.txt"] {
+        fs::write(src.join(name), name).unwrap();
+    }
+    for target in ["a.txt", "b.txt"] {
+        rip(&[target]).assert().success();
+    }
+    rip(&["--split-dirs", "proj"]).assert().success();
+    rip(&["c.txt"]).assert().success();
+
+    rip(&["-u", "--last", "2"]).assert().success();
+    for name in ["proj/x.txt", "proj/y.txt", "c.txt"] {
+        assert_eq!(fs::read_to_string(src.join(name)).unwrap(), name);
+    }
+    assert!(!src.join("b.txt").exists());
+
+    rip(&["-u", "--last", "5"]).assert().success();
+    assert!(src.join("a.txt").exists());
+    assert!(src.join("b.txt").exists());
+
+    rip(&["--last", "1"])
+        .assert()
+        .failure()
+        .stdout("Exception: --last can only be used with -u,--unbury\n");
+}
+
+/// Test that --dry-run shows where graves would go, renames included,
+/// without restoring them or touching the record
+#[test]
+fn test_unbury_dry_run() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    for contents in ["first", "second"] {
+        fs::write(src.join("a.txt"), contents).unwrap();
+        rip(&["a.txt"]).assert().success();
+    }
+    fs::write(src.join("a.txt"), "third").unwrap();
+    let record = fs::read_to_string(test_env.graveyard.join(".record")).unwrap();
+
+    let output = rip(&["-u", "-s", "--dry-run"]).output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for renamed in ["a.txt~1", "a.txt~2"] {
+        let line = format!("to {}\n", src.join(renamed).display());
+        assert!(stdout.contains(&line), "{}", stdout);
+    }
+    assert!(stdout.contains("2 files would be restored under different names"));
+    assert!(!stdout.contains("Returned"));
+    assert_eq!(fs::read_to_string(src.join("a.txt")).unwrap(), "third");
+    assert!(!src.join("a.txt~1").exists());
+    assert_eq!(
+        fs::read_to_string(test_env.graveyard.join(".record")).unwrap(),
+        record
+    );
+
+    // The last bury is picked without dropping it from the record
+    rip(&["-u", "--dry-run"]).assert().success();
+    rip(&["-u", "--last", "2", "--dry-run"]).assert().success();
+    assert_eq!(
+        fs::read_to_string(test_env.graveyard.join(".record")).unwrap(),
+        record
+    );
+
+    rip(&["-s", "--dry-run"])
+        .assert()
+        .failure()
+        .stdout("Exception: --dry-run can only be used with -u,--unbury\n");
+}
+
+/// Test that --only copies some files out of a directory grave, and
+/// leaves the grave buried
+#[test]
+fn test_unbury_only() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let dir = src.join("proj");
+    fs::create_dir_all(dir.join("src/bin")).unwrap();
+    fs::write(dir.join("src/main.rs"), "fn main() {}\n").unwrap();
+    fs::write(dir.join("src/bin/tool.rs"), "fn main() {}\n").unwrap();
+    fs::write(dir.join("src/notes.txt"), "notes\n").unwrap();
+    fs::write(dir.join("Cargo.toml"), "[package]\n").unwrap();
+    cli_runner(["--graveyard", graveyard, "proj"], Some(&src))
+        .assert()
+        .success();
+    let grave = util::join_absolute(&test_env.graveyard, &dir);
+
+    cli_runner(
+        ["--graveyard", graveyard, "-u", "--only", "src/**/*.rs"],
+        Some(&src),
+    )
+    .assert()
+    .success()
+    .stdout(format!(
+        "Returned {} to {}\nReturned {} to {}\n\
+         Restored 2 of the 4 files in {}; the grave stays buried\n",
+        grave.join("src/bin/tool.rs").display(),
+        dir.join("src/bin/tool.rs").display(),
+        grave.join("src/main.rs").display(),
+        dir.join("src/main.rs").display(),
+        grave.display()
+    ));
+    assert!(dir.join("src/main.rs").exists());
+    assert!(!dir.join("src/notes.txt").exists());
+    assert!(!dir.join("Cargo.toml").exists());
+    assert!(grave.join("src/main.rs").exists());
+
+    // A pattern without a / matches names, and restored files are renamed
+    // rather than overwritten
+    cli_runner(
+        ["--graveyard", graveyard, "-u", "--only", "main.rs"],
+        Some(&src),
+    )
+    .assert()
+    .success()
+    .stdout(predicates::str::contains(format!(
+        "Returned {} to {}~1\n",
+        grave.join("src/main.rs").display(),
+        dir.join("src/main.rs").display(),
+    )));
+
+    // The whole directory can still be restored
+    fs::remove_dir_all(&dir).unwrap();
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&src))
+        .assert()
+        .success();
+    assert!(dir.join("Cargo.toml").exists());
+    assert!(!grave.exists());
+
+    cli_runner(["--graveyard", graveyard, "--only", "*.rs"], Some(&src))
+        .assert()
+        .failure()
+        .stdout("Exception: --only can only be used with -u,--unbury\n");
+}
+
+/// Directory graves get a manifest, which rip peek lists, rip verify
+/// checks, and --only uses to warn of missing or changed files
+#[test]
+fn test_manifests() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    let dir = src.join("proj");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("a.txt"), "alpha\n").unwrap();
+    fs::write(dir.join("sub/b.txt"), "bravo\n").unwrap();
+    rip(&["proj"]).assert().success();
+    let grave = util::join_absolute(&test_env.graveyard, &dir);
+    let grave_str = grave.to_str().unwrap();
+
+    rip(&["peek", grave_str])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("a.txt\n"))
+        .stdout(predicates::str::contains("sub/b.txt\n"))
+        .stdout(predicates::str::contains("2 files, 12 B in all\n"));
+    rip(&["verify", "--manifests"])
+        .assert()
+        .success()
+        .stdout("1 directory graves match their manifests\n");
+
+    // The manifest still knows what the grave should hold
+    fs::remove_file(grave.join("sub/b.txt")).unwrap();
+    fs::write(grave.join("a.txt"), "changed\n").unwrap();
+    rip(&["peek", grave_str])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("sub/b.txt\n"));
+    rip(&["verify", "--manifests"])
+        .assert()
+        .failure()
+        .stdout(format!(
+            "{} has changed\n{} is missing\n\
+             1 of 1 directory graves don't match their manifests\n",
+            grave.join("a.txt").display(),
+            grave.join("sub/b.txt").display()
+        ));
+    rip(&["-u", "--only", "*.txt"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(format!(
+            "Warning: {} isn't what was buried at {}\n",
+            dir.join("a.txt").display(),
+            grave.join("a.txt").display()
+        )))
+        .stdout(predicates::str::contains(format!(
+            "Warning: {} was buried, but is missing from the grave\n",
+            grave.join("sub/b.txt").display()
+        )));
+
+    // Manifests leave the graveyard with their graves
+    fs::remove_dir_all(&dir).unwrap();
+    rip(&["-u"]).assert().success();
+    let manifests = test_env.graveyard.join(".manifests");
+    assert_eq!(fs::read_dir(manifests).unwrap().count(), 0);
+}
+
+/// Test that permanently unlinking graves drops them from the record
+#[test]
+fn test_unlink_grave_updates_record() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    cli_runner(
+        ["--graveyard", graveyard, "a.txt", "b.txt", "c.txt"],
+        Some(&src),
+    )
+    .assert()
+    .success();
+    let grave_of = |name: &str| util::join_absolute(&test_env.graveyard, src.join(name));
+    let graves = || -> Vec<PathBuf> {
+        record::Record::new(&test_env.graveyard)
+            .graves()
+            .unwrap()
+            .map(|grave| grave.dest)
+            .collect()
+    };
+
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            grave_of("c.txt").to_str().unwrap(),
+        ],
+        None,
+    )
+    .write_stdin("y\n")
+    .assert()
+    .success();
+    assert_eq!(graves(), [grave_of("a.txt"), grave_of("b.txt")]);
+    let output = quick_cmd_output(&mut cli_runner(
+        ["--graveyard", graveyard, "-s"],
+        Some(&src),
+    ));
+    assert!(!output.contains("c.txt"));
+
+    // Unlinking a directory above graves drops all of them
+    cli_runner(
+        ["--graveyard", graveyard, grave_of("").to_str().unwrap()],
+        None,
+    )
+    .write_stdin("y\n")
+    .assert()
+    .success();
+    assert!(graves().is_empty());
+    assert!(!grave_of("a.txt").exists());
+}
+
+/// Test that purge permanently deletes the graves selected by path, ID or glob
+#[test]
+fn test_purge_selected() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let names = ["a.txt", "b.log", "c.log", "d.txt", "e.txt"];
+    for name in names {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+    cmd.args(names).assert().success();
+    let grave_of = |name: &str| util::join_absolute(&test_env.graveyard, src.join(name));
+    let graves = || -> Vec<PathBuf> {
+        record::Record::new(&test_env.graveyard)
+            .graves()
+            .unwrap()
+            .map(|grave| grave.dest)
+            .collect()
+    };
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+
+    rip(&["purge"]).assert().failure();
+    rip(&["purge", "--match", "*.log"])
+        .write_stdin("n\n")
+        .assert()
+        .success()
+        .stdout(
+            is_match("^About to permanently delete 2 grave\\(s\\):\n  .*b.log\n  .*c.log\n")
+                .unwrap(),
+        );
+    assert_eq!(graves().len(), 5);
+
+    rip(&["purge", "--match", "*.log"])
+        .write_stdin("y\n")
+        .assert()
+        .success()
+        .stdout(is_match("Evicted 2 graves").unwrap());
+    assert!(!grave_of("b.log").exists() && !grave_of("c.log").exists());
+    assert_eq!(
+        graves(),
+        [grave_of("a.txt"), grave_of("d.txt"), grave_of("e.txt")]
+    );
+
+    let record = record::Record::new(&test_env.graveyard);
+    let id_of = |name: &str| {
+        record
+            .graves()
+            .unwrap()
+            .find(|grave| grave.orig == src.join(name))
+            .and_then(|grave| grave.id)
+            .unwrap()
+    };
+    let ids = format!("@{},{}", id_of("a.txt"), &id_of("d.txt")[..5]);
+    rip(&["-y", "purge", "--id", &ids]).assert().success();
+    assert_eq!(graves(), [grave_of("e.txt")]);
+
+    rip(&["-y", "purge", grave_of("e.txt").to_str().unwrap()])
+        .assert()
+        .success();
+    assert!(graves().is_empty());
+    assert!(!grave_of("e.txt").exists());
+    rip(&["-y", "purge", grave_of("e.txt").to_str().unwrap()])
+        .assert()
+        .failure()
+        .stdout(is_match("No grave at .*e.txt in the record").unwrap());
+}
+
+/// Test that --split-dirs gives each file in a directory its own entry,
+/// which can be restored or purged on its own, or along with the rest
+#[test]
+fn test_split_dirs() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    let dir = src.join("proj");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::create_dir_all(dir.join("empty")).unwrap();
+    fs::write(dir.join("a.txt"), "a").unwrap();
+    fs::write(dir.join("sub/b.txt"), "b").unwrap();
+    rip(&["--split-dirs", "proj"]).assert().success();
+    let grave = util::join_absolute(&test_env.graveyard, &dir);
+
+    let graves: Vec<record::RecordItem> = record::Record::new(&test_env.graveyard)
+        .graves()
+        .unwrap()
+        .collect();
+    let entries: Vec<(PathBuf, PathBuf, Option<record::FileKind>)> = graves
+        .iter()
+        .map(|item| (item.orig.clone(), item.dest.clone(), item.kind))
+        .collect();
+    assert_eq!(
+        entries,
+        [
+            (
+                dir.join("a.txt"),
+                grave.join("a.txt"),
+                Some(record::FileKind::File)
+            ),
+            (
+                dir.join("empty"),
+                grave.join("empty"),
+                Some(record::FileKind::Dir)
+            ),
+            (
+                dir.join("sub/b.txt"),
+                grave.join("sub/b.txt"),
+                Some(record::FileKind::File)
+            ),
+        ]
+    );
+    let op = graves[0].op.clone().unwrap();
+    assert!(graves.iter().all(|item| item.op.as_ref() == Some(&op)));
+    assert!(graves.iter().all(|item| item.id.as_ref() != Some(&op)));
+
+    // One file comes back by its ID, leaving the rest buried
+    let id = format!("@{}", graves[0].id.as_ref().unwrap());
+    rip(&["-u", &id]).assert().success();
+    assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "a");
+    assert!(!dir.join("sub").exists());
+
+    // A directory inside is purged by its path in the graveyard
+    let sub = grave.join("sub");
+    rip(&["-y", "purge", sub.to_str().unwrap()])
+        .assert()
+        .success();
+    assert!(!grave.join("sub/b.txt").exists());
+
+    // And what's left comes back by the ID of the whole directory
+    rip(&["-u", &format!("@{}", op)]).assert().success();
+    assert!(dir.join("empty").is_dir());
+    assert!(!grave.exists());
+
+    // Without a target, all of the last directory comes back
+    fs::create_dir_all(src.join("other")).unwrap();
+    fs::write(src.join("other/x.txt"), "x").unwrap();
+    fs::write(src.join("other/y.txt"), "y").unwrap();
+    cli_runner(["--graveyard", graveyard, "other"], Some(&src))
+        .env("RIP_SPLIT_DIRS", "1")
+        .assert()
+        .success();
+    rip(&["-u"]).assert().success();
+    assert!(src.join("other/x.txt").exists());
+    assert!(src.join("other/y.txt").exists());
+    assert_eq!(
+        record::Record::new(&test_env.graveyard)
+            .graves()
+            .unwrap()
+            .count(),
+        0
+    );
+}
+
+/// Test that files in a directory that can't be read are left where they
+/// are by --skip-unreadable, and the rest buried
+#[cfg(unix)]
+#[test]
+fn test_skip_unreadable() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::process::CommandExt;
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let dir = src.join("proj");
+    fs::create_dir(&dir).unwrap();
+    fs::write(dir.join("a.txt"), "alpha\n").unwrap();
+    let secret = dir.join("secret.txt");
+    fs::write(&secret, "bravo\n").unwrap();
+    fs::set_permissions(&secret, fs::Permissions::from_mode(0o000)).unwrap();
+
+    let args = [
+        "--graveyard",
+        graveyard,
+        "--strategy",
+        "copy",
+        "--skip-unreadable",
+        "proj",
+    ];
+    let mut cmd = cli_runner(args, Some(&src));
+    // Permissions don't keep root out, so as root, rip runs as nobody,
+    // who is given everything but the secret, and a copy of rip to run
+    if fs::File::open(&secret).is_ok() {
+        let nobody = 65534;
+        let tmpdir = test_env.src.parent().unwrap();
+        let rip = tmpdir.join("rip");
+        fs::copy(assert_cmd::cargo::cargo_bin("rip"), &rip).unwrap();
+        for entry in WalkDir::new(tmpdir) {
+            std::os::unix::fs::lchown(entry.unwrap().path(), Some(nobody), Some(nobody)).unwrap();
+        }
+        let mut as_nobody = std::process::Command::new(rip);
+        as_nobody
+            .args(args)
+            .current_dir(&src)
+            .env("RIP_STRATEGY", "copy")
+            .env("RIP_CONFIG", "")
+            .uid(nobody)
+            .gid(nobody);
+        cmd = assert_cmd::Command::from_std(as_nobody);
+    }
+    cmd.assert().success().stdout(format!(
+        "Warning: couldn't read {}, so left it where it was\n",
+        secret.display()
+    ));
+    let grave = util::join_absolute(&test_env.graveyard, &dir);
+    assert_eq!(fs::read_to_string(grave.join("a.txt")).unwrap(), "alpha\n");
+    assert!(!grave.join("secret.txt").exists());
+    assert!(!dir.join("a.txt").exists());
+    assert!(secret.exists());
+    fs::set_permissions(&secret, fs::Permissions::from_mode(0o644)).unwrap();
+}
+
+/// Test that a directory copied into the graveyard is removed once its
+/// grave holds everything, less what was deleted for good when asked
+#[test]
+fn test_copied_dir_is_checked() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let dir = src.join("proj");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/small.txt"), "small\n").unwrap();
+    fs::write(dir.join("big.bin"), vec![0; 2048]).unwrap();
+
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "--strategy",
+            "copy",
+            "--big-file",
+            "1K",
+            "proj",
+        ],
+        Some(&src),
+    )
+    .write_stdin("y\n")
+    .assert()
+    .success()
+    .stdout(is_match("Permanently delete this file instead\\?").unwrap());
+    assert!(!dir.exists());
+    let grave = util::join_absolute(&test_env.graveyard, &dir);
+    assert_eq!(
+        fs::read_to_string(grave.join("sub/small.txt")).unwrap(),
+        "small\n"
+    );
+    assert!(!grave.join("big.bin").exists());
+}
+
+#[rstest]
+fn test_explain() {
+    let test_env = TestEnv::new();
+    let data = TestData::new(&test_env, None);
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let grave = util::join_absolute(&test_env.graveyard, src.join("test_file.txt"));
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "--max-age",
+            "1w",
+            "--explain",
+            "test_file.txt",
+            "missing",
+        ],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success()
+    .stdout(format!(
+        "test_file.txt\n  \
+           source: {} (file)\n  \
+           graveyard: {} (from --graveyard; to be created)\n  \
+           protected: no\n  \
+           grave: {}\n  \
+           strategy: copy, so it would be copied, then removed\n  \
+           big file: no, 100 B is under 476.8 MiB\n  \
+           retention: graves older than 1w are purged first\n\
+         missing\n  \
+           source: doesn't exist, so burying it would fail\n",
+        src.join("test_file.txt").display(),
+        graveyard,
+        grave.display()
+    ));
+    // Nothing was done
+    assert!(data.path.exists());
+    assert!(!test_env.graveyard.exists());
+
+    cli_runner(["--graveyard", graveyard, "-s", "--explain"], None)
+        .assert()
+        .failure()
+        .stdout("Exception: --explain can only be used when burying\n");
+}
+
+#[rstest]
+fn test_read_only_commands_leave_no_graveyard() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&test_env.src));
+        cmd.args(args);
+        cmd
+    };
+
+    rip(&["graveyard"]).assert().success();
+    rip(&["-s"]).assert().success();
+    rip(&["-u"]).assert().failure();
+    rip(&["-y", "purge", "--id", "abcd"]).assert().failure();
+    rip(&["fsck"]).assert();
+    rip(&["missing.txt"]).assert().failure();
+    rip(&["-f", "missing.txt"]).assert().success();
+    assert!(!test_env.graveyard.exists());
+
+    // Burying is what makes it
+    TestData::new(&test_env, None);
+    rip(&["test_file.txt"]).assert().success();
+    assert!(test_env.graveyard.join(".record").exists());
+}
+
+/// Test that files with tabs and line breaks in their names round-trip
+#[cfg(unix)]
+#[test]
+fn test_awkward_names() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let names = ["tab\tname", "line\nbreak", "back\\slash\tand tab"];
+    for name in names {
+        fs::write(src.join(name), name).unwrap();
+    }
+    let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+    cmd.args(names).assert().success();
+    assert!(names.iter().all(|name| !src.join(name).exists()));
+
+    cli_runner(["--graveyard", graveyard, "fsck"], None)
+        .assert()
+        .success();
+    cli_runner(["--graveyard", graveyard, "-su"], Some(&src))
+        .assert()
+        .success();
+    for name in names {
+        assert_eq!(fs::read_to_string(src.join(name)).unwrap(), name);
+    }
+}
+
+/// Test that the graveyard is private, and that doctor can repair it
+#[cfg(unix)]
+#[rstest]
+fn test_graveyard_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let mode_of = |path: &PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    TestData::new(&test_env, Some(&PathBuf::from("a.txt")));
+    TestData::new(&test_env, Some(&PathBuf::from("b.txt")));
+
+    cli_runner(["--graveyard", graveyard, "a.txt"], Some(&test_env.src))
+        .assert()
+        .success()
+        .stdout("");
+    assert_eq!(mode_of(&test_env.graveyard), 0o700);
+
+    // Loosen the graveyard and a directory leading to the grave
+    let intermediate = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    );
+    for dir in [&test_env.graveyard, &intermediate] {
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    cli_runner(["--graveyard", graveyard, "b.txt"], Some(&test_env.src))
+        .assert()
+        .success()
+        .stdout(is_match("Warning: graveyard .* is accessible by other users \\(755\\)").unwrap());
+
+    cli_runner(["--graveyard", graveyard, "doctor"], None)
+        .assert()
+        .success()
+        .stdout(is_match(format!("{} is accessible", intermediate.display())).unwrap())
+        .stdout(is_match("rip doctor --fix-perms").unwrap());
+    cli_runner(["--graveyard", graveyard, "doctor", "--fix-perms"], None)
+        .assert()
+        .success()
+        .stdout(is_match("Fixed .* \\(was 755\\)").unwrap());
+    assert_eq!(mode_of(&test_env.graveyard), 0o700);
+    assert_eq!(mode_of(&intermediate), 0o700);
+    cli_runner(["--graveyard", graveyard, "doctor"], None)
+        .assert()
+        .success()
+        .stdout("Every grave is in the record\nGraveyard permissions are OK\n");
+}
+
+/// Test the policies for permissions of graves
+#[cfg(unix)]
+#[rstest]
+fn test_grave_permission_policy(
+    #[values("mirror", "private", "umask")] policy: &str,
+    #[values(false, true)] use_env: bool,
+) {
+    use std::os::unix::fs::PermissionsExt;
+    let test_env = TestEnv::new();
+    let mode_of = |path: &PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    let dir = test_env.src.join("dir");
+    fs::create_dir(&dir).unwrap();
+    let data = TestData::new(&test_env, Some(&PathBuf::from("dir/script.sh")));
+    fs::set_permissions(&data.path, fs::Permissions::from_mode(0o754)).unwrap();
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o775)).unwrap();
+
+    let mut cmd = cli_runner(
+        ["--graveyard", test_env.graveyard.to_str().unwrap(), "dir"],
+        Some(&test_env.src),
+    );
+    if use_env {
+        cmd.env("RIP_GRAVE_PERMS", policy);
+    } else {
+        cmd.arg("--grave-perms").arg(policy);
+    }
+    cmd.assert().success();
+
+    let grave_dir = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap().join("dir"),
+    );
+    let grave_file = grave_dir.join("script.sh");
+    let umask = util::umask();
+    let (dir_mode, file_mode) = match policy {
+        "mirror" => (0o775, 0o754),
+        "private" => (0o700, 0o700),
+        _ => (0o777 & !umask, 0o777 & !umask),
+    };
+    assert_eq!(mode_of(&grave_dir), dir_mode);
+    assert_eq!(mode_of(&grave_file), file_mode);
+}
+
+/// Test burying the target of a symlink rather than the link itself
+#[cfg(unix)]
+#[rstest]
+fn test_follow_symlinks(#[values(false, true)] follow: bool) {
+    use std::os::unix::fs::symlink;
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let data = TestData::new(&test_env, Some(&PathBuf::from("target.txt")));
+    let link = test_env.src.join("link");
+    symlink(&data.path, &link).unwrap();
+
+    let mut args = vec!["--graveyard", graveyard, "-v", "link"];
+    if follow {
+        args.push("--follow-symlinks");
+    }
+    let output = cli_runner(args, Some(&test_env.src))
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    if follow {
+        assert!(output.contains("Following symlink link to"));
+        // The link is left behind, dangling
+        assert!(fs::symlink_metadata(&link).is_ok());
+        assert!(!data.path.exists());
+        let grave = util::join_absolute(&test_env.graveyard, src.join("target.txt"));
+        assert_eq!(fs::read_to_string(grave).unwrap(), data.data);
+    } else {
+        assert!(output.contains("Burying symlink link itself"));
+        assert!(fs::symlink_metadata(&link).is_err());
+        assert!(data.path.exists());
+    }
+}
+
+/// Test that following a symlink loop fails instead of hanging
+#[cfg(unix)]
+#[rstest]
+fn test_follow_symlinks_loop() {
+    use std::os::unix::fs::symlink;
+    let test_env = TestEnv::new();
+    symlink(test_env.src.join("b"), test_env.src.join("a")).unwrap();
+    symlink(test_env.src.join("a"), test_env.src.join("b")).unwrap();
+
+    cli_runner(
+        [
+            "--graveyard",
+            test_env.graveyard.to_str().unwrap(),
+            "--follow-symlinks",
+            "a",
+        ],
+        Some(&test_env.src),
+    )
+    .assert()
+    .failure()
+    .stdout(is_match("Symlink loop").unwrap());
+    assert!(fs::symlink_metadata(test_env.src.join("a")).is_ok());
+}
+
+/// Test that relative symlinks are checked when a grave is restored
+/// under a different name
+#[cfg(unix)]
+#[rstest]
+fn test_retarget_symlinks(#[values(false, true)] retarget: bool) {
+    use std::os::unix::fs::symlink;
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let proj = test_env.src.join("proj");
+    fs::create_dir(&proj).unwrap();
+    TestData::new(&test_env, Some(&PathBuf::from("proj/b")));
+    // One link goes through the tree's own name, one leaves the tree
+    symlink("../proj/b", proj.join("a")).unwrap();
+    symlink("../outside", proj.join("c")).unwrap();
+
+    cli_runner(["--graveyard", graveyard, "proj"], Some(&test_env.src))
+        .assert()
+        .success();
+    fs::create_dir(&proj).unwrap();
+
+    let mut args = vec!["--graveyard", graveyard, "-u"];
+    if retarget {
+        args.push("--retarget-symlinks");
+    }
+    let output = cli_runner(args, Some(&test_env.src))
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+
+    let restored = test_env.src.join("proj~1");
+    if retarget {
+        assert!(output.contains("Retargeted symlink"));
+        assert_eq!(
+            fs::read_link(restored.join("a")).unwrap(),
+            PathBuf::from("b")
+        );
+    } else {
+        assert!(output.contains("no longer points to"));
+        assert_eq!(
+            fs::read_link(restored.join("a")).unwrap(),
+            PathBuf::from("../proj/b")
+        );
+    }
+    // Links leaving the tree still point to the same place
+    assert_eq!(
+        fs::read_link(restored.join("c")).unwrap(),
+        PathBuf::from("../outside")
+    );
+    assert!(!output.contains("proj~1/c"));
+}
+
+/// Test that symlinks inside a buried directory can't lead the copy
+/// outside of the grave or around a loop
+#[cfg(unix)]
+#[rstest]
+fn test_hostile_symlinks() {
+    use std::os::unix::fs::symlink;
+    let test_env = TestEnv::new();
+    let outside = test_env.src.join("outside");
+    fs::create_dir(&outside).unwrap();
+    let secret = TestData::new(&test_env, Some(&PathBuf::from("outside/secret")));
+
+    let dir = test_env.src.join("dir");
+    fs::create_dir(&dir).unwrap();
+    symlink(&outside, dir.join("escape")).unwrap();
+    symlink("../..", dir.join("up")).unwrap();
+    symlink(".", dir.join("loop")).unwrap();
+    symlink("self", dir.join("self")).unwrap();
+
+    cli_runner(
+        ["--graveyard", test_env.graveyard.to_str().unwrap(), "dir"],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+
+    // The links are buried as links, and what they pointed to is untouched
+    let grave = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap().join("dir"),
+    );
+    for link in ["escape", "up", "loop", "self"] {
+        let metadata = fs::symlink_metadata(grave.join(link)).unwrap();
+        assert!(metadata.file_type().is_symlink());
+    }
+    assert_eq!(fs::read_to_string(&secret.path).unwrap(), secret.data);
+    assert!(!dir.exists());
+}
+
+/// Test recording paths through a symlinked directory as typed
+#[cfg(unix)]
+#[rstest]
+fn test_path_style(#[values("resolved", "logical")] style: &str) {
+    use std::os::unix::fs::symlink;
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let real = test_env.src.join("real");
+    let alias = test_env.src.join("alias");
+    fs::create_dir(&real).unwrap();
+    symlink(&real, &alias).unwrap();
+    let data = TestData::new(&test_env, Some(&PathBuf::from("real/file.txt")));
+
+    let run = |args: &[&str]| {
+        let mut cmd = cli_runner(args, Some(&alias));
+        cmd.env("PWD", &alias).env("RIP_PATH_STYLE", style);
+        cmd.assert().success()
+    };
+    run(&["--graveyard", graveyard, "file.txt"]);
+
+    let graves: Vec<_> = record::Record::new(&test_env.graveyard)
+        .graves()
+        .unwrap()
+        .collect();
+    let expected_dir = match style {
+        "logical" => alias.clone(),
+        _ => dunce::canonicalize(&real).unwrap(),
+    };
+    assert_eq!(graves[0].orig, expected_dir.join("file.txt"));
+    assert_eq!(
+        graves[0].dest,
+        util::join_absolute(&test_env.graveyard, expected_dir.join("file.txt"))
+    );
+
+    // Seance and unbury work from the same directory
+    let seance = run(&["--graveyard", graveyard, "-s", "-v"])
+        .get_output()
+        .stdout
+        .clone();
+    let seance = String::from_utf8(seance).unwrap();
+    assert!(seance.contains(&format!("  file.txt  {}\n", graves[0].dest.display())));
+    run(&["--graveyard", graveyard, "-u"]);
+    assert_eq!(fs::read_to_string(&data.path).unwrap(), data.data);
+}
+
+/// Test that seance shows original paths relative to the current directory
+#[rstest]
+fn test_seance_paths() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    fs::create_dir(test_env.src.join("dir")).unwrap();
+    let data = TestData::new(&test_env, Some(&PathBuf::from("dir").join("file.txt")));
+    cli_runner(
+        ["--graveyard", graveyard, "dir/file.txt"],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let orig = src.join("dir").join("file.txt");
+    let grave = util::join_absolute(&test_env.graveyard, &orig);
+
+    let seance = |args: &[&str], cwd: &PathBuf| {
+        let mut all = vec!["--graveyard", graveyard, "-s"];
+        all.extend(args);
+        quick_cmd_output(&mut cli_runner(all, Some(cwd)))
+    };
+    let relative = PathBuf::from("dir").join("file.txt");
+    let output = seance(&[], &test_env.src);
+    assert!(output.lines().next().unwrap().ends_with("\tpath"));
+    assert!(
+        output.contains(&format!("\t{}\n", relative.display())),
+        "{}",
+        output
+    );
+    assert!(!output.contains(&grave.display().to_string()));
+    let output = seance(&[], &test_env.src.join("dir"));
+    assert!(output.contains("\tfile.txt\n"), "{}", output);
+
+    let output = seance(&["--absolute"], &test_env.src);
+    assert!(
+        output.contains(&format!("\t{}\n", orig.display())),
+        "{}",
+        output
+    );
+
+    // Verbose output lines up, with the size, type and grave of each
+    let output = seance(&["-v"], &test_env.src);
+    let lines: Vec<&str> = output.lines().collect();
+    let header = lines[0];
+    assert!(header.starts_with("id"));
+    assert_eq!(
+        header.split_whitespace().collect::<Vec<_>>(),
+        ["id", "deletion_time", "size", "type", "path", "grave"]
+    );
+    assert!(
+        lines[1].ends_with(&format!("  {}  {}", relative.display(), grave.display())),
+        "{}",
+        output
+    );
+    assert_eq!(
+        header.find("path"),
+        lines[1].find(&relative.display().to_string())
+    );
+    assert_eq!(
+        header.find("grave"),
+        lines[1].find(&grave.display().to_string())
+    );
+
+    cli_runner(["--absolute", "x"], Some(&test_env.src))
+        .assert()
+        .failure()
+        .stdout(is_match("--absolute can only be used with -s,--seance").unwrap());
+    assert!(!data.path.exists());
+
+    // So does unbury's, after what was restored
+    let output = quick_cmd_output(&mut cli_runner(
+        ["--graveyard", graveyard, "-u", "-v"],
+        Some(&test_env.src),
+    ));
+    let table: Vec<&str> = output.lines().skip_while(|line| !line.is_empty()).collect();
+    assert!(table[1].starts_with("deletion_time"), "{}", output);
+    assert!(table[2].ends_with(&format!("  {}  {}", orig.display(), grave.display())));
+    assert!(data.path.exists());
+}
+
+/// Test resolving targets and seance from another directory
+#[rstest]
+fn test_cwd_override(#[values("--cwd", "--relative-to")] flag: &str) {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = test_env.src.to_str().unwrap();
+    let data = TestData::new(&test_env, Some(&PathBuf::from("file.txt")));
+    // Run from somewhere else entirely
+    let elsewhere = tempdir().unwrap();
+    let elsewhere = Some(&elsewhere.path().to_path_buf());
+
+    cli_runner(["--graveyard", graveyard, flag, src, "file.txt"], elsewhere)
+        .assert()
+        .success();
+    assert!(!data.path.exists());
+
+    let grave = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    );
+    // Graves are listed relative to the overridden directory
+    cli_runner(["--graveyard", graveyard, flag, src, "-s"], elsewhere)
+        .assert()
+        .success()
+        .stdout(is_match("\tfile.txt\n").unwrap());
+    cli_runner(["--graveyard", graveyard, flag, src, "-s", "-v"], elsewhere)
+        .assert()
+        .success()
+        .stdout(is_match(format!("{}", grave.join("file.txt").display())).unwrap());
+    cli_runner(["--graveyard", graveyard, flag, src, "recent"], elsewhere)
+        .assert()
+        .success()
+        .stdout(is_match("file.txt").unwrap());
+    cli_runner([flag, src, "graveyard", "-s"], elsewhere)
+        .assert()
+        .success()
+        .stdout(is_match(format!("{}$", dunce::canonicalize(src).unwrap().display())).unwrap());
+
+    cli_runner(["--graveyard", graveyard, flag, "missing", "-s"], elsewhere)
+        .assert()
+        .failure()
+        .stdout(is_match("missing is not a directory").unwrap());
+}
+
+/// Test that runs with their own contexts can happen concurrently
+#[rstest]
+fn test_concurrent_contexts() {
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            std::thread::spawn(|| {
+                let test_env = TestEnv::new();
+                let test_data = TestData::new(&test_env, Some(&PathBuf::from("file.txt")));
+                let ctx = Context::new(&test_env.src)
+                    .with_var("RIP_GRAVEYARD", test_env.graveyard.to_str().unwrap());
+                // Relative targets resolve against the context's directory
+                let bury = Args {
+                    targets: vec![PathBuf::from("file.txt")],
+                    ..Args::default()
+                };
+                rip2::run_with(bury, &ctx, TestMode, &mut Vec::new()).unwrap();
+                assert!(!test_data.path.exists());
+                let unbury = Args {
+                    unbury: Some(Vec::new()),
+                    ..Args::default()
+                };
+                rip2::run_with(unbury, &ctx, TestMode, &mut Vec::new()).unwrap();
+                assert_eq!(fs::read_to_string(&test_data.path).unwrap(), test_data.data);
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+/// Test that --no-copy renames or fails, but never copies
+#[cfg(unix)]
+#[rstest]
+fn test_no_copy() {
+    use std::os::unix::fs::MetadataExt;
+    let test_env = TestEnv::new();
+    let test_data = TestData::new(&test_env, None);
+    let inode = fs::metadata(&test_data.path).unwrap().ino();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+
+    // Renamed in spite of RIP_STRATEGY=copy, so it's the same file
+    cli_runner(
+        ["--graveyard", graveyard, "--no-copy", "test_file.txt"],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+    let grave = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    )
+    .join("test_file.txt");
+    assert_eq!(fs::metadata(&grave).unwrap().ino(), inode);
+
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "--no-copy",
+            "--strategy",
+            "copy",
+            "x",
+        ],
+        Some(&test_env.src),
+    )
+    .assert()
+    .failure()
+    .stdout(is_match("--no-copy can only be used with --strategy auto or rename").unwrap());
+
+    // Across filesystems, the target is left where it is
+    let shm = std::path::Path::new("/dev/shm");
+    let same_device = |a: &std::path::Path, b: &std::path::Path| {
+        fs::metadata(a).unwrap().dev() == fs::metadata(b).unwrap().dev()
+    };
+    if !shm.is_dir() || same_device(shm, &test_env.graveyard) {
+        return;
+    }
+    let elsewhere = tempfile::tempdir_in(shm).unwrap();
+    let target = elsewhere.path().join("far.txt");
+    fs::write(&target, "far away").unwrap();
+    let output = quick_cmd_output(&mut cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "--no-copy",
+            target.to_str().unwrap(),
+        ],
+        None,
+    ));
+    assert!(output.contains("without copying (--no-copy)"), "{}", output);
+    assert_eq!(fs::read_to_string(&target).unwrap(), "far away");
+}
+
+/// Test that the library's bury returns where each target was buried
+#[rstest]
+fn test_bury_api() {
+    let test_env = TestEnv::new();
+    let first = TestData::new(&test_env, Some(&PathBuf::from("first.txt")));
+    let second = TestData::new(&test_env, Some(&PathBuf::from("second.txt")));
+    let ctx = Context::new(&test_env.src);
+    let graves = rip2::bury(
+        Args {
+            targets: vec![
+                "first.txt".into(),
+                "missing.txt".into(),
+                "second.txt".into(),
+            ],
+            graveyard: Some(test_env.graveyard.clone()),
+            force: true,
+            ..Args::default()
+        },
+        &ctx,
+        TestMode,
+        &mut Vec::new(),
+    )
+    .unwrap();
+
+    // The missing target is skipped under -f, so has no entry
+    assert_eq!(graves.len(), 2);
+    for (grave, data) in graves.iter().zip([&first, &second]) {
+        assert_eq!(fs::read_to_string(&grave.dest).unwrap(), data.data);
+        assert!(grave.dest.starts_with(&test_env.graveyard));
+        assert_eq!(
+            grave.orig.file_name(),
+            data.path.file_name(),
+            "{:?} was buried from the wrong place",
+            grave
+        );
+    }
+    let record: Vec<rip2::RecordItem> = rip2::Record::new(&test_env.graveyard)
+        .graves()
+        .unwrap()
+        .collect();
+    assert_eq!(record, graves);
+
+    let seance = Args {
+        seance: true,
+        ..Args::default()
+    };
+    assert!(rip2::bury(seance, &ctx, TestMode, &mut Vec::new()).is_err());
+}
+
+/// Test retention against an injected clock rather than the wall clock
+#[rstest]
+fn test_fixed_clock_retention() {
+    use rip2::context::FixedClock;
+
+    let test_env = TestEnv::new();
+    let old = TestData::new(&test_env, Some(&PathBuf::from("old.txt")));
+    let new = TestData::new(&test_env, Some(&PathBuf::from("new.txt")));
+    let start = chrono::DateTime::parse_from_rfc3339("2024-01-01T12:00:00+00:00")
+        .unwrap()
+        .with_timezone(&chrono::Local);
+    let bury = |target: &PathBuf, now| {
+        let ctx = Context::new(&test_env.src).with_clock(FixedClock(now));
+        let mut log = Vec::new();
+        rip2::run_with(
+            Args {
+                targets: vec![target.clone()],
+                graveyard: Some(test_env.graveyard.clone()),
+                max_age: Some(chrono::Duration::days(30)),
+                ..Args::default()
+            },
+            &ctx,
+            TestMode,
+            &mut log,
+        )
+        .unwrap();
+        String::from_utf8(log).unwrap()
+    };
+
+    bury(&old.path, start);
+    let graves: Vec<_> = record::Record::new(&test_env.graveyard)
+        .graves()
+        .unwrap()
+        .collect();
+    assert_eq!(graves[0].time, start.to_rfc3339());
+
+    // A day short of the retention period, nothing is purged
+    let log = bury(&new.path, start + chrono::Duration::days(29));
+    assert!(!log.contains("Evicted"));
+    assert!(graves[0].dest.exists());
+
+    // Burying again once it's past the retention period purges the old grave
+    let newer = TestData::new(&test_env, Some(&PathBuf::from("newer.txt")));
+    let log = bury(&newer.path, start + chrono::Duration::days(31));
+    assert!(log.contains(&format!("Evicted {}", graves[0].dest.display())));
+    assert!(!graves[0].dest.exists());
+}
+
+/// Test picking graves to unbury or show by when they were buried
+#[rstest]
+fn test_time_window() {
+    use rip2::context::FixedClock;
+
+    let test_env = TestEnv::new();
+    let old = TestData::new(&test_env, Some(&PathBuf::from("old.txt")));
+    let new = TestData::new(&test_env, Some(&PathBuf::from("new.txt")));
+    let newer = TestData::new(&test_env, Some(&PathBuf::from("newer.txt")));
+    let start = chrono::DateTime::parse_from_rfc3339("2024-01-01T12:00:00+00:00")
+        .unwrap()
+        .with_timezone(&chrono::Local);
+    let now = start + chrono::Duration::days(2) + chrono::Duration::hours(1);
+    let run = |args: Args, now| {
+        let ctx = Context::new(&test_env.src).with_clock(FixedClock(now));
+        let mut log = Vec::new();
+        rip2::run_with(
+            Args {
+                graveyard: Some(test_env.graveyard.clone()),
+                ..args
+            },
+            &ctx,
+            TestMode,
+            &mut log,
+        )
+        .unwrap();
+        String::from_utf8(log).unwrap()
+    };
+    let bury = |target: &PathBuf, now| {
+        run(
+            Args {
+                targets: vec![target.clone()],
+                ..Args::default()
+            },
+            now,
+        )
+    };
+    bury(&old.path, start);
+    bury(&new.path, start + chrono::Duration::days(2));
+    bury(&newer.path, start + chrono::Duration::days(2));
+
+    let seance = run(
+        Args {
+            seance: true,
+            since: Some(chrono::Duration::days(1)),
+            ..Args::default()
+        },
+        now,
+    );
+    assert!(!seance.contains("old.txt"));
+    assert!(seance.contains("new.txt"));
+    assert!(seance.contains("newer.txt"));
+
+    // Without -s, a window picks from the graves of the current directory
+    run(
+        Args {
+            unbury: Some(Vec::new()),
+            before: Some(chrono::Duration::days(1)),
+            ..Args::default()
+        },
+        now,
+    );
+    assert_eq!(fs::read_to_string(&old.path).unwrap(), old.data);
+    assert!(!new.path.exists());
+
+    let log = run(
+        Args {
+            unbury: Some(Vec::new()),
+            since: Some(chrono::Duration::minutes(30)),
+            ..Args::default()
+        },
+        now,
+    );
+    assert_eq!(log, "Nothing here was buried in the last 30m\n");
+    assert!(!new.path.exists());
+
+    // Graves named are only unburied if they're in the window
+    let grave = util::join_absolute(&test_env.graveyard, &newer.path);
+    let log = run(
+        Args {
+            unbury: Some(Vec::new()),
+            targets: vec![grave.clone()],
+            before: Some(chrono::Duration::days(1)),
+            ..Args::default()
+        },
+        now,
+    );
+    assert_eq!(log, "None of those graves were buried more than 1d ago\n");
+    run(
+        Args {
+            unbury: Some(Vec::new()),
+            targets: vec![grave],
+            since: Some(chrono::Duration::days(1)),
+            ..Args::default()
+        },
+        now,
+    );
+    assert_eq!(fs::read_to_string(&newer.path).unwrap(), newer.data);
+    assert!(!new.path.exists());
+
+    let mut cmd = cli_runner(["--since", "1h"], Some(&test_env.src));
+    cmd.assert().failure();
+    let mut cmd = cli_runner(
+        ["-s", "--since", "1h", "--before", "2h"],
+        Some(&test_env.src),
+    );
+    cmd.assert().failure().stdout(predicates::str::contains(
+        "--since must be longer ago than --before",
+    ));
+}
+
+/// Test restoring graves whose original directory was renamed
+#[rstest]
+fn test_remap_missing_parent(#[values(false, true)] remap: bool) {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    fs::create_dir_all(test_env.src.join("foo").join("sub")).unwrap();
+    let a = TestData::new(&test_env, Some(&PathBuf::from("foo/sub/a.txt")));
+    let b = TestData::new(&test_env, Some(&PathBuf::from("foo/sub/b.txt")));
+
+    cli_runner(
+        ["--graveyard", graveyard, "foo/sub/a.txt", "foo/sub/b.txt"],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+    fs::rename(test_env.src.join("foo"), test_env.src.join("bar")).unwrap();
+
+    let mut args = vec!["--graveyard", graveyard, "-s", "-u"];
+    if remap {
+        args.extend(["--remap", "foo=bar"]);
+    }
+    let output = cli_runner(args, Some(&test_env.src))
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+
+    let bar = test_env.src.join("bar").join("sub");
+    if remap {
+        for data in [&a, &b] {
+            let name = data.path.file_name().unwrap();
+            assert_eq!(fs::read_to_string(bar.join(name)).unwrap(), data.data);
+        }
+        assert!(!test_env.src.join("foo").exists());
+        assert!(!output.contains("is missing"));
+    } else {
+        // Without an answer to the prompt, the old directory is recreated
+        assert!(output.contains(&format!(
+            "Original directory {} is missing",
+            dunce::canonicalize(&test_env.src)
+                .unwrap()
+                .join("foo")
+                .display()
+        )));
+        assert_eq!(fs::read_to_string(&a.path).unwrap(), a.data);
+        assert_eq!(fs::read_to_string(&b.path).unwrap(), b.data);
+    }
+}
+
+/// Test restoring graves into another directory with --to
+#[test]
+fn test_unbury_to() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    fs::create_dir_all(src.join("mnt")).unwrap();
+    let a = TestData::new(&test_env, Some(&PathBuf::from("mnt/a.txt")));
+    cli_runner(["--graveyard", graveyard, "mnt"], Some(&src))
+        .assert()
+        .success();
+    let grave = util::join_absolute(&test_env.graveyard, src.join("mnt"));
+
+    let rescued = src.join("rescued");
+    cli_runner(
+        ["--graveyard", graveyard, "-u", "--to", "rescued"],
+        Some(&src),
+    )
+    .assert()
+    .success()
+    .stdout(format!(
+        "Returned {} to {}\n",
+        grave.display(),
+        rescued.join("mnt").display()
+    ));
+    assert_eq!(
+        fs::read_to_string(rescued.join("mnt/a.txt")).unwrap(),
+        a.data
+    );
+    assert!(!src.join("mnt").exists());
+    assert!(!grave.exists());
+
+    // Only a rename within DIR counts as restoring under a different name
+    fs::create_dir(src.join("mnt")).unwrap();
+    cli_runner(["--graveyard", graveyard, "mnt"], Some(&src))
+        .assert()
+        .success();
+    cli_runner(
+        ["--graveyard", graveyard, "-u", "--to", "rescued"],
+        Some(&src),
+    )
+    .assert()
+    .success()
+    .stdout(format!(
+        "Returned {grave} to {renamed}\n\n\
+         1 files were restored under different names:\n\
+         requested\trestored\n\
+         {requested}\t{renamed}\n",
+        grave = grave.display(),
+        requested = rescued.join("mnt").display(),
+        renamed = rescued.join("mnt~1").display(),
+    ));
+
+    cli_runner(["--graveyard", graveyard, "--to", "rescued"], Some(&src))
+        .assert()
+        .failure()
+        .stdout("Exception: --to can only be used with -u,--unbury\n");
+}
+
+/// Test that directories gone by the time of unbury are recreated with
+/// the mode they had when the grave was buried, whatever the graveyard's
+/// copies look like
+#[cfg(unix)]
+#[rstest]
+fn test_unbury_recreates_parents(#[values("mirror", "private")] policy: &str) {
+    use std::os::unix::fs::PermissionsExt;
+    let test_env = TestEnv::new();
+    let mode_of = |path: &PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    let outer = test_env.src.join("outer");
+    let inner = outer.join("inner");
+    fs::create_dir_all(&inner).unwrap();
+    let data = TestData::new(&test_env, Some(&PathBuf::from("outer/inner/a.txt")));
+    fs::set_permissions(&inner, fs::Permissions::from_mode(0o751)).unwrap();
+    fs::set_permissions(&outer, fs::Permissions::from_mode(0o705)).unwrap();
+
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "--grave-perms",
+            policy,
+            "outer/inner/a.txt",
+        ],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+    fs::set_permissions(&outer, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::remove_dir_all(&outer).unwrap();
+
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&test_env.src))
+        .assert()
+        .success();
+
+    assert_eq!(fs::read_to_string(&data.path).unwrap(), data.data);
+    assert_eq!(mode_of(&inner), 0o751);
+    assert_eq!(mode_of(&outer), 0o705);
+}
+
+/// Test that protected graves lose write permission in the graveyard,
+/// and get it back when unburied, or can still be purged
+#[cfg(unix)]
+#[rstest]
+fn test_protect_graves(
+    #[values("read-only", "immutable")] protection: &str,
+    #[values(false, true)] decompose: bool,
+) {
+    use std::os::unix::fs::PermissionsExt;
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let writable = |path: &PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o222 != 0;
+    fs::create_dir(test_env.src.join("dir")).unwrap();
+    let data = TestData::new(&test_env, Some(&PathBuf::from("dir/a.txt")));
+    // A file also linked from outside, which renaming keeps shared
+    TestData::new(&test_env, Some(&PathBuf::from("dir/b.txt")));
+    let link = test_env.src.join("b-link.txt");
+    fs::hard_link(test_env.src.join("dir/b.txt"), &link).unwrap();
+
+    cli_runner(
+        ["--graveyard", graveyard, "--strategy", "rename", "dir"],
+        Some(&test_env.src),
+    )
+    .env("RIP_PROTECT", protection)
+    .assert()
+    .success();
+    fs::write(&link, "still writable").unwrap();
+
+    let grave_dir = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap().join("dir"),
+    );
+    assert!(!writable(&grave_dir));
+    assert!(!writable(&grave_dir.join("a.txt")));
+
+    if decompose {
+        cli_runner(
+            ["--graveyard", graveyard, "--decompose"],
+            Some(&test_env.src),
+        )
+        .write_stdin("y\n")
+        .assert()
+        .success();
+        assert!(!grave_dir.exists());
+    } else {
+        cli_runner(["--graveyard", graveyard, "-u"], Some(&test_env.src))
+            .assert()
+            .success();
+        assert_eq!(fs::read_to_string(&data.path).unwrap(), data.data);
+        assert!(writable(&data.path));
+        assert!(writable(&test_env.src.join("dir")));
+    }
+}
+
+/// Test that a hash-chained record survives rip's own edits,
+/// and that `rip verify` catches edits made behind its back
+#[rstest]
+fn test_chain_record() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    for name in ["a.txt", "b.txt", "c.txt", "d.txt"] {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    let verify = || cli_runner(["--graveyard", graveyard, "verify", "--record"], None);
+    let unbury_last = || {
+        cli_runner(["--graveyard", graveyard, "-u"], None)
+            .assert()
+            .success()
+    };
+
+    cli_runner(
+        ["--graveyard", graveyard, "a.txt", "b.txt", "c.txt"],
+        Some(&test_env.src),
+    )
+    .env("RIP_CHAIN_RECORD", "1")
+    .assert()
+    .success();
+    verify()
+        .assert()
+        .success()
+        .stdout(is_match("intact \\(3 of 3").unwrap());
+
+    // Unburying and annotating re-link the chain, and it carries on
+    // without being asked for
+    unbury_last();
+    let grave_a = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap().join("a.txt"),
+    );
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "note",
+            grave_a.to_str().unwrap(),
+            "hi",
+        ],
+        None,
+    )
+    .assert()
+    .success();
+    cli_runner(["--graveyard", graveyard, "d.txt"], Some(&test_env.src))
+        .assert()
+        .success();
+    verify()
+        .assert()
+        .success()
+        .stdout(is_match("intact \\(3 of 3").unwrap());
+
+    // Edit an entry behind rip's back
+    let record_path = test_env.graveyard.join(record::RECORD);
+    let contents = fs::read_to_string(&record_path).unwrap();
+    fs::write(&record_path, contents.replace("note=hi", "note=bye")).unwrap();
+    verify()
+        .assert()
+        .failure()
+        .stdout(is_match("Line 3: hash chain broken").unwrap());
+
+    // Tampering stays evident after rip rewrites the record
+    unbury_last();
+    verify()
+        .assert()
+        .failure()
+        .stdout(is_match("Line 3: hash chain broken").unwrap());
+}
+
+/// Test that burying and unburying work from inside the sandbox
+#[cfg(target_os = "linux")]
+#[rstest]
+fn test_sandbox(#[values(false, true)] use_env: bool) {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    fs::create_dir(test_env.src.join("dir")).unwrap();
+    let data = TestData::new(&test_env, Some(&PathBuf::from("dir/a.txt")));
+    let sandboxed = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&test_env.src));
+        cmd.args(args);
+        if use_env {
+            cmd.env("RIP_SANDBOX", "1");
+        } else {
+            cmd.arg("--sandbox");
+        }
+        cmd
+    };
+
+    sandboxed(&["dir/a.txt"]).assert().success();
+    assert!(!data.path.exists());
+    // Restoring recreates the missing directory
+    fs::remove_dir(test_env.src.join("dir")).unwrap();
+    sandboxed(&["-u"]).assert().success();
+    assert_eq!(fs::read_to_string(&data.path).unwrap(), data.data);
+}
+
+/// Test importing a config file, that its settings take effect
+/// below flags and environment variables, and exporting it again
+/// Test listing every setting with its value in the config file
+#[rstest]
+fn test_config_list() {
+    let test_env = TestEnv::new();
+    let config_path = test_env.src.join("config.toml");
+    fs::write(
+        &config_path,
+        "strategy = \"copy\"\nexclude = [\"*.o\"]\n\n[profiles.ci]\nforce = true\n",
+    )
+    .unwrap();
+    let list = |args: &[&str]| {
+        let mut cmd = cli_runner(args, Some(&test_env.src));
+        cmd.env("RIP_CONFIG", &config_path);
+        quick_cmd_output(&mut cmd)
+    };
+
+    let output = list(&["config", "list"]);
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), rip2::config::KEYS.len() + 1);
+    assert!(lines[0].starts_with("key"));
+    let row = |key: &str| {
+        let line = lines
+            .iter()
+            .find(|line| line.split_whitespace().next() == Some(key))
+            .unwrap();
+        line.split_whitespace()
+            .skip(1)
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    assert_eq!(row("strategy"), "$RIP_STRATEGY \"copy\"");
+    assert_eq!(row("exclude"), "$RIP_EXCLUDE [\"*.o\"]");
+    assert_eq!(row("force"), "$RIP_FORCE");
+
+    let output = list(&["--profile", "ci", "config", "list"]);
+    assert!(output.contains("true"));
+    assert!(!output.contains("\"copy\""));
+}
+
+#[rstest]
+fn test_config_import_export() {
+    let test_env = TestEnv::new();
+    let config_path = test_env.src.join("config").join("config.toml");
+    let config_cmd = |args: &[&str]| {
+        let mut cmd = cli_runner(args, Some(&test_env.src));
+        cmd.env("RIP_CONFIG", &config_path);
+        cmd
+    };
+    let settings = format!(
+        "graveyard = {:?}\nmax-age = \"30d\"\nchain-record = true\n",
+        test_env.graveyard.to_str().unwrap()
+    );
+    fs::write(test_env.src.join("fleet.toml"), &settings).unwrap();
+    fs::write(test_env.src.join("bad.toml"), "protect = \"sometimes\"\n").unwrap();
+
+    config_cmd(&["config", "import", "fleet.toml"])
+        .assert()
+        .success()
+        .stdout(is_match("Imported 3 setting").unwrap());
+    config_cmd(&["config", "import", "bad.toml"])
+        .assert()
+        .failure()
+        .stderr(is_match("Invalid protect: sometimes \\(expected one of off").unwrap());
+    config_cmd(&["config", "show"])
+        .assert()
+        .success()
+        .stdout(is_match("max-age = \"30d\"").unwrap());
+
+    // The graveyard comes from the config
+    let data = TestData::new(&test_env, Some(&PathBuf::from("a.txt")));
+    config_cmd(&["a.txt"]).assert().success();
+    assert!(!data.path.exists());
+    let grave = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap().join("a.txt"),
+    );
+    assert!(grave.exists());
+
+    let output = config_cmd(&["--max-age", "1d", "config", "show", "--effective"])
+        .env("RIP_CHAIN_RECORD", "0")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("max-age = \"86400s\"  # flag"));
+    assert!(output.contains("chain-record = false  # $RIP_CHAIN_RECORD"));
+    assert!(output.contains(&format!(
+        "graveyard = {:?}  # {}",
+        test_env.graveyard.to_str().unwrap(),
+        config_path.display()
+    )));
+    assert!(output.contains("strategy = \"copy\"  # $RIP_STRATEGY"));
+    assert!(output.contains("protect = \"off\"  # default"));
+
+    // Exporting captures what was chosen, but not the defaults
+    let output = config_cmd(&["config", "export"])
+        .env_remove("RIP_STRATEGY")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let exported = String::from_utf8(output).unwrap();
+    assert_eq!(exported.lines().count(), 3);
+    for line in settings.lines() {
+        assert!(exported.contains(line));
+    }
+}
+
+#[test]
+fn test_config_get_set_unset() {
+    let test_env = TestEnv::new();
+    let config_path = test_env.src.join("config.toml");
+    let config_cmd = |args: &[&str]| {
+        let mut cmd = cli_runner(args, Some(&test_env.src));
+        cmd.env("RIP_CONFIG", &config_path);
+        cmd
+    };
+
+    config_cmd(&["config", "get", "strategy"])
+        .assert()
+        .failure()
+        .stderr(is_match("strategy is not set in").unwrap());
+    config_cmd(&["config", "set", "strategy", "copy"])
+        .assert()
+        .success()
+        .stdout("strategy = \"copy\"\n");
+    config_cmd(&["config", "set", "sandbox", "yes"])
+        .assert()
+        .success();
+    config_cmd(&["config", "set", "max-age", "soon"])
+        .assert()
+        .failure()
+        .stderr(is_match("Invalid max-age").unwrap());
+    config_cmd(&["config", "set", "colour", "blue"])
+        .assert()
+        .failure()
+        .stderr(is_match("invalid value 'colour' for '<KEY>'").unwrap());
+    assert_eq!(
+        fs::read_to_string(&config_path).unwrap(),
+        "sandbox = true\nstrategy = \"copy\"\n"
+    );
+
+    config_cmd(&["config", "get", "strategy"])
+        .assert()
+        .success()
+        .stdout("copy\n");
+    config_cmd(&["config", "unset", "strategy"])
+        .assert()
+        .success()
+        .stdout("Removed strategy\n");
+    config_cmd(&["config", "unset", "strategy"])
+        .assert()
+        .success()
+        .stdout("strategy was not set\n");
+    assert_eq!(
+        fs::read_to_string(&config_path).unwrap(),
+        "sandbox = true\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_config_edit() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let test_env = TestEnv::new();
+    let config_path = test_env.src.join("config.toml");
+    let edit_path = test_env.src.join("config.toml.edit");
+    // An "editor" that replaces the file with the contents of $NEW_CONFIG
+    let editor = test_env.src.join("editor.sh");
+    fs::write(&editor, "#!/bin/sh\nprintf '%s' \"$NEW_CONFIG\" > \"$1\"\n").unwrap();
+    fs::set_permissions(&editor, fs::Permissions::from_mode(0o755)).unwrap();
+    let edit_cmd = |new_config: &str| {
+        let mut cmd = cli_runner(["config", "edit"], Some(&test_env.src));
+        cmd.env("RIP_CONFIG", &config_path)
+            .env_remove("VISUAL")
+            .env("EDITOR", &editor)
+            .env("NEW_CONFIG", new_config);
+        cmd
+    };
+
+    // Invalid edits leave the config alone, but are kept for the next edit
+    edit_cmd("strategy = \"teleport\"\n")
+        .assert()
+        .failure()
+        .stderr(is_match("Invalid strategy: teleport").unwrap());
+    assert!(!config_path.exists());
+    assert_eq!(
+        fs::read_to_string(&edit_path).unwrap(),
+        "strategy = \"teleport\"\n"
+    );
+
+    edit_cmd("# mine\nstrategy = \"copy\"\n")
+        .assert()
+        .success()
+        .stdout(is_match("Saved").unwrap());
+    assert!(!edit_path.exists());
+    // Edits are kept as written, comments and all
+    assert_eq!(
+        fs::read_to_string(&config_path).unwrap(),
+        "# mine\nstrategy = \"copy\"\n"
+    );
+
+    // A failing editor changes nothing
+    cli_runner(["config", "edit"], Some(&test_env.src))
+        .env("RIP_CONFIG", &config_path)
+        .env("VISUAL", "false")
+        .assert()
+        .failure()
+        .stderr(is_match("false exited with").unwrap());
+    assert_eq!(
+        fs::read_to_string(&config_path).unwrap(),
+        "# mine\nstrategy = \"copy\"\n"
+    );
+}
+
+/// Warnings are printed at the end, and fail the run under --strict
+#[cfg(unix)]
+#[test]
+fn test_strict_warnings() {
+    use std::os::unix::fs::PermissionsExt;
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    TestData::new(&test_env, Some(&PathBuf::from("a.txt")));
+    TestData::new(&test_env, Some(&PathBuf::from("b.txt")));
+    cli_runner(["--graveyard", graveyard, "a.txt"], Some(&test_env.src))
+        .assert()
+        .success();
+
+    // A line that isn't a grave is skipped, with a warning
+    let record_path = test_env.graveyard.join(record::RECORD);
+    let mut record_file = fs::OpenOptions::new()
+        .append(true)
+        .open(&record_path)
+        .unwrap();
+    writeln!(record_file, "garbage").unwrap();
+    cli_runner(["--graveyard", graveyard, "-s"], Some(&test_env.src))
+        .assert()
+        .success()
+        .stdout(
+            is_match("a.txt\nWarning: skipped unreadable line\\(s\\) 3 of the record\n$").unwrap(),
+        );
+    cli_runner(["--graveyard", graveyard, "-s", "--strict"], Some(&test_env.src))
+        .assert()
+        .failure()
+        .stdout(is_match("Warning: skipped unreadable line\\(s\\) 3 of the record\nException: 1 warning\\(s\\), which --strict treats as errors").unwrap());
+    fs::write(
+        &record_path,
+        fs::read_to_string(&record_path)
+            .unwrap()
+            .replace("garbage\n", ""),
+    )
+    .unwrap();
+
+    // Strictness can also come from the environment, and the work is
+    // still done before the run fails
+    fs::set_permissions(&test_env.graveyard, fs::Permissions::from_mode(0o755)).unwrap();
+    cli_runner(["--graveyard", graveyard, "b.txt"], Some(&test_env.src))
+        .env("RIP_STRICT", "1")
+        .assert()
+        .failure()
+        .stdout(is_match("Warning: graveyard .* is accessible by other users").unwrap());
+    assert!(!test_env.src.join("b.txt").exists());
+}
+
+/// Test that a lost record can be rebuilt from the graveyard
+#[test]
+fn test_rebuild_record() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    fs::create_dir(src.join("dir")).unwrap();
+    for name in ["a.txt", "b.txt", "dir/c.txt"] {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    cli_runner(["--graveyard", graveyard, "a.txt", "dir"], Some(&src))
+        .assert()
+        .success();
+    // Bury b.txt twice, so the second grave is renamed
+    for _ in 0..2 {
+        TestData::new(&test_env, Some(&PathBuf::from("b.txt")));
+        cli_runner(["--graveyard", graveyard, "b.txt"], Some(&src))
+            .assert()
+            .success();
+    }
+
+    fs::remove_file(test_env.graveyard.join(record::RECORD)).unwrap();
+    cli_runner(["--graveyard", graveyard, "-s"], Some(&src))
+        .assert()
+        .success()
+        .stdout(is_match("Warning: the record is empty, but the graveyard isn't").unwrap());
+    cli_runner(["--graveyard", graveyard, "doctor"], None)
+        .assert()
+        .success()
+        .stdout(is_match("b.txt~1 is missing from the record").unwrap())
+        .stdout(is_match("rip doctor --adopt` to add 4 grave\\(s\\)").unwrap());
+
+    cli_runner(
+        ["--graveyard", graveyard, "doctor", "--rebuild-record"],
+        None,
+    )
+    .assert()
+    .success()
+    .stdout(is_match("^Recovered 4 grave\\(s\\) into the record\n").unwrap());
+    let graves: Vec<_> = record::Record::new(&test_env.graveyard)
+        .graves()
+        .unwrap()
+        .map(|grave| (grave.orig, grave.dest))
+        .collect();
+    let grave_of = |name: &str| util::join_absolute(&test_env.graveyard, src.join(name));
+    for (orig, dest) in [
+        ("a.txt", "a.txt"),
+        ("dir", "dir"),
+        ("b.txt", "b.txt"),
+        ("b.txt", "b.txt~1"),
+    ] {
+        assert!(graves.contains(&(src.join(orig), grave_of(dest))));
+    }
+    assert_eq!(graves.len(), 4);
+
+    // Everything can be restored again
+    cli_runner(["--graveyard", graveyard, "-su"], Some(&src))
+        .assert()
+        .success();
+    assert!(src.join("a.txt").exists());
+    assert!(src.join("dir/c.txt").exists());
+    assert!(src.join("b.txt").exists());
+}
+
+/// Test that graves copied into the graveyard by hand can be adopted
+#[test]
+fn test_adopt_orphans() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    TestData::new(&test_env, Some(&PathBuf::from("a.txt")));
+    cli_runner(["--graveyard", graveyard, "a.txt"], Some(&src))
+        .assert()
+        .success();
+    let orphan = util::join_absolute(&test_env.graveyard, src.join("manual.txt"));
+    fs::write(&orphan, "copied by hand").unwrap();
+
+    cli_runner(["--graveyard", graveyard, "doctor", "--orphans"], None)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", orphan.display()));
+    cli_runner(["--graveyard", graveyard, "doctor", "--adopt"], None)
+        .assert()
+        .success()
+        .stdout(
+            is_match("^Adopted .*manual.txt from .*manual.txt\nAdopted 1 grave\\(s\\)").unwrap(),
+        );
+    cli_runner(["--graveyard", graveyard, "doctor", "--orphans"], None)
+        .assert()
+        .success()
+        .stdout("");
+    cli_runner(["--graveyard", graveyard, "-s"], Some(&src))
+        .assert()
+        .success()
+        .stdout(is_match("\tpath\tstatus\n.*manual.txt\tadopted\n.*a.txt\t\n").unwrap());
+
+    // Adopted graves count as the oldest, so the last bury comes back first
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&src))
+        .assert()
+        .success();
+    assert!(src.join("a.txt").exists());
+    cli_runner(["--graveyard", graveyard, "-u"], Some(&src))
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(src.join("manual.txt")).unwrap(),
+        "copied by hand"
+    );
+}
+
+/// Test that fsck finds and repairs disagreements between the record and the graveyard
+#[test]
+fn test_fsck() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    for name in ["a.txt", "b.txt"] {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    cli_runner(["--graveyard", graveyard, "a.txt", "b.txt"], Some(&src))
+        .assert()
+        .success();
+    cli_runner(["--graveyard", graveyard, "fsck"], None)
+        .assert()
+        .success()
+        .stdout("The record and the graveyard agree\n");
+
+    let grave_of = |name: &str| util::join_absolute(&test_env.graveyard, src.join(name));
+    fs::remove_file(grave_of("b.txt")).unwrap();
+    fs::write(grave_of("manual.txt"), "copied by hand").unwrap();
+    let record_path = test_env.graveyard.join(record::RECORD);
+    let contents = fs::read_to_string(&record_path).unwrap();
+    let a_line = contents
+        .lines()
+        .find(|line| line.contains("a.txt"))
+        .unwrap()
+        .to_string();
+    fs::write(
+        &record_path,
+        format!("{}not a grave\n{}\n", contents, a_line),
+    )
+    .unwrap();
+
+    cli_runner(["--graveyard", graveyard, "fsck"], None)
+        .assert()
+        .failure()
+        .stdout(is_match("Line 4: unreadable\n").unwrap())
+        .stdout(is_match("b.txt is in the record, but not in the graveyard\n").unwrap())
+        .stdout(is_match("a.txt is in the record 2 times\n").unwrap())
+        .stdout(is_match("manual.txt is missing from the record\n").unwrap())
+        .stdout(is_match("Found 4 problem\\(s\\); run `rip fsck --repair`").unwrap());
+
+    cli_runner(["--graveyard", graveyard, "fsck", "--repair"], None)
+        .assert()
+        .success()
+        .stdout(is_match("Dropped 1 unreadable line\\(s\\)\n").unwrap())
+        .stdout(is_match("Adopted 1 grave\\(s\\) into the record\n").unwrap());
+    cli_runner(["--graveyard", graveyard, "fsck"], None)
+        .assert()
+        .success()
+        .stdout("The record and the graveyard agree\n");
+    let graves: Vec<_> = record::Record::new(&test_env.graveyard)
+        .graves()
+        .unwrap()
+        .map(|grave| grave.dest)
+        .collect();
+    assert_eq!(graves, vec![grave_of("manual.txt"), grave_of("a.txt")]);
+}
+
+#[rstest]
+fn test_migrate() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let grave_of = |name: &str| util::join_absolute(&test_env.graveyard, src.join(name));
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    TestData::new(&test_env, Some(&PathBuf::from("new.txt")));
+    rip(&["new.txt"]).assert().success();
+    for name in ["old.txt", "older.txt"] {
+        fs::write(grave_of(name), name).unwrap();
+    }
+    // As the original rip left it, with no header, and then rip2 added to
+    let record_path = test_env.graveyard.join(record::RECORD);
+    let new_line = fs::read_to_string(&record_path)
+        .unwrap()
+        .lines()
+        .nth(1)
+        .unwrap()
+        .to_string();
+    let legacy = format!(
+        "Sun Jun 10 12:30:45 2018\t{}\t{}\nMon Jul  2 09:05:01 2018\t{}\t{}\n{}\n",
+        src.join("older.txt").display(),
+        grave_of("older.txt").display(),
+        src.join("old.txt").display(),
+        grave_of("old.txt").display(),
+        new_line,
+    );
+    fs::write(&record_path, &legacy).unwrap();
+
+    rip(&["-s"])
+        .assert()
+        .success()
+        .stdout(is_match("Warning: .*run `rip migrate` to convert it").unwrap());
+    fs::write(&record_path, format!("{}not a grave\n", legacy)).unwrap();
+    rip(&["migrate"])
+        .assert()
+        .failure()
+        .stderr(is_match("^Line 4 of the record is in neither").unwrap());
+    fs::write(&record_path, &legacy).unwrap();
+    rip(&["migrate"])
+        .assert()
+        .success()
+        .stdout("Migrated 2 grave(s) from the original rip's record\n");
+
+    let graves: Vec<record::RecordItem> = record::Record::new(&test_env.graveyard)
+        .graves()
+        .unwrap()
+        .collect();
+    assert_eq!(graves.len(), 3);
+    assert!(graves[0].time.starts_with("2018-06-10T12:30:45"));
+    assert!(graves.iter().all(|grave| grave.id.is_some()));
+    assert_eq!(record::RecordItem::new(&new_line).unwrap(), graves[2]);
+    rip(&["-s", "--strict"]).assert().success();
+    rip(&["migrate"])
+        .assert()
+        .success()
+        .stdout("The record is already in rip2's format\n");
+}
+
+/// Test that burying many targets at once asks once, with a summary
+#[test]
+fn test_many_targets_summary() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let names: Vec<String> = (0..=rip2::MANY_TARGETS)
+        .map(|i| format!("file{}.txt", i))
+        .collect();
+    for name in &names {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    fs::write(test_env.src.join("file0.txt"), vec![b'x'; 4096]).unwrap();
+    let rip = |extra: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&test_env.src));
+        cmd.args(extra).args(&names);
+        cmd
+    };
+
+    rip(&[])
+        .write_stdin("n\n")
+        .assert()
+        .success()
+        .stdout(
+            is_match(format!(
+                "^About to bury {} targets: {} files and 0 directories, .* in total\nLargest:\n  4.0 KiB\tfile0.txt\n",
+                names.len(),
+                names.len()
+            ))
+            .unwrap(),
+        );
+    assert!(names.iter().all(|name| test_env.src.join(name).exists()));
+
+    rip(&[]).write_stdin("y\n").assert().success();
+    assert!(names.iter().all(|name| !test_env.src.join(name).exists()));
+
+    // --yes skips the question
+    for name in &names {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    rip(&["--yes"]).assert().success().stdout("");
+    assert!(names.iter().all(|name| !test_env.src.join(name).exists()));
+}
+
+/// Test the big file threshold and --force, from flags and the config
+#[test]
+fn test_big_file_and_force() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let config_path = test_env.src.join("config.toml");
+    fs::write(&config_path, "big-file = \"1K\"\n").unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&test_env.src));
+        cmd.args(args).env("RIP_CONFIG", &config_path);
+        cmd
+    };
+    let big = test_env.src.join("big.bin");
+
+    // The threshold comes from the config, and a flag overrides it
+    fs::write(&big, vec![0; 2048]).unwrap();
+    rip(&["big.bin"])
+        .write_stdin("n\n")
+        .assert()
+        .success()
+        .stdout(is_match("About to copy a big file .*big.bin is 2.0 KiB").unwrap());
+    assert!(!big.exists());
+    fs::write(&big, vec![0; 2048]).unwrap();
+    rip(&["--big-file", "1M", "big.bin"])
+        .assert()
+        .success()
+        .stdout("");
+
+    // --force doesn't ask, and skips targets that don't exist
+    fs::write(&big, vec![0; 2048]).unwrap();
+    rip(&["missing.txt", "big.bin"])
+        .assert()
+        .failure()
+        .stdout(is_match("Cannot remove missing.txt").unwrap());
+    rip(&["-f", "missing.txt", "big.bin"])
+        .assert()
+        .success()
+        .stdout("");
+    assert!(!big.exists());
+    let grave = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src)
+            .unwrap()
+            .join("big.bin~1"),
+    );
+    assert_eq!(fs::metadata(grave).unwrap().len(), 2048);
+}
+
+#[test]
+fn test_riprc() {
+    let test_env = TestEnv::new();
+    let project = dunce::canonicalize(&test_env.src).unwrap();
+    let sub = project.join("sub");
+    fs::create_dir(&sub).unwrap();
+    fs::write(
+        project.join(".riprc"),
+        "graveyard = \".graves\"\nexclude = [\"*.keep\", \"sub/secret.txt\"]\n",
+    )
+    .unwrap();
+    for name in ["a.txt", "b.keep", "secret.txt"] {
+        fs::write(sub.join(name), name).unwrap();
+    }
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(args, Some(&sub));
+        cmd.env_remove("RIP_GRAVEYARD").env_remove("RIP_EXCLUDE");
+        cmd
+    };
+
+    // The .riprc of an ancestor applies, with its graveyard relative to it
+    rip(&["a.txt", "b.keep", "secret.txt"])
+        .assert()
+        .success()
+        .stdout(
+            "Skipped b.keep: matches exclude pattern *.keep\n\
+             Skipped secret.txt: matches exclude pattern sub/secret.txt\n",
+        );
+    let graves = project.join(".graves");
+    assert!(util::join_absolute(&graves, sub.join("a.txt")).exists());
+    assert!(sub.join("b.keep").exists());
+    assert!(sub.join("secret.txt").exists());
+
+    let shown = quick_cmd_output(
+        rip(&["config", "show", "--effective"]).env("RIP_CONFIG", sub.join("none.toml")),
+    );
+    assert!(shown.contains(&format!(
+        "graveyard = \"{}\"  # {}",
+        graves.display(),
+        project.join(".riprc").display()
+    )));
+    assert!(shown.contains("exclude = [\"*.keep\", \"sub/secret.txt\"]"));
+
+    // Environment variables still override it
+    rip(&["b.keep"])
+        .env("RIP_EXCLUDE", "")
+        .env("RIP_GRAVEYARD", &test_env.graveyard)
+        .assert()
+        .success()
+        .stdout("");
+    assert!(util::join_absolute(&test_env.graveyard, sub.join("b.keep")).exists());
+}
+
+#[test]
+fn test_seance_by_type() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    fs::write(test_env.src.join("photo.JPG"), "not really").unwrap();
+    fs::write(test_env.src.join("paper.pdf"), "%PDF-1.7").unwrap();
+    fs::write(test_env.src.join("scan"), b"\x89PNG\r\n\x1a\n").unwrap();
+    fs::write(test_env.src.join("notes.txt"), "notes").unwrap();
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "photo.JPG",
+            "paper.pdf",
+            "scan",
+            "notes.txt",
+        ],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+
+    let seance = |types: &[&str]| {
+        let mut args = vec!["--graveyard", graveyard, "-s"];
+        for t in types {
+            args.extend(["--type", t]);
+        }
+        let output = quick_cmd_output(&mut cli_runner(args, Some(&test_env.src)));
+        ["photo.JPG", "paper.pdf", "scan", "notes.txt"]
+            .into_iter()
+            .filter(|name| output.contains(&format!("{}\n", name)))
+            .collect::<Vec<_>>()
+    };
+    // By extension, or by contents when there's no extension
+    assert_eq!(seance(&["image"]), ["photo.JPG", "scan"]);
+    assert_eq!(seance(&["pdf"]), ["paper.pdf"]);
+    assert_eq!(seance(&[".pdf", "text"]), ["paper.pdf", "notes.txt"]);
+    assert_eq!(seance(&[]).len(), 4);
+    let previews = quick_cmd_output(&mut cli_runner(
+        ["--graveyard", graveyard, "-s", "--preview", "--type", "pdf"],
+        Some(&test_env.src),
+    ));
+    assert!(previews.starts_with("id  \tdeletion_time      \tpath\tpreview\n"));
+
+    // Unburying what seance found only restores those of the type
+    cli_runner(
+        ["--graveyard", graveyard, "-s", "-u", "--type", "image"],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success();
+    assert!(test_env.src.join("scan").exists());
+    assert!(!test_env.src.join("paper.pdf").exists());
+
+    cli_runner(
+        ["--graveyard", graveyard, "--type", "pdf", "x"],
+        Some(&test_env.src),
+    )
+    .assert()
+    .failure()
+    .stdout(is_match("--type can only be used with -s,--seance").unwrap());
+}
+
+#[test]
+fn test_protected_paths() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let precious = test_env.src.join("precious");
+    fs::create_dir(&precious).unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&test_env.src));
+        cmd.args(args)
+            .env("RIP_PROTECTED_PATHS", &precious)
+            .env_remove("RIP_GRAVEYARD");
+        cmd
+    };
+
+    // The graveyard itself, and configured paths, are refused
+    fs::create_dir(&test_env.graveyard).unwrap();
+    rip(&[graveyard])
+        .assert()
+        .failure()
+        .stdout(is_match("Refusing to bury .*: it is a protected path").unwrap());
+    rip(&["precious"])
+        .assert()
+        .failure()
+        .stdout(is_match("Refusing to bury precious: it is a protected path").unwrap());
+    assert!(precious.exists());
+    rip(&["precious/."]).assert().failure();
+
+    // Unless explicitly allowed
+    rip(&["--allow-protected", "precious"]).assert().success();
+    assert!(!precious.exists());
+
+    // The defaults apply when nothing is configured
+    cli_runner(
+        ["--graveyard", graveyard, "config", "show", "--effective"],
+        None,
+    )
+    .env("RIP_CONFIG", test_env.src.join("none.toml"))
+    .env_remove("RIP_PROTECTED_PATHS")
+    .assert()
+    .success()
+    .stdout(is_match("protected-paths = \\[.*\\]  # default").unwrap());
+}
+
+#[test]
+fn test_prompt_policy() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&test_env.src));
+        cmd.args(args);
+        cmd
+    };
+    let big = test_env.src.join("big.bin");
+
+    // Deleting for good can require typing yes in full
+    fs::write(&big, vec![0; 2048]).unwrap();
+    rip(&["--big-file", "1K", "big.bin"])
+        .env("RIP_TYPE_YES", "1")
+        .write_stdin("y\n")
+        .assert()
+        .success()
+        .stdout(
+            is_match("Permanently delete this file instead\\? \\(type yes to confirm\\)").unwrap(),
+        );
+    assert!(!big.exists());
+    let grave = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap().join("big.bin"),
+    );
+    assert!(grave.exists());
+    fs::write(&big, vec![0; 2048]).unwrap();
+    rip(&["--big-file", "1K", "big.bin"])
+        .env("RIP_TYPE_YES", "1")
+        .write_stdin("yes\n")
+        .assert()
+        .success();
+    assert!(!big.exists());
+    assert!(!util::symlink_exists(grave.with_file_name("big.bin~1")));
+
+    // Pressing enter can mean yes, but never for deleting for good
+    let names: Vec<String> = (0..=rip2::MANY_TARGETS)
+        .map(|i| format!("{}.txt", i))
+        .collect();
+    for name in &names {
+        fs::write(test_env.src.join(name), name).unwrap();
+    }
+    let mut args: Vec<&str> = names.iter().map(String::as_str).collect();
+    args.extend(["--big-file", "1K", "big.bin"]);
+    fs::write(&big, vec![0; 2048]).unwrap();
+    let output = quick_cmd_output(
+        rip(&args)
+            .env("RIP_PROMPT_DEFAULT", "yes")
+            .write_stdin("\n\n"),
+    );
+    assert!(output.contains("Send them all to the graveyard? (Y/n)"));
+    assert!(output.contains("Permanently delete this file instead? (y/N)"));
+    assert!(!test_env.src.join("0.txt").exists());
+    assert!(util::symlink_exists(grave.with_file_name("big.bin~1")));
+}
+
+#[test]
+fn test_stats() {
+    let test_env = TestEnv::new();
+    let data = TestData::new(&test_env, None);
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    cli_runner(
+        ["--graveyard", graveyard, data.path.to_str().unwrap()],
+        None,
+    )
+    .assert()
+    .success();
+
+    // Burying takes the day's snapshot
+    let stats = fs::read_to_string(test_env.graveyard.join(rip2::stats::STATS)).unwrap();
+    let lines: Vec<&str> = stats.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[1].contains(",1,"));
+
+    cli_runner(["--graveyard", graveyard, "stats"], None)
+        .assert()
+        .success()
+        .stdout(is_match("^1 graves, .* in ").unwrap());
+
+    // Only once a day
+    fs::write(
+        test_env.graveyard.join(rip2::stats::STATS),
+        format!("date,graves,bytes\n2000-01-01,0,0\n{}\n", lines[1]),
+    )
+    .unwrap();
+    cli_runner(["--graveyard", graveyard, "stats", "--history"], None)
+        .assert()
+        .success()
+        .stdout(is_match("^date +graves +size\n[0-9-]+ +1 +").unwrap());
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "stats",
+            "--history",
+            "--days",
+            "100000",
+        ],
+        None,
+    )
+    .assert()
+    .success()
+    .stdout(is_match("2000-01-01 +0 +0 B +\n(.|\n)*Grew ").unwrap());
+}
+
+#[test]
+fn test_print_grave_path() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    for name in ["a.txt", "b c.txt"] {
+        fs::write(src.join(name), name).unwrap();
+    }
+    let grave = |name: &str| util::join_absolute(&test_env.graveyard, src.join(name));
+
+    cli_runner(
+        [
+            "--graveyard",
+            graveyard,
+            "--print-grave-path",
+            "a.txt",
+            "b c.txt",
+        ],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success()
+    .stdout(format!(
+        "{}\n{}\n",
+        grave("a.txt").display(),
+        grave("b c.txt").display()
+    ));
+
+    // Renamed graves are printed as they are, here NUL-terminated
+    fs::write(src.join("a.txt"), "again").unwrap();
+    cli_runner(
+        ["--graveyard", graveyard, "-0", "a.txt"],
+        Some(&test_env.src),
+    )
+    .assert()
+    .success()
+    .stdout(format!("{}\0", grave("a.txt~1").display()));
+    assert_eq!(fs::read_to_string(grave("a.txt~1")).unwrap(), "again");
+}
+
+#[test]
+fn test_profiles() {
+    let test_env = TestEnv::new();
+    let config_path = test_env.src.join("config.toml");
+    let scratch = test_env.src.join("scratch");
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(args, Some(&test_env.src));
+        cmd.env("RIP_CONFIG", &config_path)
+            .env_remove("RIP_GRAVEYARD")
+            .env_remove("RIP_PROFILE");
+        cmd
+    };
+
+    // Settings go into a profile when one is given
+    fs::write(
+        &config_path,
+        format!(
+            "graveyard = {:?}\n",
+            test_env.graveyard.display().to_string()
+        ),
+    )
+    .unwrap();
+    rip(&[
+        "--profile",
+        "scratch",
+        "config",
+        "set",
+        "graveyard",
+        scratch.to_str().unwrap(),
+    ])
+    .assert()
+    .success();
+    rip(&["--profile", "scratch", "config", "set", "max-age", "1d"])
+        .assert()
+        .success();
+    let config = fs::read_to_string(&config_path).unwrap();
+    assert!(config.contains("\n[profiles.scratch]\ngraveyard = "));
+    rip(&["--profile", "scratch", "config", "get", "max-age"])
+        .assert()
+        .success()
+        .stdout("1d\n");
+    rip(&["config", "get", "max-age"]).assert().failure();
+
+    // A profile's settings override the rest of the config
+    fs::write(test_env.src.join("a.txt"), "a").unwrap();
+    rip(&["--profile", "scratch", "a.txt"]).assert().success();
+    assert!(scratch.exists());
+    assert!(!test_env.graveyard.exists());
+    fs::write(test_env.src.join("b.txt"), "b").unwrap();
+    rip(&["b.txt"]).assert().success();
+    assert!(test_env.graveyard.exists());
+
+    // $RIP_PROFILE selects one too
+    rip(&["config", "show", "--effective"])
+        .env("RIP_PROFILE", "scratch")
+        .assert()
+        .success()
+        .stdout(is_match("profile = \"scratch\"  # \\$RIP_PROFILE\n").unwrap());
+
+    rip(&["--profile", "work", "c.txt"])
+        .assert()
+        .failure()
+        .stderr("No profile named work in the config (found: scratch)\n");
+    rip(&["--profile", "scratch", "config", "set", "profile", "work"])
+        .assert()
+        .failure();
+}
+
+/// Test that excluded entries of a buried directory are left in place
+#[rstest]
+fn test_exclude_within_dirs(#[values("copy", "rename")] strategy: &str) {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let project = test_env.src.join("project");
+    let objects = project.join(".git").join("objects").join("ab");
+    fs::create_dir_all(&objects).unwrap();
+    fs::write(objects.join("cdef"), "object").unwrap();
+    fs::write(project.join(".git").join("HEAD"), "ref").unwrap();
+    fs::write(project.join("main.rs"), "fn main() {}").unwrap();
+    fs::write(project.join("agent.sock"), "").unwrap();
+    fs::create_dir(test_env.src.join("other")).unwrap();
+    fs::write(test_env.src.join("other").join("x.txt"), "x").unwrap();
+
+    let bury = |target: &str, extra: &[&str]| {
+        let mut args = vec!["--graveyard", graveyard];
+        args.extend(extra);
+        args.push(target);
+        let mut cmd = cli_runner(args, Some(&test_env.src));
+        cmd.env("RIP_STRATEGY", strategy)
+            .env("RIP_EXCLUDE", "*.sock:.git/objects/**");
+        quick_cmd_output(&mut cmd)
+    };
+    let output = bury("project", &[]);
+    assert!(output.contains("excluded entries in"), "{}", output);
+
+    let grave = util::join_absolute(
+        &test_env.graveyard,
+        dunce::canonicalize(&test_env.src).unwrap(),
+    )
+    .join("project");
+    assert!(grave.join("main.rs").exists());
+    assert!(grave.join(".git").join("HEAD").exists());
+    assert!(!grave.join("agent.sock").exists());
+    assert!(!grave.join(".git").join("objects").join("ab").exists());
+    // What's excluded stays, and nothing else
+    assert!(project.join("agent.sock").exists());
+    assert_eq!(fs::read_to_string(objects.join("cdef")).unwrap(), "object");
+    assert!(!project.join("main.rs").exists());
+    assert!(!project.join(".git").join("HEAD").exists());
+
+    // A directory without excluded entries goes whole
+    let output = bury("other", &[]);
+    assert!(!output.contains("excluded"), "{}", output);
+    assert!(!test_env.src.join("other").exists());
+
+    bury("project", &["--no-exclude"]);
+    assert!(!project.exists());
+}
+
+/// Test that a graveyard that can't work is reported before anything else
+#[rstest]
+fn test_graveyard_problems() {
+    let test_env = TestEnv::new();
+    let data = TestData::new(&test_env, None);
+    let file = test_env.src.join("not_a_dir");
+    fs::write(&file, "").unwrap();
+    let bury_with = |graveyard: &ffi::OsStr| {
+        let mut cmd = cli_runner([data.path.as_os_str()], Some(&test_env.src));
+        cmd.env("RIP_GRAVEYARD", graveyard);
+        quick_cmd_output(&mut cmd)
+    };
+
+    let output = bury_with(file.as_os_str());
+    assert!(output.contains("from $RIP_GRAVEYARD as the graveyard: it is a file, not a directory"));
+    assert!(output.contains("(see `rip graveyard --explain`)"));
+    let output = bury_with(file.join("graveyard").as_os_str());
+    assert!(output.contains(&format!("{} is a file, not a directory", file.display())));
+    let mut cmd = cli_runner([data.path.as_os_str()], Some(&test_env.src));
+    cmd.env("RIP_GRAVEYARD", "relative/graveyard")
+        .env_remove("HOME");
+    let output = quick_cmd_output(&mut cmd);
+    assert!(output.contains("it is a relative path"), "{}", output);
+    assert!(data.path.exists());
+    assert!(!test_env.src.join("relative").exists());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let locked = test_env.src.join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o555)).unwrap();
+        // Permissions don't hold back root
+        if fs::write(locked.join("probe"), "").is_err() {
+            let output = bury_with(locked.join("graveyard").as_os_str());
+            assert!(output.contains("isn't writable"), "{}", output);
+        }
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let explain = |graveyard: &std::path::Path| {
+        let mut cmd = cli_runner(["graveyard", "--explain"], Some(&test_env.src));
+        cmd.env("RIP_GRAVEYARD", graveyard);
+        quick_cmd_output(&mut cmd)
+    };
+    let output = explain(&file);
+    assert!(output.starts_with(&format!("{}\n  from: $RIP_GRAVEYARD\n", file.display())));
+    assert!(output.contains("status: unusable: it is a file"));
+    let output = explain(&test_env.graveyard);
+    assert!(output.contains("status: doesn't exist yet"), "{}", output);
+}
+
+/// Test that the record can be kept in SQLite, and moved between stores
+#[cfg(feature = "sqlite")]
+#[rstest]
+fn test_sqlite_record() {
+    let test_env = TestEnv::new();
+    let data = TestData::new(&test_env, None);
+    let graveyard = test_env.graveyard.as_os_str();
+    let run = |args: &[&ffi::OsStr], backend: &str| {
+        let mut cmd = cli_runner(args, Some(&test_env.src));
+        cmd.env("RIP_GRAVEYARD", graveyard)
+            .env("RIP_RECORD_BACKEND", backend);
+        let output = cmd.output().unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    run(
+        &[ffi::OsStr::new("--record-origin"), data.path.as_os_str()],
+        "sqlite",
+    );
+    assert!(test_env.graveyard.join(rip2::store::DATABASE).exists());
+    assert!(!test_env.graveyard.join(record::RECORD).exists());
+    let record = record::Record::new(&test_env.graveyard);
+    let graves: Vec<_> = record.graves().unwrap().collect();
+    assert_eq!(graves.len(), 1);
+    assert_eq!(
+        graves[0].orig,
+        dunce::canonicalize(&test_env.src)
+            .unwrap()
+            .join("test_file.txt")
+    );
+    let origin = graves[0].origin.clone().unwrap();
+    assert_eq!(origin.cwd, dunce::canonicalize(&test_env.src).unwrap());
+    drop(record);
+
+    // Left unset, the record stays where it is
+    let mut cmd = cli_runner(["-s"], Some(&test_env.src));
+    cmd.env("RIP_GRAVEYARD", graveyard);
+    assert!(quick_cmd_output(&mut cmd).contains("test_file.txt"));
+
+    run(&[ffi::OsStr::new("-s")], "tsv");
+    assert!(!test_env.graveyard.join(rip2::store::DATABASE).exists());
+    let contents = fs::read_to_string(test_env.graveyard.join(record::RECORD)).unwrap();
+    assert_eq!(contents.lines().count(), 2);
+    let graves: Vec<_> = record::Record::new(&test_env.graveyard)
+        .graves()
+        .unwrap()
+        .collect();
+    assert_eq!(graves[0].origin, Some(origin));
+
+    run(&[ffi::OsStr::new("-u")], "sqlite");
+    assert!(data.path.exists());
+    assert_eq!(
+        record::Record::new(&test_env.graveyard)
+            .graves()
+            .unwrap()
+            .count(),
+        0
+    );
+}
+
+/// Test that asking for SQLite without the feature fails, leaving the
+/// file alone
+#[cfg(not(feature = "sqlite"))]
+#[rstest]
+fn test_sqlite_record_unsupported() {
+    let test_env = TestEnv::new();
+    let data = TestData::new(&test_env, None);
+    let mut cmd = cli_runner([data.path.as_os_str()], Some(&test_env.src));
+    cmd.env("RIP_GRAVEYARD", &test_env.graveyard)
+        .env("RIP_RECORD_BACKEND", "sqlite");
+    let output = cmd.output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("built without the sqlite feature"));
+    assert!(data.path.exists());
+}
+
+/// Test that relative graveyards are resolved against where they were set
+#[rstest]
+fn test_relative_graveyard() {
+    let test_env = TestEnv::new();
+    let root = dunce::canonicalize(test_env.src.parent().unwrap()).unwrap();
+    let home = root.join("home");
+    let config_dir = root.join("config");
+    fs::create_dir_all(&home).unwrap();
+    fs::create_dir_all(&config_dir).unwrap();
+    let config = config_dir.join("config.toml");
+    fs::write(&config, "graveyard = \"../from_config\"\n").unwrap();
+
+    let bury = |args: &[&str], graveyard: Option<&str>, expected: &std::path::Path| {
+        let data = TestData::new(&test_env, None);
+        let mut cmd = cli_runner(args, Some(&test_env.src));
+        cmd.arg(&data.path)
+            .env("HOME", &home)
+            .env("RIP_CONFIG", &config)
+            .env_remove("RIP_GRAVEYARD");
+        if let Some(graveyard) = graveyard {
+            cmd.env("RIP_GRAVEYARD", graveyard);
+        }
+        cmd.assert().success();
+        let grave = util::join_absolute(expected, dunce::canonicalize(&test_env.src).unwrap())
+            .join("test_file.txt");
+        assert!(grave.exists(), "{} wasn't buried", grave.display());
+    };
+
+    // $RIP_GRAVEYARD is relative to the home directory
+    bury(&[], Some("from_env"), &home.join("from_env"));
+    bury(&[], Some("~/tilde"), &home.join("tilde"));
+    // The config file's is relative to the config file
+    bury(&[], None, &root.join("from_config"));
+    // --graveyard is relative to the current directory
+    bury(
+        &["--graveyard", "../from_flag"],
+        None,
+        &root.join("from_flag"),
+    );
+    bury(
+        &["--graveyard", "~/./flag_tilde"],
+        None,
+        &home.join("flag_tilde"),
+    );
+}
+
+/// Test that a portable graveyard keeps paths on its drive relative
+#[rstest]
+fn test_portable_graveyard() {
+    let test_env = TestEnv::new();
+    let data = TestData::new(&test_env, None);
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(args, Some(&test_env.src));
+        cmd.env("RIP_GRAVEYARD", &test_env.graveyard);
+        cmd
+    };
+
+    rip(&["--portable", "test_file.txt"]).assert().success();
+    assert!(test_env.graveyard.join(rip2::portable::MARKER).exists());
+    let contents = fs::read_to_string(test_env.graveyard.join(record::RECORD)).unwrap();
+    let line = contents.lines().nth(1).unwrap();
+    let fields: Vec<&str> = line.split('\t').collect();
+    assert!(std::path::Path::new(fields[1]).is_relative(), "{}", line);
+    assert!(fields[1].ends_with("test_file.txt"));
+
+    // Once marked, it stays portable
+    let seance = quick_cmd_output(&mut rip(&["-s"]));
+    assert!(seance.contains("test_file.txt"), "{}", seance);
+    let volume = rip2::portable::Volume::of(&test_env.graveyard).unwrap();
+    let grave = volume.grave_of(
+        &dunce::canonicalize(&test_env.src)
+            .unwrap()
+            .join("test_file.txt"),
+    );
+    assert!(grave.exists());
+
+    rip(&["-u"]).assert().success();
+    assert!(data.path.exists());
+    assert_eq!(fs::read_to_string(&data.path).unwrap(), data.data);
+}
+
+/// Test that the mode and timestamps of a buried file are put back on unbury
+#[cfg(unix)]
+#[rstest]
+fn test_restore_metadata() {
+    use rip2::meta::FileMeta;
+    use std::os::unix::fs::PermissionsExt;
+
+    let test_env = TestEnv::new();
+    let data = TestData::new(&test_env, None);
+    fs::set_permissions(&data.path, fs::Permissions::from_mode(0o640)).unwrap();
+    let original = FileMeta {
+        mtime: 1_500_000_000_123_456_789,
+        atime: 1_500_000_100_000_000_000,
+        ..FileMeta::of(&data.path).unwrap()
+    };
+    original.apply(&data.path).unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(args, Some(&test_env.src));
+        cmd.env("RIP_GRAVEYARD", &test_env.graveyard);
+        cmd
+    };
+
+    rip(&["test_file.txt"]).assert().success();
+    let record = record::Record::new(&test_env.graveyard);
+    let grave = record.graves().unwrap().next().unwrap();
+    assert_eq!(grave.meta, Some(original));
+    // Copying into the graveyard keeps the modification time
+    assert_eq!(FileMeta::of(&grave.dest).unwrap().mtime, original.mtime);
+    fs::set_permissions(&grave.dest, fs::Permissions::from_mode(0o600)).unwrap();
+
+    rip(&["-u"]).assert().success();
+    let restored = FileMeta::of(&data.path).unwrap();
+    assert_eq!(restored.mode, 0o640);
+    assert_eq!(restored.mtime, original.mtime);
+    assert_eq!(restored.atime, original.atime);
+}
+
+/// Test that graves are checksummed, and checked on unbury
+#[rstest]
+fn test_checksum() {
+    let test_env = TestEnv::new();
+    let data = TestData::new(&test_env, None);
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(args, Some(&test_env.src));
+        cmd.env("RIP_GRAVEYARD", &test_env.graveyard);
+        cmd
+    };
+    let last_grave = || {
+        record::Record::new(&test_env.graveyard)
+            .graves()
+            .unwrap()
+            .last()
+            .unwrap()
+    };
+
+    rip(&["test_file.txt"]).assert().success();
+    let grave = last_grave();
+    let checksum = grave.checksum.clone().unwrap();
+    assert!(checksum.starts_with("xxh3:"), "{}", checksum);
+    assert_eq!(rip2::checksum::of(&grave.dest).unwrap(), checksum);
+
+    // An untouched grave comes back without complaint
+    let output = quick_cmd_output(&mut rip(&["-u", "--strict"]));
+    assert!(!output.contains("Warning"), "{}", output);
+    assert_eq!(fs::read_to_string(&data.path).unwrap(), data.data);
+
+    rip(&["test_file.txt"]).assert().success();
+    fs::write(&last_grave().dest, "tampered").unwrap();
+    let output = rip(&["-u", "--strict"])
+        .assert()
+        .failure()
+        .get_output()
+        .clone();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("doesn't match the checksum it was buried with"),
+        "{}",
+        stdout
+    );
+    // It is restored all the same
+    assert_eq!(fs::read_to_string(&data.path).unwrap(), "tampered");
+
+    rip(&["--no-checksum", "test_file.txt"]).assert().success();
+    assert_eq!(last_grave().checksum, None);
+}
+
+/// Test that restoring into another user's directory needs confirmation
+#[cfg(unix)]
+#[rstest]
+fn test_cross_user_restore() {
+    let test_env = TestEnv::new();
+    let shared = test_env.src.join("shared");
+    fs::create_dir(&shared).unwrap();
+    let file = shared.join("notes.txt");
+    fs::write(&file, "notes").unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(args, Some(&test_env.src));
+        cmd.env("RIP_GRAVEYARD", &test_env.graveyard);
+        cmd
+    };
+    rip(&["shared/notes.txt"]).assert().success();
+    // Only root can give the directory away
+    if std::os::unix::fs::chown(&shared, Some(54321), None).is_err() {
+        return;
+    }
+
+    let output = quick_cmd_output(rip(&["-u"]).write_stdin("\n"));
+    assert!(
+        output.contains("notes.txt would be restored among uid 54321's files"),
+        "{}",
+        output
+    );
+    assert!(output.contains("Left "), "{}", output);
+    assert!(!file.exists());
+    assert_eq!(
+        record::Record::new(&test_env.graveyard)
+            .graves()
+            .unwrap()
+            .count(),
+        1
+    );
+
+    rip(&["-u", "--yes"]).assert().success();
+    assert_eq!(fs::read_to_string(&file).unwrap(), "notes");
+}
+
+#[rstest]
+fn test_export() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    // Nothing buried yet
+    rip(&["export", "--format", "csv"])
+        .assert()
+        .success()
+        .stdout("time,orig,dest,id,kind,note,status,checksum,op,user,host,cwd\n");
+
+    for name in ["old.txt", "a,\"b\".txt"] {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    rip(&["old.txt"]).assert().success();
+    rip(&["-m", "spare", "a,\"b\".txt"]).assert().success();
+    // Bury the first a week ago
+    let record_path = test_env.graveyard.join(record::RECORD);
+    let contents = fs::read_to_string(&record_path).unwrap();
+    let time = contents.lines().nth(1).unwrap().split('\t').next().unwrap();
+    let week_ago = (chrono::Local::now() - chrono::Duration::days(7)).to_rfc3339();
+    fs::write(&record_path, contents.replacen(time, &week_ago, 1)).unwrap();
+
+    let output = rip(&["export"]).output().unwrap();
+    assert!(output.status.success());
+    let exported: rip2::schema::Versioned<Vec<record::RecordItem>> =
+        serde_json::from_slice(&output.stdout).unwrap();
+    let graves = exported.into_data().unwrap();
+    assert_eq!(graves.len(), 2);
+    assert_eq!(graves[0].orig, src.join("old.txt"));
+    assert_eq!(graves[1].note.as_deref(), Some("spare"));
+
+    let output = rip(&["export", "--format", "csv", "--since", "1d"])
+        .output()
+        .unwrap();
+    let csv = String::from_utf8(output.stdout).unwrap();
+    let rows: Vec<&str> = csv.lines().collect();
+    assert_eq!(rows.len(), 2);
+    assert!(rows[1].starts_with(&format!(
+        "{},\"{}\",\"{}\",",
+        graves[1].time,
+        src.join("a,\"\"b\"\".txt").display(),
+        graves[1].dest.display().to_string().replace('"', "\"\"")
+    )));
+    assert!(rows[1].contains(",file,spare,"));
+}
+
+#[cfg(unix)]
+#[rstest]
+fn test_record_kind() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    fs::write(src.join("file"), "contents").unwrap();
+    fs::create_dir(src.join("dir")).unwrap();
+    std::os::unix::fs::symlink("file", src.join("link")).unwrap();
+    rip(&["file", "dir", "link"]).assert().success();
+
+    let graves: Vec<_> = record::Record::new(&test_env.graveyard)
+        .graves()
+        .unwrap()
+        .collect();
+    let kinds: Vec<_> = graves.iter().map(|grave| grave.kind).collect();
+    use record::FileKind;
+    assert_eq!(
+        kinds,
+        [
+            Some(FileKind::File),
+            Some(FileKind::Dir),
+            Some(FileKind::Symlink)
+        ]
+    );
+    rip(&["-s", "-v"])
+        .assert()
+        .success()
+        .stdout(is_match("size +type +path").unwrap())
+        .stdout(is_match(" symlink +[^\n]*link").unwrap());
+
+    // What's in the grave's place now isn't what was buried
+    fs::remove_file(&graves[0].dest).unwrap();
+    fs::create_dir(&graves[0].dest).unwrap();
+    rip(&["-u", graves[0].dest.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(is_match("was buried as a file, but is now something else").unwrap());
+}
+
+#[rstest]
+fn test_import() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let old = test_env.src.parent().unwrap().join("old");
+    let moved = test_env.src.parent().unwrap().join("moved");
+    let rip = |graveyard: &str, args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    // Buried into the old graveyard first, which is then moved
+    fs::create_dir(src.join("dir")).unwrap();
+    for name in ["a.txt", "dir/b.txt"] {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    rip(old.to_str().unwrap(), &["a.txt", "dir"])
+        .assert()
+        .success();
+    // And two versions of a file, the second stored as a delta
+    let first: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10000)
+        .map(char::from)
+        .collect();
+    let second = format!("{}edited{}", &first[..5000], &first[5000..]);
+    for contents in [&first, &second] {
+        fs::write(src.join("f.txt"), contents).unwrap();
+        rip(old.to_str().unwrap(), &["--delta", "f.txt"])
+            .assert()
+            .success();
+    }
+    fs::rename(&old, &moved).unwrap();
+    TestData::new(&test_env, Some(&PathBuf::from("a.txt")));
+    rip(graveyard, &["a.txt"]).assert().success();
+
+    rip(graveyard, &["import", graveyard])
+        .assert()
+        .failure()
+        .stderr("Can't import a graveyard into itself\n");
+    rip(graveyard, &["import", moved.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(format!(
+            "Imported 4 grave(s) from {}\nRenamed 1 grave(s) whose place was taken\n",
+            dunce::canonicalize(&moved).unwrap().display()
+        ));
+
+    let graves: Vec<record::RecordItem> = record::Record::new(&test_env.graveyard)
+        .graves()
+        .unwrap()
+        .collect();
+    let dests: Vec<PathBuf> = graves.iter().map(|grave| grave.dest.clone()).collect();
+    let grave_of = |name: &str| util::join_absolute(&test_env.graveyard, src.join(name));
+    let mut renamed = grave_of("a.txt").into_os_string();
+    renamed.push("~1");
+    // In order of burial, the imported ones first
+    let mut delta = grave_of("f.txt").into_os_string();
+    delta.push("~1");
+    let delta = PathBuf::from(delta);
+    assert_eq!(
+        dests,
+        [
+            PathBuf::from(renamed),
+            grave_of("dir"),
+            grave_of("f.txt"),
+            delta.clone(),
+            grave_of("a.txt")
+        ]
+    );
+    assert!(grave_of("dir/b.txt").is_file());
+    // The old graveyard is left as it was
+    assert!(util::join_absolute(&moved, src.join("dir/b.txt")).is_file());
+
+    rip(graveyard, &["-u", grave_of("dir").to_str().unwrap()])
+        .assert()
+        .success();
+    assert!(src.join("dir/b.txt").is_file());
+
+    // The delta was brought over whole, so it restores without its base
+    rip(graveyard, &["-u", delta.to_str().unwrap()])
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(src.join("f.txt")).unwrap(), second);
+}
+
+#[rstest]
+fn test_prune_empty_dirs() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let grave_of = |name: &str| util::join_absolute(&test_env.graveyard, src.join(name));
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    fs::create_dir_all(src.join("a/b")).unwrap();
+    fs::create_dir(src.join("empty")).unwrap();
+    for name in ["a/b/c.txt", "a/d.txt"] {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    rip(&["a/b/c.txt", "a/d.txt", "empty"]).assert().success();
+
+    // Its directory is left empty, but its parent still holds a grave
+    rip(&["-u", grave_of("a/b/c.txt").to_str().unwrap()])
+        .assert()
+        .success();
+    assert!(!grave_of("a/b").exists());
+    assert!(grave_of("a").is_dir());
+
+    rip(&["purge", grave_of("a/d.txt").to_str().unwrap()])
+        .write_stdin("y\n")
+        .assert()
+        .success();
+    assert!(!grave_of("a").exists());
+    // A grave that is an empty directory isn't pruned
+    assert!(grave_of("empty").is_dir());
+
+    rip(&["-u", grave_of("empty").to_str().unwrap()])
+        .assert()
+        .success();
+    assert!(!test_env.graveyard.join(src.iter().nth(1).unwrap()).exists());
+    assert!(test_env.graveyard.is_dir());
+
+    // As left behind by older versions
+    fs::create_dir_all(grave_of("x/y/z")).unwrap();
+    fs::create_dir_all(test_env.graveyard.join(".deltas")).unwrap();
+    let depth = grave_of("x/y/z")
+        .strip_prefix(&test_env.graveyard)
+        .unwrap()
+        .components()
+        .count();
+    rip(&["doctor", "--prune-empty"])
+        .assert()
+        .success()
+        .stdout(is_match(format!("Removed {} empty director", depth)).unwrap());
+    assert!(!grave_of("x").exists());
+    assert!(test_env.graveyard.join(".deltas").is_dir());
+}
+
+#[rstest]
+fn test_renumber() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let grave_of = |name: &str| util::join_absolute(&test_env.graveyard, src.join(name));
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    for i in 0..4 {
+        fs::create_dir(src.join("d")).unwrap();
+        fs::write(src.join("d/f"), i.to_string()).unwrap();
+        rip(&["d"]).assert().success();
+    }
+    // Its name isn't from a rename, so it stays as it is
+    fs::write(src.join("notes~2"), "").unwrap();
+    rip(&["notes~2"]).assert().success();
+
+    rip(&["purge", grave_of("d~1").to_str().unwrap()])
+        .write_stdin("y\n")
+        .assert()
+        .success();
+    rip(&["-u", grave_of("d~2").to_str().unwrap()])
+        .assert()
+        .success();
+    fs::remove_dir_all(src.join("d")).unwrap();
+
+    rip(&["doctor", "--renumber"])
+        .assert()
+        .success()
+        .stdout(is_match("Renumbered 1 grave").unwrap());
+    assert!(!grave_of("d~3").exists());
+    assert_eq!(fs::read_to_string(grave_of("d~1/f")).unwrap(), "3");
+    assert!(grave_of("notes~2").exists());
+
+    // Once the first is gone too, the chain starts over
+    rip(&["purge", grave_of("d").to_str().unwrap()])
+        .write_stdin("y\n")
+        .assert()
+        .success();
+    rip(&["doctor", "--renumber"])
+        .assert()
+        .success()
+        .stdout(is_match("Renumbered 1 grave").unwrap());
+    rip(&["-u", grave_of("d").to_str().unwrap()])
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(src.join("d/f")).unwrap(), "3");
+
+    rip(&["doctor", "--renumber"])
+        .assert()
+        .success()
+        .stdout(is_match("Renumbered 0 grave").unwrap());
+}
+
+#[rstest]
+fn test_compact() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let grave_of = |name: &str| util::join_absolute(&test_env.graveyard, src.join(name));
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.args(args);
+        cmd
+    };
+    rip(&["compact"])
+        .assert()
+        .success()
+        .stdout(is_match("^No record at").unwrap());
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    rip(&["a.txt", "b.txt", "c.txt"]).assert().success();
+    rip(&["compact"])
+        .assert()
+        .success()
+        .stdout("The record is already compact\n");
+
+    fs::remove_file(grave_of("b.txt")).unwrap();
+    let record_path = test_env.graveyard.join(record::RECORD);
+    let contents = fs::read_to_string(&record_path).unwrap();
+    let first = contents.lines().nth(1).unwrap();
+    fs::write(
+        &record_path,
+        format!("{}{}\nnot a grave\n{}\n", contents, first, first),
+    )
+    .unwrap();
+    rip(&["compact"])
+        .assert()
+        .success()
+        .stdout("Dropped 1 entries for graves that are gone\nDropped 2 repeated entries\n");
+    let compacted = fs::read_to_string(&record_path).unwrap();
+    let lines: Vec<&str> = compacted.lines().skip(1).collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], first);
+    assert!(lines[1].contains("c.txt"));
+    assert_eq!(lines[2], "not a grave");
+}
+
+#[rstest]
+fn test_record_origin() {
+    let test_env = TestEnv::new();
+    let graveyard = test_env.graveyard.to_str().unwrap();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    let rip = |args: &[&str]| {
+        let mut cmd = cli_runner(["--graveyard", graveyard], Some(&src));
+        cmd.env("USER", "alice").env("USERNAME", "alice").args(args);
+        cmd
+    };
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        TestData::new(&test_env, Some(&PathBuf::from(name)));
+    }
+    rip(&["a.txt"]).assert().success();
+    rip(&["--record-origin", "b.txt"]).assert().success();
+    rip(&["c.txt"])
+        .env("RIP_RECORD_ORIGIN", "1")
+        .assert()
+        .success();
+
+    let graves: Vec<record::RecordItem> = record::Record::new(&test_env.graveyard)
+        .graves()
+        .unwrap()
+        .collect();
+    assert_eq!(graves[0].origin, None);
+    for grave in &graves[1..] {
+        let origin = grave.origin.as_ref().unwrap();
+        assert_eq!(origin.user, "alice");
+        assert_eq!(origin.cwd, src);
+        assert!(!origin.host.is_empty());
+    }
+    let output = rip(&["export", "--format", "csv"]).output().unwrap();
+    let csv = String::from_utf8(output.stdout).unwrap();
+    let last = csv.lines().last().unwrap();
+    assert!(last.contains(",alice,"));
+    assert!(last.ends_with(&format!(",{}", src.display())));
+}
+
+#[rstest]
+fn test_copy_of_changing_file() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let test_env = TestEnv::new();
+    let src = dunce::canonicalize(&test_env.src).unwrap();
+    fs::create_dir(src.join("dir")).unwrap();
+    let paths = [src.join("live.log"), src.join("dir").join("live.log")];
+    for path in &paths {
+        fs::write(path, vec![b'x'; 32 << 20]).unwrap();
+    }
+
+    // Something is still writing to them as they're buried
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let stop = Arc::clone(&stop);
+        let mut files: Vec<fs::File> = paths
+            .iter()
+            .map(|path| fs::OpenOptions::new().append(true).open(path).unwrap())
+            .collect();
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                for file in &mut files {
+                    file.write_all(b"more\n").unwrap();
+                }
+            }
+        })
+    };
+    while fs::metadata(&paths[1]).unwrap().len() == 32 << 20 {
+        std::thread::yield_now();
+    }
+    let output = cli_runner(
+        [
+            "--graveyard",
+            test_env.graveyard.to_str().unwrap(),
+            "live.log",
+            "dir",
+        ],
+        Some(&src),
+    )
+    .output()
+    .unwrap();
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for path in &paths {
+        assert!(
+            stdout.contains(&format!(
+                "Warning: {} changed while it was being copied, 3 times over",
+                path.display()
+            )),
+            "{}",
+            stdout
+        );
+    }
+    assert!(!paths[0].exists());
+}
@@ -0,0 +1,197 @@
+use proptest::prelude::*;
+use rip2::diff;
+use rip2::meta::FileMeta;
+use rip2::record::{FileKind, Origin, Record, RecordItem};
+use rip2::util::{join_absolute, normalize_path, original_of_grave, relative_path, rename_grave};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use tempfile::tempdir;
+
+/// A path component without separators, which may hold the tabs and
+/// line breaks that delimit the record's columns and lines
+fn component() -> impl Strategy<Value = String> {
+    "[^/\\\\\0]{1,12}".prop_filter("not . or ..", |c| c != "." && c != "..")
+}
+
+/// An absolute path, possibly very deeply nested
+fn absolute_path() -> impl Strategy<Value = PathBuf> {
+    prop::collection::vec(component(), 1..64)
+        .prop_map(|components| Path::new("/").join(components.join("/")))
+}
+
+/// A time as written to the record
+fn time() -> impl Strategy<Value = String> {
+    (0_i64..4_000_000_000).prop_map(|secs| {
+        chrono::DateTime::from_timestamp(secs, 0)
+            .unwrap()
+            .with_timezone(&chrono::Local)
+            .to_rfc3339()
+    })
+}
+
+/// Apply the unified diff `hunks` to `old`
+fn patch(old: &str, hunks: &str) -> String {
+    let old: Vec<&str> = old.split_inclusive('\n').collect();
+    let (mut out, mut at) = (String::new(), 0);
+    let mut lines = hunks.split_inclusive('\n').peekable();
+    while let Some(line) = lines.next() {
+        let mut line = line.to_string();
+        // The line before the marker has no line break of its own
+        if lines.peek() == Some(&"\\ No newline at end of file\n") {
+            line.pop();
+            lines.next();
+        }
+        if let Some(range) = line.strip_prefix("@@ -") {
+            let range = range.split(' ').next().unwrap();
+            let start: usize = range.split(',').next().unwrap().parse().unwrap();
+            let start = if range.ends_with(",0") {
+                start
+            } else {
+                start - 1
+            };
+            out += &old[at..start].concat();
+            at = start;
+        } else if let Some(line) = line.strip_prefix('+') {
+            out += line;
+        } else {
+            assert_eq!(old[at], &line[1..]);
+            if let Some(line) = line.strip_prefix(' ') {
+                out += line;
+            }
+            at += 1;
+        }
+    }
+    out += &old[at..].concat();
+    out
+}
+
+proptest! {
+    #[test]
+    fn record_item_roundtrip(
+        time in time(),
+        orig in absolute_path(),
+        dest in absolute_path(),
+        id in prop::option::of("[0-9a-f]{8}"),
+        note in prop::option::of("[^\t\n\r]*"),
+        status in prop::option::of("[a-z]+"),
+        prev in prop::option::of("[0-9a-f]{64}"),
+        meta in prop::option::of((0u32..0o7777, any::<u32>(), any::<u32>(), any::<i64>(), any::<i64>())),
+        checksum in prop::option::of("xxh3:[0-9a-f]{16}"),
+        op in prop::option::of("[0-9a-f]{8}"),
+        kind in prop::option::of(prop::sample::select(&[FileKind::File, FileKind::Dir, FileKind::Symlink, FileKind::Special][..])),
+        origin in prop::option::of(("[a-z_][a-z0-9_-]{0,15}", "[a-z0-9.-]{1,20}", absolute_path())),
+    ) {
+        let meta = meta.map(|(mode, uid, gid, mtime, atime)| FileMeta { mode, uid, gid, mtime, atime });
+        let origin = origin.map(|(user, host, cwd)| Origin { user, host, cwd });
+        let item = RecordItem { time, orig, dest, id, note, status, meta, kind, checksum, op, origin, prev };
+        let parsed = RecordItem::new(&item.to_line()).unwrap();
+        prop_assert_eq!(&parsed.time, &item.time);
+        prop_assert_eq!(&parsed.orig, &item.orig);
+        prop_assert_eq!(&parsed.dest, &item.dest);
+        prop_assert_eq!(&parsed.id, &item.id);
+        prop_assert_eq!(&parsed.note, &item.note);
+        prop_assert_eq!(&parsed.status, &item.status);
+        prop_assert_eq!(&parsed.meta, &item.meta);
+        prop_assert_eq!(&parsed.kind, &item.kind);
+        prop_assert_eq!(&parsed.checksum, &item.checksum);
+        prop_assert_eq!(&parsed.op, &item.op);
+        prop_assert_eq!(&parsed.origin, &item.origin);
+        prop_assert_eq!(&parsed.prev, &item.prev);
+    }
+
+    #[test]
+    fn record_item_never_panics(line in any::<String>()) {
+        if let Some(item) = RecordItem::new(&line) {
+            prop_assert!(line.starts_with(&item.time));
+        } else {
+            prop_assert!(line.matches('\t').count() < 2);
+        }
+    }
+
+    #[test]
+    fn record_file_roundtrip(paths in prop::collection::vec(absolute_path(), 1..8)) {
+        let tmpdir = tempdir().unwrap();
+        let graveyard = tmpdir.path().join("graveyard");
+        fs::create_dir(&graveyard).unwrap();
+        let record = Record::new(&graveyard);
+        for path in &paths {
+            record.write_log(path, join_absolute(&graveyard, path)).unwrap();
+        }
+        // Lines that aren't valid items are skipped rather than panicking
+        fs::write(
+            graveyard.join(rip2::record::RECORD),
+            fs::read_to_string(graveyard.join(rip2::record::RECORD)).unwrap() + "garbage\n",
+        )
+        .unwrap();
+        let graves: Vec<_> = record.graves().unwrap().collect();
+        prop_assert_eq!(graves.len(), paths.len());
+        for (grave, path) in graves.iter().zip(&paths) {
+            prop_assert_eq!(&grave.orig, path);
+            prop_assert_eq!(&grave.dest, &join_absolute(&graveyard, path));
+        }
+    }
+
+    #[test]
+    fn join_absolute_stays_in_graveyard(path in absolute_path()) {
+        let graveyard = Path::new("/graveyard");
+        let joined = join_absolute(graveyard, &path);
+        prop_assert!(joined.starts_with(graveyard));
+        // The original path can be read back off the grave
+        prop_assert_eq!(original_of_grave(graveyard, &joined), Some(path));
+    }
+
+    #[test]
+    fn rename_grave_is_fresh(name in component(), taken in 0_usize..4) {
+        let tmpdir = tempdir().unwrap();
+        let grave = tmpdir.path().join(&name);
+        fs::write(&grave, "").unwrap();
+        for _ in 0..taken {
+            fs::write(rename_grave(&grave), "").unwrap();
+        }
+        let renamed = rename_grave(&grave);
+        prop_assert!(!renamed.exists());
+        prop_assert_eq!(renamed.parent(), grave.parent());
+        prop_assert_eq!(
+            renamed.file_name().unwrap().to_string_lossy(),
+            format!("{}~{}", name, taken + 1)
+        );
+    }
+
+    #[test]
+    fn relative_path_leads_to_target(
+        from in absolute_path(),
+        to in absolute_path(),
+    ) {
+        let relative = relative_path(&from, &to);
+        prop_assert!(relative.components().all(|c| c != Component::RootDir));
+        prop_assert_eq!(normalize_path(&from.join(relative)), to);
+    }
+
+    #[test]
+    fn unified_diff_patches_old_into_new(
+        old in prop::collection::vec("[abc]{0,2}\n?", 0..40),
+        new in prop::collection::vec("[abc]{0,2}\n?", 0..40),
+    ) {
+        let (old, new) = (old.concat(), new.concat());
+        let hunks = diff::unified(&old, &new).unwrap();
+        prop_assert_eq!(patch(&old, &hunks), new);
+    }
+}
+
+#[cfg(unix)]
+proptest! {
+    #[test]
+    fn non_utf8_paths_dont_panic(bytes in prop::collection::vec(1_u8..=255, 1..32)) {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let name: Vec<u8> = bytes.into_iter().filter(|&b| b != b'/').collect();
+        prop_assume!(!name.is_empty() && name != b"." && name != b"..");
+
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join(OsStr::from_bytes(&name));
+        let joined = join_absolute(Path::new("/graveyard"), &path);
+        prop_assert!(joined.starts_with("/graveyard"));
+        let renamed = rename_grave(&path);
+        prop_assert!(renamed.as_os_str().as_bytes().starts_with(path.as_os_str().as_bytes()));
+    }
+}
@@ -1,13 +1,15 @@
-use lazy_static::lazy_static;
-use rip2::args::{validate_args, Args, Commands};
-use rip2::completions;
-use rip2::util::{humanize_bytes, TestMode};
+use rip2::args::{resolve_args, validate_args, Args, Commands};
+use rip2::context::Context;
+use rip2::util::{
+    claim_path, get_ids, humanize_bytes, humanize_duration, parse_duration, parse_size, IdGen,
+    PermissionPolicy, TestMode,
+};
+use rip2::{completions, delta, diff};
 use rstest::rstest;
 use std::fs;
 use std::io::{Cursor, ErrorKind};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::{Mutex, MutexGuard};
 use tempfile::tempdir;
 
 #[cfg(unix)]
@@ -22,14 +24,6 @@ use std::os::unix::net::UnixListener;
 #[cfg(target_os = "macos")]
 use std::os::unix::fs::FileTypeExt;
 
-lazy_static! {
-    static ref GLOBAL_LOCK: Mutex<()> = Mutex::new(());
-}
-
-fn aquire_lock() -> MutexGuard<'static, ()> {
-    GLOBAL_LOCK.lock().unwrap()
-}
-
 #[rstest]
 fn test_validation() {
     let bad_completions = Args {
@@ -47,6 +41,42 @@ fn test_validation() {
         ..Args::default()
     };
     validate_args(&bad_decompose).expect_err("-d,--decompose can only be used with --graveyard");
+
+    let bad_force = Args {
+        force: true,
+        inspect: true,
+        ..Args::default()
+    };
+    validate_args(&bad_force).expect_err("-f,--force can't be used with -i,--inspect");
+
+    let bad_explain = Args {
+        explain: true,
+        seance: true,
+        ..Args::default()
+    };
+    validate_args(&bad_explain).expect_err("--explain can only be used when burying");
+}
+
+#[rstest]
+fn test_force_from_env() {
+    let ctx = Context::new(std::env::temp_dir())
+        .with_var("RIP_FORCE", "1")
+        .with_var("RIP_YES", "true");
+    let cli = resolve_args(Args::default(), &ctx).unwrap();
+    assert!(cli.force);
+    assert!(cli.yes);
+
+    // An explicit -i still asks about each target
+    let inspect = Args {
+        inspect: true,
+        ..Args::default()
+    };
+    let cli = resolve_args(inspect, &ctx).unwrap();
+    assert!(!cli.force);
+    assert!(cli.inspect);
+
+    let ctx = Context::new(std::env::temp_dir()).with_var("RIP_FORCE", "0");
+    assert!(!resolve_args(Args::default(), &ctx).unwrap().force);
 }
 
 #[rstest]
@@ -104,7 +134,14 @@ fn test_filetypes(
     if copy {
         rip2::copy_file(&source_path, &dest_path, &mode, &mut log).unwrap();
     } else {
-        rip2::move_target(&source_path, &dest_path, &mode, &mut log).unwrap();
+        rip2::move_target(
+            &source_path,
+            &dest_path,
+            &rip2::MoveOptions::default(),
+            &mode,
+            &mut log,
+        )
+        .unwrap();
     }
 
     let log_s = String::from_utf8(log).unwrap();
@@ -155,13 +192,34 @@ fn test_filetypes(
     }
 }
 
+#[test]
+fn test_completions_of_config_keys() {
+    let mut output = Vec::new();
+    completions::generate_shell_completions("bash", &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let keys: Vec<&str> = rip2::config::KEYS.iter().map(|(key, _)| *key).collect();
+    // Once for each of `get`, `set` and `unset`
+    assert_eq!(output.matches(&keys.join(" ")).count(), 3);
+}
+
+#[rstest]
+fn test_typed_yes(#[values("yes\n", "YES", "y\n", "yess", "", "q\n")] answer: &str) {
+    let result = rip2::util::typed_yes(Cursor::new(answer));
+    match answer {
+        "yes\n" | "YES" => assert!(result.unwrap()),
+        "q\n" => assert_eq!(result.unwrap_err().kind(), ErrorKind::Interrupted),
+        _ => assert!(!result.unwrap()),
+    }
+}
+
 #[rstest]
 fn test_prompt_read(#[values("y", "Y", "n", "N", "", "\n", "q", "Q", "k")] key: &str) {
     let input = Cursor::new(key);
     let result = rip2::util::yes_no_quit(input);
     match key {
-        "y" | "Y" => assert!(result.unwrap()),
-        "n" | "N" | "" | "\n" => assert!(!result.unwrap()),
+        "y" | "Y" => assert_eq!(result.unwrap(), Some(true)),
+        "n" | "N" => assert_eq!(result.unwrap(), Some(false)),
+        "" | "\n" => assert_eq!(result.unwrap(), None),
         "q" | "Q" => {
             let err = result.unwrap_err();
             assert_eq!(err.kind(), ErrorKind::Interrupted);
@@ -216,17 +274,14 @@ fn test_completions(
 
 #[rstest]
 fn test_graveyard_path() {
-    let _env_lock = aquire_lock();
-
-    // Clear env:
-    std::env::remove_var("RIP_GRAVEYARD");
-    std::env::remove_var("XDG_DATA_HOME");
+    // An environment without RIP_GRAVEYARD or XDG_DATA_HOME
+    let ctx = Context::new(std::env::temp_dir()).with_var("USER", "someone");
 
     // Check default graveyard path
-    let graveyard = rip2::get_graveyard(None);
+    let graveyard = rip2::get_graveyard(None, &ctx);
     assert_eq!(
         graveyard,
-        std::env::temp_dir().join(format!("graveyard-{}", rip2::util::get_user()))
+        std::env::temp_dir().join(format!("graveyard-{}", rip2::util::get_user(&ctx)))
     );
 }
 
@@ -257,3 +312,846 @@ fn fail_move_dir() {
         assert!(e.to_string().contains("Failed to remove dir"));
     }
 }
+
+#[rstest]
+fn test_delta_roundtrip(#[values(0, 10, 63, 64, 65, 1000)] edit_at: usize) {
+    let base: Vec<u8> = (0..5000_u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut new = base.clone();
+    new.insert(edit_at, b'x');
+    new.truncate(4000);
+    let encoded = delta::encode(&base, &new);
+    assert!(encoded.len() < new.len() / 10);
+    assert_eq!(delta::decode(&base, &encoded).unwrap(), new);
+
+    // Unrelated contents still round-trip, but aren't worth storing
+    let unrelated = vec![b'z'; 100];
+    assert_eq!(
+        delta::decode(&base, &delta::encode(&base, &unrelated)).unwrap(),
+        unrelated
+    );
+    assert!(delta::try_encode(&base, &unrelated).is_none());
+
+    // A corrupt delta is an error, not a panic
+    let mut corrupt = b"RIPDELTA1\n\0".to_vec();
+    corrupt.extend(u64::MAX.to_le_bytes());
+    corrupt.extend(2_u64.to_le_bytes());
+    assert!(delta::decode(&base, &corrupt).is_err());
+    let mut corrupt = b"RIPDELTA1\n\x01".to_vec();
+    corrupt.extend(u64::MAX.to_le_bytes());
+    assert!(delta::decode(&base, &corrupt).is_err());
+}
+
+#[rstest]
+fn test_parse_size() {
+    assert_eq!(parse_size("1024"), Ok(1024));
+    assert_eq!(parse_size("1K"), Ok(1024));
+    assert_eq!(parse_size("1.5MiB"), Ok(1024 * 1024 + 1024 * 512));
+    assert_eq!(parse_size("2 gb"), Ok(2 * 1024 * 1024 * 1024));
+    assert!(parse_size("ten").is_err());
+    assert!(parse_size("10 parsecs").is_err());
+}
+
+#[rstest]
+fn test_durations() {
+    assert_eq!(parse_duration("30s"), Ok(chrono::Duration::seconds(30)));
+    assert_eq!(parse_duration("10m"), Ok(chrono::Duration::minutes(10)));
+    assert_eq!(parse_duration("24h"), Ok(chrono::Duration::days(1)));
+    assert_eq!(parse_duration("1w"), Ok(chrono::Duration::weeks(1)));
+    assert!(parse_duration("soon").is_err());
+    assert!(parse_duration("3 fortnights").is_err());
+
+    let hours = |h: i64, m: i64| Ok(chrono::Duration::hours(h) + chrono::Duration::minutes(m));
+    assert_eq!(parse_duration("2h30m"), hours(2, 30));
+    assert_eq!(parse_duration("1 day, 6 hours"), hours(30, 0));
+    assert_eq!(parse_duration("90 Minutes"), hours(1, 30));
+    assert_eq!(parse_duration("PT2H30M"), hours(2, 30));
+    assert_eq!(parse_duration("P1DT6H"), hours(30, 0));
+    assert_eq!(parse_duration("P2W"), Ok(chrono::Duration::weeks(2)));
+    assert_eq!(parse_duration("PT90S"), Ok(chrono::Duration::seconds(90)));
+    for invalid in [
+        "", "30", "h", "2h30", "P", "PT", "P1M", "P1Y", "PT1D", "P1H",
+    ] {
+        assert!(parse_duration(invalid).is_err(), "{:?} parsed", invalid);
+    }
+    assert!(parse_duration("P1M").unwrap_err().contains("months vary"));
+    assert!(parse_duration("99999999999999w").is_err());
+
+    assert_eq!(humanize_duration(chrono::Duration::seconds(59)), "59s");
+    assert_eq!(humanize_duration(chrono::Duration::hours(47)), "1d");
+    assert_eq!(humanize_duration(chrono::Duration::zero()), "0s");
+}
+
+#[rstest]
+fn test_seeded_ids() {
+    let names = |ids: &IdGen| (0..3).map(|_| ids.next_id()).collect::<Vec<_>>();
+    let seeded = names(&IdGen::seeded(7));
+    assert_eq!(seeded, names(&IdGen::seeded(7)));
+    assert_ne!(seeded, names(&IdGen::seeded(8)));
+    assert!(seeded.iter().all(|id| id.len() == 8));
+    assert_ne!(seeded[0], seeded[1]);
+
+    let ctx = Context::new("/").with_var("RIP_SEED", "7");
+    assert_eq!(names(&get_ids(None, &ctx).unwrap()), seeded);
+    assert_ne!(names(&get_ids(Some(8), &ctx).unwrap()), seeded);
+    assert_eq!(names(&Context::new("/").with_seed(7).ids), seeded);
+    let ctx = Context::new("/").with_var("RIP_SEED", "lucky");
+    assert_eq!(
+        get_ids(None, &ctx).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+}
+
+#[cfg(unix)]
+#[rstest]
+fn test_create_dirs_with_permissions() {
+    use rip2::util::{create_dirs_with_permissions, DirCache, PermissionPolicy};
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmpdir = tempdir().unwrap();
+    let source = tmpdir.path().join("src").join("private").join("readonly");
+    fs::create_dir_all(&source).unwrap();
+    let mode_of = |path: &PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    for (dir, mode) in [(source.parent().unwrap(), 0o750), (source.as_path(), 0o555)] {
+        fs::set_permissions(dir, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    // The graveyard is nested differently from the source
+    let dest = tmpdir
+        .path()
+        .join("graveyard")
+        .join("nested")
+        .join("private")
+        .join("readonly");
+    let mut cache = DirCache::default();
+    create_dirs_with_permissions(&dest, Some(&source), PermissionPolicy::Mirror, &mut cache)
+        .unwrap();
+    assert_eq!(mode_of(&dest.parent().unwrap().to_path_buf()), 0o750);
+    // The owner keeps write access so the grave can be filled
+    assert_eq!(mode_of(&dest), 0o755);
+
+    // Cached directories aren't created again
+    fs::remove_dir(&dest).unwrap();
+    create_dirs_with_permissions(&dest, Some(&source), PermissionPolicy::Mirror, &mut cache)
+        .unwrap();
+    assert!(!dest.exists());
+    create_dirs_with_permissions(
+        &dest,
+        Some(&source),
+        PermissionPolicy::Mirror,
+        &mut DirCache::default(),
+    )
+    .unwrap();
+    assert!(dest.exists());
+}
+
+#[rstest]
+fn test_relative_path() {
+    use rip2::util::{normalize_path, relative_path};
+    assert_eq!(
+        normalize_path(Path::new("/a/b/../c/./d")),
+        PathBuf::from("/a/c/d")
+    );
+    assert_eq!(
+        relative_path(Path::new("/a/b"), Path::new("/a/c/d")),
+        PathBuf::from("../c/d")
+    );
+    assert_eq!(
+        relative_path(Path::new("/a/b"), Path::new("/a/b/c")),
+        PathBuf::from("c")
+    );
+    assert_eq!(
+        relative_path(Path::new("/a/b"), Path::new("/a/b")),
+        PathBuf::from(".")
+    );
+}
+
+#[cfg(unix)]
+#[rstest]
+fn test_ensure_within() {
+    use rip2::util::ensure_within;
+
+    let tmpdir = tempdir().unwrap();
+    let root = tmpdir.path().join("grave");
+    let outside = tmpdir.path().join("outside");
+    fs::create_dir_all(root.join("dir")).unwrap();
+    fs::create_dir(&outside).unwrap();
+    symlink(&outside, root.join("link")).unwrap();
+
+    assert!(ensure_within(&root, &root.join("dir").join("file")).is_ok());
+    for escaping in [
+        root.join("link").join("file"),
+        root.join("link"),
+        root.join("dir").join("..").join("..").join("outside"),
+        outside.join("file"),
+    ] {
+        assert_eq!(
+            ensure_within(&root, &escaping).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+    }
+}
+
+#[cfg(windows)]
+#[rstest]
+fn test_windows_prefix_mapping(
+    #[values(
+        (r"C:\foo\bar", r"G:\graveyard\DISK_C\foo\bar"),
+        (r"\\?\C:\foo", r"G:\graveyard\DISK_C\foo"),
+        (r"\\server\share\foo", r"G:\graveyard\UNC\server\share\foo"),
+        (r"\\?\UNC\server\share\foo", r"G:\graveyard\UNC\server\share\foo"),
+        (r"\\.\COM1\foo", r"G:\graveyard\DEVICE\COM1\foo")
+    )]
+    case: (&str, &str),
+) {
+    use rip2::util::{join_absolute, original_of_grave};
+    let graveyard = Path::new(r"G:\graveyard");
+    let (orig, grave) = case;
+    let joined = join_absolute(graveyard, orig);
+    assert_eq!(joined, PathBuf::from(grave));
+    // Verbatim paths come back in their plain form
+    let reversed = original_of_grave(graveyard, &joined).unwrap();
+    assert_eq!(join_absolute(graveyard, reversed), joined);
+}
+
+#[rstest]
+fn test_config_roundtrip() {
+    use rip2::config::Config;
+    let mut config = Config::default();
+    config
+        .insert("graveyard", "C:\\Users\\\"me\"\\graveyard")
+        .unwrap();
+    config.insert("min-free", "2GiB").unwrap();
+    config.insert("sandbox", "yes").unwrap();
+    assert!(config.insert("strategy", "sideways").is_err());
+    assert!(config.insert("colour", "blue").is_err());
+
+    let toml = config.to_toml();
+    assert!(toml.contains("sandbox = true"));
+    let parsed = Config::parse(&toml).unwrap();
+    assert_eq!(
+        parsed.get("graveyard"),
+        Some("C:\\Users\\\"me\"\\graveyard")
+    );
+    assert_eq!(parsed.get("min-free"), Some("2GiB"));
+    assert_eq!(parsed.get("sandbox"), Some("true"));
+    assert!(Config::parse("sandbox = [1]").is_err());
+    assert!(Config::parse("sandbox = ").is_err());
+}
+
+#[rstest]
+fn test_type_filters() {
+    use rip2::filetype::{parse_type_filter, sniff, Group, TypeFilter};
+    assert_eq!(
+        parse_type_filter("Image"),
+        Ok(TypeFilter::Group(Group::Image))
+    );
+    assert_eq!(
+        parse_type_filter(".PDF"),
+        Ok(TypeFilter::Extension("pdf".to_string()))
+    );
+    assert!(parse_type_filter("*.pdf").is_err());
+    assert!(parse_type_filter("").is_err());
+    assert!(TypeFilter::Group(Group::Image).matches("jpeg"));
+    assert!(!TypeFilter::Extension("pdf".to_string()).matches("png"));
+
+    let dir = tempdir().unwrap();
+    let file = dir.path().join("scan");
+    fs::write(&file, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+    assert_eq!(sniff(&file), Some("png"));
+    fs::write(&file, b"\0\0\0\x18ftypmp42").unwrap();
+    assert_eq!(sniff(&file), Some("mp4"));
+    fs::write(&file, b"just text").unwrap();
+    assert_eq!(sniff(&file), None);
+}
+
+#[rstest]
+fn test_media_previews() {
+    use rip2::media::describe;
+    let dir = tempdir().unwrap();
+    let file = dir.path().join("grave");
+    let preview = |bytes: &[u8]| {
+        fs::write(&file, bytes).unwrap();
+        describe(&file)
+    };
+
+    let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+    png.extend(640_u32.to_be_bytes());
+    png.extend(480_u32.to_be_bytes());
+    assert_eq!(preview(&png).as_deref(), Some("640x480"));
+    assert_eq!(preview(b"GIF89a\x10\x00\x20\x00").as_deref(), Some("16x32"));
+
+    // A JPEG with an EXIF date taken, in a little-endian TIFF structure
+    let mut tiff = b"II*\0\x08\0\0\0".to_vec();
+    tiff.extend(b"\x01\0\x69\x87\x04\0\x01\0\0\0\x1a\0\0\0\0\0\0\0");
+    tiff.extend(b"\x01\0\x03\x90\x02\0\x14\0\0\0\x2c\0\0\0\0\0\0\0");
+    tiff.extend(b"2023:06:01 12:34:56\0");
+    let mut exif = b"Exif\0\0".to_vec();
+    exif.extend(&tiff);
+    let mut jpeg = b"\xff\xd8\xff\xe1".to_vec();
+    jpeg.extend((exif.len() as u16 + 2).to_be_bytes());
+    jpeg.extend(&exif);
+    jpeg.extend(b"\xff\xc0\0\x11\x08\x0b\xd0\x0f\xc0\x03");
+    jpeg.extend([0; 9]);
+    assert_eq!(
+        preview(&jpeg).as_deref(),
+        Some("4032x3024, taken 2023:06:01 12:34:56")
+    );
+
+    // 90 seconds of 8kHz mono 8-bit audio
+    let mut wav = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0".to_vec();
+    wav.extend(8000_u32.to_le_bytes());
+    wav.extend(8000_u32.to_le_bytes());
+    wav.extend(b"\x01\0\x08\0data");
+    wav.extend(720000_u32.to_le_bytes());
+    assert_eq!(preview(&wav).as_deref(), Some("1:30"));
+
+    // An hour and a bit of video, with its movie header after the data
+    let mut mp4 = b"\0\0\0\x10ftypisom\0\0\0\0\0\0\0\x08mdat\0\0\0\x24moov".to_vec();
+    mp4.extend(b"\0\0\0\x1cmvhd\0\0\0\0\0\0\0\0\0\0\0\0");
+    mp4.extend(1000_u32.to_be_bytes());
+    mp4.extend(3723000_u32.to_be_bytes());
+    assert_eq!(preview(&mp4).as_deref(), Some("1:02:03"));
+
+    let pdf = b"%PDF-1.7\n1 0 obj <</Type /Pages /Kids [2 0 R 3 0 R]>>\n\
+                2 0 obj <</Type /Page>>\n3 0 obj <</Type/Page>>\n";
+    assert_eq!(preview(pdf).as_deref(), Some("2 pages"));
+
+    assert_eq!(preview(b"plain text"), None);
+}
+
+/// Test that truncated or garbled media files get no preview, rather than
+/// breaking seance
+#[rstest]
+fn test_media_previews_of_bad_files() {
+    use rip2::media::describe;
+    let dir = tempdir().unwrap();
+    let file = dir.path().join("grave");
+    let preview = |bytes: &[u8]| {
+        fs::write(&file, bytes).unwrap();
+        describe(&file)
+    };
+
+    assert_eq!(preview(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0"), None);
+    assert_eq!(preview(b"GIF89a\x10"), None);
+    // A frame header too short to hold the size
+    assert_eq!(preview(b"\xff\xd8\xff\xc0\x00\x04\x08\x00"), None);
+    // EXIF data cut off in its directory
+    assert_eq!(
+        preview(b"\xff\xd8\xff\xe1\x00\x10Exif\0\0II*\0\xff\xff\0\0"),
+        None
+    );
+    assert_eq!(preview(b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0"), None);
+    // A data chunk before any format chunk
+    assert_eq!(preview(b"RIFF\0\0\0\0WAVEdata\x04\0\0\0abcd"), None);
+    assert_eq!(preview(b"fLaC\0\0\0\x22\0\0"), None);
+    // A box that claims to run past the end of any file
+    let mut mp4 = b"\0\0\0\x01ftyp".to_vec();
+    mp4.extend(u64::MAX.to_be_bytes());
+    mp4.extend(b"\0\0\0\x08moov");
+    assert_eq!(preview(&mp4), None);
+    assert_eq!(preview(b"\0\0\0\x18ftypmp42\0\0\0\x08moov"), None);
+    assert_eq!(preview(b"%PDF-1.7\n/Type"), None);
+}
+
+#[rstest]
+fn test_stats_history() {
+    use rip2::stats::{print_history, Snapshot};
+    let snapshot = |line: &str| Snapshot::new(line).unwrap();
+    let snapshots = [
+        snapshot("2026-09-01,50,4096"),
+        snapshot("2026-10-01,1,1024"),
+        snapshot("2026-10-03,2,2048"),
+        snapshot("2026-10-11,4,4096"),
+    ];
+    assert_eq!(Snapshot::new("2026-10-01,x,1"), None);
+
+    let mut output = Vec::new();
+    print_history(&snapshots, 30, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[1].starts_with("2026-10-01       1     1.0 KiB  ##########"));
+    assert!(lines[3].ends_with(&"#".repeat(40)));
+    assert_eq!(lines[4], "Grew 3.0 KiB over 10 days, about 307 B a day");
+
+    let mut output = Vec::new();
+    print_history(&[], 30, &mut output).unwrap();
+    assert_eq!(output, b"No history yet\n");
+}
+
+#[rstest]
+fn test_config_profiles() {
+    use rip2::config::Config;
+    let config = Config::parse(
+        "strategy = \"copy\"\nmin-free = \"1G\"\n\n\
+         [profiles.nfs]\nstrategy = \"rename\"\n\n\
+         [profiles.\"local disk\"]\nmax-age = \"7d\"\n",
+    )
+    .unwrap();
+    let nfs = config.with_profile("nfs").unwrap();
+    assert_eq!(nfs.get("strategy"), Some("rename"));
+    assert_eq!(nfs.get("min-free"), Some("1G"));
+    assert_eq!(config.get("strategy"), Some("copy"));
+    assert!(config.with_profile("work").is_none());
+    assert_eq!(
+        config.profile_names().collect::<Vec<_>>(),
+        ["local disk", "nfs"]
+    );
+    assert_eq!(Config::parse(&config.to_toml()).unwrap(), config);
+
+    assert!(Config::parse("[profiles.nfs]\nstrategy = \"sideways\"\n").is_err());
+    assert!(Config::parse("[profiles.nfs]\nprofile = \"other\"\n").is_err());
+    assert!(Config::parse("profiles = 1\n").is_err());
+}
+
+#[rstest]
+fn test_serialization() {
+    use rip2::audit::{LogItem, Reason};
+    use rip2::record::RecordItem;
+    use rip2::schema::{self, Versioned};
+
+    let grave =
+        RecordItem::new("2024-01-01T12:00:00+00:00\t/home/a/f\t/graveyard/home/a/f\tnote=old")
+            .unwrap();
+    let json = serde_json::to_string(&grave).unwrap();
+    // Fields that aren't set are left out
+    assert_eq!(
+        json,
+        r#"{"time":"2024-01-01T12:00:00+00:00","orig":"/home/a/f","dest":"/graveyard/home/a/f","note":"old"}"#
+    );
+    assert_eq!(serde_json::from_str::<RecordItem>(&json).unwrap(), grave);
+
+    let purge = LogItem::new("2024-01-02T00:00:00+00:00\tpurge\tage\t12\t/graveyard/x").unwrap();
+    let json = serde_json::to_value(&purge).unwrap();
+    assert_eq!(json["bytes"], 12);
+    assert!(json.get("restored").is_none());
+    assert_eq!(serde_json::to_value(Reason::Age).unwrap(), "age");
+
+    let versioned = serde_json::to_string(&Versioned::new(vec![grave.clone()])).unwrap();
+    assert!(versioned.starts_with(&format!(r#"{{"version":{},"data":["#, schema::VERSION)));
+    let read: Versioned<Vec<RecordItem>> = serde_json::from_str(&versioned).unwrap();
+    assert_eq!(read.into_data().unwrap(), vec![grave]);
+
+    let future = Versioned {
+        version: schema::VERSION + 1,
+        data: (),
+    };
+    assert_eq!(
+        future.into_data().unwrap_err().kind(),
+        ErrorKind::InvalidData
+    );
+}
+
+#[rstest]
+fn test_portable_volume() {
+    use rip2::portable::Volume;
+    use rip2::RecordItem;
+
+    let (home, away) = (Path::new("/media/usb"), Path::new("/mnt/elsewhere"));
+    let volume = |root: &Path| Volume {
+        graveyard: root.join("graveyard"),
+        root: root.to_path_buf(),
+    };
+    let at_home = volume(home);
+    let grave = at_home.grave_of(&home.join("photos/cat.jpg"));
+    assert_eq!(grave, home.join("graveyard/photos/cat.jpg"));
+    assert_eq!(
+        at_home.original_of(&grave),
+        Some(home.join("photos/cat.jpg"))
+    );
+    // Off the drive, graves are where they'd always be
+    assert_eq!(
+        at_home.grave_of(Path::new("/etc/hosts")),
+        home.join("graveyard/etc/hosts")
+    );
+
+    let item = RecordItem::buried(chrono::Local::now(), home.join("photos/cat.jpg"), &grave);
+    let stored = at_home.store(item.clone());
+    assert_eq!(stored.orig, Path::new("photos/cat.jpg"));
+    assert_eq!(stored.dest, Path::new("photos/cat.jpg"));
+    assert_eq!(at_home.load(stored.clone()), item);
+
+    // Mounted somewhere else, the grave follows the drive
+    let moved = volume(away).load(stored);
+    assert_eq!(moved.orig, away.join("photos/cat.jpg"));
+    assert_eq!(moved.dest, away.join("graveyard/photos/cat.jpg"));
+
+    let elsewhere = RecordItem::buried(chrono::Local::now(), "/etc/hosts", "/tmp/g/etc/hosts");
+    assert_eq!(at_home.store(elsewhere.clone()), elsewhere);
+}
+
+#[rstest]
+fn test_grave_ids() {
+    use rip2::record::{self, RecordItem};
+
+    let now = chrono::Local::now();
+    let a = RecordItem::buried(now, "/home/a/f", "/graveyard/home/a/f");
+    let b = RecordItem::buried(now, "/home/a/g", "/graveyard/home/a/g");
+    let id = a.id.clone().unwrap();
+    assert_eq!(id.len(), 8);
+    assert!(id.bytes().all(|b| b.is_ascii_hexdigit()));
+    assert_ne!(a.id, b.id);
+    assert_eq!(RecordItem::new(&a.to_line()).unwrap().id, a.id);
+
+    let with_id = |id: &str| RecordItem {
+        id: Some(id.to_string()),
+        ..a.clone()
+    };
+    // Never fewer than four digits, and as many as it takes to be unique
+    assert_eq!(record::id_length(&[]), 4);
+    assert_eq!(
+        record::id_length(&[with_id("abcd1234"), with_id("abce0000")]),
+        4
+    );
+    assert_eq!(
+        record::id_length(&[with_id("abcd1234"), with_id("abcd5678")]),
+        5
+    );
+    assert_eq!(
+        record::id_length(&[with_id("abcd1234"), with_id("abcd1234")]),
+        8
+    );
+}
+
+#[rstest]
+fn test_record_escaping() {
+    use rip2::record::RecordItem;
+
+    let now = chrono::Local::now();
+    let item = RecordItem::buried(now, "/home/a/tab\there\nand\\back", "/g/home/a/tab\there");
+    let line = item.to_line();
+    assert_eq!(line.lines().count(), 1);
+    assert!(line.contains("\t/home/a/tab\\there\\nand\\\\back\t"));
+    assert!(line.contains("\tescaped=1"));
+    assert_eq!(RecordItem::new(&line).unwrap(), item);
+
+    // Lines without anything to escape are written as they always were,
+    // so backslashes in old records, like Windows paths, are read as-is
+    let plain = RecordItem::buried(now, r"C:\Users\tom", r"C:\g\DISK_C\Users\tom");
+    assert!(!plain.to_line().contains("escaped"));
+    let old = "2024-01-01T12:00:00+00:00\tC:\\Users\\tom\tC:\\g\\DISK_C\\Users\\tom";
+    assert_eq!(
+        RecordItem::new(old).unwrap().orig,
+        std::path::PathBuf::from(r"C:\Users\tom")
+    );
+}
+
+#[cfg(unix)]
+#[rstest]
+fn test_record_rewrite_is_atomic() {
+    use rip2::record::{Record, RECORD};
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmpdir = tempdir().unwrap();
+    let graveyard = tmpdir.path();
+    let record = Record::new(graveyard);
+    for name in ["a", "b"] {
+        record
+            .write_log(Path::new("/src").join(name), graveyard.join(name))
+            .unwrap();
+    }
+    let path = graveyard.join(RECORD);
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+    record.log_exhumed_graves(&[graveyard.join("a")]).unwrap();
+    let graves: Vec<PathBuf> = record.graves().unwrap().map(|grave| grave.dest).collect();
+    assert_eq!(graves, [graveyard.join("b")]);
+    // The new record took the place of the old, leaving nothing behind
+    // but the lock that writers take turns holding
+    let mut names: Vec<_> = fs::read_dir(graveyard)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, [RECORD, rip2::store::LOCK]);
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[rstest]
+fn test_claim_path() {
+    let tmpdir = tempdir().unwrap();
+    let (file, dir) = (tmpdir.path().join("file"), tmpdir.path().join("dir"));
+    fs::write(&file, "").unwrap();
+    fs::create_dir(&dir).unwrap();
+    let graves = tmpdir.path().join("graves");
+    fs::create_dir(&graves).unwrap();
+
+    let claims: Vec<PathBuf> = std::thread::scope(|scope| {
+        let claimers: Vec<_> = (0..8)
+            .map(|_| scope.spawn(|| claim_path(&graves.join("x"), &file, PermissionPolicy::Mirror)))
+            .collect();
+        claimers
+            .into_iter()
+            .map(|claimer| claimer.join().unwrap().unwrap())
+            .collect()
+    });
+    // Each claims a name of its own
+    let unique: std::collections::HashSet<_> = claims.iter().collect();
+    assert_eq!(unique.len(), 8);
+    assert!(claims.iter().all(|claim| claim.is_file()));
+
+    let claim = claim_path(&graves.join("x"), &dir, PermissionPolicy::Mirror).unwrap();
+    assert_eq!(claim, graves.join("x~8"));
+    assert!(claim.is_dir());
+}
+
+#[rstest]
+fn test_record_writers_take_turns() {
+    use rip2::record::Record;
+
+    let tmpdir = tempdir().unwrap();
+    let graveyard = tmpdir.path();
+    std::thread::scope(|scope| {
+        for writer in 0..4 {
+            scope.spawn(move || {
+                // Each as a separate rip would open it
+                let record = Record::new(graveyard).with_chain(true);
+                for i in 0..25 {
+                    let name = format!("{}-{}", writer, i);
+                    record
+                        .write_log(Path::new("/src").join(&name), graveyard.join(&name))
+                        .unwrap();
+                    if i % 5 == 0 {
+                        record
+                            .annotate(&graveyard.join(&name), "rewritten")
+                            .unwrap();
+                    }
+                }
+            });
+        }
+    });
+    let report = Record::new(graveyard).verify_chain().unwrap();
+    assert_eq!(report.entries, 100);
+    assert_eq!(report.chained, 100);
+    assert!(report.broken.is_empty());
+}
+
+#[rstest]
+fn test_record_readers_share() {
+    use rip2::record::Record;
+    use std::time::{Duration, Instant};
+
+    let tmpdir = tempdir().unwrap();
+    let graveyard = tmpdir.path();
+    let record = Record::new(graveyard);
+    record
+        .write_log(Path::new("/src/a"), graveyard.join("a"))
+        .unwrap();
+
+    let reading = record.graves().unwrap();
+    // Another reader doesn't wait
+    assert_eq!(Record::new(graveyard).graves().unwrap().count(), 1);
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        let writer = scope.spawn(|| {
+            Record::new(graveyard)
+                .write_log(Path::new("/src/b"), graveyard.join("b"))
+                .unwrap();
+            start.elapsed()
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(reading.count(), 1);
+        // A writer waits for the reader to be done
+        assert!(writer.join().unwrap() >= Duration::from_millis(200));
+    });
+    assert_eq!(record.graves().unwrap().count(), 2);
+}
+
+#[cfg(feature = "testkit")]
+#[rstest]
+fn test_testkit() {
+    use rip2::testkit::TestEnv;
+
+    let env = TestEnv::new();
+    env.file("dir/a.txt", "a");
+    env.file("b.txt", "b");
+    let output = env.run(&["dir", "--print-grave-path"]).unwrap();
+    assert_eq!(output.trim_end(), env.grave_of("dir").display().to_string());
+    env.assert_buried("dir");
+    env.assert_not_buried("b.txt");
+    assert_eq!(env.graves().len(), 1);
+
+    // Answers go to the prompts in turn
+    let grave = env.grave_of("dir");
+    env.run_answering(&[grave.to_str().unwrap()], &["n"])
+        .unwrap();
+    env.assert_buried("dir");
+    env.run_answering(&[grave.to_str().unwrap()], &["y"])
+        .unwrap();
+    assert!(env.graves().is_empty());
+    env.run(&["--no-such-flag"]).unwrap_err();
+}
+
+#[rstest]
+fn test_legacy_record_lines() {
+    use rip2::record::RecordItem;
+
+    let grave =
+        RecordItem::from_legacy("Thu Jan  1 08:05:09 1998\t/home/a\t/tmp/g/home/a").unwrap();
+    assert!(grave.time.starts_with("1998-01-01T08:05:09"));
+    assert_eq!(grave.orig, Path::new("/home/a"));
+    assert_eq!(grave.dest, Path::new("/tmp/g/home/a"));
+    assert!(grave.id.is_some());
+    // rip2's own lines aren't legacy ones
+    let line = RecordItem::buried(chrono::Local::now(), "/home/a", "/tmp/g/home/a").to_line();
+    assert_eq!(RecordItem::from_legacy(&line), None);
+}
+
+#[rstest]
+fn test_long_record_is_streamed(#[values(false, true)] database: bool) {
+    use rip2::record::{Record, RecordItem};
+    use rip2::store::{self, Backend};
+
+    if database && !cfg!(feature = "sqlite") {
+        return;
+    }
+    let tmpdir = tempdir().unwrap();
+    let graveyard = tmpdir.path();
+    // Spanning several blocks of the file and pages of the database
+    let now = chrono::Local::now();
+    let dests: Vec<PathBuf> = (0..2500)
+        .map(|i| graveyard.join(format!("{:0>100}", i)))
+        .collect();
+    Record::new(graveyard)
+        .replace(
+            dests
+                .iter()
+                .map(|dest| RecordItem::buried(now, "/src/file", dest)),
+        )
+        .unwrap();
+    if database {
+        store::convert(graveyard, Backend::Sqlite).unwrap();
+    }
+    let record = Record::new(graveyard);
+    let graves = || -> Vec<PathBuf> { record.graves().unwrap().map(|grave| grave.dest).collect() };
+    assert_eq!(graves(), dests);
+
+    // Graves looked up by path come in the order they were buried
+    let wanted = [
+        dests[2000].clone(),
+        graveyard.join("missing"),
+        dests[5].clone(),
+    ];
+    let found: Vec<PathBuf> = record
+        .graves_at(&wanted)
+        .unwrap()
+        .into_iter()
+        .map(|grave| grave.dest)
+        .collect();
+    assert_eq!(found, [dests[5].clone(), dests[2000].clone()]);
+
+    // The graves after the last one still there are dropped on the way
+    fs::write(&dests[1200], "").unwrap();
+    assert_eq!(record.get_last_bury().unwrap(), dests[1200]);
+    assert_eq!(graves(), dests[..=1200]);
+
+    // As are those between the last few, which stop at the one after them
+    for i in [1000, 1100] {
+        fs::write(&dests[i], "").unwrap();
+    }
+    assert_eq!(
+        record.get_last_n_buries(2).unwrap(),
+        [dests[1200].clone(), dests[1100].clone()]
+    );
+    let kept = [&dests[..=1100], &dests[1200..=1200]].concat();
+    assert_eq!(graves(), kept);
+    record.write_log("/src/file", &dests[1201]).unwrap();
+    assert_eq!(graves(), [&kept[..], &dests[1201..=1201]].concat());
+}
+
+#[cfg(unix)]
+#[rstest]
+fn test_dir_held_through_swap() {
+    use rip2::dirfd::Dir;
+    use std::ffi::OsStr;
+
+    let tmpdir = tempdir().unwrap();
+    let victim = tmpdir.path().join("victim");
+    fs::create_dir(&victim).unwrap();
+    fs::write(victim.join("keep.txt"), "keep").unwrap();
+    let target = tmpdir.path().join("target");
+    fs::create_dir_all(target.join("sub")).unwrap();
+    fs::write(target.join("sub/a.txt"), "a").unwrap();
+
+    let dir = Dir::open(&target).unwrap();
+    let sub = dir.open_dir(OsStr::new("sub")).unwrap();
+    // Swap the directory for a symlink out of the target once it's open
+    fs::rename(target.join("sub"), target.join("moved")).unwrap();
+    symlink(&victim, target.join("sub")).unwrap();
+    assert_eq!(sub.entries().unwrap(), ["a.txt"]);
+    sub.clear().unwrap();
+    assert!(victim.join("keep.txt").exists());
+    assert_eq!(fs::read_dir(target.join("moved")).unwrap().count(), 0);
+
+    // Nor is a symlink opened as a directory
+    assert!(dir.open_dir(OsStr::new("sub")).is_err());
+    assert!(Dir::open(&target.join("sub")).is_err());
+}
+
+#[rstest]
+fn test_record_format_upgrade() {
+    use rip2::record::{Record, FORMAT_VERSION, RECORD};
+
+    let tmpdir = tempdir().unwrap();
+    let graveyard = tmpdir.path();
+    let path = graveyard.join(RECORD);
+    // Written before the header had a version
+    let old = "Time\tOriginal\tDestination\n\
+               2024-01-01T12:00:00+00:00\t/src/a\t/graveyard/src/a\n";
+    fs::write(&path, old).unwrap();
+    let record = Record::new(graveyard);
+    let dests = |record: &Record| -> Vec<PathBuf> {
+        record.graves().unwrap().map(|grave| grave.dest).collect()
+    };
+    assert_eq!(dests(&record), [PathBuf::from("/graveyard/src/a")]);
+    // Reading leaves it as it was
+    assert_eq!(fs::read_to_string(&path).unwrap(), old);
+
+    // Writing brings it up to date first
+    record
+        .write_log(Path::new("/src/b"), Path::new("/graveyard/src/b"))
+        .unwrap();
+    let contents = fs::read_to_string(&path).unwrap();
+    assert!(contents.starts_with(&format!(
+        "Time\tOriginal\tDestination\tversion={}\n",
+        FORMAT_VERSION
+    )));
+    assert_eq!(
+        dests(&record),
+        [
+            PathBuf::from("/graveyard/src/a"),
+            PathBuf::from("/graveyard/src/b")
+        ]
+    );
+
+    // A newer format isn't guessed at
+    let newer = contents.replacen(&format!("version={}", FORMAT_VERSION), "version=99", 1);
+    fs::write(&path, newer).unwrap();
+    let err = record.graves().map(|_| ()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("upgrade rip"));
+}
+
+#[test]
+fn test_unified_diff() {
+    let old: String = (1..=20).map(|i| format!("{}\n", i)).collect();
+    let new: String = (1..=20)
+        .filter(|&i| i != 18)
+        .map(|i| match i {
+            2 => "two\n".to_string(),
+            i => format!("{}\n", i),
+        })
+        .collect();
+    assert_eq!(
+        diff::unified(&old, &new).unwrap(),
+        "@@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n\
+         @@ -15,6 +15,5 @@\n 15\n 16\n 17\n-18\n 19\n 20\n"
+    );
+    assert_eq!(diff::unified("", "a\n").unwrap(), "@@ -0,0 +1 @@\n+a\n");
+    assert_eq!(
+        diff::unified("a\n", "a").unwrap(),
+        "@@ -1 +1 @@\n-a\n+a\n\\ No newline at end of file\n"
+    );
+    assert_eq!(diff::unified("same\n", "same\n").unwrap(), "");
+}